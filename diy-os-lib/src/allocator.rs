@@ -13,7 +13,8 @@ pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
 
-#[global_allocator]
+// the host's allocator is used when running on a hosted os
+#[cfg_attr(not(feature = "host"), global_allocator)]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
 #[allow(fuzzy_provenance_casts)]
@@ -59,6 +60,17 @@ pub fn setup_heap(
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+}
+
+/// Returns the size of the kernel heap and how much of it is in use.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
 /// A wrapper around [`Spinlock`] to permit trait implementations.
 pub struct Locked<A> {
     inner: Spinlock<A>,
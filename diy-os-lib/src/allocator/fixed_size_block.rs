@@ -52,6 +52,33 @@ impl FixedSizeBlockAllocator {
         }
     }
 
+    /// Returns the size of the heap and how much of it is in use.
+    ///
+    /// Blocks sitting in the free lists are counted as free.
+    pub fn stats(&self) -> super::HeapStats {
+        let cached: usize = self
+            .list_heads
+            .iter()
+            .zip(BLOCK_SIZES)
+            .map(|(head, block_size)| {
+                let mut count = 0;
+                let mut node = head.as_deref();
+
+                while let Some(current) = node {
+                    count += 1;
+                    node = current.next.as_deref();
+                }
+
+                count * block_size
+            })
+            .sum();
+
+        super::HeapStats {
+            size: self.fallback_allocator.size(),
+            used: self.fallback_allocator.used() - cached,
+        }
+    }
+
     /// Allocates using the fallback allocator.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         self.fallback_allocator
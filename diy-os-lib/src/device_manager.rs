@@ -2,18 +2,37 @@ use crate::println;
 use alloc::sync::Arc;
 
 use alloc::boxed::Box;
-use anyhow::Error;
 
+use crate::device_manager::cache::BlockCache;
+use crate::device_manager::partition::{Partition, read_partion_table};
+use crate::memory::BootInfoFrameAllocator;
 use crate::multitasking::mutex::Mutex;
-use crate::pci;
-use crate::pci::ide::{IdeError, create_ide_controller};
-use crate::pci::{ClassCode, MassStorageSubclass};
+use crate::pci::ahci::AhciError;
+use crate::pci::ide::IdeError;
+use crate::pci::nvme::NvmeError;
+use crate::pci::virtio::blk::VirtioBlkError;
+use crate::pci::{self, PciDevice};
 use alloc::vec::Vec;
+use x86_64::structures::paging::OffsetPageTable;
+
+pub mod cache;
+pub mod driver;
+#[cfg(feature = "host")]
+pub mod file;
+pub mod partition;
+pub mod queue;
+pub mod ramdisk;
 
 pub struct DeviceManager {
     pub devices: Vec<Arc<Mutex<dyn Device>>>,
 
     pub block_devices: Vec<Arc<Mutex<dyn BlockDevice>>>,
+
+    /// Partitions of the disks in `block_devices`
+    pub partitions: Vec<Arc<Mutex<Partition>>>,
+
+    /// Every pci function and the name of the driver bound to it
+    pub pci_devices: Vec<(PciDevice, Option<&'static str>)>,
 }
 
 impl DeviceManager {
@@ -21,6 +40,10 @@ impl DeviceManager {
         for device in &self.devices {
             device.acquire().print_device();
         }
+
+        for partition in &self.partitions {
+            partition.acquire().print_device();
+        }
     }
 
     pub fn register_device(&mut self, device: Arc<Mutex<dyn Device>>) {
@@ -30,34 +53,97 @@ impl DeviceManager {
     pub fn register_block_device(&mut self, device: Arc<Mutex<dyn BlockDevice>>) {
         self.block_devices.push(device);
     }
-}
 
-const PCI_AVAILABLE: bool = true;
+    pub fn register_partition(&mut self, partition: Partition) -> Arc<Mutex<Partition>> {
+        let partition = Arc::new(Mutex::new(partition));
+
+        self.partitions.push(partition.clone());
+
+        partition
+    }
+
+    /// Returns the partitions of `disk` in the order of its partition table.
+    pub fn partitions_of(
+        &self,
+        disk: &Arc<Mutex<dyn BlockDevice>>,
+    ) -> impl Iterator<Item = &Arc<Mutex<Partition>>> {
+        self.partitions
+            .iter()
+            .filter(|partition| partition.acquire().is_on(disk))
+    }
+
+    /// Returns the pci functions no driver is bound to.
+    pub fn unbound_pci_devices(&self) -> impl Iterator<Item = &PciDevice> {
+        self.pci_devices
+            .iter()
+            .filter(|(_, driver)| driver.is_none())
+            .map(|(device, _)| device)
+    }
+
+    /// Puts a [`BlockCache`] of [`cache::DEFAULT_CAPACITY`] bytes in front of every block device,
+    /// the devices themselves stay in `devices`.
+    pub fn cache_block_devices(&mut self) {
+        for device in &mut self.block_devices {
+            *device = BlockCache::new(device.clone(), cache::DEFAULT_CAPACITY);
+        }
+    }
+
+    /// Reads the partition table of every block device and registers their partitions, as
+    /// children of the disk as well.
+    ///
+    /// Disks without a valid partition table are skipped.
+    pub fn scan_partitions(&mut self) {
+        for disk in self.block_devices.clone() {
+            match read_partion_table(&disk) {
+                Ok(entries) => {
+                    for (number, entry) in entries {
+                        let partition =
+                            self.register_partition(Partition::new(disk.clone(), entry, number));
+
+                        if !disk.acquire().add_child(&(partition as DeviceWrapped)) {
+                            log::debug!("disk doesn't keep track of partition {number}");
+                        }
+                    }
+                }
+                Err(err) => log::warn!("failed to read a partition table, {err}"),
+            }
+        }
+    }
+}
 
-/// # Errors
-/// Will error if ide controller fails to initialize
-pub fn init_device_manager() -> Result<DeviceManager, Error> {
+/// Binds a driver to every pci function that one supports, then caches the block devices found
+/// and scans them for partitions.
+pub fn init_device_manager(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> DeviceManager {
     let mut dm = DeviceManager {
         devices: Vec::new(),
         block_devices: Vec::new(),
+        partitions: Vec::new(),
+        pci_devices: Vec::new(),
     };
-    // check what buses/protocols supported, rn only pci, assuming it's available
-    const {
-        assert!(PCI_AVAILABLE);
-    }
 
-    let pci = pci::enumerate();
+    for device in pci::enumerate() {
+        let driver = driver::bind(device, &mut dm, mapper, frame_allocator);
 
-    if let Some(device) = pci.iter().find(|device| {
-        device.class_code == ClassCode::MassStorageController
-            && device.subclass == MassStorageSubclass::Ide
-    }) {
-        // assuming ide, I am lazyyy
-        let device = create_ide_controller(*device, &mut dm)?;
-        dm.register_device(Arc::new(Mutex::new(device)));
+        if driver.is_none() {
+            log::debug!(
+                "no driver for {:04x}:{:04x} at {:?}",
+                device.info.vendor_id,
+                device.info.device_num,
+                device.address
+            );
+        }
+
+        dm.pci_devices.push((device, driver));
     }
 
-    Ok(dm)
+    // partitions and filesystems go through the cache of their disk
+    dm.cache_block_devices();
+    dm.scan_partitions();
+
+    dm
 }
 
 type DeviceWrapped = Arc<Mutex<dyn Device>>;
@@ -79,12 +165,64 @@ pub trait Device: core::fmt::Debug + Send + Sync {
     fn as_block_device(&mut self) -> Option<&mut dyn BlockDevice> {
         None
     }
+
+    /// Adds a device on top of this one, like a partition of a disk, to its children. Returns
+    /// false if the device doesn't keep track of them.
+    fn add_child(&mut self, _child: &DeviceWrapped) -> bool {
+        false
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum BlockDeviceError {
     #[error("The device ran into the following error `{0:?}`")]
     ControllerError(#[from] IdeError),
+    #[error("The ahci port ran into the following error `{0:?}`")]
+    AhciError(#[from] AhciError),
+    #[error("The nvme namespace ran into the following error `{0:?}`")]
+    NvmeError(#[from] NvmeError),
+    #[error("The virtio block device ran into the following error `{0:?}`")]
+    VirtioBlkError(#[from] VirtioBlkError),
+    #[error("Access of `{count}` sectors at lba `{lba}` is outside of the `{sectors}` sectors")]
+    OutOfBounds { lba: u64, count: u16, sectors: u64 },
+    #[error("The buffer is `{len}` bytes but `{expected}` bytes are needed")]
+    BufferTooSmall { expected: usize, len: usize },
+    #[error("The device is read only")]
+    ReadOnly,
+    #[cfg(feature = "host")]
+    #[error("The image ran into the following error `{0}`")]
+    Io(#[from] std::io::Error),
+}
+
+/// Checks that `count` sectors from `lba` are on a drive of `sectors` sectors and fit in the
+/// buffer, returning the number of bytes that will be transferred.
+pub(crate) fn check_access(
+    sectors: u64,
+    sector_size: usize,
+    lba: u64,
+    count: u16,
+    buffer_len: usize,
+) -> Result<usize, BlockDeviceError> {
+    if lba
+        .checked_add(u64::from(count))
+        .is_none_or(|end| end > sectors)
+    {
+        return Err(BlockDeviceError::OutOfBounds {
+            lba,
+            count,
+            sectors,
+        });
+    }
+
+    let expected = usize::from(count) * sector_size;
+    if buffer_len < expected {
+        return Err(BlockDeviceError::BufferTooSmall {
+            expected,
+            len: buffer_len,
+        });
+    }
+
+    Ok(expected)
 }
 
 // TODO: proper errors
@@ -93,12 +231,16 @@ pub trait BlockDevice: Device {
     fn read_sectors(
         &mut self,
         lba: u64,
-        count: u8,
+        count: u16,
         buffer: &mut [u8],
     ) -> Result<(), BlockDeviceError>;
     /// # Errors
-    fn write_sectors(&mut self, lba: u64, count: u8, buffer: &[u8])
-    -> Result<(), BlockDeviceError>;
+    fn write_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &[u8],
+    ) -> Result<(), BlockDeviceError>;
     fn total_sectors(&self) -> u64;
     fn sector_size(&self) -> usize {
         512
@@ -0,0 +1,459 @@
+use alloc::boxed::Box;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use crate::device_manager::queue::{self, RequestHandle, RequestQueue};
+use crate::device_manager::{BlockDevice, BlockDeviceError, Device, DeviceWrapped, check_access};
+use crate::multitasking::mutex::Mutex;
+use crate::multitasking::sleep;
+use crate::timer::{Duration, Seconds};
+
+/// The number of bytes a cache holds by default.
+pub const DEFAULT_CAPACITY: usize = 0x4_0000;
+
+/// How often the flusher task writes dirty sectors back.
+pub const FLUSH_INTERVAL: Duration = Seconds(5).into();
+
+/// The number of sectors read past the end of a sequential read.
+const READ_AHEAD: u16 = 16;
+
+/// Every cache, for the flusher task.
+static CACHES: Mutex<Vec<Arc<Mutex<BlockCache>>>> = Mutex::new(Vec::new());
+
+/// Counters for tuning the cache, they only ever go up.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    /// Sectors read from the cache
+    pub hits: u64,
+    /// Sectors read that had to come from the device
+    pub misses: u64,
+    /// Sectors read from the device ahead of a sequential read
+    pub read_ahead: u64,
+    /// Sectors dropped to make room
+    pub evictions: u64,
+    /// Dirty sectors written to the device
+    pub write_backs: u64,
+}
+
+#[derive(Debug)]
+struct CachedSector {
+    data: Box<[u8]>,
+    /// Written to since it was read from or written to the device
+    dirty: bool,
+    /// The key of the sector in the lru order
+    last_used: u64,
+    /// The write that last dirtied it, to tell if it changed while being written back
+    written: u64,
+    /// A copy is being written back, it can't be evicted until that's done
+    flushing: bool,
+}
+
+/// A run of consecutive dirty sectors copied out of a cache to be written back.
+struct DirtyRun {
+    lba: u64,
+    /// The write that last dirtied each sector
+    written: Vec<u64>,
+    /// Moved into the write request once it's submitted
+    data: Vec<u8>,
+}
+
+/// A write-back cache of the sectors of a block device.
+///
+/// Writes only reach the device when the sector is evicted or the cache is synced with [`sync`],
+/// which the flusher task does every [`FLUSH_INTERVAL`]. The least recently used sector is
+/// evicted once the cache is full.
+///
+/// The device is only reached through its [`RequestQueue`], so the cache never holds the device
+/// and its transfers are ordered and merged with everyone else's.
+#[derive(Debug)]
+pub struct BlockCache {
+    device: Arc<Mutex<dyn BlockDevice>>,
+    queue: Arc<RequestQueue>,
+    sectors: u64,
+    sector_size: usize,
+    /// The most sectors held at once
+    capacity: usize,
+    cached: BTreeMap<u64, CachedSector>,
+    /// The cached lbas by when they were last used, the first is the least recently used
+    lru: BTreeMap<u64, u64>,
+    next_use: u64,
+    /// The number of writes to the cache
+    writes: u64,
+    /// Where the last read ended, a read starting there is sequential
+    sequential_lba: Option<u64>,
+    stats: CacheStats,
+    /// The partitions on the device, weak since they hold the cache
+    partitions: Vec<Weak<Mutex<dyn Device>>>,
+}
+
+impl BlockCache {
+    /// Creates a cache of `device` holding `capacity` bytes and registers it with the flusher
+    /// task.
+    pub fn new(device: Arc<Mutex<dyn BlockDevice>>, capacity: usize) -> Arc<Mutex<Self>> {
+        let (sectors, sector_size) =
+            device.with_ref(|device| (device.total_sectors(), device.sector_size()));
+
+        let cache = Arc::new(Mutex::new(Self {
+            queue: queue::queue_for(&device),
+            device,
+            sectors,
+            sector_size,
+            capacity: (capacity / sector_size).max(1),
+            cached: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            next_use: 0,
+            writes: 0,
+            sequential_lba: None,
+            stats: CacheStats::default(),
+            partitions: Vec::new(),
+        }));
+
+        CACHES.with_mut_ref(|caches| caches.push(cache.clone()));
+
+        cache
+    }
+
+    pub const fn device(&self) -> &Arc<Mutex<dyn BlockDevice>> {
+        &self.device
+    }
+
+    pub const fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// The number of sectors held and how many of them are dirty.
+    pub fn usage(&self) -> (usize, usize) {
+        (
+            self.cached.len(),
+            self.cached.values().filter(|sector| sector.dirty).count(),
+        )
+    }
+
+    /// The most sectors held at once.
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Moves `lba` to the back of the lru order.
+    fn touch(&mut self, lba: u64) {
+        let Some(sector) = self.cached.get_mut(&lba) else {
+            return;
+        };
+
+        self.lru.remove(&sector.last_used);
+
+        sector.last_used = self.next_use;
+        self.lru.insert(self.next_use, lba);
+        self.next_use += 1;
+    }
+
+    /// Caches `data` as the contents of `lba`, evicting the least recently used sector if the
+    /// cache is full.
+    ///
+    /// # Errors
+    /// Will error if writing back an evicted dirty sector fails
+    fn insert(&mut self, lba: u64, data: &[u8], dirty: bool) -> Result<(), BlockDeviceError> {
+        if dirty {
+            self.writes += 1;
+        }
+
+        if let Some(sector) = self.cached.get_mut(&lba) {
+            sector.data.copy_from_slice(data);
+
+            if dirty {
+                sector.dirty = true;
+                sector.written = self.writes;
+            }
+
+            self.touch(lba);
+
+            return Ok(());
+        }
+
+        if self.cached.len() >= self.capacity {
+            self.evict()?;
+        }
+
+        self.cached.insert(
+            lba,
+            CachedSector {
+                data: data.into(),
+                dirty,
+                last_used: self.next_use,
+                written: self.writes,
+                flushing: false,
+            },
+        );
+        self.lru.insert(self.next_use, lba);
+        self.next_use += 1;
+
+        Ok(())
+    }
+
+    /// Drops the least recently used sector, writing it back first if it's dirty.
+    ///
+    /// Sectors being written back by [`sync`] are skipped, if that's all of them the cache grows
+    /// past its capacity until the write back is done.
+    fn evict(&mut self) -> Result<(), BlockDeviceError> {
+        let Some((&last_used, &lba)) = self.lru.iter().find(|(_, lba)| !self.cached[lba].flushing)
+        else {
+            return Ok(());
+        };
+
+        if self.cached[&lba].dirty {
+            self.queue
+                .write(lba, 1, self.cached[&lba].data.to_vec())?
+                .wait()?;
+
+            self.stats.write_backs += 1;
+        }
+
+        self.lru.remove(&last_used);
+        self.cached.remove(&lba);
+        self.stats.evictions += 1;
+
+        Ok(())
+    }
+
+    /// Reads `count` sectors from `lba` on the device and caches them, returning the sectors.
+    fn fill(&mut self, lba: u64, count: u16) -> Result<Vec<u8>, BlockDeviceError> {
+        let data = self.queue.read(lba, count)?.wait()?;
+
+        for (lba, sector) in (lba..).zip(data.chunks_exact(self.sector_size)) {
+            self.insert(lba, sector, false)?;
+        }
+
+        Ok(data)
+    }
+
+    /// Reads the uncached sectors after `lba`, up to the first one that's cached.
+    fn read_ahead(&mut self, lba: u64) -> Result<(), BlockDeviceError> {
+        let count = (lba..self.sectors)
+            .take(usize::from(READ_AHEAD))
+            .take_while(|lba| !self.cached.contains_key(lba))
+            .count();
+
+        if count == 0 {
+            return Ok(());
+        }
+
+        self.fill(lba, u16::try_from(count).unwrap())?;
+        self.stats.read_ahead += count as u64;
+
+        Ok(())
+    }
+
+    /// Copies out the dirty sectors in runs of consecutive sectors, marking them as being
+    /// written back.
+    fn take_dirty_runs(&mut self) -> Vec<DirtyRun> {
+        let dirty: Vec<u64> = self
+            .cached
+            .iter()
+            .filter(|(_, sector)| sector.dirty && !sector.flushing)
+            .map(|(&lba, _)| lba)
+            .collect();
+
+        dirty
+            .chunk_by(|a, b| a + 1 == *b)
+            .flat_map(|run| run.chunks(usize::from(u16::MAX)))
+            .map(|run| {
+                let mut data = Vec::with_capacity(run.len() * self.sector_size);
+                let mut written = Vec::with_capacity(run.len());
+
+                for lba in run {
+                    let sector = self.cached.get_mut(lba).unwrap();
+
+                    sector.flushing = true;
+                    data.extend_from_slice(&sector.data);
+                    written.push(sector.written);
+                }
+
+                DirtyRun {
+                    lba: run[0],
+                    written,
+                    data,
+                }
+            })
+            .collect()
+    }
+
+    /// Ends the write back of `run`, the sectors that weren't written again since it was taken
+    /// are clean if it was `written`.
+    fn finish_run(&mut self, run: &DirtyRun, written: bool) {
+        for (lba, &version) in (run.lba..).zip(&run.written) {
+            if let Some(sector) = self.cached.get_mut(&lba) {
+                sector.flushing = false;
+
+                if written && sector.written == version {
+                    sector.dirty = false;
+                }
+            }
+        }
+
+        if written {
+            self.stats.write_backs += run.written.len() as u64;
+        }
+    }
+}
+
+/// Writes every dirty sector of `cache` back to the device, runs of consecutive sectors are
+/// written together.
+///
+/// Every run is submitted before waiting on any, so the queue can order them. The cache isn't
+/// held while waiting, so reads and writes through it aren't stuck behind the write back.
+///
+/// # Errors
+/// Will return the first error, the sectors that weren't written stay dirty
+pub fn sync(cache: &Mutex<BlockCache>) -> Result<(), BlockDeviceError> {
+    let (queue, mut runs) =
+        cache.with_mut_ref(|cache| (cache.queue.clone(), cache.take_dirty_runs()));
+
+    let handles: Vec<_> = runs
+        .iter_mut()
+        .map(|run| {
+            let count = u16::try_from(run.written.len()).unwrap();
+
+            queue.write(run.lba, count, core::mem::take(&mut run.data))
+        })
+        .collect();
+
+    let mut result = Ok(());
+
+    for (run, handle) in runs.iter().zip(handles) {
+        let written = handle.and_then(RequestHandle::wait);
+
+        cache.with_mut_ref(|cache| cache.finish_run(run, written.is_ok()));
+
+        if let Err(err) = written
+            && result.is_ok()
+        {
+            result = Err(err);
+        }
+    }
+
+    result
+}
+
+impl Device for BlockCache {
+    /// The cached device followed by its partitions.
+    fn children(&self) -> Option<Box<dyn Iterator<Item = DeviceWrapped>>> {
+        let partitions: Vec<DeviceWrapped> =
+            self.partitions.iter().filter_map(Weak::upgrade).collect();
+
+        Some(Box::new(
+            core::iter::once(self.device.clone() as Arc<Mutex<dyn Device>>).chain(partitions),
+        ))
+    }
+
+    fn add_child(&mut self, child: &DeviceWrapped) -> bool {
+        self.partitions.push(Arc::downgrade(child));
+
+        true
+    }
+
+    fn as_block_device(&mut self) -> Option<&mut dyn BlockDevice> {
+        Some(self)
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn read_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let length = check_access(self.sectors, self.sector_size, lba, count, buffer.len())?;
+        let sequential = self.sequential_lba == Some(lba);
+
+        let buffer = &mut buffer[..length];
+        let mut index = 0;
+
+        while index < usize::from(count) {
+            let current = lba + index as u64;
+            let offset = index * self.sector_size;
+
+            if let Some(sector) = self.cached.get(&current) {
+                buffer[offset..offset + self.sector_size].copy_from_slice(&sector.data);
+
+                self.touch(current);
+                self.stats.hits += 1;
+                index += 1;
+
+                continue;
+            }
+
+            // read the whole run of uncached sectors at once
+            let run = (current..lba + u64::from(count))
+                .take_while(|lba| !self.cached.contains_key(lba))
+                .count();
+
+            let data = self.fill(current, u16::try_from(run).unwrap())?;
+            buffer[offset..offset + data.len()].copy_from_slice(&data);
+
+            self.stats.misses += run as u64;
+            index += run;
+        }
+
+        let end = lba + u64::from(count);
+        self.sequential_lba = Some(end);
+
+        if sequential && let Err(err) = self.read_ahead(end) {
+            log::warn!("block cache read ahead failed, {err}");
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        let length = check_access(self.sectors, self.sector_size, lba, count, buffer.len())?;
+
+        for (lba, data) in (lba..).zip(buffer[..length].chunks_exact(self.sector_size)) {
+            self.insert(lba, data, true)?;
+        }
+
+        Ok(())
+    }
+
+    fn total_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+}
+
+/// Returns every cache in the order they were created.
+pub fn caches() -> Vec<Arc<Mutex<BlockCache>>> {
+    CACHES.with_ref(Clone::clone)
+}
+
+/// Writes the dirty sectors of every cache back.
+///
+/// # Errors
+/// Will return the first error, the other caches are still synced
+pub fn sync_all() -> Result<(), BlockDeviceError> {
+    // the registry isn't held while writing
+    caches()
+        .iter()
+        .map(|cache| sync(cache))
+        .fold(Ok(()), Result::and)
+}
+
+/// Syncs every cache each [`FLUSH_INTERVAL`].
+pub fn flusher_task() -> ! {
+    loop {
+        sleep(FLUSH_INTERVAL);
+
+        if let Err(err) = sync_all() {
+            log::error!("failed to write back the block caches, {err}");
+        }
+    }
+}
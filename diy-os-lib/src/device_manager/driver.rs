@@ -0,0 +1,127 @@
+use alloc::vec::Vec;
+use anyhow::Error;
+use x86_64::structures::paging::OffsetPageTable;
+
+use crate::device_manager::DeviceManager;
+use crate::memory::BootInfoFrameAllocator;
+use crate::multitasking::mutex::Mutex;
+use crate::pci::{self, ClassCode, MassStorageSubclass, PciDevice};
+
+/// The drivers that come with the kernel, tried first and in this order.
+const BUILTIN_DRIVERS: [&PciDriver; 4] = [
+    &pci::ide::DRIVER,
+    &pci::ahci::DRIVER,
+    &pci::nvme::DRIVER,
+    &pci::virtio::blk::DRIVER,
+];
+
+/// Drivers registered with [`register_driver`], tried after the built in ones.
+static DRIVERS: Mutex<Vec<&'static PciDriver>> = Mutex::new(Vec::new());
+
+/// The functions a driver supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciMatch {
+    /// Functions of a class and subclass, with any programming interface if it's [`None`]
+    Class {
+        class: u8,
+        subclass: u8,
+        prog_if: Option<u8>,
+    },
+    /// A device of a vendor
+    Id { vendor: u16, device: u16 },
+}
+
+impl PciMatch {
+    /// Matches mass storage controllers of `subclass`, with any programming interface if
+    /// `prog_if` is [`None`].
+    pub const fn mass_storage(subclass: MassStorageSubclass, prog_if: Option<u8>) -> Self {
+        Self::Class {
+            class: ClassCode::MassStorageController.into_bits(),
+            subclass: subclass.into_bits(),
+            prog_if,
+        }
+    }
+
+    pub fn matches(self, device: &PciDevice) -> bool {
+        let info = device.info;
+
+        match self {
+            Self::Class {
+                class,
+                subclass,
+                prog_if,
+            } => {
+                info.class_code.into_bits() == class
+                    && info.subclass == subclass
+                    && prog_if.is_none_or(|prog_if| info.prog_if == prog_if)
+            }
+            Self::Id { vendor, device } => info.vendor_id == vendor && info.device_num == device,
+        }
+    }
+}
+
+/// Sets up the function for the driver, registering the devices it finds with the device
+/// manager.
+pub type Probe = fn(
+    PciDevice,
+    &mut DeviceManager,
+    &mut OffsetPageTable<'static>,
+    &mut BootInfoFrameAllocator,
+) -> Result<(), Error>;
+
+/// A driver for pci functions, it's bound to the functions it supports if probing them succeeds.
+#[derive(Debug)]
+pub struct PciDriver {
+    pub name: &'static str,
+    /// The driver supports a function if any of these match it
+    pub matches: &'static [PciMatch],
+    pub probe: Probe,
+}
+
+impl PciDriver {
+    pub fn supports(&self, device: &PciDevice) -> bool {
+        self.matches.iter().any(|pattern| pattern.matches(device))
+    }
+}
+
+/// Adds a driver for the functions found from now on, the built in drivers are tried first.
+pub fn register_driver(driver: &'static PciDriver) {
+    DRIVERS.with_mut_ref(|drivers| drivers.push(driver));
+}
+
+/// Returns every driver in the order they're tried.
+pub fn drivers() -> Vec<&'static PciDriver> {
+    BUILTIN_DRIVERS
+        .into_iter()
+        .chain(DRIVERS.with_ref(Clone::clone))
+        .collect()
+}
+
+/// Probes `device` with each driver that supports it until one succeeds, returning the name of
+/// the driver bound to it.
+pub fn bind(
+    device: PciDevice,
+    device_manager: &mut DeviceManager,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Option<&'static str> {
+    for driver in drivers()
+        .into_iter()
+        .filter(|driver| driver.supports(&device))
+    {
+        match (driver.probe)(device, device_manager, mapper, frame_allocator) {
+            Ok(()) => {
+                log::info!("bound the {} driver to {:?}", driver.name, device.address);
+
+                return Some(driver.name);
+            }
+            Err(err) => log::warn!(
+                "the {} driver failed to probe {:?}, {err}",
+                driver.name,
+                device.address
+            ),
+        }
+    }
+
+    None
+}
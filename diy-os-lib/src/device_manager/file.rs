@@ -0,0 +1,94 @@
+use alloc::boxed::Box;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::device_manager::{BlockDevice, BlockDeviceError, Device, DeviceWrapped, check_access};
+
+/// A block device backed by a disk image on the host, for testing drivers with `cargo test`.
+#[derive(Debug)]
+pub struct FileBlockDevice {
+    file: File,
+    sectors: u64,
+    sector_size: usize,
+    read_only: bool,
+}
+
+impl FileBlockDevice {
+    /// Opens the image at `path`, a partial sector at the end isn't accessible.
+    ///
+    /// # Errors
+    /// Will error if the image can't be opened
+    pub fn open(path: impl AsRef<Path>, sector_size: usize, read_only: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+
+        let sectors = file.metadata()?.len() / sector_size as u64;
+
+        Ok(Self {
+            file,
+            sectors,
+            sector_size,
+            read_only,
+        })
+    }
+
+    /// Seeks to `lba` once the access is checked, returning the number of bytes to transfer.
+    fn seek(&mut self, lba: u64, count: u16, buffer_len: usize) -> Result<usize, BlockDeviceError> {
+        let length = check_access(self.sectors, self.sector_size, lba, count, buffer_len)?;
+
+        self.file
+            .seek(SeekFrom::Start(lba * self.sector_size as u64))?;
+
+        Ok(length)
+    }
+}
+
+impl Device for FileBlockDevice {
+    fn children(&self) -> Option<Box<dyn Iterator<Item = DeviceWrapped>>> {
+        None
+    }
+
+    fn as_block_device(&mut self) -> Option<&mut dyn BlockDevice> {
+        Some(self)
+    }
+}
+
+impl BlockDevice for FileBlockDevice {
+    fn read_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let length = self.seek(lba, count, buffer.len())?;
+
+        self.file.read_exact(&mut buffer[..length])?;
+
+        Ok(())
+    }
+
+    fn write_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        if self.read_only {
+            return Err(BlockDeviceError::ReadOnly);
+        }
+
+        let length = self.seek(lba, count, buffer.len())?;
+
+        self.file.write_all(&buffer[..length])?;
+
+        Ok(())
+    }
+
+    fn total_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+}
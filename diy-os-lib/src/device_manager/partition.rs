@@ -0,0 +1,146 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::device_manager::{BlockDevice, BlockDeviceError, Device, DeviceWrapped};
+use crate::filesystem::FileSystemSetupError;
+use crate::filesystem::gpt::{PartionTableHeader, PartitionEntry, mbr::MBR};
+use crate::multitasking::mutex::Mutex;
+
+/// A partition of a disk, sector 0 is the first sector of the partition.
+///
+/// Every access is checked against the size of the partition so a filesystem can't read or
+/// write outside of it.
+#[derive(Debug)]
+pub struct Partition {
+    parent: Arc<Mutex<dyn BlockDevice>>,
+    entry: PartitionEntry,
+    number: usize,
+}
+
+impl Partition {
+    pub const fn new(
+        parent: Arc<Mutex<dyn BlockDevice>>,
+        entry: PartitionEntry,
+        number: usize,
+    ) -> Self {
+        Self {
+            parent,
+            entry,
+            number,
+        }
+    }
+
+    pub const fn parent(&self) -> &Arc<Mutex<dyn BlockDevice>> {
+        &self.parent
+    }
+
+    /// The entry describing this partition, MBR partitions are converted to a GPT entry.
+    pub const fn entry(&self) -> &PartitionEntry {
+        &self.entry
+    }
+
+    /// The position of the partition in the partition table, starting at 1.
+    pub const fn number(&self) -> usize {
+        self.number
+    }
+
+    /// Returns true if this is a partition of `disk`.
+    pub fn is_on(&self, disk: &Arc<Mutex<dyn BlockDevice>>) -> bool {
+        core::ptr::addr_eq(Arc::as_ptr(&self.parent), Arc::as_ptr(disk))
+    }
+
+    /// Returns the lba on the parent of `lba`, if `count` sectors from it are in the partition.
+    fn translate(&self, lba: u64, count: u16) -> Result<u64, BlockDeviceError> {
+        let sectors = self.total_sectors();
+
+        if lba
+            .checked_add(u64::from(count))
+            .is_none_or(|end| end > sectors)
+        {
+            return Err(BlockDeviceError::OutOfBounds {
+                lba,
+                count,
+                sectors,
+            });
+        }
+
+        Ok(self.entry.starting_lba.get() + lba)
+    }
+}
+
+impl Device for Partition {
+    fn children(&self) -> Option<Box<dyn Iterator<Item = DeviceWrapped>>> {
+        None
+    }
+
+    fn as_block_device(&mut self) -> Option<&mut dyn BlockDevice> {
+        Some(self)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn read_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let lba = self.translate(lba, count)?;
+
+        self.parent.acquire().read_sectors(lba, count, buffer)
+    }
+
+    fn write_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        let lba = self.translate(lba, count)?;
+
+        self.parent.acquire().write_sectors(lba, count, buffer)
+    }
+
+    fn total_sectors(&self) -> u64 {
+        self.entry.ending_lba.get() - self.entry.starting_lba.get() + 1
+    }
+
+    fn sector_size(&self) -> usize {
+        self.parent.acquire().sector_size()
+    }
+}
+
+/// Reads the partition table of `disk`, returning the used entries with their number.
+///
+/// Disks with a valid MBR that isn't protective are read as MBR, including logical
+/// partitions which are numbered after the primary partitions, otherwise the GPT is used and
+/// partitions are numbered by their slot.
+///
+/// # Errors
+///
+/// Returns [`FileSystemSetupError`] if:
+/// - the GPT header is missing, corrupt, or fails CRC validation
+/// - an extended boot record is invalid
+/// - the device read fails
+pub fn read_partion_table(
+    disk: &Arc<Mutex<dyn BlockDevice>>,
+) -> Result<Vec<(usize, PartitionEntry)>, FileSystemSetupError> {
+    match MBR::from_device(disk, 0) {
+        Ok(mbr) if !mbr.is_protective() => {
+            log::debug!("found a mbr partion table");
+
+            Ok((1..)
+                .zip(mbr.partions(disk)?.iter().map(PartitionEntry::from))
+                .collect())
+        }
+        _ => {
+            let header = PartionTableHeader::from_device(disk)?;
+
+            Ok((1..)
+                .zip(header.read_entries(disk)?)
+                .filter(|(_, entry)| entry.partion_type_guid.get() != 0)
+                .collect())
+        }
+    }
+}
@@ -0,0 +1,604 @@
+use alloc::boxed::Box;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::device_manager::{BlockDevice, BlockDeviceError, check_access};
+use crate::multitasking::mutex::Mutex;
+use crate::multitasking::{BlockedReason, SCHEDULER, Scheduler, block_task, current_task_waker};
+
+/// Every queue, for the io task.
+static QUEUES: Mutex<Vec<Arc<RequestQueue>>> = Mutex::new(Vec::new());
+
+/// Wakes the io task up when a request is submitted, set once it's running.
+static IO_TASK: Mutex<Option<Waker>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// The buffer that was read into or written from, or why the transfer failed.
+pub type RequestResult = Result<Vec<u8>, BlockDeviceError>;
+
+/// Called by the io task once a request is done.
+pub type Callback = Box<dyn FnOnce(RequestResult) + Send>;
+
+/// How the submitter of a request is told it's done.
+enum Completion {
+    Callback(Callback),
+    Handle(Arc<Mutex<HandleState>>),
+}
+
+impl fmt::Debug for Completion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Callback(_) => f.write_str("Callback"),
+            Self::Handle(state) => f.debug_tuple("Handle").field(state).finish(),
+        }
+    }
+}
+
+impl Completion {
+    fn complete(self, result: RequestResult) {
+        match self {
+            Self::Callback(callback) => callback(result),
+            Self::Handle(state) => {
+                let waker = state.with_mut_ref(|state| {
+                    state.result = Some(result);
+                    state.waker.take()
+                });
+
+                // the state isn't held while waking
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct HandleState {
+    result: Option<RequestResult>,
+    /// Woken once the result is set
+    waker: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct Request {
+    operation: Operation,
+    lba: u64,
+    count: u16,
+    /// Exactly `count` sectors long
+    buffer: Vec<u8>,
+    completion: Completion,
+}
+
+impl Request {
+    const fn end(&self) -> u64 {
+        self.lba + u64::from(self.count)
+    }
+
+    /// Returns true if the requests have to be done in the order they were submitted, which is
+    /// when they overlap and either writes.
+    fn conflicts(&self, other: &Self) -> bool {
+        (self.operation == Operation::Write || other.operation == Operation::Write)
+            && self.lba < other.end()
+            && other.lba < self.end()
+    }
+}
+
+/// Counters for tuning the queue, they only ever go up.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QueueStats {
+    pub submitted: u64,
+    /// Requests done as part of another request's transfer
+    pub merged: u64,
+    /// Transfers sent to the device
+    pub transfers: u64,
+}
+
+/// The requests waiting for the device, keyed by their lba then the order they were submitted.
+type Key = (u64, u64);
+
+#[derive(Debug)]
+struct Pending {
+    requests: BTreeMap<Key, Request>,
+    next_sequence: u64,
+    /// Where the elevator is, the end of the last transfer going up or its start going down
+    head: u64,
+    ascending: bool,
+    stats: QueueStats,
+}
+
+impl Pending {
+    /// The request the elevator goes to next, it keeps going in its direction and turns around
+    /// once there's nothing left that way.
+    fn next_key(&mut self) -> Option<Key> {
+        for _ in 0..2 {
+            let key = if self.ascending {
+                self.requests
+                    .range((self.head, 0)..)
+                    .next()
+                    .map(|(&key, _)| key)
+            } else {
+                // the first submitted of the closest lba below
+                self.requests
+                    .range(..(self.head, 0))
+                    .next_back()
+                    .and_then(|(&(lba, _), _)| self.requests.range((lba, 0)..).next())
+                    .map(|(&key, _)| key)
+            };
+
+            if key.is_some() {
+                return key;
+            }
+
+            self.ascending = !self.ascending;
+        }
+
+        None
+    }
+
+    /// Returns the first submitted request that has to be done before `key`, or `key` if there
+    /// isn't one.
+    fn first_dependency(&self, mut key: Key) -> Key {
+        loop {
+            let request = &self.requests[&key];
+
+            let earlier = self
+                .requests
+                .iter()
+                .filter(|&(&(_, sequence), other)| sequence < key.1 && other.conflicts(request))
+                .min_by_key(|&(&(_, sequence), _)| sequence)
+                .map(|(&key, _)| key);
+
+            match earlier {
+                Some(earlier) => key = earlier,
+                None => return key,
+            }
+        }
+    }
+
+    /// Returns a request that can be merged onto `batch`, one starting at `lba` if `after`
+    /// otherwise one ending at it.
+    fn mergeable(&self, batch: &VecDeque<Request>, lba: u64, after: bool) -> Option<Key> {
+        let operation = batch[0].operation;
+        let count: u32 = batch.iter().map(|request| u32::from(request.count)).sum();
+
+        let mut candidates = self.requests.iter().filter(|(_, request)| {
+            request.operation == operation
+                && u16::try_from(count + u32::from(request.count)).is_ok()
+        });
+
+        let key = if after {
+            candidates.find(|(_, request)| request.lba == lba)
+        } else {
+            candidates.find(|(_, request)| request.end() == lba && request.count != 0)
+        }
+        .map(|(&key, _)| key)?;
+
+        // merging can't move a request ahead of one it depends on
+        (self.first_dependency(key) == key).then_some(key)
+    }
+
+    /// Takes the next request along with the adjacent requests it can be merged with, in lba
+    /// order.
+    fn take_batch(&mut self) -> Option<VecDeque<Request>> {
+        let key = self.next_key()?;
+        let key = self.first_dependency(key);
+
+        let mut batch = VecDeque::from([self.requests.remove(&key)?]);
+
+        while let Some(key) = self.mergeable(&batch, batch[batch.len() - 1].end(), true) {
+            let request = self.requests.remove(&key).expect("Should be pending");
+            batch.push_back(request);
+        }
+
+        while let Some(key) = self.mergeable(&batch, batch[0].lba, false) {
+            let request = self.requests.remove(&key).expect("Should be pending");
+            batch.push_front(request);
+        }
+
+        self.head = if self.ascending {
+            batch[batch.len() - 1].end()
+        } else {
+            batch[0].lba
+        };
+
+        self.stats.merged += batch.len() as u64 - 1;
+        self.stats.transfers += 1;
+
+        Some(batch)
+    }
+}
+
+/// A queue of requests for a block device, submitting doesn't wait for the device.
+///
+/// The io task does the requests in elevator order, merging adjacent requests of the same kind
+/// into one transfer. Overlapping requests are done in the order they were submitted if either
+/// of them writes.
+///
+/// Submitters never hold the device, only whoever services the queue does and only for a
+/// transfer. The io task is woken up by submissions rather than by the device, the drivers'
+/// transfers block it until their interrupt.
+#[derive(Debug)]
+pub struct RequestQueue {
+    device: Arc<Mutex<dyn BlockDevice>>,
+    sectors: u64,
+    sector_size: usize,
+    pending: Mutex<Pending>,
+}
+
+impl RequestQueue {
+    fn create(device: Arc<Mutex<dyn BlockDevice>>) -> Self {
+        let (sectors, sector_size) =
+            device.with_ref(|device| (device.total_sectors(), device.sector_size()));
+
+        Self {
+            device,
+            sectors,
+            sector_size,
+            pending: Mutex::new(Pending {
+                requests: BTreeMap::new(),
+                next_sequence: 0,
+                head: 0,
+                ascending: true,
+                stats: QueueStats::default(),
+            }),
+        }
+    }
+
+    /// Creates a queue for `device` and registers it with the io task.
+    ///
+    /// Requests that go around the queue aren't ordered with the ones in it, see [`queue_for`].
+    pub fn new(device: Arc<Mutex<dyn BlockDevice>>) -> Arc<Self> {
+        let queue = Arc::new(Self::create(device));
+
+        QUEUES.with_mut_ref(|queues| queues.push(queue.clone()));
+
+        queue
+    }
+
+    pub const fn device(&self) -> &Arc<Mutex<dyn BlockDevice>> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.pending.with_ref(|pending| pending.stats)
+    }
+
+    /// The number of requests waiting for the device.
+    pub fn pending(&self) -> usize {
+        self.pending.with_ref(|pending| pending.requests.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending() == 0
+    }
+
+    fn submit(
+        &self,
+        operation: Operation,
+        lba: u64,
+        count: u16,
+        mut buffer: Vec<u8>,
+        completion: Completion,
+    ) -> Result<(), BlockDeviceError> {
+        let length = check_access(self.sectors, self.sector_size, lba, count, buffer.len())?;
+        buffer.truncate(length);
+
+        self.pending.with_mut_ref(|pending| {
+            let sequence = pending.next_sequence;
+            pending.next_sequence += 1;
+            pending.stats.submitted += 1;
+
+            pending.requests.insert(
+                (lba, sequence),
+                Request {
+                    operation,
+                    lba,
+                    count,
+                    buffer,
+                    completion,
+                },
+            );
+        });
+
+        // the waker isn't held while waking
+        if let Some(waker) = IO_TASK.with_ref(Clone::clone) {
+            waker.wake();
+        }
+
+        Ok(())
+    }
+
+    /// Submits a read of `count` sectors from `lba`, the handle resolves to the sectors read.
+    ///
+    /// # Errors
+    /// Will error if the sectors aren't on the device
+    pub fn read(self: &Arc<Self>, lba: u64, count: u16) -> Result<RequestHandle, BlockDeviceError> {
+        let buffer = vec![0; usize::from(count) * self.sector_size];
+        let (handle, completion) = RequestHandle::new(self.clone());
+
+        self.submit(Operation::Read, lba, count, buffer, completion)?;
+
+        Ok(handle)
+    }
+
+    /// Submits a write of `data` to `count` sectors from `lba`, the handle resolves to `data`.
+    ///
+    /// # Errors
+    /// Will error if the sectors aren't on the device or `data` is too short
+    pub fn write(
+        self: &Arc<Self>,
+        lba: u64,
+        count: u16,
+        data: Vec<u8>,
+    ) -> Result<RequestHandle, BlockDeviceError> {
+        let (handle, completion) = RequestHandle::new(self.clone());
+
+        self.submit(Operation::Write, lba, count, data, completion)?;
+
+        Ok(handle)
+    }
+
+    /// Submits a read of `count` sectors from `lba`, `callback` is called with the sectors by the
+    /// io task.
+    ///
+    /// # Errors
+    /// Will error if the sectors aren't on the device
+    pub fn read_then(
+        &self,
+        lba: u64,
+        count: u16,
+        callback: impl FnOnce(RequestResult) + Send + 'static,
+    ) -> Result<(), BlockDeviceError> {
+        let buffer = vec![0; usize::from(count) * self.sector_size];
+
+        self.submit(
+            Operation::Read,
+            lba,
+            count,
+            buffer,
+            Completion::Callback(Box::new(callback)),
+        )
+    }
+
+    /// Submits a write of `data` to `count` sectors from `lba`, `callback` is called with `data`
+    /// by the io task.
+    ///
+    /// # Errors
+    /// Will error if the sectors aren't on the device or `data` is too short
+    pub fn write_then(
+        &self,
+        lba: u64,
+        count: u16,
+        data: Vec<u8>,
+        callback: impl FnOnce(RequestResult) + Send + 'static,
+    ) -> Result<(), BlockDeviceError> {
+        self.submit(
+            Operation::Write,
+            lba,
+            count,
+            data,
+            Completion::Callback(Box::new(callback)),
+        )
+    }
+
+    fn transfer(
+        &self,
+        operation: Operation,
+        lba: u64,
+        count: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let mut device = self.device.acquire();
+
+        match operation {
+            Operation::Read => device.read_sectors(lba, count, buffer),
+            Operation::Write => device.write_sectors(lba, count, buffer),
+        }
+    }
+
+    fn dispatch(&self, mut request: Request) {
+        let result = self
+            .transfer(
+                request.operation,
+                request.lba,
+                request.count,
+                &mut request.buffer,
+            )
+            .map(|()| request.buffer);
+
+        request.completion.complete(result);
+    }
+
+    /// Does the next transfer and completes its requests, returning false if there weren't any
+    /// requests.
+    ///
+    /// The queue isn't held during the transfer so requests can still be submitted.
+    pub fn service(&self) -> bool {
+        let Some(mut batch) = self.pending.with_mut_ref(Pending::take_batch) else {
+            return false;
+        };
+
+        if batch.len() == 1 {
+            self.dispatch(batch.pop_front().expect("Should have a request"));
+
+            return true;
+        }
+
+        let operation = batch[0].operation;
+        let lba = batch[0].lba;
+        let count: u16 = batch.iter().map(|request| request.count).sum();
+
+        let mut buffer = match operation {
+            Operation::Read => vec![0; usize::from(count) * self.sector_size],
+            Operation::Write => batch
+                .iter()
+                .flat_map(|request| &request.buffer)
+                .copied()
+                .collect(),
+        };
+
+        if let Err(err) = self.transfer(operation, lba, count, &mut buffer) {
+            // the error can't be shared, so each request gets its own
+            log::warn!("merged transfer of {count} sectors at {lba} failed, {err}, retrying");
+
+            for request in batch {
+                self.dispatch(request);
+            }
+
+            return true;
+        }
+
+        let mut sectors = buffer.as_slice();
+
+        for mut request in batch {
+            let (data, rest) = sectors.split_at(request.buffer.len());
+            sectors = rest;
+
+            if operation == Operation::Read {
+                request.buffer.copy_from_slice(data);
+            }
+
+            request.completion.complete(Ok(request.buffer));
+        }
+
+        true
+    }
+}
+
+/// A submitted request, waited on with [`Self::wait`] or by awaiting it.
+///
+/// Awaiting only resolves once the io task has done the request.
+#[derive(Debug)]
+#[must_use]
+pub struct RequestHandle {
+    state: Arc<Mutex<HandleState>>,
+    queue: Arc<RequestQueue>,
+}
+
+impl RequestHandle {
+    fn new(queue: Arc<RequestQueue>) -> (Self, Completion) {
+        let state = Arc::new(Mutex::new(HandleState::default()));
+
+        (
+            Self {
+                state: state.clone(),
+                queue,
+            },
+            Completion::Handle(state),
+        )
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state.with_ref(|state| state.result.is_some())
+    }
+
+    /// Blocks until the request is done.
+    ///
+    /// Without the io task, or before the scheduler is running, the queue is serviced by the
+    /// caller instead. Mustn't be called from a callback since the io task would wait on itself.
+    ///
+    /// # Errors
+    /// Will return the error of the transfer
+    pub fn wait(self) -> RequestResult {
+        loop {
+            if let Some(result) = self.state.with_mut_ref(|state| state.result.take()) {
+                return result;
+            }
+
+            if IO_TASK.with_ref(Option::is_some) && SCHEDULER.with_ref(Scheduler::can_block) {
+                // interrupts are disabled between checking and blocking so the wake up can't be
+                // missed
+                without_interrupts(|| {
+                    let waiting = self.state.with_mut_ref(|state| {
+                        state.waker = current_task_waker();
+                        state.result.is_none()
+                    });
+
+                    if waiting {
+                        // SAFETY: the scheduler and time keeper aren't held
+                        unsafe { block_task(BlockedReason::Paused) };
+                    }
+                });
+            } else {
+                self.queue.service();
+            }
+        }
+    }
+}
+
+impl Future for RequestHandle {
+    type Output = RequestResult;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        self.state.with_mut_ref(|state| {
+            state.result.take().map_or_else(
+                || {
+                    state.waker = Some(context.waker().clone());
+                    Poll::Pending
+                },
+                Poll::Ready,
+            )
+        })
+    }
+}
+
+/// Returns every queue in the order they were created.
+pub fn queues() -> Vec<Arc<RequestQueue>> {
+    QUEUES.with_ref(Clone::clone)
+}
+
+/// Returns the queue of `device`, creating one if it doesn't have one yet.
+pub fn queue_for(device: &Arc<Mutex<dyn BlockDevice>>) -> Arc<RequestQueue> {
+    QUEUES.with_mut_ref(|queues| {
+        if let Some(queue) = queues
+            .iter()
+            .find(|queue| core::ptr::addr_eq(Arc::as_ptr(&queue.device), Arc::as_ptr(device)))
+        {
+            return queue.clone();
+        }
+
+        let queue = Arc::new(RequestQueue::create(device.clone()));
+        queues.push(queue.clone());
+
+        queue
+    })
+}
+
+/// Does the requests of every queue, a transfer from each in turn, and blocks while they're all
+/// empty.
+pub fn io_task() -> ! {
+    let waker = current_task_waker().expect("Should be running as a task");
+    IO_TASK.with_mut_ref(|io_task| *io_task = Some(waker));
+
+    loop {
+        let serviced = queues()
+            .iter()
+            .map(|queue| queue.service())
+            .fold(false, |serviced, queue| serviced | queue);
+
+        if serviced {
+            continue;
+        }
+
+        // interrupts are disabled between checking and blocking so a submission can't be missed
+        without_interrupts(|| {
+            if queues().iter().all(|queue| queue.is_empty()) {
+                // SAFETY: the scheduler and time keeper aren't held
+                unsafe { block_task(BlockedReason::Paused) };
+            }
+        });
+    }
+}
@@ -0,0 +1,103 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::device_manager::{BlockDevice, BlockDeviceError, Device, DeviceWrapped, check_access};
+
+/// A block device backed by memory, for testing filesystems without a disk.
+#[derive(Debug)]
+pub struct RamDisk {
+    data: Vec<u8>,
+    sector_size: usize,
+}
+
+impl RamDisk {
+    /// Creates a zeroed disk of `sectors` sectors of `sector_size` bytes.
+    pub fn new(sectors: usize, sector_size: usize) -> Self {
+        Self {
+            data: vec![0; sectors * sector_size],
+            sector_size,
+        }
+    }
+
+    /// Creates a disk holding `data`, like a disk image, the last partial sector is dropped.
+    pub fn from_bytes(mut data: Vec<u8>, sector_size: usize) -> Self {
+        data.truncate(data.len() - data.len() % sector_size);
+
+        Self { data, sector_size }
+    }
+
+    /// The contents of the disk.
+    pub const fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// The bytes of `count` sectors from `lba`, once checked.
+    fn range(
+        &self,
+        lba: u64,
+        count: u16,
+        buffer_len: usize,
+    ) -> Result<core::ops::Range<usize>, BlockDeviceError> {
+        let length = check_access(
+            self.total_sectors(),
+            self.sector_size,
+            lba,
+            count,
+            buffer_len,
+        )?;
+        let start = usize::try_from(lba).expect("Should be in memory") * self.sector_size;
+
+        Ok(start..start + length)
+    }
+}
+
+impl Device for RamDisk {
+    fn children(&self) -> Option<Box<dyn Iterator<Item = DeviceWrapped>>> {
+        None
+    }
+
+    fn as_block_device(&mut self) -> Option<&mut dyn BlockDevice> {
+        Some(self)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn read_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let range = self.range(lba, count, buffer.len())?;
+
+        buffer[..range.len()].copy_from_slice(&self.data[range]);
+
+        Ok(())
+    }
+
+    fn write_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        let range = self.range(lba, count, buffer.len())?;
+
+        self.data[range.clone()].copy_from_slice(&buffer[..range.len()]);
+
+        Ok(())
+    }
+
+    fn total_sectors(&self) -> u64 {
+        (self.data.len() / self.sector_size) as u64
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+}
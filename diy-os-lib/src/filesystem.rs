@@ -3,8 +3,12 @@ use alloc::{string::String, vec::Vec};
 
 use crate::device_manager::BlockDeviceError;
 use crate::filesystem::gpt::PartionTableHeaderError;
+use crate::filesystem::gpt::mbr::MbrError;
 
+pub mod devfs;
 pub mod gpt;
+pub mod procfs;
+pub mod tmpfs;
 pub mod ustar;
 
 #[derive(thiserror::Error, Debug)]
@@ -13,24 +17,192 @@ pub enum FileSystemSetupError {
     BlockDeviceError(#[from] BlockDeviceError),
     #[error("Encountered an error while parsing the gpt header, `{0}`")]
     PartionTableHeaderError(#[from] PartionTableHeaderError),
+    #[error("Encountered an error while parsing the mbr, `{0}`")]
+    MbrError(#[from] MbrError),
+    #[error("Failed to mount the filesystem, `{0}`")]
+    MountError(#[from] MountError),
+    #[error("The disk has no partitions")]
+    NoPartitions,
+    #[error("There is no filesystem driver for partitions of type `{0}`")]
+    UnsupportedPartionType(String),
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum MountError {
+    #[error("The underlaying block device experienced an error")]
+    BlockDeviceError(#[from] BlockDeviceError),
+    #[error("The filesystem is invalid or unsupported, `{0}`")]
+    InvalidFileSystem(&'static str),
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileSystemError {
+    #[error("No such file or directory")]
+    NotFound,
+    #[error("The file or directory already exists")]
+    AlreadyExists,
+    #[error("A component of the path is not a directory")]
+    NotADirectory,
+    #[error("The path is a directory")]
+    IsADirectory,
+    #[error("The directory is not empty")]
+    DirectoryNotEmpty,
+    #[error("The path is invalid")]
+    InvalidPath,
+    #[error("Can't rename across filesystems")]
+    CrossDevice,
+    #[error("The filesystem is read only")]
+    ReadOnly,
+    #[error("The operation is not supported by the filesystem")]
+    NotSupported,
+}
+
+/// Routes paths to the filesystem mounted at the longest matching mount point.
 pub struct VFS {
-    mount_point: Box<dyn FileSystem>,
+    mounts: Vec<(String, Box<dyn FileSystem>)>,
 }
 
 impl VFS {
-    pub fn new(mount_point: Box<dyn FileSystem>) -> Self {
-        Self { mount_point }
+    /// Creates a new VFS with `root` mounted at `/`.
+    pub fn new(root: Box<dyn FileSystem>) -> Self {
+        Self {
+            mounts: alloc::vec![(String::from("/"), root)],
+        }
+    }
+
+    /// Mounts `filesystem` at `path`, replacing any filesystem already mounted there.
+    pub fn mount(&mut self, path: &str, filesystem: Box<dyn FileSystem>) {
+        let trimmed = path.trim_end_matches('/');
+        let path = if trimmed.is_empty() { "/" } else { trimmed };
+
+        self.mounts.retain(|(mount_point, _)| mount_point != path);
+        self.mounts.push((String::from(path), filesystem));
     }
 
     pub fn open(&mut self, path: &str) -> Option<Box<dyn FileTrait + '_>> {
-        self.mount_point.open(path)
+        let (index, relative) = self.route(path)?;
+
+        self.mounts[index].1.open(relative)
+    }
+
+    /// Creates an empty file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FileSystemError`] if no filesystem is mounted at `path` or the filesystem
+    /// fails to create the file.
+    pub fn create(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let (index, relative) = self.route(path).ok_or(FileSystemError::NotFound)?;
+
+        self.mounts[index].1.create(relative)
+    }
+
+    /// Creates an empty directory at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FileSystemError`] if no filesystem is mounted at `path` or the filesystem
+    /// fails to create the directory.
+    pub fn mkdir(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let (index, relative) = self.route(path).ok_or(FileSystemError::NotFound)?;
+
+        self.mounts[index].1.mkdir(relative)
+    }
+
+    /// Removes the file or empty directory at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FileSystemError`] if no filesystem is mounted at `path` or the filesystem
+    /// fails to remove it.
+    pub fn unlink(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let (index, relative) = self.route(path).ok_or(FileSystemError::NotFound)?;
+
+        self.mounts[index].1.unlink(relative)
+    }
+
+    /// Moves `from` to `to`, both must be on the same filesystem.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FileSystemError::CrossDevice`] if the paths are on different filesystems, or
+    /// the filesystem's error if the rename fails.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        let (from_index, from) = self.route(from).ok_or(FileSystemError::NotFound)?;
+        let (to_index, to) = self.route(to).ok_or(FileSystemError::NotFound)?;
+
+        if from_index != to_index {
+            return Err(FileSystemError::CrossDevice);
+        }
+
+        self.mounts[from_index].1.rename(from, to)
+    }
+
+    /// Finds the filesystem mounted at the longest mount point matching `path`.
+    ///
+    /// Returns its index and `path` relative to the mount point.
+    fn route<'a>(&self, path: &'a str) -> Option<(usize, &'a str)> {
+        let (index, mount_point) = self
+            .mounts
+            .iter()
+            .map(|(mount_point, _)| mount_point)
+            .enumerate()
+            .filter(|(_, mount_point)| {
+                *mount_point == "/"
+                    || path
+                        .strip_prefix(mount_point.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|(_, mount_point)| mount_point.len())?;
+
+        let relative = if mount_point == "/" {
+            path
+        } else {
+            &path[mount_point.len()..]
+        };
+
+        Some((index, relative))
     }
 }
 
 pub trait FileSystem {
     fn open(&mut self, path: &str) -> Option<Box<dyn FileTrait + '_>>;
+
+    /// Creates an empty file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FileSystemError::NotSupported`] unless the filesystem implements it.
+    fn create(&mut self, _path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Creates an empty directory at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FileSystemError::NotSupported`] unless the filesystem implements it.
+    fn mkdir(&mut self, _path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Removes the file or empty directory at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FileSystemError::NotSupported`] unless the filesystem implements it.
+    fn unlink(&mut self, _path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::NotSupported)
+    }
+
+    /// Moves `from` to `to`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FileSystemError::NotSupported`] unless the filesystem implements it.
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::NotSupported)
+    }
 }
 
 // pub trait DirTrait {
@@ -76,17 +248,9 @@ pub struct File {
     pub data: Vec<u8>,
 }
 
+#[derive(Debug)]
 pub struct Dir {
     pub name: String,
     pub dirs: Vec<Self>,
     pub files: Vec<File>,
 }
-
-pub struct Root {
-    pub dirs: Vec<Self>,
-    pub files: Vec<File>,
-}
-
-// impl Root<'_> {
-//     pub fn open(path: &str) -> File {}
-// }
@@ -0,0 +1,280 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+
+use crate::device_manager::{BlockDevice, BlockDeviceError, DeviceManager};
+use crate::filesystem::{FileSystem, FileTrait, INError, OUTError};
+use crate::framebuffer::FRAME_BUFER;
+use crate::multitasking::mutex::Mutex;
+use crate::random;
+use crate::serial::SERIAL1;
+
+#[derive(Clone)]
+enum DeviceFile {
+    Null,
+    Zero,
+    Random,
+    Serial,
+    FrameBuffer,
+    /// A whole disk or a partition
+    Block(Arc<Mutex<dyn BlockDevice>>),
+}
+
+/// Exposes devices as files, mounted at `/dev`.
+pub struct DevFs {
+    files: Vec<(String, DeviceFile)>,
+}
+
+impl DevFs {
+    /// Creates the device files for every block device in `device_manager`.
+    ///
+    /// Disks are named `hda`, `hdb`, ... in the order they were registered and partitions are
+    /// named after their number in the partition table, `hda1`, ...
+    pub fn new(device_manager: &DeviceManager) -> Self {
+        let mut files = vec![
+            (String::from("null"), DeviceFile::Null),
+            (String::from("zero"), DeviceFile::Zero),
+            (String::from("random"), DeviceFile::Random),
+            (String::from("serial0"), DeviceFile::Serial),
+        ];
+
+        if FRAME_BUFER.acquire().is_some() {
+            files.push((String::from("fb0"), DeviceFile::FrameBuffer));
+        }
+
+        for (device, letter) in device_manager.block_devices.iter().zip('a'..='z') {
+            let disk = format!("hd{letter}");
+
+            files.push((disk.clone(), DeviceFile::Block(device.clone())));
+
+            for partition in device_manager.partitions_of(device) {
+                let number = partition.acquire().number();
+
+                files.push((
+                    format!("{disk}{number}"),
+                    DeviceFile::Block(partition.clone()),
+                ));
+            }
+        }
+
+        Self { files }
+    }
+}
+
+impl FileSystem for DevFs {
+    fn open(&mut self, path: &str) -> Option<Box<dyn FileTrait + '_>> {
+        let name = path.trim_matches('/');
+
+        let (_, file) = self.files.iter().find(|(file, _)| file == name)?;
+
+        Some(match file.clone() {
+            DeviceFile::Null => Box::new(NullFile),
+            DeviceFile::Zero => Box::new(ZeroFile),
+            DeviceFile::Random => Box::new(RandomFile),
+            DeviceFile::Serial => Box::new(SerialFile),
+            DeviceFile::FrameBuffer => Box::new(FrameBufferFile),
+            DeviceFile::Block(device) => Box::new(BlockDeviceFile { device }),
+        })
+    }
+}
+
+/// Discards writes and reads nothing.
+struct NullFile;
+
+impl FileTrait for NullFile {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, INError> {
+        Ok(0)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, OUTError> {
+        Ok(buf.len())
+    }
+}
+
+/// Discards writes and reads zeros.
+struct ZeroFile;
+
+impl FileTrait for ZeroFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, INError> {
+        buf.fill(0);
+
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, OUTError> {
+        Ok(buf.len())
+    }
+}
+
+/// Reads random bytes from `RDRAND`, falling back to a xorshift generator if the cpu does not
+/// support it.
+struct RandomFile;
+
+impl FileTrait for RandomFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, INError> {
+        random::fill(buf);
+
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, OUTError> {
+        Ok(buf.len())
+    }
+}
+
+/// The first serial port, reads only return bytes that have already been received.
+struct SerialFile;
+
+impl FileTrait for SerialFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, INError> {
+        // prevents deadlock waiting for the lock
+        interrupts::without_interrupts(|| {
+            let mut serial = SERIAL1.acquire();
+
+            let mut read = 0;
+            while let Some(byte) = buf.get_mut(read)
+                && let Ok(received) = serial.try_receive()
+            {
+                *byte = received;
+                read += 1;
+            }
+
+            Ok(read)
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, OUTError> {
+        interrupts::without_interrupts(|| {
+            let mut serial = SERIAL1.acquire();
+
+            for byte in buf {
+                serial.send_raw(*byte);
+            }
+        });
+
+        Ok(buf.len())
+    }
+}
+
+/// The raw pixels of the framebuffer.
+struct FrameBufferFile;
+
+impl FileTrait for FrameBufferFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, INError> {
+        FRAME_BUFER
+            .acquire()
+            .as_ref()
+            .map(|framebuffer| framebuffer.read_bytes(buf))
+            .ok_or(INError::NotReadable)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, OUTError> {
+        FRAME_BUFER
+            .acquire()
+            .as_mut()
+            .map(|framebuffer| framebuffer.write_bytes(buf))
+            .ok_or(OUTError::NotWritable)
+    }
+}
+
+/// The sectors of a disk or partition.
+struct BlockDeviceFile {
+    device: Arc<Mutex<dyn BlockDevice>>,
+}
+
+impl BlockDeviceFile {
+    /// Reads whole sectors starting at the start of the file into `buffer`.
+    fn read_sectors(&self, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let mut device = self.device.acquire();
+        let sector_size = device.sector_size();
+
+        for (lba, chunk) in (0..)
+            .step_by(usize::from(u16::MAX))
+            .zip(buffer.chunks_mut(sector_size * usize::from(u16::MAX)))
+        {
+            device.read_sectors(
+                lba,
+                u16::try_from(chunk.len() / sector_size).unwrap(),
+                chunk,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&self, buffer: &[u8]) -> Result<(), BlockDeviceError> {
+        let mut device = self.device.acquire();
+        let sector_size = device.sector_size();
+
+        for (lba, chunk) in (0..)
+            .step_by(usize::from(u16::MAX))
+            .zip(buffer.chunks(sector_size * usize::from(u16::MAX)))
+        {
+            device.write_sectors(
+                lba,
+                u16::try_from(chunk.len() / sector_size).unwrap(),
+                chunk,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        let device = self.device.acquire();
+        let sector_size = device.sector_size();
+
+        usize::try_from(device.total_sectors())
+            .map_or(usize::MAX, |sectors| sectors.saturating_mul(sector_size))
+    }
+}
+
+impl FileTrait for BlockDeviceFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, INError> {
+        let sector_size = self.device.acquire().sector_size();
+        let length = buf.len().min(self.len());
+
+        let mut sectors = vec![0u8; length.div_ceil(sector_size) * sector_size];
+        self.read_sectors(&mut sectors).map_err(|err| {
+            log::error!("failed to read block device, {err}");
+            INError::NotReadable
+        })?;
+
+        buf[..length].copy_from_slice(&sectors[..length]);
+
+        Ok(length)
+    }
+
+    /// Writes `buf` to the start of the device, preserving the rest of the last sector.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, OUTError> {
+        if buf.len() > self.len() {
+            return Err(OUTError::WriteLargerThenMaxFileSize);
+        }
+
+        let sector_size = self.device.acquire().sector_size();
+        let mut sectors = vec![0u8; buf.len().div_ceil(sector_size) * sector_size];
+
+        let result = if buf.len().is_multiple_of(sector_size) {
+            Ok(())
+        } else {
+            // keeps the bytes of the last sector past the end of `buf`
+            self.read_sectors(&mut sectors)
+        };
+
+        result
+            .and_then(|()| {
+                sectors[..buf.len()].copy_from_slice(buf);
+                self.write_sectors(&sectors)
+            })
+            .map_err(|err| {
+                log::error!("failed to write block device, {err}");
+                OUTError::NotWritable
+            })?;
+
+        Ok(buf.len())
+    }
+}
@@ -2,18 +2,19 @@
 // and https://web.archive.org/web/20250630111613/https://wiki.osdev.org/GPT#Layout and https://web.archive.org/web/20250306133759/http://wiki.osdev.org/Partition_Table
 // wayback machine links incase any content changes or disappears
 use alloc::sync::Arc;
-use core::fmt::Debug;
+use bitfield_struct::bitfield;
+use core::fmt::{Debug, Display};
 use core::hint::assert_unchecked;
 use core::mem::Assume;
 use core::mem::TransmuteFrom;
 use core::mem::offset_of;
 use core::str::FromStr;
-use crc32fast::Hasher;
+use zerocopy::KnownLayout;
 use zerocopy::little_endian::{U16, U32, U64, U128};
 use zerocopy::{FromBytes, Immutable, IntoBytes, Unaligned};
-use zerocopy::{FromZeros, KnownLayout};
 
-use alloc::string::String;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
 use crate::device_manager::{BlockDevice, BlockDeviceError};
 use crate::multitasking::mutex::Mutex;
@@ -48,6 +49,16 @@ pub struct PartionTableHeader {
     pub partion_entry_lba: u64,
     pub num_of_partions: u32,
     pub size_of_partion_entry: u32,
+    pub crc32_partion_entry_array: u32,
+    /// Which copy of the header this was read from
+    pub copy: HeaderCopy,
+}
+
+/// The GPT is stored twice, the primary header at LBA 1 and the backup at the last LBA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderCopy {
+    Primary,
+    Backup,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -56,57 +67,126 @@ pub enum PartionTableHeaderError {
     DeviceError(#[from] BlockDeviceError),
     #[error("Invalid signature, received")]
     InvalidSignature([u8; 8]),
+    #[error("Invalid header size `{0}`")]
+    InvalidHeaderSize(u32),
     #[error("Invalid crc32 header checksum expected: `{expected}` but calculated `{calculated}`")]
     InvalidCrc32HeaderChecksum { expected: u32, calculated: u32 },
+    #[error("Header claims to be at lba `{found}` but was read from lba `{expected}`")]
+    InvalidHeaderLba { expected: u64, found: u64 },
+    #[error("Invalid usable block range `{first}` to `{last}`")]
+    InvalidUsableRange { first: u64, last: u64 },
+    #[error("Invalid partion entry array, {0}")]
+    InvalidPartionEntryArray(&'static str),
     #[error(
         "Invalid crc32 partion entries checksum expected: `{expected}` but calculated `{calculated}`"
     )]
     InvalidCrc32PartionEntriesChecksum { expected: u32, calculated: u32 },
+    #[error("Partion `{index}` is outside of the usable block range")]
+    PartionOutOfRange { index: usize },
+    #[error("Partion `{first}` overlaps partion `{second}`")]
+    OverlappingPartions { first: usize, second: usize },
 }
 
 impl PartionTableHeader {
+    /// The size of the header defined by the spec, newer revisions may be larger.
+    const MIN_HEADER_SIZE: u32 = 92;
+    const MIN_PARTION_ENTRY_SIZE: u32 = 128;
+
     /// Reads and validates the GPT partition table header from `device`.
     ///
-    /// Reads LBA 1, checks the `"EFI PART"` signature, validates the header CRC32
-    /// (computed with the CRC field zeroed), then reads and validates the partition
-    /// entry array CRC32.
+    /// The primary header at LBA 1 is used if it is valid, otherwise the backup header at the
+    /// last LBA is used. When both are valid they are cross checked and any differences are
+    /// logged. The copy that was used is recorded in [`PartionTableHeader::copy`].
     ///
     /// # Errors
     ///
-    /// Returns [`PartionTableHeaderError`] if:
-    /// - the underlying device read fails
-    /// - the GPT signature is missing or wrong
-    /// - either the header or partition entry array CRC fails to match
+    /// Returns the error of the primary header if neither copy is valid, see
+    /// [`PartionTableHeader::read_header`] for what is validated.
     pub fn from_device(
         device: &Arc<Mutex<dyn BlockDevice>>,
     ) -> Result<Self, PartionTableHeaderError> {
-        let mut header = PartionTableHeaderRaw::new_zeroed();
+        let last_lba = device.acquire().total_sectors().saturating_sub(1);
+
+        match Self::read_header(device, 1, HeaderCopy::Primary) {
+            Ok(primary) => {
+                if primary.lba_alt_table_header == last_lba {
+                    match Self::read_header(device, last_lba, HeaderCopy::Backup) {
+                        Ok(backup) => primary.cross_check(&backup),
+                        Err(err) => log::warn!("GPT backup header is invalid, {err}"),
+                    }
+                } else {
+                    log::warn!(
+                        "GPT backup header is at lba {} instead of the last lba {last_lba}",
+                        primary.lba_alt_table_header
+                    );
+                }
+
+                Ok(primary)
+            }
+            Err(primary_err) => {
+                log::warn!("GPT primary header is invalid, {primary_err}, trying the backup");
 
-        {
-            let mut drive = device.acquire();
+                let backup = Self::read_header(device, last_lba, HeaderCopy::Backup).map_err(
+                    |backup_err| {
+                        log::error!("GPT backup header is invalid, {backup_err}");
+
+                        primary_err
+                    },
+                )?;
 
-            drive.read_sectors(1, 1, header.as_mut_bytes())?;
+                log::info!("using the GPT backup header");
+
+                Ok(backup)
+            }
         }
+    }
+
+    /// Reads and validates the header at `lba`.
+    ///
+    /// Checks the `"EFI PART"` signature, the header CRC32 (computed with the CRC field zeroed),
+    /// that the header is at `lba`, that the usable range and partition entry array fit on the
+    /// disk without overlapping each other or the header, the partition entry array CRC32, and
+    /// that every partition lies within the usable range without overlapping another.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PartionTableHeaderError`] if the underlying device read fails or any of the
+    /// checks fail.
+    fn read_header(
+        device: &Arc<Mutex<dyn BlockDevice>>,
+        lba: u64,
+        copy: HeaderCopy,
+    ) -> Result<Self, PartionTableHeaderError> {
+        let (mut sector, total_sectors) = {
+            let mut drive = device.acquire();
+
+            let mut sector = alloc::vec![0u8; drive.sector_size()];
+            drive.read_sectors(lba, 1, &mut sector)?;
+
+            (sector, drive.total_sectors())
+        };
+
+        let (header, _) = PartionTableHeaderRaw::read_from_prefix(&sector).unwrap();
 
         if *b"EFI PART" != header.signature {
             return Err(PartionTableHeaderError::InvalidSignature(header.signature));
         }
 
-        // Validate partition header checksum
-        let mut hasher = Hasher::new();
-        let bytes = header.as_bytes();
-
-        // Relies on the order of field to compute checksum
-        hasher.update(&bytes[0..const { offset_of!(PartionTableHeaderRaw, crc32_checksum) }]);
-        hasher.update(
-            &[0u8; const {
-                offset_of!(PartionTableHeaderRaw, reserved)
-                    - offset_of!(PartionTableHeaderRaw, crc32_checksum)
-            }],
-        );
-        hasher.update(&bytes[const { offset_of!(PartionTableHeaderRaw, reserved) }..]);
+        let header_size = header.header_size.get();
+
+        if header_size < Self::MIN_HEADER_SIZE
+            || usize::try_from(header_size).unwrap() > sector.len()
+        {
+            return Err(PartionTableHeaderError::InvalidHeaderSize(header_size));
+        }
 
-        let hash = hasher.finalize();
+        // Validate partition header checksum, calculated over `header_size` bytes
+        sector[const { offset_of!(PartionTableHeaderRaw, crc32_checksum) }..const {
+            offset_of!(PartionTableHeaderRaw, reserved)
+        }]
+            .fill(0);
+
+        let hash = crc32fast::hash(&sector[..usize::try_from(header_size).unwrap()]);
 
         if hash != header.crc32_checksum.get() {
             return Err(PartionTableHeaderError::InvalidCrc32HeaderChecksum {
@@ -115,57 +195,206 @@ impl PartionTableHeader {
             });
         }
 
-        // Validate partition entries checksum
-        let array_size = usize::try_from(header.size_of_partition_entry.get())
-            .unwrap()
-            .checked_mul(usize::try_from(header.num_of_partions.get()).unwrap())
-            .unwrap();
+        let header = Self {
+            lba_table_header: header.lba_table_header.get(),
+            lba_alt_table_header: header.lba_alt_table_header.get(),
+            first_usable_logical_block: header.first_usable_logical_block.get(),
+            last_usable_logical_block: header.last_usable_logical_block.get(),
+            disk_guid: header.disk_guid.get(),
+            partion_entry_lba: header.partion_entry_lba.get(),
+            num_of_partions: header.num_of_partions.get(),
+            size_of_partion_entry: header.size_of_partition_entry.get(),
+            crc32_partion_entry_array: header.crc32_partion_entry_array.get(),
+            copy,
+        };
+
+        if header.lba_table_header != lba {
+            return Err(PartionTableHeaderError::InvalidHeaderLba {
+                expected: lba,
+                found: header.lba_table_header,
+            });
+        }
 
-        let mut buffer = alloc::vec![0u8; array_size];
+        let first = header.first_usable_logical_block;
+        let last = header.last_usable_logical_block;
 
+        // LBA 0 is the protective MBR, LBA 1 and the last LBA are the headers
+        if first < 2 || first > last || last >= total_sectors.saturating_sub(1) {
+            return Err(PartionTableHeaderError::InvalidUsableRange { first, last });
+        }
+
+        if header.size_of_partion_entry < Self::MIN_PARTION_ENTRY_SIZE
+            || !header.size_of_partion_entry.is_power_of_two()
         {
-            let mut drive = device.acquire();
+            return Err(PartionTableHeaderError::InvalidPartionEntryArray(
+                "entry size is not a power of two of at least 128 bytes",
+            ));
+        }
+
+        let array_sectors = u64::try_from(header.array_size().div_ceil(sector.len())).unwrap();
+        let array_start = header.partion_entry_lba;
+        let array_end = array_start
+            .checked_add(array_sectors)
+            .filter(|end| *end <= total_sectors)
+            .ok_or(PartionTableHeaderError::InvalidPartionEntryArray(
+                "array runs past the end of the disk",
+            ))?;
+
+        if array_start == 0 || (array_start..array_end).contains(&lba) {
+            return Err(PartionTableHeaderError::InvalidPartionEntryArray(
+                "array overlaps a header",
+            ));
+        }
+
+        if array_start <= last && array_end > first {
+            return Err(PartionTableHeaderError::InvalidPartionEntryArray(
+                "array overlaps the usable block range",
+            ));
+        }
 
-            let sector_size = drive.sector_size();
+        // Validate partition entries checksum
+        let array = read_partion_entry_array(device, array_start, header.array_size())?;
 
-            drive.read_sectors(
-                header.partion_entry_lba.get(),
-                u8::try_from(array_size.div_ceil(sector_size)).unwrap(),
-                &mut buffer,
-            )?;
+        let hash = crc32fast::hash(&array);
 
-            let hash = crc32fast::hash(buffer.as_bytes());
+        if hash != header.crc32_partion_entry_array {
+            return Err(
+                PartionTableHeaderError::InvalidCrc32PartionEntriesChecksum {
+                    expected: header.crc32_partion_entry_array,
+                    calculated: hash,
+                },
+            );
+        }
 
-            if hash != header.crc32_partion_entry_array.get() {
-                return Err(
-                    PartionTableHeaderError::InvalidCrc32PartionEntriesChecksum {
-                        expected: header.crc32_partion_entry_array.get(),
-                        calculated: hash,
-                    },
-                );
+        header.validate_entries(&header.parse_entries(&array))?;
+
+        Ok(header)
+    }
+
+    /// Checks every used entry lies within the usable range and doesn't overlap another.
+    fn validate_entries(&self, entries: &[PartitionEntry]) -> Result<(), PartionTableHeaderError> {
+        let mut used: Vec<(usize, &PartitionEntry)> = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.partion_type_guid.get() != 0)
+            .collect();
+
+        for (index, entry) in &used {
+            if entry.starting_lba.get() > entry.ending_lba.get()
+                || entry.starting_lba.get() < self.first_usable_logical_block
+                || entry.ending_lba.get() > self.last_usable_logical_block
+            {
+                return Err(PartionTableHeaderError::PartionOutOfRange { index: *index });
             }
         }
 
-        // TODO: FIx
-        // Check if current header is alt or not
-        // if header.lba_table_header.get() == 1 {
-        //     // If it successfully returns the header must be valid
-        //     let _ = Self::from_device(device, true)?;
-        // }
+        used.sort_unstable_by_key(|(_, entry)| entry.starting_lba.get());
 
-        // TODO validate self lba?? lowkey forget what this is been way too long
+        for pair in used.windows(2) {
+            let [(first, before), (second, after)] = pair else {
+                unreachable!()
+            };
 
-        Ok(Self {
-            lba_table_header: header.lba_table_header.get(),
-            lba_alt_table_header: header.lba_alt_table_header.get(),
-            first_usable_logical_block: header.first_usable_logical_block.get(),
-            last_usable_logical_block: header.last_usable_logical_block.get(),
-            disk_guid: header.disk_guid.get(),
-            partion_entry_lba: header.partion_entry_lba.get(),
-            num_of_partions: header.num_of_partions.get(),
-            size_of_partion_entry: header.size_of_partition_entry.get(),
-        })
+            if before.ending_lba.get() >= after.starting_lba.get() {
+                return Err(PartionTableHeaderError::OverlappingPartions {
+                    first: *first,
+                    second: *second,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Logs every difference between this header and the other copy.
+    fn cross_check(&self, other: &Self) {
+        let mismatch = |field: &str, matches: bool| {
+            if !matches {
+                log::warn!("GPT {field} differs between the primary and backup header");
+            }
+        };
+
+        mismatch(
+            "alternate header lba",
+            self.lba_alt_table_header == other.lba_table_header
+                && other.lba_alt_table_header == self.lba_table_header,
+        );
+        mismatch(
+            "usable block range",
+            self.first_usable_logical_block == other.first_usable_logical_block
+                && self.last_usable_logical_block == other.last_usable_logical_block,
+        );
+        mismatch("disk guid", self.disk_guid == other.disk_guid);
+        mismatch(
+            "partion entry layout",
+            self.num_of_partions == other.num_of_partions
+                && self.size_of_partion_entry == other.size_of_partion_entry,
+        );
+        mismatch(
+            "partion entry array",
+            self.crc32_partion_entry_array == other.crc32_partion_entry_array,
+        );
+    }
+
+    /// The size of the partition entry array in bytes.
+    fn array_size(&self) -> usize {
+        usize::try_from(self.size_of_partion_entry)
+            .unwrap()
+            .checked_mul(usize::try_from(self.num_of_partions).unwrap())
+            .unwrap()
     }
+
+    /// Entries larger then [`PartitionEntry`] have their trailing bytes ignored.
+    fn parse_entries(&self, array: &[u8]) -> Vec<PartitionEntry> {
+        array
+            .chunks_exact(usize::try_from(self.size_of_partion_entry).unwrap())
+            .filter_map(|entry| PartitionEntry::read_from_prefix(entry).ok())
+            .map(|(entry, _)| entry)
+            .collect()
+    }
+
+    /// Reads the partition entry array described by this header.
+    ///
+    /// Entries larger then [`PartitionEntry`] have their trailing bytes ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BlockDeviceError`] if reading the array fails.
+    pub fn read_entries(
+        &self,
+        device: &Arc<Mutex<dyn BlockDevice>>,
+    ) -> Result<Vec<PartitionEntry>, BlockDeviceError> {
+        let array = read_partion_entry_array(device, self.partion_entry_lba, self.array_size())?;
+
+        Ok(self.parse_entries(&array))
+    }
+}
+
+/// Reads `size` bytes starting at `lba`, in as many reads as needed.
+fn read_partion_entry_array(
+    device: &Arc<Mutex<dyn BlockDevice>>,
+    lba: u64,
+    size: usize,
+) -> Result<Vec<u8>, BlockDeviceError> {
+    let mut drive = device.acquire();
+    let sector_size = drive.sector_size();
+
+    let mut buffer = alloc::vec![0u8; size.div_ceil(sector_size) * sector_size];
+
+    for (lba, chunk) in (lba..)
+        .step_by(usize::from(u16::MAX))
+        .zip(buffer.chunks_mut(sector_size * usize::from(u16::MAX)))
+    {
+        drive.read_sectors(
+            lba,
+            u16::try_from(chunk.len() / sector_size).unwrap(),
+            chunk,
+        )?;
+    }
+
+    buffer.truncate(size);
+
+    Ok(buffer)
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -174,9 +403,67 @@ impl PartionTableHeader {
 pub enum FSGuid {
     SimpleFileSystem = 0x5346_5353_4653_061A_450C_11BF_4EBF_0E06,
     MicrosoftData = 0xC799_26B7_B668_C087_4433_B9E5_EBD0_A0A2,
+    LinuxFilesystem = 0xE47D_47D8_693D_798E_4772_8483_0FC6_3DAF,
+    EfiSystem = 0x3BC9_3EC9_A000_4BBA_11D2_F81F_C12A_7328,
+    LinuxSwap = 0x4F4F_4BC8_3309_E584_43C4_A4AB_0657_FD6D,
+    LinuxLvm = 0x28F9_3D2A_8F23_3CA2_44C2_F507_E6D6_D379,
+    BiosBoot = 0x4946_4564_6565_4E74_6E6F_6449_2168_6148,
+    MicrosoftReserved = 0xAE15_02F0_2DF9_7D81_4DB8_0B5C_E3C9_E316,
+    LinuxRootX86_64 = 0x09B7_84F9_CAFB_E796_4DB1_E8CD_4F68_BCE3,
 }
 
-#[derive(Debug)]
+impl FSGuid {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::SimpleFileSystem => "Simple file system",
+            Self::MicrosoftData => "Microsoft basic data",
+            Self::LinuxFilesystem => "Linux filesystem",
+            Self::EfiSystem => "EFI System",
+            Self::LinuxSwap => "Linux swap",
+            Self::LinuxLvm => "Linux LVM",
+            Self::BiosBoot => "BIOS boot",
+            Self::MicrosoftReserved => "Microsoft reserved",
+            Self::LinuxRootX86_64 => "Linux root (x86-64)",
+        }
+    }
+}
+
+impl Display for FSGuid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Attributes of a partition, bits 48 to 63 are specific to the partition type.
+#[bitfield(u64)]
+pub struct PartitionAttributes {
+    /// The partition is required for the platform to function
+    #[bits(1)]
+    pub required_partition: bool,
+    /// Firmware must not provide block IO for the partition
+    #[bits(1)]
+    pub no_block_io_protocol: bool,
+    #[bits(1)]
+    pub legacy_bios_bootable: bool,
+    #[bits(45)]
+    _reserved: u64,
+    #[bits(12)]
+    pub type_specific: u16,
+    /// [`FSGuid::MicrosoftData`] only
+    #[bits(1)]
+    pub read_only: bool,
+    /// [`FSGuid::MicrosoftData`] only
+    #[bits(1)]
+    pub shadow_copy: bool,
+    /// [`FSGuid::MicrosoftData`] only
+    #[bits(1)]
+    pub hidden: bool,
+    /// [`FSGuid::MicrosoftData`] only
+    #[bits(1)]
+    pub no_drive_letter: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct GuidNode(u32, u16);
 
@@ -206,7 +493,7 @@ impl<'a> From<GuidStr<'a>> for &'a str {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct Guid {
     time_low: u32,
@@ -330,6 +617,34 @@ impl Guid {
     }
 }
 
+impl Display for Guid {
+    /// Formats the GUID in its usual form, `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let bytes = u128::from(*self).to_le_bytes();
+
+        // The first three fields are little endian and the rest are stored in order
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+            u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
+        )?;
+
+        for byte in &bytes[8..10] {
+            write!(f, "{byte:02X}")?;
+        }
+
+        f.write_str("-")?;
+
+        for byte in &bytes[10..16] {
+            write!(f, "{byte:02X}")?;
+        }
+
+        Ok(())
+    }
+}
+
 impl const From<Guid> for u128 {
     fn from(value: Guid) -> Self {
         unsafe { core::mem::transmute::<Guid, Self>(value) }
@@ -380,12 +695,19 @@ impl TryFrom<u128> for FSGuid {
         match value {
             val if val == Self::SimpleFileSystem as u128 => Ok(Self::SimpleFileSystem),
             val if val == Self::MicrosoftData as u128 => Ok(Self::MicrosoftData),
+            val if val == Self::LinuxFilesystem as u128 => Ok(Self::LinuxFilesystem),
+            val if val == Self::EfiSystem as u128 => Ok(Self::EfiSystem),
+            val if val == Self::LinuxSwap as u128 => Ok(Self::LinuxSwap),
+            val if val == Self::LinuxLvm as u128 => Ok(Self::LinuxLvm),
+            val if val == Self::BiosBoot as u128 => Ok(Self::BiosBoot),
+            val if val == Self::MicrosoftReserved as u128 => Ok(Self::MicrosoftReserved),
+            val if val == Self::LinuxRootX86_64 as u128 => Ok(Self::LinuxRootX86_64),
             _ => Err("Can't find filesystem with that guid"),
         }
     }
 }
 
-#[derive(Debug, FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
 #[repr(C)]
 // should add ending reserved fields
 pub struct PartitionEntry {
@@ -416,30 +738,19 @@ impl PartitionEntry {
     pub fn get_fs(&self) -> Result<FSGuid, &'static str> {
         FSGuid::try_from(self.partion_type_guid.get())
     }
-}
 
-pub mod mbr {
-    #[repr(C, packed)]
-    pub struct MBR {
-        boot_code: [u8; 440],
-        unique_mbr_disk_signature: u32,
-        unknown: u16,
-        pub partion_record: [PartionTableEntry; 4],
-        pub signature: u16,
+    /// Returns the name of the partition type, or its GUID if the type is unknown.
+    pub fn type_name(&self) -> String {
+        self.get_fs().map_or_else(
+            |_| Guid::from_u128(self.partion_type_guid.get()).to_string(),
+            |fs| String::from(fs.name()),
+        )
     }
 
-    #[derive(Debug, Clone, Copy)]
-    #[repr(C)]
-    pub struct PartionTableEntry {
-        drive_attributes: u8,
-        starting_chs: CHSAddress,
-        os_type: u8,
-        ending_chs: CHSAddress,
-        starting_lba: u32,
-        ending_lba: u32,
+    pub const fn attributes(&self) -> PartitionAttributes {
+        PartitionAttributes::from_bits(self.attributes.get())
     }
-
-    #[derive(Debug, Clone, Copy)]
-    #[allow(dead_code)]
-    pub struct CHSAddress(u8, u8, u8);
 }
+
+pub mod mbr;
+pub mod writer;
@@ -0,0 +1,244 @@
+// reference docs at https://web.archive.org/web/20250306133759/http://wiki.osdev.org/Partition_Table
+// and https://web.archive.org/web/20250320100541/https://en.wikipedia.org/wiki/Extended_boot_record
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use zerocopy::little_endian::{U16, U32, U64, U128};
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout, Unaligned};
+
+use super::{FSGuid, PartitionEntry};
+use crate::device_manager::{BlockDevice, BlockDeviceError};
+use crate::multitasking::mutex::Mutex;
+
+/// Extended boot records are followed at most this many times, in case the chain loops.
+const MAX_LOGICAL_PARTIONS: usize = 128;
+
+#[derive(thiserror::Error, Debug)]
+pub enum MbrError {
+    #[error("The underlying device ran into an error")]
+    DeviceError(#[from] BlockDeviceError),
+    #[error("Invalid signature, received `{0:#x}`")]
+    InvalidSignature(u16),
+    #[error("Invalid extended partion, {0}")]
+    InvalidExtendedPartion(&'static str),
+}
+
+#[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+#[repr(C)]
+pub struct MBR {
+    boot_code: [u8; 440],
+    unique_mbr_disk_signature: U32,
+    unknown: U16,
+    pub partion_record: [PartionTableEntry; 4],
+    pub signature: U16,
+}
+
+impl MBR {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 512);
+    };
+
+    pub const SIGNATURE: u16 = 0xAA55;
+
+    /// The MBR placed in front of a GPT so legacy tools see the disk as in use, a single
+    /// partition of type `0xEE` covering the whole disk, capped at what fits in 32 bits.
+    pub fn protective(total_sectors: u64) -> Self {
+        let mut mbr = Self::new_zeroed();
+
+        mbr.partion_record[0] = PartionTableEntry {
+            drive_attributes: 0,
+            starting_chs: CHSAddress(0x00, 0x02, 0x00),
+            os_type: PartionTableEntry::GPT_PROTECTIVE,
+            ending_chs: CHSAddress(0xFF, 0xFF, 0xFF),
+            starting_lba: U32::new(1),
+            sector_count: U32::new(u32::try_from(total_sectors - 1).unwrap_or(u32::MAX)),
+        };
+        mbr.signature = U16::new(Self::SIGNATURE);
+
+        mbr
+    }
+
+    /// Reads the MBR, or an extended boot record, at `lba`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MbrError`] if the read fails or the boot signature is missing.
+    pub fn from_device(device: &Arc<Mutex<dyn BlockDevice>>, lba: u64) -> Result<Self, MbrError> {
+        let mut sector = {
+            let mut drive = device.acquire();
+
+            let mut sector = vec![0u8; drive.sector_size()];
+            drive.read_sectors(lba, 1, &mut sector)?;
+
+            sector
+        };
+
+        sector.truncate(size_of::<Self>());
+        let mbr = Self::read_from_bytes(&sector).unwrap();
+
+        if mbr.signature.get() != Self::SIGNATURE {
+            return Err(MbrError::InvalidSignature(mbr.signature.get()));
+        }
+
+        Ok(mbr)
+    }
+
+    /// Returns true if this MBR only exists to protect a GPT.
+    pub fn is_protective(&self) -> bool {
+        self.partion_record
+            .iter()
+            .any(|entry| entry.os_type == PartionTableEntry::GPT_PROTECTIVE)
+    }
+
+    /// Returns the primary partitions followed by the logical partitions of the extended
+    /// partition, if there is one, in the order they appear in the chain.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MbrError`] if an extended boot record can't be read, is invalid or lies
+    /// outside of the extended partition.
+    pub fn partions(
+        &self,
+        device: &Arc<Mutex<dyn BlockDevice>>,
+    ) -> Result<Vec<MbrPartion>, MbrError> {
+        let total_sectors = device.acquire().total_sectors();
+
+        let mut partions: Vec<MbrPartion> = self
+            .partion_record
+            .iter()
+            .filter(|entry| entry.is_used() && !entry.is_extended())
+            .map(|entry| MbrPartion::new(entry, 0))
+            .collect();
+
+        let Some(extended) = self.partion_record.iter().find(|entry| entry.is_extended()) else {
+            return Ok(partions);
+        };
+
+        let extended_start = u64::from(extended.starting_lba.get());
+        let extended_end = extended_start + u64::from(extended.sector_count.get());
+
+        if extended_end > total_sectors {
+            return Err(MbrError::InvalidExtendedPartion(
+                "runs past the end of the disk",
+            ));
+        }
+
+        let mut ebr_lba = extended_start;
+
+        for _ in 0..MAX_LOGICAL_PARTIONS {
+            let ebr = Self::from_device(device, ebr_lba)?;
+            let [logical, next, ..] = ebr.partion_record;
+
+            // the logical partition is relative to its EBR
+            if logical.is_used() {
+                let partion = MbrPartion::new(&logical, ebr_lba);
+
+                if partion.starting_lba + partion.sector_count > extended_end {
+                    return Err(MbrError::InvalidExtendedPartion(
+                        "logical partion runs past the end of the extended partion",
+                    ));
+                }
+
+                partions.push(partion);
+            }
+
+            if !next.is_extended() {
+                return Ok(partions);
+            }
+
+            // the next EBR is relative to the start of the extended partition
+            ebr_lba = extended_start + u64::from(next.starting_lba.get());
+
+            if ebr_lba >= extended_end || ebr_lba == extended_start {
+                return Err(MbrError::InvalidExtendedPartion(
+                    "extended boot record outside of the extended partion",
+                ));
+            }
+        }
+
+        Err(MbrError::InvalidExtendedPartion(
+            "too many logical partions, the chain might loop",
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+#[repr(C)]
+pub struct PartionTableEntry {
+    drive_attributes: u8,
+    starting_chs: CHSAddress,
+    os_type: u8,
+    ending_chs: CHSAddress,
+    starting_lba: U32,
+    sector_count: U32,
+}
+
+impl PartionTableEntry {
+    pub const GPT_PROTECTIVE: u8 = 0xEE;
+    pub const BOOTABLE: u8 = 0x80;
+
+    pub const fn is_used(&self) -> bool {
+        self.os_type != 0 && self.sector_count.get() != 0
+    }
+
+    /// Returns true for the CHS, LBA and Linux extended partition types.
+    pub const fn is_extended(&self) -> bool {
+        matches!(self.os_type, 0x05 | 0x0F | 0x85)
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+#[repr(C)]
+#[allow(dead_code)]
+pub struct CHSAddress(u8, u8, u8);
+
+/// A primary or logical partition with its start made absolute.
+#[derive(Debug, Clone, Copy)]
+pub struct MbrPartion {
+    pub os_type: u8,
+    pub bootable: bool,
+    pub starting_lba: u64,
+    pub sector_count: u64,
+}
+
+impl MbrPartion {
+    const fn new(entry: &PartionTableEntry, relative_to: u64) -> Self {
+        Self {
+            os_type: entry.os_type,
+            bootable: entry.drive_attributes & PartionTableEntry::BOOTABLE != 0,
+            starting_lba: relative_to + u64::from(entry.starting_lba.get()),
+            sector_count: u64::from(entry.sector_count.get()),
+        }
+    }
+
+    /// Maps the `os_type` to the filesystem drivers that handle it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the `os_type` is not a known file system.
+    pub const fn get_fs(&self) -> Result<FSGuid, &'static str> {
+        match self.os_type {
+            // FAT16 and FAT32 with CHS or LBA addressing, and exFAT/NTFS
+            0x04 | 0x06 | 0x0E | 0x0B | 0x0C | 0x07 => Ok(FSGuid::MicrosoftData),
+            0x83 => Ok(FSGuid::LinuxFilesystem),
+            0x82 => Ok(FSGuid::LinuxSwap),
+            0x8E => Ok(FSGuid::LinuxLvm),
+            0xEF => Ok(FSGuid::EfiSystem),
+            _ => Err("Can't find filesystem with that os type"),
+        }
+    }
+}
+
+impl From<&MbrPartion> for PartitionEntry {
+    /// Describes the partition like a GPT entry so it can be handed to the same drivers, the
+    /// type GUID is zero if the `os_type` is unknown.
+    fn from(partion: &MbrPartion) -> Self {
+        let mut entry = Self::new_zeroed();
+
+        entry.partion_type_guid = U128::new(partion.get_fs().map_or(0, |fs| fs as u128));
+        entry.starting_lba = U64::new(partion.starting_lba);
+        entry.ending_lba = U64::new(partion.starting_lba + partion.sector_count - 1);
+
+        entry
+    }
+}
@@ -0,0 +1,358 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::iter;
+use zerocopy::little_endian::{U16, U32, U64, U128};
+use zerocopy::{FromZeros, IntoBytes};
+
+use super::mbr::MBR;
+use super::{PartionTableHeader, PartionTableHeaderError, PartionTableHeaderRaw, PartitionEntry};
+use crate::device_manager::{BlockDevice, BlockDeviceError};
+use crate::multitasking::mutex::Mutex;
+use crate::random::random_u64;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PartionTableWriteError {
+    #[error("The underlying device ran into an error")]
+    DeviceError(#[from] BlockDeviceError),
+    #[error("The existing partion table is invalid")]
+    InvalidPartionTable(#[from] PartionTableHeaderError),
+    #[error("The disk is too small to hold a partion table")]
+    DiskTooSmall,
+    #[error("Every partion entry is in use")]
+    TableFull,
+    #[error("There is no free space large enough for the partion")]
+    NoSpace,
+    #[error("Partion entry `{0}` is not in use")]
+    InvalidIndex(usize),
+    #[error("Partion name is longer then 36 UTF-16 code units")]
+    NameTooLong,
+}
+
+/// A GPT being edited in memory, nothing is written until [`PartionTable::write`] is called.
+pub struct PartionTable {
+    disk_guid: u128,
+    total_sectors: u64,
+    sector_size: usize,
+    first_usable_logical_block: u64,
+    last_usable_logical_block: u64,
+    size_of_partion_entry: u32,
+    entries: Vec<PartitionEntry>,
+}
+
+impl PartionTable {
+    const DEFAULT_NUM_OF_PARTIONS: usize = 128;
+    const REVISION: u32 = 0x0001_0000;
+    /// Partitions are aligned to 1 MiB like other partitioning tools.
+    const PARTION_ALIGNMENT: usize = 1024 * 1024;
+
+    /// Creates an empty table covering all of `device` with a new random disk GUID.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PartionTableWriteError::DiskTooSmall`] if the headers and partition entry
+    /// arrays don't leave any usable space.
+    pub fn new(device: &Arc<Mutex<dyn BlockDevice>>) -> Result<Self, PartionTableWriteError> {
+        let (sector_size, total_sectors) = {
+            let drive = device.acquire();
+
+            (drive.sector_size(), drive.total_sectors())
+        };
+
+        if sector_size < size_of::<MBR>() {
+            return Err(PartionTableWriteError::DiskTooSmall);
+        }
+
+        let mut table = Self {
+            disk_guid: random_guid(),
+            total_sectors,
+            sector_size,
+            first_usable_logical_block: 0,
+            last_usable_logical_block: 0,
+            size_of_partion_entry: u32::try_from(size_of::<PartitionEntry>()).unwrap(),
+            entries: vec![PartitionEntry::new_zeroed(); Self::DEFAULT_NUM_OF_PARTIONS],
+        };
+
+        let array_sectors = table.array_sectors();
+
+        // The protective MBR and primary header and array come first, the backup array and
+        // header last
+        table.first_usable_logical_block = 2 + array_sectors;
+        table.last_usable_logical_block = total_sectors
+            .checked_sub(2 + array_sectors)
+            .filter(|last| *last >= table.first_usable_logical_block)
+            .ok_or(PartionTableWriteError::DiskTooSmall)?;
+
+        Ok(table)
+    }
+
+    /// Loads the existing table on `device` so it can be edited.
+    ///
+    /// Entries larger then [`PartitionEntry`] lose their trailing bytes when written back.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PartionTableWriteError`] if the table can't be read or is invalid.
+    pub fn from_device(
+        device: &Arc<Mutex<dyn BlockDevice>>,
+    ) -> Result<Self, PartionTableWriteError> {
+        let header = PartionTableHeader::from_device(device)?;
+        let entries = header.read_entries(device)?;
+
+        let (sector_size, total_sectors) = {
+            let drive = device.acquire();
+
+            (drive.sector_size(), drive.total_sectors())
+        };
+
+        Ok(Self {
+            disk_guid: header.disk_guid,
+            total_sectors,
+            sector_size,
+            first_usable_logical_block: header.first_usable_logical_block,
+            last_usable_logical_block: header.last_usable_logical_block,
+            size_of_partion_entry: header.size_of_partion_entry,
+            entries,
+        })
+    }
+
+    pub const fn entries(&self) -> &[PartitionEntry] {
+        self.entries.as_slice()
+    }
+
+    /// Adds a partition in the first free entry, placed in the first aligned gap that fits it.
+    ///
+    /// If `sectors` is [`None`] the partition fills the whole gap. Returns the index of the
+    /// entry used.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PartionTableWriteError`] if the name is too long, every entry is in use or there
+    /// isn't enough free space.
+    pub fn add_partion(
+        &mut self,
+        type_guid: u128,
+        name: &str,
+        sectors: Option<u64>,
+    ) -> Result<usize, PartionTableWriteError> {
+        let partion_name = encode_name(name)?;
+
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.partion_type_guid.get() == 0)
+            .ok_or(PartionTableWriteError::TableFull)?;
+
+        let (starting_lba, ending_lba) = self.find_free_space(sectors)?;
+
+        self.entries[index] = PartitionEntry {
+            partion_type_guid: U128::new(type_guid),
+            unique_partion_guid: U128::new(random_guid()),
+            starting_lba: U64::new(starting_lba),
+            ending_lba: U64::new(ending_lba),
+            attributes: U64::new(0),
+            partion_name,
+        };
+
+        Ok(index)
+    }
+
+    /// # Errors
+    ///
+    /// Returns [`PartionTableWriteError::InvalidIndex`] if the entry is not in use.
+    pub fn delete_partion(&mut self, index: usize) -> Result<(), PartionTableWriteError> {
+        *self.used_entry(index)? = PartitionEntry::new_zeroed();
+
+        Ok(())
+    }
+
+    /// # Errors
+    ///
+    /// Returns [`PartionTableWriteError`] if the entry is not in use or the name is too long.
+    pub fn rename_partion(
+        &mut self,
+        index: usize,
+        name: &str,
+    ) -> Result<(), PartionTableWriteError> {
+        let partion_name = encode_name(name)?;
+
+        self.used_entry(index)?.partion_name = partion_name;
+
+        Ok(())
+    }
+
+    /// Writes the protective MBR and both copies of the table to `device`.
+    ///
+    /// The backup is written first, so if writing is interrupted the primary header either
+    /// still matches its array or fails its CRC and the new backup is used.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BlockDeviceError`] if any write fails.
+    pub fn write(&self, device: &Arc<Mutex<dyn BlockDevice>>) -> Result<(), BlockDeviceError> {
+        let mut array = Vec::new();
+
+        for entry in &self.entries {
+            array.extend_from_slice(entry.as_bytes());
+            array.resize(
+                array.len() + self.entry_size() - size_of::<PartitionEntry>(),
+                0,
+            );
+        }
+
+        let array_crc = crc32fast::hash(&array);
+        array.resize(
+            usize::try_from(self.array_sectors()).unwrap() * self.sector_size,
+            0,
+        );
+
+        let last_lba = self.total_sectors - 1;
+        let backup_array_lba = self.last_usable_logical_block + 1;
+
+        let mut mbr = vec![0u8; self.sector_size];
+        mbr[..size_of::<MBR>()].copy_from_slice(MBR::protective(self.total_sectors).as_bytes());
+
+        let mut drive = device.acquire();
+
+        write_sectors(&mut *drive, backup_array_lba, &array)?;
+        write_sectors(
+            &mut *drive,
+            last_lba,
+            &self.header_sector(last_lba, 1, backup_array_lba, array_crc),
+        )?;
+        write_sectors(&mut *drive, 2, &array)?;
+        write_sectors(
+            &mut *drive,
+            1,
+            &self.header_sector(1, last_lba, 2, array_crc),
+        )?;
+        write_sectors(&mut *drive, 0, &mbr)?;
+
+        Ok(())
+    }
+
+    fn header_sector(&self, lba: u64, alt_lba: u64, array_lba: u64, array_crc: u32) -> Vec<u8> {
+        let mut header = PartionTableHeaderRaw {
+            signature: *b"EFI PART",
+            gpt_revison: U32::new(Self::REVISION),
+            header_size: U32::new(u32::try_from(size_of::<PartionTableHeaderRaw>()).unwrap()),
+            crc32_checksum: U32::new(0),
+            reserved: U32::new(0),
+            lba_table_header: U64::new(lba),
+            lba_alt_table_header: U64::new(alt_lba),
+            first_usable_logical_block: U64::new(self.first_usable_logical_block),
+            last_usable_logical_block: U64::new(self.last_usable_logical_block),
+            disk_guid: U128::new(self.disk_guid),
+            partion_entry_lba: U64::new(array_lba),
+            num_of_partions: U32::new(u32::try_from(self.entries.len()).unwrap()),
+            size_of_partition_entry: U32::new(self.size_of_partion_entry),
+            crc32_partion_entry_array: U32::new(array_crc),
+        };
+
+        header.crc32_checksum = U32::new(crc32fast::hash(header.as_bytes()));
+
+        let mut sector = vec![0u8; self.sector_size];
+        sector[..size_of::<PartionTableHeaderRaw>()].copy_from_slice(header.as_bytes());
+
+        sector
+    }
+
+    fn used_entry(&mut self, index: usize) -> Result<&mut PartitionEntry, PartionTableWriteError> {
+        self.entries
+            .get_mut(index)
+            .filter(|entry| entry.partion_type_guid.get() != 0)
+            .ok_or(PartionTableWriteError::InvalidIndex(index))
+    }
+
+    /// Returns the first and last lba of the first aligned gap that fits `sectors`.
+    fn find_free_space(&self, sectors: Option<u64>) -> Result<(u64, u64), PartionTableWriteError> {
+        let alignment = u64::try_from((Self::PARTION_ALIGNMENT / self.sector_size).max(1)).unwrap();
+        let align = |lba: u64| lba.div_ceil(alignment) * alignment;
+
+        let mut used: Vec<(u64, u64)> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.partion_type_guid.get() != 0)
+            .map(|entry| (entry.starting_lba.get(), entry.ending_lba.get()))
+            .collect();
+        used.sort_unstable();
+
+        let end = self.last_usable_logical_block + 1;
+        let mut start = align(self.first_usable_logical_block);
+
+        // the end of the usable range acts as a partition so the last gap is checked
+        for (used_start, used_end) in used.into_iter().chain(iter::once((end, end))) {
+            let available = used_start.saturating_sub(start);
+
+            match sectors {
+                Some(sectors) if sectors != 0 && sectors <= available => {
+                    return Ok((start, start + sectors - 1));
+                }
+                None if available != 0 => return Ok((start, used_start - 1)),
+                _ => {}
+            }
+
+            start = start.max(align(used_end + 1));
+        }
+
+        Err(PartionTableWriteError::NoSpace)
+    }
+
+    fn entry_size(&self) -> usize {
+        usize::try_from(self.size_of_partion_entry).unwrap()
+    }
+
+    fn array_sectors(&self) -> u64 {
+        u64::try_from((self.entries.len() * self.entry_size()).div_ceil(self.sector_size)).unwrap()
+    }
+}
+
+/// Writes whole sectors starting at `lba`, in as many writes as needed.
+fn write_sectors(
+    drive: &mut dyn BlockDevice,
+    lba: u64,
+    buffer: &[u8],
+) -> Result<(), BlockDeviceError> {
+    let sector_size = drive.sector_size();
+
+    for (lba, chunk) in (lba..)
+        .step_by(usize::from(u16::MAX))
+        .zip(buffer.chunks(sector_size * usize::from(u16::MAX)))
+    {
+        drive.write_sectors(
+            lba,
+            u16::try_from(chunk.len() / sector_size).unwrap(),
+            chunk,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Generates a random version 4 GUID.
+fn random_guid() -> u128 {
+    let mut bytes = ((u128::from(random_u64()) << 64) | u128::from(random_u64())).to_le_bytes();
+
+    // the version is the high nibble of `time_high_and_version` and the variant the high bits
+    // of `clock_seq_high_and_reserved`
+    bytes[7] = (bytes[7] & 0x0F) | 0x40;
+    bytes[8] = (bytes[8] & 0x3F) | 0x80;
+
+    u128::from_le_bytes(bytes)
+}
+
+/// Encodes `name` as UTF-16 padded with NULs.
+fn encode_name(name: &str) -> Result<[U16; 36], PartionTableWriteError> {
+    let mut encoded = [U16::new(0); 36];
+    let mut units = name.encode_utf16();
+
+    for (slot, unit) in encoded.iter_mut().zip(&mut units) {
+        *slot = U16::new(unit);
+    }
+
+    if units.next().is_some() {
+        return Err(PartionTableWriteError::NameTooLong);
+    }
+
+    Ok(encoded)
+}
@@ -0,0 +1,220 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+
+use crate::allocator;
+use crate::device_manager::cache;
+use crate::device_manager::{BlockDevice, DeviceManager};
+use crate::filesystem::{FileSystem, FileTrait, INError, OUTError};
+use crate::logger::LOGGER;
+use crate::memory::{ALLOCATED_FRAMES, USABLE_FRAMES};
+use crate::multitasking::SCHEDULER;
+use crate::pci::Capability;
+use crate::pci::capability::CapabilityId;
+use crate::timer::TIME_KEEPER;
+
+/// Exposes kernel state as read only text files, mounted at `/proc`.
+///
+/// The contents of a file are generated when it is opened.
+pub struct ProcFs {
+    device_manager: Arc<DeviceManager>,
+}
+
+impl ProcFs {
+    pub const fn new(device_manager: Arc<DeviceManager>) -> Self {
+        Self { device_manager }
+    }
+
+    fn tasks() -> String {
+        let tasks: Vec<_> = SCHEDULER.with_ref(|scheduler| {
+            scheduler
+                .get_current_task()
+                .into_iter()
+                .chain(scheduler.get_ready_tasks().iter().cloned())
+                .chain(scheduler.get_blocked_tasks().iter().cloned())
+                .collect()
+        });
+
+        let mut contents = String::from("id\tstate\ttime used\tname\n");
+
+        for task in tasks {
+            // the task reading the file holds its own lock
+            let Some(task) = task.try_acquire() else {
+                continue;
+            };
+
+            let _ = writeln!(
+                contents,
+                "{}\t{:?}\t{}\t{}",
+                task.id.0, task.state, task.time_used, task.common_name
+            );
+        }
+
+        contents
+    }
+
+    fn uptime() -> String {
+        let uptime = TIME_KEEPER.with_ref(|keeper| keeper.time_since_boot.time);
+
+        alloc::format!("{uptime}\n")
+    }
+
+    fn meminfo() -> String {
+        let heap = allocator::heap_stats();
+        let usable_frames = USABLE_FRAMES.load(Ordering::Relaxed);
+        let allocated_frames = ALLOCATED_FRAMES.load(Ordering::Relaxed);
+
+        alloc::format!(
+            "HeapTotal: {} bytes\nHeapUsed: {} bytes\nHeapFree: {} bytes\nFramesTotal: {usable_frames}\nFramesAllocated: {allocated_frames}\nFramesFree: {}\n",
+            heap.size,
+            heap.used,
+            heap.size - heap.used,
+            usable_frames.saturating_sub(allocated_frames),
+        )
+    }
+
+    fn devices(&self) -> String {
+        let mut contents = String::new();
+
+        for device in &self.device_manager.devices {
+            if let Some(device) = device.try_acquire() {
+                let _ = writeln!(contents, "{:?}", &*device);
+            }
+        }
+
+        for (device, letter) in self.device_manager.block_devices.iter().zip('a'..='z') {
+            if let Some(device) = device.try_acquire() {
+                let _ = writeln!(
+                    contents,
+                    "hd{letter}: {} sectors of {} bytes",
+                    device.total_sectors(),
+                    device.sector_size()
+                );
+            }
+
+            for partition in self.device_manager.partitions_of(device) {
+                if let Some(partition) = partition.try_acquire() {
+                    let _ = writeln!(
+                        contents,
+                        "hd{letter}{}: {} sectors starting at lba {}, {}",
+                        partition.number(),
+                        partition.total_sectors(),
+                        partition.entry().starting_lba.get(),
+                        partition.entry().type_name()
+                    );
+                }
+            }
+        }
+
+        contents
+    }
+
+    fn cache(&self) -> String {
+        let mut contents = String::from(
+            "disk\tcached\tdirty\tcapacity\thits\tmisses\tread ahead\tevictions\twrite backs\n",
+        );
+
+        let registered = cache::caches();
+
+        for (device, letter) in self.device_manager.block_devices.iter().zip('a'..='z') {
+            let Some(cache) = registered
+                .iter()
+                .find(|cache| core::ptr::addr_eq(Arc::as_ptr(cache), Arc::as_ptr(device)))
+            else {
+                continue;
+            };
+
+            if let Some(cache) = cache.try_acquire() {
+                let (cached, dirty) = cache.usage();
+                let stats = cache.stats();
+
+                let _ = writeln!(
+                    contents,
+                    "hd{letter}\t{cached}\t{dirty}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    cache.capacity(),
+                    stats.hits,
+                    stats.misses,
+                    stats.read_ahead,
+                    stats.evictions,
+                    stats.write_backs
+                );
+            }
+        }
+
+        contents
+    }
+
+    fn pci(&self) -> String {
+        let mut contents = String::from("address\tvendor\tdevice\tclass\tdriver\tcapabilities\n");
+
+        for (device, driver) in &self.device_manager.pci_devices {
+            let address = device.address;
+            let capabilities: Vec<CapabilityId> =
+                device.capabilities().map(Capability::kind).collect();
+
+            let _ = writeln!(
+                contents,
+                "{:02x}:{:02x}.{}\t{:04x}\t{:04x}\t{:?}\t{}\t{:?}",
+                address.bus,
+                address.slot,
+                address.func,
+                device.info.vendor_id,
+                device.info.device_num,
+                device.info.class(),
+                driver.unwrap_or("-"),
+                capabilities
+            );
+        }
+
+        contents
+    }
+
+    fn log() -> String {
+        LOGGER.with_ref(|logger| {
+            let mut contents = String::new();
+
+            for event in logger.get_events() {
+                let _ = writeln!(contents, "{event}");
+            }
+
+            contents
+        })
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn open(&mut self, path: &str) -> Option<Box<dyn FileTrait + '_>> {
+        let contents = match path.trim_matches('/') {
+            "tasks" => Self::tasks(),
+            "uptime" => Self::uptime(),
+            "meminfo" => Self::meminfo(),
+            "devices" => self.devices(),
+            "cache" => self.cache(),
+            "pci" => self.pci(),
+            "log" => Self::log(),
+            _ => return None,
+        };
+
+        Some(Box::new(ProcFile { contents }))
+    }
+}
+
+struct ProcFile {
+    contents: String,
+}
+
+impl FileTrait for ProcFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, INError> {
+        let length = buf.len().min(self.contents.len());
+        buf[..length].copy_from_slice(&self.contents.as_bytes()[..length]);
+
+        Ok(length)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, OUTError> {
+        Err(OUTError::NotWritable)
+    }
+}
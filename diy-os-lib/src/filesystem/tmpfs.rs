@@ -0,0 +1,287 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::filesystem::{Dir, File, FileSystem, FileSystemError, FileTrait, INError, OUTError};
+
+/// A writable filesystem that only lives on the heap.
+///
+/// The total size of all file data is limited to the capacity it was created with.
+#[derive(Debug)]
+pub struct Tmpfs {
+    root: Dir,
+    used: usize,
+    capacity: usize,
+}
+
+impl Tmpfs {
+    /// The capacity of the tmpfs mounted at `/tmp`.
+    pub const DEFAULT_CAPACITY: usize = 1024 * 1024;
+
+    /// Creates an empty tmpfs that can hold `capacity` bytes of file data.
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            root: Dir {
+                name: String::new(),
+                dirs: Vec::new(),
+                files: Vec::new(),
+            },
+            used: 0,
+            capacity,
+        }
+    }
+
+    /// Returns the number of bytes of file data stored.
+    pub const fn used(&self) -> usize {
+        self.used
+    }
+
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Splits `path` into its components, resolving `.` and `..`.
+fn components(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+
+    components
+}
+
+/// Splits `path` into the components of its parent and its name.
+fn split(path: &str) -> Result<(Vec<&str>, &str), FileSystemError> {
+    let mut components = components(path);
+    let name = components.pop().ok_or(FileSystemError::InvalidPath)?;
+
+    Ok((components, name))
+}
+
+impl Dir {
+    fn contains(&self, name: &str) -> bool {
+        self.dirs.iter().any(|dir| dir.name == name)
+            || self.files.iter().any(|file| file.name == name)
+    }
+
+    fn dir(&self, components: &[&str]) -> Result<&Self, FileSystemError> {
+        components.iter().try_fold(self, |dir, component| {
+            dir.dirs
+                .iter()
+                .find(|dir| dir.name == *component)
+                .ok_or_else(|| dir.missing(component))
+        })
+    }
+
+    fn dir_mut(&mut self, components: &[&str]) -> Result<&mut Self, FileSystemError> {
+        components.iter().try_fold(self, |dir, component| {
+            let error = dir.missing(component);
+
+            dir.dirs
+                .iter_mut()
+                .find(|dir| dir.name == *component)
+                .ok_or(error)
+        })
+    }
+
+    /// The error for a directory `name` that could not be found.
+    fn missing(&self, name: &str) -> FileSystemError {
+        if self.files.iter().any(|file| file.name == name) {
+            FileSystemError::NotADirectory
+        } else {
+            FileSystemError::NotFound
+        }
+    }
+}
+
+impl FileSystem for Tmpfs {
+    fn open(&mut self, path: &str) -> Option<Box<dyn FileTrait + '_>> {
+        let (parent, name) = split(path).ok()?;
+
+        let file = self
+            .root
+            .dir_mut(&parent)
+            .ok()?
+            .files
+            .iter_mut()
+            .find(|file| file.name == name)?;
+
+        Some(Box::new(TmpfsFile {
+            file,
+            used: &mut self.used,
+            capacity: self.capacity,
+        }))
+    }
+
+    fn create(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let (parent, name) = split(path)?;
+        let parent = self.root.dir_mut(&parent)?;
+
+        if parent.contains(name) {
+            return Err(FileSystemError::AlreadyExists);
+        }
+
+        parent.files.push(File {
+            name: String::from(name),
+            data: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let (parent, name) = split(path)?;
+        let parent = self.root.dir_mut(&parent)?;
+
+        if parent.contains(name) {
+            return Err(FileSystemError::AlreadyExists);
+        }
+
+        parent.dirs.push(Dir {
+            name: String::from(name),
+            dirs: Vec::new(),
+            files: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let (parent, name) = split(path)?;
+        let parent = self.root.dir_mut(&parent)?;
+
+        if let Some(index) = parent.files.iter().position(|file| file.name == name) {
+            let file = parent.files.swap_remove(index);
+            self.used -= file.data.len();
+
+            return Ok(());
+        }
+
+        let index = parent
+            .dirs
+            .iter()
+            .position(|dir| dir.name == name)
+            .ok_or(FileSystemError::NotFound)?;
+
+        if !parent.dirs[index].dirs.is_empty() || !parent.dirs[index].files.is_empty() {
+            return Err(FileSystemError::DirectoryNotEmpty);
+        }
+
+        parent.dirs.swap_remove(index);
+
+        Ok(())
+    }
+
+    /// Moves a file or directory, replacing the destination if both are files.
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        let (from_parent, from_name) = split(from)?;
+        let (to_parent, to_name) = split(to)?;
+
+        if from_parent == to_parent && from_name == to_name {
+            return Ok(());
+        }
+
+        // A directory can't be moved inside of itself
+        if to_parent.len() > from_parent.len()
+            && to_parent.starts_with(&from_parent)
+            && to_parent[from_parent.len()] == from_name
+        {
+            return Err(FileSystemError::InvalidPath);
+        }
+
+        let source = self.root.dir(&from_parent)?;
+        let source_is_file = if source.files.iter().any(|file| file.name == from_name) {
+            true
+        } else if source.dirs.iter().any(|dir| dir.name == from_name) {
+            false
+        } else {
+            return Err(FileSystemError::NotFound);
+        };
+
+        let destination = self.root.dir_mut(&to_parent)?;
+
+        if let Some(index) = destination
+            .files
+            .iter()
+            .position(|file| file.name == to_name)
+            && source_is_file
+        {
+            let replaced = destination.files.swap_remove(index);
+            self.used -= replaced.data.len();
+        } else if destination.contains(to_name) {
+            return Err(FileSystemError::AlreadyExists);
+        }
+
+        let source = self.root.dir_mut(&from_parent)?;
+
+        if source_is_file {
+            let index = source
+                .files
+                .iter()
+                .position(|file| file.name == from_name)
+                .unwrap();
+            let mut file = source.files.swap_remove(index);
+            file.name = String::from(to_name);
+
+            self.root.dir_mut(&to_parent)?.files.push(file);
+        } else {
+            let index = source
+                .dirs
+                .iter()
+                .position(|dir| dir.name == from_name)
+                .unwrap();
+            let mut dir = source.dirs.swap_remove(index);
+            dir.name = String::from(to_name);
+
+            self.root.dir_mut(&to_parent)?.dirs.push(dir);
+        }
+
+        Ok(())
+    }
+}
+
+struct TmpfsFile<'a> {
+    file: &'a mut File,
+    used: &'a mut usize,
+    capacity: usize,
+}
+
+impl FileTrait for TmpfsFile<'_> {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, INError> {
+        let length = buf.len().min(self.file.data.len());
+        buf[..length].copy_from_slice(&self.file.data[..length]);
+
+        Ok(length)
+    }
+
+    /// Writes `buf` to the start of the file, growing it if needed.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, OUTError> {
+        let growth = buf.len().saturating_sub(self.file.data.len());
+
+        if *self.used + growth > self.capacity {
+            return Err(OUTError::WriteLargerThenMaxFileSize);
+        }
+
+        self.file
+            .data
+            .try_reserve(growth)
+            .map_err(|_| OUTError::WriteLargerThenMaxFileSize)?;
+
+        if buf.len() > self.file.data.len() {
+            self.file.data.resize(buf.len(), 0);
+        }
+
+        self.file.data[..buf.len()].copy_from_slice(buf);
+        *self.used += growth;
+
+        Ok(buf.len())
+    }
+}
@@ -1,105 +1,293 @@
-use alloc::{slice, vec::Vec};
-use core::ffi::{c_char, c_uchar};
+// reference docs at https://web.archive.org/web/20250226060538/https://wiki.osdev.org/USTAR
+// and https://web.archive.org/web/20250319010232/https://www.gnu.org/software/tar/manual/html_node/Standard.html
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
 
+use crate::filesystem::{FileSystem, FileTrait, INError, MountError, OUTError};
+
+/// Symbolic links are followed at most this many times while resolving a path.
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+/// A read only filesystem backed by a ustar archive in memory.
 pub struct Ustar {
-    ptr: *mut MetaData,
+    files: Vec<File>,
 }
 
 impl Ustar {
-    const BLOCK_SIZE: u16 = 512;
+    const BLOCK_SIZE: usize = 512;
 
-    /// Crates a new Ustar ramdisk
+    /// Indexes every entry of the archive, validating each header's checksum.
+    ///
+    /// # Errors
     ///
-    /// # Safety
-    /// Caller must make that the addr is valid
-    pub const unsafe fn new(addr: usize) -> Self {
-        Self {
-            ptr: core::ptr::with_exposed_provenance_mut::<MetaData>(addr),
+    /// Returns [`MountError::InvalidFileSystem`] if a header is invalid or an entry runs past the
+    /// end of the archive.
+    pub fn new(archive: &'static [u8]) -> Result<Self, MountError> {
+        let mut files = Vec::new();
+        let mut offset = 0;
+
+        while let Some(block) = archive.get(offset..offset + Self::BLOCK_SIZE) {
+            // The archive ends with two empty blocks, a single one is enough to stop
+            if block.iter().all(|byte| *byte == 0) {
+                break;
+            }
+
+            let metadata = MetaData::ref_from_bytes(block).unwrap();
+
+            metadata.validate().map_err(MountError::InvalidFileSystem)?;
+
+            let size = usize::try_from(
+                parse_octal(&metadata.file_size)
+                    .ok_or(MountError::InvalidFileSystem("Invalid file size"))?,
+            )
+            .map_err(|_| MountError::InvalidFileSystem("File size is too large"))?;
+
+            let data_start = offset + Self::BLOCK_SIZE;
+
+            // Only regular files have data, the size of links and directories is meaningless
+            let data =
+                if metadata.file_type() == Some(FileType::NormalFile) {
+                    archive.get(data_start..data_start + size).ok_or(
+                        MountError::InvalidFileSystem("File runs past the end of the archive"),
+                    )?
+                } else {
+                    &[]
+                };
+
+            files.push(File {
+                path: metadata.path(),
+                metadata,
+                data,
+            });
+
+            offset = data_start + size.div_ceil(Self::BLOCK_SIZE) * Self::BLOCK_SIZE;
         }
+
+        Ok(Self { files })
     }
 
-    pub fn get_files(&self) -> Vec<File> {
-        let mut files: Vec<File> = Vec::new();
+    pub const fn get_files(&self) -> &[File] {
+        self.files.as_slice()
+    }
 
-        let mut ptr = self.ptr;
-        loop {
-            // if the next 2 block are empty exit
-            if unsafe { slice::from_raw_parts(ptr as *const u8, (Self::BLOCK_SIZE * 2).into()) }
-                .iter()
-                .all(|x| *x == 0)
-            {
-                break;
-            }
+    fn find(&self, path: &str) -> Option<&File> {
+        // later entries replace earlier ones with the same name
+        self.files.iter().rev().find(|file| file.path == path)
+    }
 
-            let metadata = unsafe { &mut *ptr };
+    /// Resolves `path` relative to the root of the archive, following symbolic links.
+    ///
+    /// Absolute link targets are resolved from the root of the archive.
+    fn resolve(&self, path: &str, depth: usize) -> Option<&File> {
+        let components: Vec<&str> = path
+            .split('/')
+            .filter(|component| !component.is_empty() && *component != ".")
+            .collect();
 
-            let has_data = metadata.oct_to_bin() != 0;
+        let mut current = String::new();
 
-            let mut file = File {
-                inode: metadata,
-                data: None,
+        for (index, component) in components.iter().enumerate() {
+            if *component == ".." {
+                current = current
+                    .rsplit_once('/')
+                    .map_or_else(String::new, |(parent, _)| parent.to_string());
+                continue;
+            }
+
+            let candidate = if current.is_empty() {
+                (*component).to_string()
+            } else {
+                format!("{current}/{component}")
             };
 
-            if has_data {
-                let data = unsafe { &mut *(ptr.add(1).cast::<Data>()) };
+            if let Some(link) = self
+                .find(&candidate)
+                .filter(|file| file.metadata.file_type() == Some(FileType::SymbolicLink))
+            {
+                if depth >= MAX_SYMLINK_DEPTH {
+                    log::error!("too many levels of symbolic links resolving {path}");
+                    return None;
+                }
 
-                file.data = Some(data);
+                let target = c_str(&link.metadata.link_name);
+                let rest = components[index + 1..].join("/");
 
-                ptr = unsafe { ptr.add(2) };
-            } else {
-                ptr = unsafe { ptr.add(1) };
+                let target = if target.starts_with('/') {
+                    format!("{target}/{rest}")
+                } else {
+                    format!("{current}/{target}/{rest}")
+                };
+
+                return self.resolve(&target, depth + 1);
             }
 
-            files.push(file);
+            // Archives don't need an entry for every directory
+            current = candidate;
+        }
+
+        self.find(&current)
+    }
+}
+
+impl FileSystem for Ustar {
+    fn open(&mut self, path: &str) -> Option<Box<dyn FileTrait + '_>> {
+        let mut file = self.resolve(path, 0)?;
+
+        if file.metadata.file_type() == Some(FileType::HardLink) {
+            file = self.find(&normalize(c_str(&file.metadata.link_name)))?;
+        }
+
+        match file.metadata.file_type() {
+            Some(FileType::NormalFile) => Some(Box::new(UstarFile { data: file.data })),
+            _ => None,
+        }
+    }
+}
+
+struct UstarFile {
+    data: &'static [u8],
+}
+
+impl FileTrait for UstarFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, INError> {
+        let length = buf.len().min(self.data.len());
+        buf[..length].copy_from_slice(&self.data[..length]);
+
+        Ok(length)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, OUTError> {
+        Err(OUTError::NotWritable)
+    }
+}
+
+/// Parses a NUL or space terminated octal field.
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = field
+        .iter()
+        .skip_while(|byte| **byte == b' ')
+        .take_while(|byte| **byte != 0 && **byte != b' ');
+
+    let mut result: u64 = 0;
+
+    for digit in digits {
+        if !(b'0'..=b'7').contains(digit) {
+            return None;
         }
 
-        files
+        result = result.checked_mul(8)? + u64::from(digit - b'0');
     }
+
+    Some(result)
 }
 
-#[repr(C, packed)]
-#[derive(Debug)]
+/// Reads a NUL terminated field, fields that fill the whole array have no terminator.
+fn c_str(field: &[u8]) -> &str {
+    let length = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+
+    str::from_utf8(&field[..length]).unwrap_or_default()
+}
+
+/// Strips the leading `./` or `/` and trailing `/` archivers add to names.
+fn normalize(path: &str) -> String {
+    path.trim_start_matches("./").trim_matches('/').to_string()
+}
+
+#[derive(Debug, FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+#[repr(C)]
 pub struct MetaData {
-    pub file_name: [c_char; 100],
-    file_mode: u64,
-    uid: u64,
-    gui: u64,
-    pub file_size: [c_uchar; 12],
-    last_modfication_time: [u8; 12],
-    check_sum: u64,
-    type_flag: u8,
-    pub link_name: [c_char; 100],
-    pub ustar_indicator: [c_char; 6],
-    pub ustar_version: [c_char; 2],
-    pub user_name: [c_char; 32],
-    pub group_name: [c_char; 32],
-    pub device_major_number: [c_char; 8],
-    pub device_minor_number: [c_char; 8],
-    pub file_name_prefix: [c_char; 155],
+    pub file_name: [u8; 100],
+    pub file_mode: [u8; 8],
+    pub uid: [u8; 8],
+    pub gid: [u8; 8],
+    pub file_size: [u8; 12],
+    pub last_modfication_time: [u8; 12],
+    pub check_sum: [u8; 8],
+    pub type_flag: u8,
+    pub link_name: [u8; 100],
+    pub ustar_indicator: [u8; 6],
+    pub ustar_version: [u8; 2],
+    pub user_name: [u8; 32],
+    pub group_name: [u8; 32],
+    pub device_major_number: [u8; 8],
+    pub device_minor_number: [u8; 8],
+    pub file_name_prefix: [u8; 155],
     _padding: [u8; 12],
 }
 
 impl MetaData {
-    pub fn oct_to_bin(&self) -> u32 {
-        let mut result: u32 = 0;
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == Ustar::BLOCK_SIZE);
+    };
 
-        for i in self.file_size {
-            if i == 0 {
-                break;
-            }
+    /// Checks the header checksum, calculated with the checksum field as spaces.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of why the header is invalid.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let expected = parse_octal(&self.check_sum).ok_or("Invalid header checksum field")?;
 
-            result *= 8;
-            result += u32::from(i - b'0');
+        let checksum_start = core::mem::offset_of!(Self, check_sum);
+        let checksum_range = checksum_start..checksum_start + self.check_sum.len();
+
+        let calculated: u64 = self
+            .as_bytes()
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| {
+                if checksum_range.contains(&i) {
+                    u64::from(b' ')
+                } else {
+                    u64::from(*byte)
+                }
+            })
+            .sum();
+
+        if calculated != expected {
+            return Err("Header checksum mismatch");
         }
 
-        result
+        Ok(())
     }
-}
 
-pub struct Data {
-    pub bytes: [u8; 512],
+    /// Returns the full path of the entry, joining the ustar prefix and name.
+    pub fn path(&self) -> String {
+        let name = c_str(&self.file_name);
+
+        if self.ustar_indicator.starts_with(b"ustar") {
+            let prefix = c_str(&self.file_name_prefix);
+
+            if !prefix.is_empty() {
+                return normalize(&format!("{prefix}/{name}"));
+            }
+        }
+
+        normalize(name)
+    }
+
+    pub const fn file_type(&self) -> Option<FileType> {
+        match self.type_flag {
+            // pre POSIX archives use NUL for normal files and 7 is contiguous files
+            b'0' | 0 | b'7' => Some(FileType::NormalFile),
+            b'1' => Some(FileType::HardLink),
+            b'2' => Some(FileType::SymbolicLink),
+            b'3' => Some(FileType::CharDevice),
+            b'4' => Some(FileType::BlockDevice),
+            b'5' => Some(FileType::Directory),
+            b'6' => Some(FileType::NamedPipe),
+            _ => None,
+        }
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum FileType {
     NormalFile = b'0',
@@ -112,13 +300,15 @@ pub enum FileType {
 }
 
 pub struct File {
-    pub inode: &'static mut MetaData,
-    data: Option<&'static mut Data>,
+    /// path relative to the root of the archive without a leading or trailing `/`
+    pub path: String,
+    pub metadata: &'static MetaData,
+    data: &'static [u8],
 }
 
 impl File {
-    pub fn get_raw_bytes(&self) -> Option<&[u8; 512]> {
-        self.data.as_ref().map(|data| &data.bytes)
+    pub const fn get_raw_bytes(&self) -> &'static [u8] {
+        self.data
     }
 }
 
@@ -128,9 +318,8 @@ mod tests {
 
     // #[test]
     // pub fn test_git_files() {
-    //     let mut bytes = std::fs::read("../bin/hello_world.tar").unwrap();
-    //     let file = bytes.as_mut_ptr();
-    //     let ramdisk = unsafe { Ustar::new(file as usize) };
+    //     let bytes = std::fs::read("../bin/hello_world.tar").unwrap();
+    //     let ramdisk = Ustar::new(bytes.leak()).unwrap();
     //
     //     let _ = ramdisk.get_files();
     // }
@@ -82,6 +82,25 @@ impl FrameBuffer {
         }
     }
 
+    /// Copies the start of the back buffer into `buf`, returning the number of bytes copied.
+    pub fn read_bytes(&self, buf: &mut [u8]) -> usize {
+        let length = buf.len().min(self.buffer.len());
+        buf[..length].copy_from_slice(&self.buffer[..length]);
+
+        length
+    }
+
+    /// Overwrites the start of the back buffer with raw pixel data and flips it to the screen.
+    ///
+    /// Returns the number of bytes written.
+    pub fn write_bytes(&mut self, buf: &[u8]) -> usize {
+        let length = buf.len().min(self.buffer.len());
+        self.buffer[..length].copy_from_slice(&buf[..length]);
+        self.flip();
+
+        length
+    }
+
     fn write_pixel(&mut self, byte_offset: usize, color: Color) {
         let bytes = (self.bytes_fn)(self.info.pixel_format, color);
         self.buffer[byte_offset] = bytes.0;
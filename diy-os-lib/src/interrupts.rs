@@ -6,13 +6,16 @@ use x86_64::{
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
 };
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use crate::{
     gdt,
-    multitasking::{SCHEDULER, Scheduler, schedule},
+    multitasking::{BlockedReason, SCHEDULER, Scheduler, block_task, schedule},
+    pci::{ahci, ide, nvme, virtio},
     println,
     ps2::controller::{InitalTrait, ReadyToReadTrait, WaitingToReadTrait},
     syscalls,
-    timer::{TIME_KEEPER, TimeKeeper},
+    timer::{Duration, TIME_KEEPER, TimeKeeper},
 };
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -25,6 +28,8 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         set_general_handler!(&mut idt, general_handler);
+        // lines without a dedicated handler, pci devices can be routed to any of them
+        set_general_handler!(&mut idt, irq_handler, PIC_1_OFFSET..PIC_2_OFFSET + 8);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         unsafe {
             idt.invalid_opcode
@@ -60,7 +65,22 @@ pub fn init_idt() {
 }
 
 pub fn unmask() {
-    unsafe { PICS.acquire().write_masks(0b1111_1000, 0b1111_1111) };
+    unsafe { PICS.acquire().write_masks(0b1111_1000, 0b0011_1111) };
+}
+
+/// Unmasks `irq` on the PIC, for devices whose line is only known once they are found.
+pub fn unmask_irq(irq: u8) {
+    PICS.with_mut_ref(|pics| {
+        let [mut primary, mut secondary] = unsafe { pics.read_masks() };
+
+        if irq < 8 {
+            primary &= !(1 << irq);
+        } else {
+            secondary &= !(1 << (irq - 8));
+        }
+
+        unsafe { pics.write_masks(primary, secondary) };
+    });
 }
 
 #[allow(clippy::needless_pass_by_value)]
@@ -180,6 +200,62 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+/// Waits up to `timeout` for the handler of `irq` to set `fired`, clearing it again. Returns
+/// false if it timed out.
+///
+/// The current task is blocked until the interrupt or the timeout, before the scheduler is running
+/// the cpu halts instead.
+pub fn wait_for_interrupt(irq: u8, fired: &AtomicBool, timeout: Duration) -> bool {
+    let until = now() + timeout.get_nanoseconds();
+
+    loop {
+        // interrupts are disabled between checking and blocking so the wake up can't be missed
+        let done = x86_64::instructions::interrupts::without_interrupts(|| {
+            if fired.swap(false, Ordering::Acquire) {
+                return true;
+            }
+
+            if SCHEDULER.with_ref(Scheduler::can_block) {
+                // SAFETY: the scheduler and time keeper aren't held
+                unsafe { block_task(BlockedReason::WaitingForInterrupt { irq, until }) };
+            } else {
+                // the timer interrupt wakes the cpu up to check the timeout
+                x86_64::instructions::interrupts::enable_and_hlt();
+                x86_64::instructions::interrupts::disable();
+            }
+
+            false
+        });
+
+        if done {
+            return true;
+        }
+
+        if now() >= until {
+            return false;
+        }
+    }
+}
+
+/// The time since boot, it only advances while interrupts are enabled.
+pub(crate) fn now() -> Duration {
+    TIME_KEEPER.with_ref(|keeper| keeper.time_since_boot.time)
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn irq_handler(_stack_frame: InterruptStackFrame, index: u8, _error_code: Option<u64>) {
+    let irq = index - PIC_1_OFFSET;
+
+    ide::irq::handle(irq);
+    ahci::handle_irq(irq);
+    nvme::handle_irq(irq);
+    virtio::handle_irq(irq);
+
+    unsafe {
+        PICS.acquire().notify_end_of_interrupt(index);
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -23,6 +23,8 @@ use timer::SystemTimerError;
 use x86_64::structures::paging::{OffsetPageTable, Size4KiB, mapper::MapToError};
 
 extern crate alloc;
+#[cfg(feature = "host")]
+extern crate std;
 
 #[cfg(not(test))]
 pub mod allocator;
@@ -42,6 +44,7 @@ pub mod multitasking;
 pub mod pci;
 pub mod pit;
 pub mod ps2;
+pub mod random;
 pub mod serial;
 pub mod syscalls;
 pub mod timer;
@@ -86,6 +89,10 @@ pub fn kernel_early(
     // setup the heap
     let mut frame_allocator =
         unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    memory::USABLE_FRAMES.store(
+        frame_allocator.usable_frame_count(),
+        core::sync::atomic::Ordering::Relaxed,
+    );
     let mut mapper = unsafe { memory::init(offset_addr) };
     #[cfg(not(test))]
     allocator::setup_heap(&mut mapper, &mut frame_allocator)
@@ -1,15 +1,24 @@
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use x86_64::registers::control::Cr3;
 use x86_64::{
     PhysAddr, VirtAddr,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB, mapper::MapToError,
+    },
 };
 
+/// The virtual address the bootloader mapped all of physical memory at, set by [`init`].
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
 /// initalis a [`OffsetPageTable`] from a `physical_memory_offset`
 ///
 /// # Safety
 /// The caller must guarantee that the `physical_memory_offset` is correct.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+
     unsafe {
         let level_4_table = active_level_4_table(physical_memory_offset);
 
@@ -32,6 +41,64 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     unsafe { &mut *page_table_ptr }
 }
 
+/// Returns the virtual address `addr` is mapped at in the physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// Where device memory is mapped, far away from the heap.
+const MMIO_START: u64 = 0x_5555_5555_0000;
+
+/// The next free page for [`map_mmio`].
+static NEXT_MMIO_PAGE: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Maps `size` bytes of device memory starting at `phys_addr` with caching disabled, returning
+/// the virtual address of `phys_addr`.
+///
+/// # Errors
+///
+/// Returns [`MapToError`] if a page table couldn't be allocated.
+///
+/// # Safety
+///
+/// `phys_addr` must be the memory mapped registers of a device, which nothing else maps.
+pub unsafe fn map_mmio(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys_addr: PhysAddr,
+    size: usize,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let frames = PhysFrame::<Size4KiB>::range_inclusive(
+        PhysFrame::containing_address(phys_addr),
+        PhysFrame::containing_address(phys_addr + (size as u64 - 1)),
+    );
+
+    let start = VirtAddr::new(NEXT_MMIO_PAGE.fetch_add(frames.len() * 4096, Ordering::Relaxed));
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+
+    for (page, frame) in Page::range(
+        Page::containing_address(start),
+        Page::containing_address(start) + frames.len(),
+    )
+    .zip(frames)
+    {
+        // SAFETY: the pages are only handed out once and the caller guarantees the frames are
+        // device memory
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    Ok(start + phys_addr.as_u64() % 4096)
+}
+
+/// The number of usable physical frames, set once the frame allocator is created.
+pub static USABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+/// The number of physical frames handed out by the frame allocator.
+pub static ALLOCATED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
 /// A [`FrameAllocator`] that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryRegions,
@@ -53,6 +120,57 @@ impl BootInfoFrameAllocator {
         }
     }
 
+    /// Returns the number of usable frames in the memory map.
+    pub fn usable_frame_count(&self) -> usize {
+        self.usable_frames().count()
+    }
+
+    /// Allocates `count` physically contiguous frames that end below `limit`, with the first
+    /// frame aligned to `align` bytes.
+    ///
+    /// Frames skipped while looking for a run are never handed out.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: u64,
+        limit: PhysAddr,
+    ) -> Option<PhysFrame> {
+        let mut run: Option<PhysFrame> = None;
+        let mut run_length = 0;
+        let mut found = None;
+
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            // the memory map is sorted so every frame after this one is above the limit too
+            if frame.start_address() + frame.size() > limit {
+                return None;
+            }
+
+            let continues_run = run.is_some_and(|start| start + run_length == frame);
+
+            if !continues_run {
+                if !frame.start_address().is_aligned(align) {
+                    run = None;
+                    continue;
+                }
+
+                run = Some(frame);
+                run_length = 0;
+            }
+
+            run_length += 1;
+
+            if run_length == count as u64 {
+                found = Some(index);
+                break;
+            }
+        }
+
+        self.next = found? + 1;
+        ALLOCATED_FRAMES.fetch_add(count, Ordering::Relaxed);
+
+        run
+    }
+
     /// Returns an iterator over the usable frames specified in the memory map.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // get usable regions from memory map
@@ -72,6 +190,66 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
+
+        if frame.is_some() {
+            ALLOCATED_FRAMES.fetch_add(1, Ordering::Relaxed);
+        }
+
         frame
     }
 }
+
+/// Physically contiguous memory below 4 GiB that devices can read and write directly.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    start: PhysFrame,
+    frames: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates a buffer of at least `size` bytes aligned to `align` bytes, returning [`None`]
+    /// if no run of frames that large is left below 4 GiB.
+    pub fn allocate(
+        frame_allocator: &mut BootInfoFrameAllocator,
+        size: usize,
+        align: u64,
+    ) -> Option<Self> {
+        let frames = size.div_ceil(4096);
+
+        let start =
+            frame_allocator.allocate_contiguous(frames, align, PhysAddr::new(0x1_0000_0000))?;
+
+        let mut buffer = Self { start, frames };
+
+        // the frames might have been used before
+        buffer.as_mut_slice().fill(0);
+
+        Some(buffer)
+    }
+
+    /// The physical address of the start of the buffer, it fits in 32 bits.
+    pub const fn phys_addr(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    /// The size of the buffer in bytes.
+    pub const fn size(&self) -> usize {
+        self.frames * 4096
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        let start = phys_to_virt(self.phys_addr()).as_ptr();
+
+        // SAFETY: the frames were allocated for this buffer only and are mapped by the
+        // physical memory mapping
+        unsafe { core::slice::from_raw_parts(start, self.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        let start = phys_to_virt(self.phys_addr()).as_mut_ptr();
+
+        // SAFETY: the frames were allocated for this buffer only and are mapped by the
+        // physical memory mapping
+        unsafe { core::slice::from_raw_parts_mut(start, self.size()) }
+    }
+}
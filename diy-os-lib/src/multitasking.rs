@@ -4,7 +4,9 @@ use crate::timer::{Duration, Miliseconds, TIME_KEEPER, TimeKeeper};
 use alloc::collections::linked_list::LinkedList;
 use alloc::string::String;
 use alloc::sync::{Arc, Weak};
+use alloc::task::Wake;
 use alloc::vec::Vec;
+use core::task::Waker;
 use log::{debug, info};
 use spinlock::Spinlock;
 use x86_64::VirtAddr;
@@ -12,10 +14,7 @@ use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
 use x86_64::{registers::control::Cr3, structures::paging::PhysFrame};
 
-// pub mod mutex;
-pub mod mutex {
-    pub type Mutex<T> = spinlock::Spinlock<T>;
-}
+pub mod mutex;
 
 // TEMP, this is not checked big UB!
 // Setup some way to track used pages
@@ -85,19 +84,39 @@ impl Scheduler {
         weak
     }
 
-    /// Wakes up the sleeping tasks that have completed.
+    /// Wakes up the sleeping tasks that have completed, and the tasks whose interrupt timed out.
     pub fn wake_up_sleeping_tasks(&mut self, time_keeper: &mut TimeKeeper) {
         let instant = time_keeper.time_since_boot.time;
 
+        let tasks: Vec<Arc<Spinlock<Task>>> = self
+            .blocked_tasks
+            .extract_if(|task| {
+                task.with_ref(|task| match task.state {
+                    State::Blocked(
+                        BlockedReason::SleepingUntil(until)
+                        | BlockedReason::WaitingForInterrupt { until, .. },
+                    ) => until <= instant,
+                    _ => false,
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            self.ready_task(task);
+        }
+    }
+
+    /// Wakes up the tasks waiting for `irq`, called from the interrupt handler.
+    pub fn wake_up_interrupt_waiters(&mut self, irq: u8) {
         let tasks: Vec<Arc<Spinlock<Task>>> = self
             .blocked_tasks
             .extract_if(|task| {
                 task.with_ref(|task| {
-                    if let State::Blocked(BlockedReason::SleepingUntil(until)) = task.state {
-                        until <= instant
-                    } else {
-                        false
-                    }
+                    matches!(
+                        task.state,
+                        State::Blocked(BlockedReason::WaitingForInterrupt { irq: waiting_for, .. })
+                            if waiting_for == irq
+                    )
                 })
             })
             .collect();
@@ -107,6 +126,33 @@ impl Scheduler {
         }
     }
 
+    /// Wakes up the task `id` if it's paused, returning false if it isn't.
+    pub fn wake_up_paused_task(&mut self, id: TaskID) -> bool {
+        self.wake_up_blocked_task(id, BlockedReason::Paused)
+    }
+
+    /// Wakes up the task `id` if it's waiting for a mutex, returning false if it isn't.
+    pub fn wake_up_mutex_waiter(&mut self, id: TaskID) -> bool {
+        self.wake_up_blocked_task(id, BlockedReason::WaitingForMutex)
+    }
+
+    fn wake_up_blocked_task(&mut self, id: TaskID, reason: BlockedReason) -> bool {
+        let task = self
+            .blocked_tasks
+            .extract_if(|task| {
+                task.with_ref(|task| task.id == id && task.state == State::Blocked(reason))
+            })
+            .next();
+
+        task.map(|task| self.ready_task(task)).is_some()
+    }
+
+    /// Returns true if [`block_task`] would switch to another task, it returns straight away
+    /// before the first task is set or when nothing else is ready.
+    pub fn can_block(&self) -> bool {
+        self.current_task.is_some() && !self.ready_tasks.is_empty()
+    }
+
     fn ready_task(&mut self, task: Arc<Spinlock<Task>>) {
         without_interrupts(|| {
             task.with_mut_ref(|task| {
@@ -162,6 +208,11 @@ pub enum BlockedReason {
     Paused,
     WaitingForMutex,
     SleepingUntil(Duration),
+    /// Waiting for the interrupt request line to fire, or the timeout to pass
+    WaitingForInterrupt {
+        irq: u8,
+        until: Duration,
+    },
     Special(SpecialCases),
 }
 
@@ -565,6 +616,31 @@ pub fn sleep(duration: Duration) {
     }
 }
 
+/// Wakes up a task paused with [`BlockedReason::Paused`].
+struct TaskWaker(TaskID);
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        SCHEDULER.with_mut_ref(|scheduler| scheduler.wake_up_paused_task(self.0));
+    }
+}
+
+/// Returns a waker for the current task, it only wakes the task up once it's blocked as
+/// [`BlockedReason::Paused`]. Returns [`None`] before the first task is set.
+///
+/// The scheduler must not be held when the waker is woken.
+pub fn current_task_waker() -> Option<Waker> {
+    let id = SCHEDULER
+        .with_ref(Scheduler::get_current_task)?
+        .with_ref(|task| task.id);
+
+    Some(Waker::from(Arc::new(TaskWaker(id))))
+}
+
 /// Terminate the current task and wakes up the cleaner.
 ///
 /// # Safety
@@ -1,14 +1,25 @@
+use alloc::collections::vec_deque::VecDeque;
 use core::{
     cell::UnsafeCell,
     fmt,
     ops::{Deref, DerefMut},
     sync::atomic::{AtomicBool, Ordering},
 };
-
-use crate::multitasking::schedule;
-
-struct Mutex<T> {
+use spinlock::Spinlock;
+
+#[cfg(not(feature = "host"))]
+use crate::multitasking::{BlockedReason, Scheduler, block_task};
+use crate::multitasking::{SCHEDULER, TaskID};
+
+/// A lock that blocks the task waiting for it instead of spinning, it can be held while the task
+/// blocks, like when waiting for a device to interrupt.
+///
+/// Interrupts stay enabled while it's held, so interrupt handlers must use a
+/// [`Spinlock`] instead.
+pub struct Mutex<T: ?Sized> {
     locked: AtomicBool,
+    /// The tasks blocked waiting for the lock, in the order they blocked
+    waiters: Spinlock<VecDeque<TaskID>>,
     data: UnsafeCell<T>,
 }
 
@@ -16,33 +27,92 @@ impl<T> Mutex<T> {
     pub const fn new(data: T) -> Self {
         Self {
             locked: AtomicBool::new(false),
+            waiters: Spinlock::new(VecDeque::new()),
             data: UnsafeCell::new(data),
         }
     }
 
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn is_acquired(&self) -> bool {
+        self.locked.load(Ordering::Acquire)
+    }
+
+    fn lock(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Acquires the lock, blocking the current task until it's released.
+    ///
+    /// Before the scheduler is running, or when no other task is ready, it spins instead.
     pub fn acquire(&self) -> MutexGuard<'_, T> {
-        while self
-            .locked
-            .compare_exchange(false, true, Ordering::SeqCst, Ordering::Acquire)
-            .is_err()
-        {
-            unsafe {
-                schedule();
-            };
+        loop {
+            if self.try_block() {
+                return MutexGuard { inner: self };
+            }
+
+            core::hint::spin_loop();
         }
+    }
 
-        MutexGuard { inner: self }
+    /// Takes the lock or blocks until it's released, returning true if it was taken.
+    #[cfg(not(feature = "host"))]
+    fn try_block(&self) -> bool {
+        // interrupts are disabled between checking and blocking so the release can't be missed
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            if self.lock() {
+                return true;
+            }
+
+            if SCHEDULER.with_ref(Scheduler::can_block) {
+                let id = SCHEDULER
+                    .with_ref(Scheduler::get_current_task)
+                    .expect("Should be running a task to block")
+                    .with_ref(|task| task.id);
+
+                self.waiters.with_mut_ref(|waiters| waiters.push_back(id));
+
+                // SAFETY: the scheduler and time keeper aren't held
+                unsafe { block_task(BlockedReason::WaitingForMutex) };
+
+                // the release already took the id if it woke this task up, otherwise it would be
+                // stale and could be popped instead of a task still waiting
+                self.waiters
+                    .with_mut_ref(|waiters| waiters.retain(|waiter| *waiter != id));
+            }
+
+            false
+        })
     }
 
-    pub fn release(&self) {
-        self.locked.store(false, Ordering::Release);
+    #[cfg(feature = "host")]
+    fn try_block(&self) -> bool {
+        self.lock()
     }
 
-    pub fn is_acquired(&self) -> bool {
-        self.locked.load(Ordering::Acquire)
+    pub fn try_acquire(&self) -> Option<MutexGuard<'_, T>> {
+        self.lock().then_some(MutexGuard { inner: self })
     }
 
-    /// Runs a closure mutable referencing the locked value
+    /// Releases the lock and wakes up the task that waited for it the longest.
+    fn release(&self) {
+        self.locked.store(false, Ordering::Release);
+
+        while let Some(id) = self.waiters.with_mut_ref(VecDeque::pop_front) {
+            // the task can have been woken up already, then it retries by itself
+            if SCHEDULER.with_mut_ref(|scheduler| scheduler.wake_up_mutex_waiter(id)) {
+                break;
+            }
+        }
+    }
+
+    /// Runs a closure referencing the locked value
     pub fn with_ref<F, R>(&self, f: F) -> R
     where
         F: FnOnce(&T) -> R,
@@ -63,41 +133,50 @@ impl<T> Mutex<T> {
     }
 }
 
-unsafe impl<T: Send> Send for Mutex<T> {}
-unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T: fmt::Debug + ?Sized> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_acquire() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
 
-pub struct MutexGuard<'a, T> {
+pub struct MutexGuard<'a, T: ?Sized> {
     inner: &'a Mutex<T>,
 }
 
-impl<T> Drop for MutexGuard<'_, T> {
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
     #[inline]
     fn drop(&mut self) {
         self.inner.release();
     }
 }
 
-impl<T> Deref for MutexGuard<'_, T> {
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        unsafe { self.inner.data.get().as_ref().unwrap() }
+        unsafe { &*self.inner.data.get() }
     }
 }
 
-impl<T> DerefMut for MutexGuard<'_, T> {
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { self.inner.data.get().as_mut().unwrap() }
+        unsafe { &mut *self.inner.data.get() }
     }
 }
 
-impl<T: fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+impl<T: fmt::Debug + ?Sized> fmt::Debug for MutexGuard<'_, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Debug::fmt(&**self, f)
     }
 }
 
-impl<T: fmt::Display> fmt::Display for MutexGuard<'_, T> {
+impl<T: fmt::Display + ?Sized> fmt::Display for MutexGuard<'_, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         (**self).fmt(f)
     }
@@ -3,7 +3,11 @@ use x86_64::instructions::port;
 
 use bitfield_struct::{bitenum, bitfield};
 
+pub mod ahci;
+pub mod capability;
 pub mod ide;
+pub mod nvme;
+pub mod virtio;
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -14,16 +18,202 @@ pub struct ConfigAddress {
     res_enabled: u8,
 }
 
+/// The location of a function on the bus, used to access its config space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub slot: u8,
+    pub func: u8,
+}
+
+impl PciAddress {
+    /// Reads the 32-bit register at `offset`, which must be 4 byte aligned.
+    pub fn read_config(self, offset: u8) -> u32 {
+        unsafe { read_pci_config_reg(self.bus, self.slot, self.func, offset) }
+    }
+
+    /// Reads the 16-bit register at `offset`, which must be 2 byte aligned.
+    pub fn read_config_u16(self, offset: u8) -> u16 {
+        let bytes = self.read_config(offset & !0b11).to_le_bytes();
+        let start = usize::from(offset & 0b10);
+
+        u16::from_le_bytes([bytes[start], bytes[start + 1]])
+    }
+
+    pub fn read_config_u8(self, offset: u8) -> u8 {
+        self.read_config(offset & !0b11).to_le_bytes()[usize::from(offset & 0b11)]
+    }
+
+    /// Writes the 32-bit register at `offset`, which must be 4 byte aligned.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure the write doesn't break anything relying on the device's
+    /// current configuration.
+    pub unsafe fn write_config(self, offset: u8, value: u32) {
+        unsafe { write_pci_config_reg(self.bus, self.slot, self.func, offset, value) };
+    }
+
+    /// Writes the 16-bit register at `offset`, which must be 2 byte aligned. The rest of the
+    /// 32-bit register isn't written, so bits cleared by writing 1 next to it are left alone.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Self::write_config`].
+    pub unsafe fn write_config_u16(self, offset: u8, value: u16) {
+        unsafe {
+            self.select(offset);
+            port::PortWriteOnly::<u16>::new(CONFIG_DATA + u16::from(offset & 0b10)).write(value);
+        };
+    }
+
+    /// Writes the byte at `offset`, the rest of the 32-bit register isn't written.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Self::write_config`].
+    pub unsafe fn write_config_u8(self, offset: u8, value: u8) {
+        unsafe {
+            self.select(offset);
+            port::PortWriteOnly::<u8>::new(CONFIG_DATA + u16::from(offset & 0b11)).write(value);
+        };
+    }
+
+    /// Points the data port at the 32-bit register holding `offset`, a narrower access of the
+    /// data port reaches the bytes of the register past `CONFIG_DATA`.
+    unsafe fn select(self, offset: u8) {
+        unsafe {
+            port::PortWriteOnly::<u32>::new(CONFIG_ADDRESS).write(config_address(
+                self.bus,
+                self.slot,
+                self.func,
+                offset & !0b11,
+            ));
+        };
+    }
+
+    pub fn command(self) -> CommandReg {
+        CommandReg::from_bits(self.read_config_u16(COMMAND_REGISTER))
+    }
+
+    /// Writes the command register with what `update` returns for its current value.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Self::write_config`].
+    pub unsafe fn update_command(self, update: impl FnOnce(CommandReg) -> CommandReg) {
+        let command = update(self.command());
+
+        unsafe { self.write_config_u16(COMMAND_REGISTER, command.into_bits()) };
+    }
+
+    /// Enables decoding of the memory BARs and bus mastering, for devices that read and write
+    /// memory themselves.
+    pub fn enable_bus_master(self) {
+        // SAFETY: only enables access to the device
+        unsafe {
+            self.update_command(|command| command.with_mem_space(true).with_bus_master(true));
+        };
+    }
+}
+
+/// The ports of the config space access mechanism, the address of a register is written to
+/// the address port then the register is accessed through the data port.
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// The command register, followed by the status register.
+const COMMAND_REGISTER: u8 = 0x4;
+
+/// Set in the status register if the function has a capability list.
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// An entry of the capability list of a function.
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u8,
+    /// Where the capability starts in the config space, 4 byte aligned
+    pub offset: u8,
+}
+
+/// A function found while enumerating and where it is.
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub info: DeviceInfo,
+}
+
+impl PciDevice {
+    /// Walks the capability list of the function, empty if it doesn't have one.
+    pub fn capabilities(&self) -> impl Iterator<Item = Capability> {
+        let address = self.address;
+
+        // the low 2 bits of the pointers are reserved
+        let mut next = if self.info.status & STATUS_CAPABILITIES == 0 {
+            0
+        } else {
+            self.info.header.cap_ptr & !0b11
+        };
+
+        core::iter::from_fn(move || {
+            if next == 0 {
+                return None;
+            }
+
+            let offset = next;
+            let [id, pointer, ..] = address.read_config(offset).to_le_bytes();
+
+            next = pointer & !0b11;
+
+            Some(Capability { id, offset })
+        })
+        // the list can't hold more than this, stops broken lists from looping forever
+        .take(48)
+    }
+
+    /// The buses behind the function if it's a pci-to-pci bridge.
+    pub fn bus_numbers(&self) -> Option<BusNumbers> {
+        if !matches!(self.info.header_type.header_type(), HeaderType::PciTopci) {
+            return None;
+        }
+
+        let [primary, secondary, subordinate, _] =
+            self.address.read_config(BUS_NUMBERS_REGISTER).to_le_bytes();
+
+        Some(BusNumbers {
+            primary,
+            secondary,
+            subordinate,
+        })
+    }
+}
+
+/// The bus numbers register of a pci-to-pci bridge.
+const BUS_NUMBERS_REGISTER: u8 = 0x18;
+
+/// The buses a pci-to-pci bridge connects, assigned by the firmware.
+#[derive(Debug, Clone, Copy)]
+pub struct BusNumbers {
+    /// The bus the bridge is on
+    pub primary: u8,
+    /// The bus directly behind the bridge, 0 if it wasn't assigned one
+    pub secondary: u8,
+    /// The highest bus behind the bridge
+    pub subordinate: u8,
+}
+
 #[derive(Debug, Copy, Clone)]
-#[repr(C)]
 pub struct DeviceInfo {
     pub vendor_id: u16,
     pub device_num: u16,
     pub command: CommandReg,
     pub status: u16,
     pub rev_id: u8,
-    pub prog_if: IdeProgIf,
-    pub subclass: MassStorageSubclass,
+    /// What the programming interface means depends on the class and subclass, see
+    /// [`Self::ide_prog_if`]
+    pub prog_if: u8,
+    /// Decoded for the class by [`Self::class`]
+    pub subclass: u8,
     pub class_code: ClassCode,
     pub cache_line_size: u8,
     pub lat_timer: u8,
@@ -32,6 +222,51 @@ pub struct DeviceInfo {
     pub header: Header,
 }
 
+impl DeviceInfo {
+    /// The class with its subclass decoded, for the classes with known subclasses.
+    pub const fn class(&self) -> Class {
+        match self.class_code {
+            ClassCode::MassStorageController => {
+                Class::MassStorage(MassStorageSubclass::from_bits(self.subclass))
+            }
+            ClassCode::NetworkController => {
+                Class::Network(NetworkSubclass::from_bits(self.subclass))
+            }
+            ClassCode::DisplayController => {
+                Class::Display(DisplaySubclass::from_bits(self.subclass))
+            }
+            ClassCode::Bridge => Class::Bridge(BridgeSubclass::from_bits(self.subclass)),
+            ClassCode::SerialBusController => {
+                Class::SerialBus(SerialBusSubclass::from_bits(self.subclass))
+            }
+            class => Class::Other {
+                class,
+                subclass: self.subclass,
+            },
+        }
+    }
+
+    /// The programming interface of an ide controller, only meaningful for one.
+    pub const fn ide_prog_if(&self) -> IdeProgIf {
+        IdeProgIf::from_bits(self.prog_if)
+    }
+}
+
+/// A class and its subclass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    MassStorage(MassStorageSubclass),
+    Network(NetworkSubclass),
+    Display(DisplaySubclass),
+    Bridge(BridgeSubclass),
+    SerialBus(SerialBusSubclass),
+    /// A class whose subclasses aren't decoded
+    Other {
+        class: ClassCode,
+        subclass: u8,
+    },
+}
+
 #[bitfield(u8)]
 pub struct IdeProgIf {
     #[bits(1)]
@@ -95,6 +330,75 @@ pub enum MassStorageSubclass {
     Unknown,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[bitenum(all = false)]
+pub enum NetworkSubclass {
+    Ethernet = 0x00,
+    TokenRing = 0x01,
+    Fddi = 0x02,
+    Atm = 0x03,
+    Isdn = 0x04,
+    WorldFip = 0x05,
+    Picmg = 0x06,
+    Infiniband = 0x07,
+    Fabric = 0x08,
+    Other = 0x80,
+    #[fallback]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[bitenum(all = false)]
+pub enum DisplaySubclass {
+    VgaCompatible = 0x00,
+    Xga = 0x01,
+    ThreeDimensional = 0x02,
+    Other = 0x80,
+    #[fallback]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[bitenum(all = false)]
+pub enum BridgeSubclass {
+    Host = 0x00,
+    Isa = 0x01,
+    Eisa = 0x02,
+    Mca = 0x03,
+    PciToPci = 0x04,
+    Pcmcia = 0x05,
+    NuBus = 0x06,
+    CardBus = 0x07,
+    RaceWay = 0x08,
+    SemiTransparentPciToPci = 0x09,
+    InfinibandToPci = 0x0A,
+    Other = 0x80,
+    #[fallback]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[bitenum(all = false)]
+pub enum SerialBusSubclass {
+    FireWire = 0x00,
+    AccessBus = 0x01,
+    Ssa = 0x02,
+    Usb = 0x03,
+    FibreChannel = 0x04,
+    SmBus = 0x05,
+    Infiniband = 0x06,
+    Ipmi = 0x07,
+    Sercos = 0x08,
+    CanBus = 0x09,
+    Other = 0x80,
+    #[fallback]
+    Unknown,
+}
+
 #[bitfield(u8)]
 pub struct BistReg {
     #[bits(4)]
@@ -116,7 +420,6 @@ pub struct Header {
     subsystem_id: u16,
     exp_rom_base_addr: u32,
     cap_ptr: u8,
-    _reserved: [u8; 7],
     interrupt_line: u8,
     interrupt_pin: u8,
     min_grant: u8,
@@ -124,8 +427,65 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn get_bar(&self, index: u8) -> Bar {
-        let raw_bits = self.bar_addr[index as usize];
+    /// Reads the fields after the common ones, where they are depends on `header_type`. Bridges
+    /// only have the first 2 BARs, the capability pointer, the expansion rom and the interrupt
+    /// registers, cardbus bridges only the capability pointer and the interrupt registers.
+    fn read(address: PciAddress, header_type: HeaderType) -> Self {
+        let general = matches!(header_type, HeaderType::GeneralDevice);
+        let bars = match header_type {
+            HeaderType::GeneralDevice => 6,
+            HeaderType::PciTopci => 2,
+            HeaderType::PciTocardbus | HeaderType::Invalid => 0,
+        };
+
+        let mut bar_addr = [0; 6];
+        for (offset, bar) in (0x10..).step_by(4).zip(bar_addr.iter_mut().take(bars)) {
+            *bar = address.read_config(offset);
+        }
+
+        let [subsystem_vendor, subsystem_id] = if general {
+            halves(address.read_config(0x2C))
+        } else {
+            [0, 0]
+        };
+
+        let cap_ptr = match header_type {
+            HeaderType::GeneralDevice | HeaderType::PciTopci => address.read_config_u8(0x34),
+            HeaderType::PciTocardbus => address.read_config_u8(0x14),
+            HeaderType::Invalid => 0,
+        };
+
+        let exp_rom_base_addr = match header_type {
+            HeaderType::GeneralDevice => address.read_config(0x30),
+            HeaderType::PciTopci => address.read_config(0x38),
+            HeaderType::PciTocardbus | HeaderType::Invalid => 0,
+        };
+
+        // bridges have their bridge control register where the grant and latency are
+        let [interrupt_line, interrupt_pin, min_grant, max_lantecy] =
+            address.read_config(0x3C).to_le_bytes();
+
+        Self {
+            bar_addr,
+            cardbus_cis_ptr: if general {
+                address.read_config(0x28)
+            } else {
+                0
+            },
+            subsystem_vendor,
+            subsystem_id,
+            exp_rom_base_addr,
+            cap_ptr,
+            interrupt_line,
+            interrupt_pin,
+            min_grant: if general { min_grant } else { 0 },
+            max_lantecy: if general { max_lantecy } else { 0 },
+        }
+    }
+
+    /// Decodes the BAR at `index`, returning [`None`] if there is no such BAR.
+    pub fn get_bar(&self, index: u8) -> Option<Bar> {
+        let raw_bits = *self.bar_addr.get(usize::from(index))?;
 
         let raw = BarRaw::from_bits(raw_bits);
 
@@ -133,16 +493,44 @@ impl Header {
             let io = IoSpaceRaw::from_bits(raw_bits);
             assert!(io.io_space());
 
-            Bar::IoSpace { addr: io.addr() }
+            // the low bits of the register are flags
+            Some(Bar::IoSpace {
+                addr: io.addr() << 2,
+            })
         } else {
             let memory = MemorySpaceRaw::from_bits(raw_bits);
-            Bar::MemorySpace {
+            Some(Bar::MemorySpace {
                 r#type: memory.r#type(),
                 pre_fetch: memory.prefetchable(),
-                addr: memory.addr(),
-            }
+                addr: memory.addr() << 4,
+            })
         }
     }
+
+    /// The base address of the memory BAR at `index`, including the upper half of a 64-bit BAR.
+    ///
+    /// Returns [`None`] for io BARs, BARs the firmware didn't assign an address, indices past
+    /// the last BAR and 64-bit BARs missing their upper half.
+    pub fn memory_bar_address(&self, index: u8) -> Option<u64> {
+        let Some(Bar::MemorySpace { r#type, addr, .. }) = self.get_bar(index) else {
+            return None;
+        };
+
+        let upper = if r#type == MemorySpaceType::Wide64 as u8 {
+            *self.bar_addr.get(usize::from(index) + 1)?
+        } else {
+            0
+        };
+
+        let addr = u64::from(upper) << 32 | u64::from(addr);
+
+        (addr != 0).then_some(addr)
+    }
+
+    /// The line of the legacy PIC the interrupt pin is routed to, 0xFF if it isn't routed.
+    pub const fn interrupt_line(&self) -> u8 {
+        self.interrupt_line
+    }
 }
 
 #[bitfield(u32)]
@@ -191,9 +579,11 @@ pub enum Bar {
     MemorySpace {
         r#type: u8,
         pre_fetch: bool,
+        /// The base address, the low 4 bits are always 0
         addr: u32,
     },
     IoSpace {
+        /// The base port, the low 2 bits are always 0
         addr: u32,
     },
 }
@@ -220,11 +610,11 @@ pub enum HeaderType {
 #[bitfield(u16)]
 pub struct CommandReg {
     #[bits(1)]
-    io_space: bool,
+    pub io_space: bool,
     #[bits(1)]
-    mem_space: bool,
+    pub mem_space: bool,
     #[bits(1)]
-    bus_master: bool,
+    pub bus_master: bool,
     #[bits(1)]
     sepcial_cylces: bool,
     #[bits(1)]
@@ -239,8 +629,9 @@ pub struct CommandReg {
     serr_enabled: bool,
     #[bits(1)]
     back_to_back_write: bool,
+    /// Stops the function asserting its interrupt pin, for functions using msi
     #[bits(1)]
-    interrupt_disable: bool,
+    pub interrupt_disable: bool,
     #[bits(5)]
     _reserved2: (),
 }
@@ -264,47 +655,61 @@ pub fn get_device_id(bus: u8, slot: u8) -> u16 {
         .expect("Device id must be a u16 and my math went wrong") // gets the secnond half of the u32
 }
 
+/// Reads the header of the function, [`None`] if there isn't one.
 pub fn get_info(bus: u8, slot: u8, func: u8) -> Option<DeviceInfo> {
-    let mut info: [u32; 16] = [0u32; 16];
-    info[0] = unsafe { read_pci_config_reg(bus, slot, func, 0) };
-    info[1] = unsafe { read_pci_config_reg(bus, slot, func, 0x4) };
-    info[2] = unsafe { read_pci_config_reg(bus, slot, func, 0x8) };
-    info[3] = unsafe { read_pci_config_reg(bus, slot, func, 0xC) };
-    info[4] = unsafe { read_pci_config_reg(bus, slot, func, 0x10) };
-    info[5] = unsafe { read_pci_config_reg(bus, slot, func, 0x14) };
-    info[6] = unsafe { read_pci_config_reg(bus, slot, func, 0x18) };
-    info[7] = unsafe { read_pci_config_reg(bus, slot, func, 0x1C) };
-    info[8] = unsafe { read_pci_config_reg(bus, slot, func, 0x20) };
-    info[9] = unsafe { read_pci_config_reg(bus, slot, func, 0x24) };
-    info[10] = unsafe { read_pci_config_reg(bus, slot, func, 0x28) };
-    info[11] = unsafe { read_pci_config_reg(bus, slot, func, 0x2C) };
-    info[12] = unsafe { read_pci_config_reg(bus, slot, func, 0x30) };
-    info[13] = unsafe { read_pci_config_reg(bus, slot, func, 0x34) };
-    info[14] = unsafe { read_pci_config_reg(bus, slot, func, 0x38) };
-    info[15] = unsafe { read_pci_config_reg(bus, slot, func, 0x3C) };
-
-    let info = unsafe { core::mem::transmute::<[u32; 16], DeviceInfo>(info) };
-
-    if info.vendor_id == 0xFFFF {
-        None
-    } else {
-        Some(info)
+    let address = PciAddress { bus, slot, func };
+
+    let [vendor_id, device_num] = halves(address.read_config(0x0));
+
+    if vendor_id == 0xFFFF {
+        return None;
     }
+
+    let [command, status] = halves(address.read_config(0x4));
+    let [rev_id, prog_if, subclass, class_code] = address.read_config(0x8).to_le_bytes();
+    let [cache_line_size, lat_timer, header_type, bist] = address.read_config(0xC).to_le_bytes();
+
+    let header_type = HeaderTypeInfo::from_bits(header_type);
+
+    Some(DeviceInfo {
+        vendor_id,
+        device_num,
+        command: CommandReg::from_bits(command),
+        status,
+        rev_id,
+        prog_if,
+        subclass,
+        class_code: ClassCode::from_bits(class_code),
+        cache_line_size,
+        lat_timer,
+        header_type,
+        bist: BistReg::from_bits(bist),
+        header: Header::read(address, header_type.header_type()),
+    })
 }
 
-unsafe fn read_pci_config_reg(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
-    let address: usize = usize::from(bus) << 16
-        | usize::from(slot) << 11
-        | usize::from(func) << 8
-        | usize::from(offset)
-        | 0x8000_0000;
+/// Splits a register into its low and high 16 bits.
+const fn halves(register: u32) -> [u16; 2] {
+    let [a, b, c, d] = register.to_le_bytes();
+
+    [u16::from_le_bytes([a, b]), u16::from_le_bytes([c, d])]
+}
+
+const fn config_address(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
+    u32::from(bus) << 16
+        | u32::from(slot) << 11
+        | u32::from(func) << 8
+        | u32::from(offset)
+        | 0x8000_0000
+}
 
-    let mut port = port::PortWriteOnly::<u32>::new(0xCF8);
+unsafe fn read_pci_config_reg(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
+    let mut port = port::PortWriteOnly::<u32>::new(CONFIG_ADDRESS);
     unsafe {
-        port.write(address.try_into().unwrap());
+        port.write(config_address(bus, slot, func, offset));
     }
 
-    let mut port_reader = port::PortReadOnly::<u32>::new(0xCFC);
+    let mut port_reader = port::PortReadOnly::<u32>::new(CONFIG_DATA);
 
     unsafe { port_reader.read() }
 
@@ -318,25 +723,84 @@ unsafe fn read_pci_config_reg(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
     // let result_lower_half = port_result & 0xFFFF;
 }
 
-/// enumerates all pci devices
-pub fn enumerate() -> Vec<DeviceInfo> {
-    let mut devices = Vec::new();
+unsafe fn write_pci_config_reg(bus: u8, slot: u8, func: u8, offset: u8, value: u32) {
+    let mut address_port = port::PortWriteOnly::<u32>::new(CONFIG_ADDRESS);
+    let mut data_port = port::PortWriteOnly::<u32>::new(CONFIG_DATA);
 
-    for bus in 0u8..=255 {
-        for dev in 0u8..32 {
-            if let Some(info) = get_info(bus, dev, 0) {
-                devices.push(info);
+    unsafe {
+        address_port.write(config_address(bus, slot, func, offset));
+        data_port.write(value);
+    }
+}
 
-                if info.header_type.multi_func() {
-                    for func in 1u8..=7 {
-                        if let Some(info) = get_info(bus, dev, func) {
-                            devices.push(info);
-                        }
-                    }
+/// Enumerates every function reachable from the host bridges, following pci-to-pci bridges to
+/// the buses behind them.
+///
+/// Bridges are expected to have been assigned bus numbers by the firmware, the buses behind
+/// ones that weren't aren't scanned.
+pub fn enumerate() -> Vec<PciDevice> {
+    let mut scan = Scan {
+        devices: Vec::new(),
+        scanned: [false; 256],
+    };
+
+    match get_info(0, 0, 0) {
+        // each function of a multi-function host bridge is the host bridge of the bus of its
+        // number
+        Some(host) if host.header_type.multi_func() => {
+            for func in 0..8 {
+                if get_info(0, 0, func).is_some() {
+                    scan.bus(func);
                 }
             }
         }
+        _ => scan.bus(0),
     }
 
-    devices
+    scan.devices
+}
+
+struct Scan {
+    devices: Vec<PciDevice>,
+    /// Stops a misconfigured bridge from scanning a bus twice or looping
+    scanned: [bool; 256],
+}
+
+impl Scan {
+    fn bus(&mut self, bus: u8) {
+        if core::mem::replace(&mut self.scanned[usize::from(bus)], true) {
+            return;
+        }
+
+        for slot in 0..32 {
+            self.slot(bus, slot);
+        }
+    }
+
+    fn slot(&mut self, bus: u8, slot: u8) {
+        let Some(info) = get_info(bus, slot, 0) else {
+            return;
+        };
+
+        let functions = if info.header_type.multi_func() { 8 } else { 1 };
+
+        for func in 0..functions {
+            if let Some(info) = get_info(bus, slot, func) {
+                self.function(PciDevice {
+                    address: PciAddress { bus, slot, func },
+                    info,
+                });
+            }
+        }
+    }
+
+    fn function(&mut self, device: PciDevice) {
+        self.devices.push(device);
+
+        if let Some(buses) = device.bus_numbers()
+            && buses.secondary != 0
+        {
+            self.bus(buses.secondary);
+        }
+    }
 }
@@ -0,0 +1,586 @@
+// reference docs at https://wiki.osdev.org/AHCI
+// and the AHCI 1.3.1 specification
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ascii::Char;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use spinlock::Spinlock;
+use x86_64::PhysAddr;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, OffsetPageTable, Size4KiB};
+use zerocopy::IntoBytes;
+use zerocopy::little_endian::{U16, U32};
+
+use self::structs::{
+    AtaCommand, CommandHeader, CommandHeaderFlags, FisRegisterHostToDevice, GlobalHostControl,
+    HbaCapabilities, HbaRegister, HbaRegisters, PhysicalRegionDescriptor, PortCommand,
+    PortInterrupt, PortRegister, PortRegisters, SATA_SIGNATURE, SataStatus, TaskFileData,
+};
+use crate::device_manager::driver::{PciDriver, PciMatch};
+use crate::device_manager::{BlockDevice, BlockDeviceError, Device, DeviceManager, check_access};
+use crate::interrupts::{self, wait_for_interrupt};
+use crate::memory::{self, BootInfoFrameAllocator, DmaBuffer};
+use crate::multitasking::SCHEDULER;
+use crate::multitasking::mutex::Mutex;
+use crate::pci::{Bar, MassStorageSubclass, PciDevice};
+use crate::timer::{Duration, Seconds, sleep};
+
+mod structs;
+
+/// The programming interface of a SATA controller using ahci, rather than a vendor specific one.
+const AHCI_PROG_IF: u8 = 0x01;
+
+/// Binds to SATA controllers using ahci.
+pub static DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    matches: &[PciMatch::mass_storage(
+        MassStorageSubclass::SerialAta,
+        Some(AHCI_PROG_IF),
+    )],
+    probe,
+};
+
+const SECTOR_SIZE: usize = 512;
+
+/// How long a drive has to finish a command before it's given up on.
+const COMMAND_TIMEOUT: Duration = Seconds(5).into();
+
+/// How many milliseconds a port has to stop or start, the specification allows 500.
+const PORT_TIMEOUT_MS: u64 = 500;
+
+/// The size of the buffer transfers go through.
+const BUFFER_SIZE: usize = 0x1_0000;
+
+/// The most sectors a single command can transfer, limited by the size of the buffer.
+const MAX_TRANSFER: usize = BUFFER_SIZE / SECTOR_SIZE;
+
+/// Where the structures of a port are in its memory, the command list has to be aligned to
+/// 1 KiB, the received FIS area to 256 bytes and the command table to 128 bytes.
+const COMMAND_LIST: usize = 0x000;
+const RECEIVED_FIS: usize = 0x400;
+const COMMAND_TABLE: usize = 0x800;
+/// The physical region descriptor table, after the command FIS and the ATAPI command.
+const PRDT: usize = COMMAND_TABLE + 0x80;
+
+/// Every controller, for the interrupt handler.
+static CONTROLLERS: Spinlock<Vec<Arc<ControllerIrq>>> = Spinlock::new(Vec::new());
+
+#[derive(thiserror::Error, Debug)]
+pub enum AhciCreationError {
+    #[error("BAR 5 of the controller isn't memory mapped")]
+    InvalidBar,
+    #[error("The controller's interrupt isn't routed, line `{0}`")]
+    NoInterruptLine(u8),
+    #[error("Failed to map the controller's registers, `{0:?}`")]
+    MapFailed(MapToError<Size4KiB>),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AhciError {
+    #[error("The drive didn't respond in time")]
+    Timeout,
+    #[error("The drive reported an error, task file `{0:?}`")]
+    TaskFileError(TaskFileData),
+    #[error("Unable to allocate dma memory below 4 GiB")]
+    OutOfMemory,
+}
+
+#[derive(Debug)]
+pub struct AhciController {
+    drives: Vec<Arc<Mutex<dyn BlockDevice>>>,
+}
+
+impl Device for AhciController {
+    fn children(&self) -> Option<Box<dyn Iterator<Item = Arc<Mutex<dyn Device>>>>> {
+        Some(Box::new(
+            self.drives
+                .clone()
+                .into_iter()
+                .map(|x| x as Arc<Mutex<dyn Device>>),
+        ))
+    }
+}
+
+/// The interrupt state of a controller.
+///
+/// This lives outside of the drives' locks since the task waiting for the interrupt holds them.
+#[derive(Debug)]
+struct ControllerIrq {
+    irq: u8,
+    hba: HbaRegisters,
+    ports: [PortIrq; 32],
+}
+
+#[derive(Debug, Default)]
+struct PortIrq {
+    fired: AtomicBool,
+    /// The interrupt status bits seen by the handler since the last command
+    status: AtomicU32,
+}
+
+impl ControllerIrq {
+    /// Acknowledges every port that interrupted and wakes up the tasks waiting on them.
+    fn handle(&self) {
+        let pending = self.hba.read(HbaRegister::InterruptStatus);
+
+        // another device on the same line
+        if pending == 0 {
+            return;
+        }
+
+        for (index, port) in self
+            .ports
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| pending & (1 << index) != 0)
+        {
+            let registers = self.hba.port(index);
+            let status = registers.read(PortRegister::InterruptStatus);
+
+            // cleared by writing 1
+            registers.write(PortRegister::InterruptStatus, status);
+
+            port.status.fetch_or(status, Ordering::Relaxed);
+            port.fired.store(true, Ordering::Release);
+        }
+
+        // cleared after the ports, otherwise it's set again straight away
+        self.hba.write(HbaRegister::InterruptStatus, pending);
+
+        SCHEDULER.with_mut_ref(|scheduler| scheduler.wake_up_interrupt_waiters(self.irq));
+    }
+}
+
+/// Handles an interrupt on `irq`, called from the interrupt handler.
+pub fn handle_irq(irq: u8) {
+    CONTROLLERS.with_ref(|controllers| {
+        controllers
+            .iter()
+            .filter(|controller| controller.irq == irq)
+            .for_each(|controller| controller.handle());
+    });
+}
+
+/// A SATA disk on a port of an ahci controller, commands are sent through the first command
+/// slot.
+#[derive(Debug)]
+#[allow(unused)]
+pub struct SataDrive {
+    controller: Arc<ControllerIrq>,
+    index: usize,
+    port: PortRegisters,
+    /// The command list, the received FIS area and the command table
+    memory: DmaBuffer,
+    /// The buffer transfers go through
+    buffer: DmaBuffer,
+    model: [Char; 41],
+    sectors: u64,
+}
+
+fn probe(
+    pci_device: PciDevice,
+    device_manager: &mut DeviceManager,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), anyhow::Error> {
+    let controller = create_ahci_controller(pci_device, device_manager, mapper, frame_allocator)?;
+    device_manager.register_device(Arc::new(Mutex::new(controller)));
+
+    Ok(())
+}
+
+/// Maps the registers of the controller, enables ahci mode and interrupts, and registers a
+/// [`SataDrive`] for every disk found.
+///
+/// # Errors
+/// Will return [`AhciCreationError`] if BAR 5 or the interrupt line are invalid, or mapping the
+/// registers fails
+pub fn create_ahci_controller(
+    pci_device: PciDevice,
+    device_manager: &mut DeviceManager,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<AhciController, AhciCreationError> {
+    let PciDevice { address, info } = pci_device;
+
+    let Some(Bar::MemorySpace { addr, .. }) = info.header.get_bar(5) else {
+        return Err(AhciCreationError::InvalidBar);
+    };
+
+    if addr == 0 {
+        return Err(AhciCreationError::InvalidBar);
+    }
+
+    let irq = info.header.interrupt_line();
+
+    // the PIC only has 16 lines, 0xFF means the pin isn't routed
+    if irq >= 16 {
+        return Err(AhciCreationError::NoInterruptLine(irq));
+    }
+
+    // the controller reads the command lists and transfers data itself
+    address.enable_bus_master();
+
+    let base = unsafe {
+        memory::map_mmio(
+            mapper,
+            frame_allocator,
+            PhysAddr::new(u64::from(addr)),
+            HbaRegisters::SIZE,
+        )
+    }
+    .map_err(AhciCreationError::MapFailed)?;
+
+    // SAFETY: just mapped
+    let hba = unsafe { HbaRegisters::new(base) };
+
+    let global = GlobalHostControl::from_bits(hba.read(HbaRegister::GlobalHostControl));
+    hba.write(
+        HbaRegister::GlobalHostControl,
+        global.with_ahci_enable(true).into_bits(),
+    );
+
+    let capabilities = HbaCapabilities::from_bits(hba.read(HbaRegister::Capabilities));
+    log::info!(
+        "ahci controller with {} ports and {} command slots",
+        capabilities.ports() + 1,
+        capabilities.command_slots() + 1
+    );
+
+    let controller = Arc::new(ControllerIrq {
+        irq,
+        hba,
+        ports: core::array::from_fn(|_| PortIrq::default()),
+    });
+
+    CONTROLLERS.with_mut_ref(|controllers| controllers.push(controller.clone()));
+    interrupts::unmask_irq(irq);
+
+    // clears interrupts from before it was ours
+    hba.write(HbaRegister::InterruptStatus, u32::MAX);
+    hba.write(
+        HbaRegister::GlobalHostControl,
+        global
+            .with_ahci_enable(true)
+            .with_interrupt_enable(true)
+            .into_bits(),
+    );
+
+    let implemented = hba.read(HbaRegister::PortsImplemented);
+
+    let drives = (0..32)
+        .filter(|index| implemented & (1 << index) != 0)
+        .filter_map(
+            |index| match init_port(&controller, index, frame_allocator) {
+                Ok(drive) => drive,
+                Err(err) => {
+                    log::warn!("failed to set up ahci port {index}, {err}");
+                    None
+                }
+            },
+        )
+        .map(|drive| {
+            let drive = Arc::new(Mutex::new(drive));
+
+            device_manager.register_device(drive.clone() as Arc<Mutex<dyn Device>>);
+            device_manager.register_block_device(drive.clone() as Arc<Mutex<dyn BlockDevice>>);
+
+            drive as Arc<Mutex<dyn BlockDevice>>
+        })
+        .collect();
+
+    Ok(AhciController { drives })
+}
+
+/// Sets up the port at `index` if a SATA disk is attached, returning [`None`] if there isn't.
+fn init_port(
+    controller: &Arc<ControllerIrq>,
+    index: usize,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<Option<SataDrive>, AhciError> {
+    let port = controller.hba.port(index);
+
+    let status = SataStatus::from_bits(port.read(PortRegister::SataStatus));
+
+    if status.detection() != SataStatus::DEVICE_PRESENT
+        || status.power_management() != SataStatus::ACTIVE
+    {
+        return Ok(None);
+    }
+
+    match port.read(PortRegister::Signature) {
+        SATA_SIGNATURE => {}
+        signature => {
+            log::info!("skipping ahci port {index} with signature {signature:#x}");
+            return Ok(None);
+        }
+    }
+
+    let memory = DmaBuffer::allocate(frame_allocator, 4096, 4096).ok_or(AhciError::OutOfMemory)?;
+    let buffer =
+        DmaBuffer::allocate(frame_allocator, BUFFER_SIZE, 4096).ok_or(AhciError::OutOfMemory)?;
+
+    stop_port(port)?;
+
+    port.write(
+        PortRegister::CommandListBase,
+        dma_address(&memory, COMMAND_LIST),
+    );
+    port.write(PortRegister::CommandListBaseUpper, 0);
+    port.write(PortRegister::FisBase, dma_address(&memory, RECEIVED_FIS));
+    port.write(PortRegister::FisBaseUpper, 0);
+
+    // cleared by writing 1
+    port.write(PortRegister::SataError, u32::MAX);
+    port.write(PortRegister::InterruptStatus, u32::MAX);
+    port.write(
+        PortRegister::InterruptEnable,
+        PortInterrupt::new()
+            .with_device_to_host(true)
+            .with_pio_setup(true)
+            .with_dma_setup(true)
+            .with_set_device_bits(true)
+            .with_task_file_error(true)
+            .into_bits(),
+    );
+
+    start_port(port)?;
+
+    let mut drive = SataDrive {
+        controller: controller.clone(),
+        index,
+        port,
+        memory,
+        buffer,
+        model: [Char::Null; 41],
+        sectors: 0,
+    };
+
+    drive.issue(AtaCommand::Identify, 0, 0, SECTOR_SIZE, false)?;
+
+    let identification = &drive.buffer.as_slice()[..SECTOR_SIZE];
+    let word =
+        |word: usize| u16::from_le_bytes([identification[word * 2], identification[word * 2 + 1]]);
+
+    // word 83 bit 10, every command used needs the 48-bit address feature set
+    if word(83) & (1 << 10) == 0 {
+        log::warn!("skipping ahci port {index}, the drive doesn't support lba48");
+        return Ok(None);
+    }
+
+    // words 100-103
+    drive.sectors = (100..104).rev().fold(0, |sectors, word_index| {
+        sectors << 16 | u64::from(word(word_index))
+    });
+
+    // words 27-46, the bytes of each word are swapped
+    for (char, byte) in drive
+        .model
+        .iter_mut()
+        .zip((27..47).flat_map(|word_index| word(word_index).to_be_bytes()))
+    {
+        *char = Char::from_u8(byte).unwrap_or(Char::QuotationMark);
+    }
+
+    log::info!(
+        "sata drive {} with {} sectors on ahci port {index}",
+        drive.model.as_str().trim_end_matches(['\0', ' ']),
+        drive.sectors
+    );
+
+    Ok(Some(drive))
+}
+
+/// Stops the port processing its command list and receiving FISes, needed before changing where
+/// they are.
+fn stop_port(port: PortRegisters) -> Result<(), AhciError> {
+    port.write_command(port.command().with_start(false));
+    wait_port(port, |command| !command.command_list_running())?;
+
+    port.write_command(port.command().with_fis_receive_enable(false));
+    wait_port(port, |command| !command.fis_receive_running())
+}
+
+fn start_port(port: PortRegisters) -> Result<(), AhciError> {
+    wait_port(port, |command| !command.command_list_running())?;
+
+    port.write_command(
+        port.command()
+            .with_spin_up(true)
+            .with_power_on(true)
+            .with_fis_receive_enable(true),
+    );
+    port.write_command(port.command().with_start(true));
+
+    Ok(())
+}
+
+/// Polls the command register of `port` until `done` or the timeout.
+fn wait_port(port: PortRegisters, done: impl Fn(PortCommand) -> bool) -> Result<(), AhciError> {
+    for _ in 0..PORT_TIMEOUT_MS {
+        if done(port.command()) {
+            return Ok(());
+        }
+
+        sleep(1);
+    }
+
+    if done(port.command()) {
+        Ok(())
+    } else {
+        Err(AhciError::Timeout)
+    }
+}
+
+/// The physical address `offset` bytes into `buffer` as the controller sees it, dma buffers are
+/// below 4 GiB.
+fn dma_address(buffer: &DmaBuffer, offset: usize) -> u32 {
+    u32::try_from(buffer.phys_addr().as_u64() + offset as u64).expect("Should be below 4 GiB")
+}
+
+impl SataDrive {
+    fn irq(&self) -> &PortIrq {
+        &self.controller.ports[self.index]
+    }
+
+    /// Sends `command` through the first slot and waits for it to finish, `length` bytes are
+    /// transferred through the buffer.
+    fn issue(
+        &mut self,
+        command: AtaCommand,
+        lba: u64,
+        count: u16,
+        length: usize,
+        write: bool,
+    ) -> Result<(), AhciError> {
+        // the drive doesn't accept commands while busy
+        wait_port(self.port, |_| {
+            let task_file = self.port.task_file_data();
+
+            !task_file.busy() && !task_file.data_request()
+        })?;
+
+        let table = dma_address(&self.memory, COMMAND_TABLE);
+        let buffer = dma_address(&self.buffer, 0);
+        let memory = self.memory.as_mut_slice();
+
+        let header = CommandHeader {
+            flags: U16::new(
+                CommandHeaderFlags::new()
+                    .with_fis_length(FisRegisterHostToDevice::LENGTH_DWORDS)
+                    .with_write(write)
+                    .into_bits(),
+            ),
+            prdt_length: U16::new(u16::from(length != 0)),
+            prd_byte_count: U32::new(0),
+            table_base: U32::new(table),
+            table_base_upper: U32::new(0),
+            _reserved: [U32::new(0); 4],
+        };
+
+        header
+            .write_to_prefix(&mut memory[COMMAND_LIST..])
+            .expect("Should fit in the command list");
+
+        FisRegisterHostToDevice::new(command, lba, count)
+            .write_to_prefix(&mut memory[COMMAND_TABLE..])
+            .expect("Should fit in the command table");
+
+        if length != 0 {
+            PhysicalRegionDescriptor::new(buffer, length)
+                .write_to_prefix(&mut memory[PRDT..])
+                .expect("Should fit in the command table");
+        }
+
+        let irq = self.irq();
+        irq.fired.store(false, Ordering::Release);
+        irq.status.store(0, Ordering::Relaxed);
+
+        self.port.write(PortRegister::CommandIssue, 1);
+
+        loop {
+            let status = PortInterrupt::from_bits(self.irq().status.swap(0, Ordering::Relaxed));
+            let task_file = self.port.task_file_data();
+
+            if status.task_file_error() || task_file.error() {
+                return Err(AhciError::TaskFileError(task_file));
+            }
+
+            // cleared by the controller once the command is done
+            if self.port.read(PortRegister::CommandIssue) & 1 == 0 {
+                return Ok(());
+            }
+
+            if !wait_for_interrupt(self.controller.irq, &self.irq().fired, COMMAND_TIMEOUT)
+                && self.port.read(PortRegister::CommandIssue) & 1 != 0
+            {
+                return Err(AhciError::Timeout);
+            }
+        }
+    }
+}
+
+impl Device for SataDrive {
+    fn children(&self) -> Option<Box<dyn Iterator<Item = Arc<Mutex<dyn Device>>>>> {
+        None
+    }
+
+    fn as_block_device(&mut self) -> Option<&mut dyn BlockDevice> {
+        Some(self)
+    }
+}
+
+impl BlockDevice for SataDrive {
+    fn read_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let length = check_access(self.sectors, SECTOR_SIZE, lba, count, buffer.len())?;
+
+        for (lba, chunk) in (lba..)
+            .step_by(MAX_TRANSFER)
+            .zip(buffer[..length].chunks_mut(MAX_TRANSFER * SECTOR_SIZE))
+        {
+            let count = u16::try_from(chunk.len() / SECTOR_SIZE).expect("Should fit in a u16");
+
+            self.issue(AtaCommand::ReadDmaExt, lba, count, chunk.len(), false)?;
+
+            chunk.copy_from_slice(&self.buffer.as_slice()[..chunk.len()]);
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        let length = check_access(self.sectors, SECTOR_SIZE, lba, count, buffer.len())?;
+
+        for (lba, chunk) in (lba..)
+            .step_by(MAX_TRANSFER)
+            .zip(buffer[..length].chunks(MAX_TRANSFER * SECTOR_SIZE))
+        {
+            let count = u16::try_from(chunk.len() / SECTOR_SIZE).expect("Should fit in a u16");
+
+            self.buffer.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+
+            self.issue(AtaCommand::WriteDmaExt, lba, count, chunk.len(), true)?;
+        }
+
+        if length != 0 {
+            self.issue(AtaCommand::CacheFlushExt, 0, 0, 0, false)?;
+        }
+
+        Ok(())
+    }
+
+    fn total_sectors(&self) -> u64 {
+        self.sectors
+    }
+}
@@ -0,0 +1,371 @@
+use bitfield_struct::bitfield;
+use x86_64::VirtAddr;
+use zerocopy::little_endian::{U16, U32};
+use zerocopy::{Immutable, IntoBytes, KnownLayout};
+
+/// Registers shared by every port, at the start of the ABAR.
+#[derive(Debug, Clone, Copy)]
+#[repr(usize)]
+pub(super) enum HbaRegister {
+    Capabilities = 0x00,
+    GlobalHostControl = 0x04,
+    InterruptStatus = 0x08,
+    PortsImplemented = 0x0C,
+}
+
+/// Registers of a single port, relative to the start of the port's registers.
+#[derive(Debug, Clone, Copy)]
+#[repr(usize)]
+#[allow(unused)]
+pub(super) enum PortRegister {
+    CommandListBase = 0x00,
+    CommandListBaseUpper = 0x04,
+    FisBase = 0x08,
+    FisBaseUpper = 0x0C,
+    InterruptStatus = 0x10,
+    InterruptEnable = 0x14,
+    Command = 0x18,
+    TaskFileData = 0x20,
+    Signature = 0x24,
+    SataStatus = 0x28,
+    SataControl = 0x2C,
+    SataError = 0x30,
+    SataActive = 0x34,
+    CommandIssue = 0x38,
+}
+
+/// The memory mapped registers of a controller.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct HbaRegisters {
+    base: VirtAddr,
+}
+
+impl HbaRegisters {
+    /// The number of bytes mapped, the generic registers and 32 ports.
+    pub(super) const SIZE: usize = Self::PORTS + 32 * Self::PORT_SIZE;
+
+    const PORTS: usize = 0x100;
+    const PORT_SIZE: usize = 0x80;
+
+    /// # Safety
+    ///
+    /// `base` must be where the ABAR of a controller is mapped, uncached and [`Self::SIZE`] bytes
+    /// long.
+    pub(super) const unsafe fn new(base: VirtAddr) -> Self {
+        Self { base }
+    }
+
+    pub(super) fn read(self, register: HbaRegister) -> u32 {
+        unsafe { read_register(self.base, register as usize) }
+    }
+
+    pub(super) fn write(self, register: HbaRegister, value: u32) {
+        unsafe { write_register(self.base, register as usize, value) };
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `index` isn't below 32.
+    pub(super) fn port(self, index: usize) -> PortRegisters {
+        assert!(index < 32, "Ahci only has 32 ports");
+
+        PortRegisters {
+            base: self.base + (Self::PORTS + index * Self::PORT_SIZE) as u64,
+        }
+    }
+}
+
+/// The memory mapped registers of a single port.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PortRegisters {
+    base: VirtAddr,
+}
+
+impl PortRegisters {
+    pub(super) fn read(self, register: PortRegister) -> u32 {
+        unsafe { read_register(self.base, register as usize) }
+    }
+
+    pub(super) fn write(self, register: PortRegister, value: u32) {
+        unsafe { write_register(self.base, register as usize, value) };
+    }
+
+    pub(super) fn command(self) -> PortCommand {
+        PortCommand::from_bits(self.read(PortRegister::Command))
+    }
+
+    pub(super) fn write_command(self, command: PortCommand) {
+        self.write(PortRegister::Command, command.into_bits());
+    }
+
+    pub(super) fn task_file_data(self) -> TaskFileData {
+        TaskFileData::from_bits(self.read(PortRegister::TaskFileData))
+    }
+}
+
+/// # Safety
+///
+/// `base + offset` must be a mapped register.
+unsafe fn read_register(base: VirtAddr, offset: usize) -> u32 {
+    unsafe { (base + offset as u64).as_ptr::<u32>().read_volatile() }
+}
+
+/// # Safety
+///
+/// `base + offset` must be a mapped register.
+unsafe fn write_register(base: VirtAddr, offset: usize, value: u32) {
+    unsafe {
+        (base + offset as u64)
+            .as_mut_ptr::<u32>()
+            .write_volatile(value);
+    };
+}
+
+#[bitfield(u32)]
+pub(super) struct HbaCapabilities {
+    /// The number of ports minus one
+    #[bits(5)]
+    pub(super) ports: u8,
+    #[bits(3)]
+    _unused: (),
+    /// The number of command slots minus one
+    #[bits(5)]
+    pub(super) command_slots: u8,
+    #[bits(14)]
+    _unused2: (),
+    #[bits(1)]
+    pub(super) staggered_spin_up: bool,
+    #[bits(3)]
+    _unused3: (),
+    #[bits(1)]
+    pub(super) addressing_64: bool,
+}
+
+#[bitfield(u32)]
+pub(super) struct GlobalHostControl {
+    #[bits(1)]
+    pub(super) reset: bool,
+    #[bits(1)]
+    pub(super) interrupt_enable: bool,
+    #[bits(29)]
+    _unused: (),
+    /// Set to use the controller through ahci instead of as a legacy ide controller
+    #[bits(1)]
+    pub(super) ahci_enable: bool,
+}
+
+#[bitfield(u32)]
+pub(super) struct PortCommand {
+    /// Set to process the command list
+    #[bits(1)]
+    pub(super) start: bool,
+    #[bits(1)]
+    pub(super) spin_up: bool,
+    #[bits(1)]
+    pub(super) power_on: bool,
+    #[bits(1)]
+    _command_list_override: bool,
+    /// Set to accept FISes from the drive
+    #[bits(1)]
+    pub(super) fis_receive_enable: bool,
+    #[bits(3)]
+    _reserved: (),
+    #[bits(5)]
+    _current_slot: u8,
+    #[bits(1)]
+    _mechanical_presence: bool,
+    #[bits(1)]
+    pub(super) fis_receive_running: bool,
+    #[bits(1)]
+    pub(super) command_list_running: bool,
+    #[bits(16)]
+    _unused: (),
+}
+
+/// The interrupt status and enable registers of a port share a layout.
+#[bitfield(u32)]
+pub(super) struct PortInterrupt {
+    /// The drive sent a register FIS, the end of most commands
+    #[bits(1)]
+    pub(super) device_to_host: bool,
+    /// The drive sent a PIO setup FIS, the end of a PIO data in command
+    #[bits(1)]
+    pub(super) pio_setup: bool,
+    #[bits(1)]
+    pub(super) dma_setup: bool,
+    #[bits(1)]
+    pub(super) set_device_bits: bool,
+    #[bits(26)]
+    _unused: (),
+    #[bits(1)]
+    pub(super) task_file_error: bool,
+    #[bits(1)]
+    _unused2: (),
+}
+
+/// A copy of the drive's status and error registers.
+#[bitfield(u32)]
+pub struct TaskFileData {
+    #[bits(1)]
+    pub(super) error: bool,
+    #[bits(2)]
+    _unused: (),
+    #[bits(1)]
+    pub(super) data_request: bool,
+    #[bits(3)]
+    _unused2: (),
+    #[bits(1)]
+    pub(super) busy: bool,
+    #[bits(8)]
+    pub(super) error_reg: u8,
+    #[bits(16)]
+    _reserved: (),
+}
+
+#[bitfield(u32)]
+pub(super) struct SataStatus {
+    #[bits(4)]
+    pub(super) detection: u8,
+    #[bits(4)]
+    pub(super) speed: u8,
+    #[bits(4)]
+    pub(super) power_management: u8,
+    #[bits(20)]
+    _reserved: (),
+}
+
+impl SataStatus {
+    /// A drive is attached and communication is established
+    pub(super) const DEVICE_PRESENT: u8 = 3;
+    /// The interface is in the active power state
+    pub(super) const ACTIVE: u8 = 1;
+}
+
+/// The signature of a SATA drive, ATAPI and port multipliers have their own.
+pub(super) const SATA_SIGNATURE: u32 = 0x0000_0101;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub(super) enum AtaCommand {
+    ReadDmaExt = 0x25,
+    WriteDmaExt = 0x35,
+    CacheFlushExt = 0xEA,
+    Identify = 0xEC,
+}
+
+#[bitfield(u16)]
+pub(super) struct CommandHeaderFlags {
+    /// The length of the command FIS in dwords
+    #[bits(5)]
+    pub(super) fis_length: u8,
+    #[bits(1)]
+    pub(super) atapi: bool,
+    /// Set if the command writes to the drive
+    #[bits(1)]
+    pub(super) write: bool,
+    #[bits(9)]
+    _unused: (),
+}
+
+/// An entry of the command list, describes the command in one slot.
+#[derive(IntoBytes, KnownLayout, Immutable)]
+#[repr(C)]
+pub(super) struct CommandHeader {
+    pub(super) flags: U16,
+    /// The number of entries in the physical region descriptor table
+    pub(super) prdt_length: U16,
+    /// The number of bytes transferred, updated by the controller
+    pub(super) prd_byte_count: U32,
+    /// The command table, aligned to 128 bytes
+    pub(super) table_base: U32,
+    pub(super) table_base_upper: U32,
+    pub(super) _reserved: [U32; 4],
+}
+
+impl CommandHeader {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 32);
+    };
+}
+
+/// A register FIS from the host to the drive, sends a command.
+#[derive(IntoBytes, KnownLayout, Immutable)]
+#[repr(C)]
+pub(super) struct FisRegisterHostToDevice {
+    fis_type: u8,
+    flags: u8,
+    command: u8,
+    feature_low: u8,
+    lba_low: [u8; 3],
+    device: u8,
+    lba_high: [u8; 3],
+    feature_high: u8,
+    count: U16,
+    icc: u8,
+    control: u8,
+    _reserved: [u8; 4],
+}
+
+impl FisRegisterHostToDevice {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == Self::LENGTH_DWORDS as usize * 4);
+    };
+
+    /// The length of the FIS for [`CommandHeaderFlags::fis_length`].
+    pub(super) const LENGTH_DWORDS: u8 = 5;
+
+    const TYPE: u8 = 0x27;
+    /// Set in the flags if the FIS updates the command register rather than the control register
+    const COMMAND: u8 = 1 << 7;
+    /// Set in the device register to use lba addressing
+    const LBA_MODE: u8 = 1 << 6;
+
+    pub(super) const fn new(command: AtaCommand, lba: u64, count: u16) -> Self {
+        let lba = lba.to_le_bytes();
+
+        Self {
+            fis_type: Self::TYPE,
+            flags: Self::COMMAND,
+            command: command as u8,
+            feature_low: 0,
+            lba_low: [lba[0], lba[1], lba[2]],
+            device: Self::LBA_MODE,
+            lba_high: [lba[3], lba[4], lba[5]],
+            feature_high: 0,
+            count: U16::new(count),
+            icc: 0,
+            control: 0,
+            _reserved: [0; 4],
+        }
+    }
+}
+
+/// An entry of the physical region descriptor table, describes one region of memory the
+/// controller transfers to or from.
+#[derive(IntoBytes, KnownLayout, Immutable)]
+#[repr(C)]
+pub(super) struct PhysicalRegionDescriptor {
+    address: U32,
+    address_upper: U32,
+    _reserved: U32,
+    /// The number of bytes minus one, bit 31 interrupts once the region is done
+    byte_count: U32,
+}
+
+impl PhysicalRegionDescriptor {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 16);
+    };
+
+    /// Creates an entry for `length` bytes at `address`, `length` must be even and at most 4 MiB.
+    pub(super) fn new(address: u32, length: usize) -> Self {
+        debug_assert!(length.is_multiple_of(2) && length <= 0x40_0000 && length != 0);
+
+        Self {
+            address: U32::new(address),
+            address_upper: U32::new(0),
+            _reserved: U32::new(0),
+            byte_count: U32::new(u32::try_from(length - 1).expect("Should be at most 4 MiB")),
+        }
+    }
+}
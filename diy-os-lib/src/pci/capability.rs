@@ -0,0 +1,464 @@
+use bitfield_struct::{bitenum, bitfield};
+
+use super::{Capability, PciAddress, PciDevice};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[bitenum(all = false)]
+pub enum CapabilityId {
+    PowerManagement = 0x01,
+    Msi = 0x05,
+    /// Laid out however the vendor wants, virtio describes its structures with these
+    VendorSpecific = 0x09,
+    PciExpress = 0x10,
+    MsiX = 0x11,
+    #[fallback]
+    Other,
+}
+
+impl Capability {
+    pub const fn kind(self) -> CapabilityId {
+        CapabilityId::from_bits(self.id)
+    }
+}
+
+impl PciDevice {
+    /// Returns the first capability of the function with `id`.
+    pub fn find_capability(&self, id: CapabilityId) -> Option<Capability> {
+        self.capabilities()
+            .find(|capability| capability.kind() == id)
+    }
+
+    pub fn power_management(&self) -> Option<PowerManagement> {
+        self.find_capability(CapabilityId::PowerManagement)
+            .map(|capability| PowerManagement {
+                address: self.address,
+                offset: capability.offset,
+            })
+    }
+
+    pub fn msi(&self) -> Option<Msi> {
+        self.find_capability(CapabilityId::Msi)
+            .map(|capability| Msi {
+                address: self.address,
+                offset: capability.offset,
+            })
+    }
+
+    pub fn msix(&self) -> Option<MsiX> {
+        self.find_capability(CapabilityId::MsiX)
+            .map(|capability| MsiX {
+                address: self.address,
+                offset: capability.offset,
+            })
+    }
+
+    pub fn pci_express(&self) -> Option<PciExpress> {
+        self.find_capability(CapabilityId::PciExpress)
+            .map(|capability| PciExpress {
+                address: self.address,
+                offset: capability.offset,
+            })
+    }
+}
+
+/// How awake a function is, only D0 handles accesses other than to its config space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
+}
+
+impl PowerState {
+    const fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => Self::D0,
+            1 => Self::D1,
+            2 => Self::D2,
+            _ => Self::D3Hot,
+        }
+    }
+
+    const fn into_bits(self) -> u8 {
+        self as u8
+    }
+}
+
+#[bitfield(u16)]
+struct PowerControl {
+    #[bits(2)]
+    state: PowerState,
+    #[bits(1)]
+    _reserved: (),
+    /// The function keeps its configuration going from D3hot to D0
+    #[bits(1)]
+    no_soft_reset: bool,
+    #[bits(4)]
+    _reserved2: (),
+    #[bits(1)]
+    pme_enable: bool,
+    #[bits(4)]
+    data_select: u8,
+    #[bits(2)]
+    data_scale: u8,
+    /// Cleared by writing 1
+    #[bits(1)]
+    pme_status: bool,
+}
+
+/// The power management capability of a function.
+#[derive(Debug, Clone, Copy)]
+pub struct PowerManagement {
+    address: PciAddress,
+    offset: u8,
+}
+
+impl PowerManagement {
+    const CONTROL: u8 = 4;
+
+    fn control(self) -> PowerControl {
+        PowerControl::from_bits(self.address.read_config_u16(self.offset + Self::CONTROL))
+    }
+
+    pub fn state(self) -> PowerState {
+        self.control().state()
+    }
+
+    /// Returns true if the function keeps its configuration going from D3hot to D0.
+    pub fn no_soft_reset(self) -> bool {
+        self.control().no_soft_reset()
+    }
+
+    /// Moves the function to `state`, going from D3hot to D0 takes up to 10ms and resets the
+    /// function unless [`Self::no_soft_reset`].
+    ///
+    /// # Safety
+    ///
+    /// Nothing can be using the function when it's moved out of D0.
+    pub unsafe fn set_state(self, state: PowerState) {
+        // writing the status back would clear it
+        let control = self.control().with_state(state).with_pme_status(false);
+
+        unsafe {
+            self.address
+                .write_config_u16(self.offset + Self::CONTROL, control.into_bits());
+        };
+    }
+}
+
+#[bitfield(u16)]
+struct MsiControl {
+    #[bits(1)]
+    enable: bool,
+    /// The log2 of the vectors the function wants
+    #[bits(3)]
+    multiple_message_capable: u8,
+    /// The log2 of the vectors the function is given
+    #[bits(3)]
+    multiple_message_enable: u8,
+    #[bits(1)]
+    address_64: bool,
+    #[bits(1)]
+    per_vector_masking: bool,
+    #[bits(7)]
+    _reserved: (),
+}
+
+/// The msi capability of a function, it interrupts by writing `data` to `address`.
+#[derive(Debug, Clone, Copy)]
+pub struct Msi {
+    address: PciAddress,
+    offset: u8,
+}
+
+impl Msi {
+    const CONTROL: u8 = 2;
+    const ADDRESS: u8 = 4;
+
+    fn control(self) -> MsiControl {
+        MsiControl::from_bits(self.address.read_config_u16(self.offset + Self::CONTROL))
+    }
+
+    /// Returns true if the message address can be above 4 GiB.
+    pub fn is_64bit(self) -> bool {
+        self.control().address_64()
+    }
+
+    /// The number of vectors the function wants.
+    pub fn vectors(self) -> u8 {
+        1 << self.control().multiple_message_capable()
+    }
+
+    pub fn is_enabled(self) -> bool {
+        self.control().enable()
+    }
+
+    /// Makes the function interrupt by writing `data` to `message_address` with a single
+    /// vector, and stops it asserting its interrupt pin.
+    ///
+    /// # Safety
+    ///
+    /// The message has to raise an interrupt the driver handles, the upper half of
+    /// `message_address` is dropped unless [`Self::is_64bit`].
+    pub unsafe fn enable(self, message_address: u64, data: u16) {
+        let [low, high] = [message_address & 0xFFFF_FFFF, message_address >> 32]
+            .map(|half| u32::try_from(half).expect("Should be 32 bits"));
+        let control = self.control();
+
+        // the data follows the address, which is 8 bytes if it's 64-bit
+        let data_offset = if control.address_64() {
+            Self::ADDRESS + 8
+        } else {
+            Self::ADDRESS + 4
+        };
+
+        unsafe {
+            self.address.write_config(self.offset + Self::ADDRESS, low);
+
+            if control.address_64() {
+                self.address
+                    .write_config(self.offset + Self::ADDRESS + 4, high);
+            }
+
+            self.address
+                .write_config_u16(self.offset + data_offset, data);
+
+            self.address.write_config_u16(
+                self.offset + Self::CONTROL,
+                control
+                    .with_multiple_message_enable(0)
+                    .with_enable(true)
+                    .into_bits(),
+            );
+
+            self.address
+                .update_command(|command| command.with_interrupt_disable(true));
+        };
+    }
+
+    /// Stops the function sending messages, it goes back to its interrupt pin.
+    ///
+    /// # Safety
+    ///
+    /// The interrupt pin has to be routed and handled.
+    pub unsafe fn disable(self) {
+        unsafe {
+            self.address.write_config_u16(
+                self.offset + Self::CONTROL,
+                self.control().with_enable(false).into_bits(),
+            );
+
+            self.address
+                .update_command(|command| command.with_interrupt_disable(false));
+        };
+    }
+}
+
+#[bitfield(u16)]
+struct MsiXControl {
+    /// One less than the number of entries in the table
+    #[bits(11)]
+    table_size: u16,
+    #[bits(3)]
+    _reserved: (),
+    /// Masks every vector, whatever their own mask is
+    #[bits(1)]
+    function_mask: bool,
+    #[bits(1)]
+    enable: bool,
+}
+
+/// Where a structure of the msi-x capability is, in the memory of one of the BARs.
+#[derive(Debug, Clone, Copy)]
+pub struct BarOffset {
+    pub bar: u8,
+    pub offset: u32,
+}
+
+impl BarOffset {
+    /// The low 3 bits are the BAR, the offset is 8 byte aligned.
+    const fn from_bits(register: u32) -> Self {
+        Self {
+            bar: register.to_le_bytes()[0] & 0b111,
+            offset: register & !0b111,
+        }
+    }
+}
+
+/// The msi-x capability of a function, each vector has an entry in a table in the memory of a
+/// BAR with its own address, data and mask.
+///
+/// Mapping the table and filling it in is up to the driver.
+#[derive(Debug, Clone, Copy)]
+pub struct MsiX {
+    address: PciAddress,
+    offset: u8,
+}
+
+impl MsiX {
+    const CONTROL: u8 = 2;
+    const TABLE: u8 = 4;
+    const PENDING_BITS: u8 = 8;
+
+    fn control(self) -> MsiXControl {
+        MsiXControl::from_bits(self.address.read_config_u16(self.offset + Self::CONTROL))
+    }
+
+    fn write_control(self, control: MsiXControl) {
+        // SAFETY: only the msi-x control register is written, callers are unsafe
+        unsafe {
+            self.address
+                .write_config_u16(self.offset + Self::CONTROL, control.into_bits());
+        };
+    }
+
+    /// The number of entries in the table, 16 bytes each.
+    pub fn table_size(self) -> u16 {
+        self.control().table_size() + 1
+    }
+
+    pub fn table(self) -> BarOffset {
+        BarOffset::from_bits(self.address.read_config(self.offset + Self::TABLE))
+    }
+
+    /// The pending bit array, a bit for each entry of the table.
+    pub fn pending_bits(self) -> BarOffset {
+        BarOffset::from_bits(self.address.read_config(self.offset + Self::PENDING_BITS))
+    }
+
+    pub fn is_enabled(self) -> bool {
+        self.control().enable()
+    }
+
+    /// Enables msi-x with every vector masked until [`Self::set_function_mask`] clears the mask,
+    /// and stops the function asserting its interrupt pin.
+    ///
+    /// # Safety
+    ///
+    /// The entries of the table have to raise interrupts the driver handles before the mask is
+    /// cleared.
+    pub unsafe fn enable(self) {
+        self.write_control(self.control().with_function_mask(true).with_enable(true));
+
+        unsafe {
+            self.address
+                .update_command(|command| command.with_interrupt_disable(true));
+        };
+    }
+
+    /// Masks or unmasks every vector at once.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Self::enable`].
+    pub unsafe fn set_function_mask(self, masked: bool) {
+        self.write_control(self.control().with_function_mask(masked));
+    }
+
+    /// Stops the function sending messages, it goes back to its interrupt pin.
+    ///
+    /// # Safety
+    ///
+    /// The interrupt pin has to be routed and handled.
+    pub unsafe fn disable(self) {
+        self.write_control(self.control().with_enable(false));
+
+        unsafe {
+            self.address
+                .update_command(|command| command.with_interrupt_disable(false));
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[bitenum(all = false)]
+pub enum PciExpressType {
+    Endpoint = 0x0,
+    LegacyEndpoint = 0x1,
+    RootPort = 0x4,
+    UpstreamSwitchPort = 0x5,
+    DownstreamSwitchPort = 0x6,
+    PciExpressToPciBridge = 0x7,
+    PciToPciExpressBridge = 0x8,
+    RootComplexIntegratedEndpoint = 0x9,
+    RootComplexEventCollector = 0xA,
+    #[fallback]
+    Unknown,
+}
+
+#[bitfield(u16)]
+struct PciExpressCapabilities {
+    #[bits(4)]
+    version: u8,
+    #[bits(4)]
+    device_type: PciExpressType,
+    #[bits(1)]
+    slot_implemented: bool,
+    #[bits(5)]
+    interrupt_message: u8,
+    #[bits(2)]
+    _reserved: (),
+}
+
+#[bitfield(u16)]
+struct LinkStatus {
+    /// Indexes the supported speeds, 1 is 2.5 GT/s
+    #[bits(4)]
+    speed: u8,
+    #[bits(6)]
+    width: u8,
+    #[bits(6)]
+    _rest: (),
+}
+
+/// The pci express capability, every pci express function has it.
+#[derive(Debug, Clone, Copy)]
+pub struct PciExpress {
+    address: PciAddress,
+    offset: u8,
+}
+
+impl PciExpress {
+    const CAPABILITIES: u8 = 2;
+    const LINK_STATUS: u8 = 0x12;
+
+    fn capabilities(self) -> PciExpressCapabilities {
+        PciExpressCapabilities::from_bits(
+            self.address
+                .read_config_u16(self.offset + Self::CAPABILITIES),
+        )
+    }
+
+    /// The version of the capability structure.
+    pub fn version(self) -> u8 {
+        self.capabilities().version()
+    }
+
+    pub fn device_type(self) -> PciExpressType {
+        self.capabilities().device_type()
+    }
+
+    /// The speed and number of lanes the link trained to, as in the link status register.
+    ///
+    /// Returns [`None`] for functions without a link, those in the root complex.
+    pub fn link(self) -> Option<(u8, u8)> {
+        if matches!(
+            self.device_type(),
+            PciExpressType::RootComplexIntegratedEndpoint
+                | PciExpressType::RootComplexEventCollector
+        ) {
+            return None;
+        }
+
+        let status = LinkStatus::from_bits(
+            self.address
+                .read_config_u16(self.offset + Self::LINK_STATUS),
+        );
+
+        Some((status.speed(), status.width()))
+    }
+}
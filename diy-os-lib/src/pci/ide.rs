@@ -1,22 +1,52 @@
-use crate::device_manager::BlockDeviceError;
 use crate::device_manager::DeviceManager;
+use crate::device_manager::driver::{PciDriver, PciMatch};
+use crate::device_manager::{BlockDeviceError, check_access};
+use crate::pci::ide::atapi::AtapiDrive;
 use crate::pci::ide::structs::Drive;
 use crate::pci::ide::structs::DriveType;
 use crate::pci::ide::structs::IdentificationSpaceRaw;
 use crate::pci::ide::structs::Status;
 use crate::pci::ide::structs::{Channel, Command};
+use crate::pci::{Bar, IdeProgIf, MassStorageSubclass, PciAddress, PciDevice, ide::dma::BusMaster};
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use core::ascii::Char;
+use x86_64::structures::paging::OffsetPageTable;
 
 use crate::device_manager::BlockDevice;
+use crate::interrupts;
+use crate::memory::BootInfoFrameAllocator;
 use crate::multitasking::mutex::Mutex;
 use crate::pci::ide::structs::HddSelect;
-use crate::timer::sleep;
+use crate::timer::{Duration, Seconds, sleep};
 use crate::{device_manager::Device, pci::DeviceInfo};
 
+mod atapi;
+mod dma;
+pub mod irq;
 mod structs;
 
+/// Binds to ide controllers in compatibility or pci native mode.
+pub static DRIVER: PciDriver = PciDriver {
+    name: "ide",
+    matches: &[PciMatch::mass_storage(MassStorageSubclass::Ide, None)],
+    probe,
+};
+
+const SECTOR_SIZE: usize = 512;
+
+/// How long a drive has to interrupt after a command or sector before it's given up on.
+const COMMAND_TIMEOUT: Duration = Seconds(5).into();
+
+/// How long a drive can stay busy before it's given up on.
+const BUSY_TIMEOUT: Duration = Seconds(5).into();
+
+/// The most sectors a single DMA command can transfer, limited by the size of the buffer.
+const DMA_MAX_TRANSFER: usize = dma::BUFFER_SIZE / SECTOR_SIZE;
+
+/// The number of sectors addressable with a 28-bit lba, 128 GiB.
+const LBA28_SECTORS: u64 = 1 << 28;
+
 #[derive(Debug)]
 pub struct IdeController {
     drive_1: Option<Arc<Mutex<dyn BlockDevice>>>,
@@ -55,20 +85,23 @@ impl BlockDevice for Drive {
     fn read_sectors(
         &mut self,
         lba: u64,
-        count: u8,
+        count: u16,
         buffer: &mut [u8],
     ) -> Result<(), BlockDeviceError> {
-        Ok(ide_read_sectors(self, count, lba, buffer)?)
+        let length = check_access(self.size, SECTOR_SIZE, lba, count, buffer.len())?;
+
+        Ok(ide_read_sectors(self, lba, &mut buffer[..length])?)
     }
 
-    #[allow(unused)]
     fn write_sectors(
         &mut self,
         lba: u64,
-        count: u8,
+        count: u16,
         buffer: &[u8],
     ) -> Result<(), BlockDeviceError> {
-        todo!()
+        let length = check_access(self.size, SECTOR_SIZE, lba, count, buffer.len())?;
+
+        Ok(ide_write_sectors(self, lba, &buffer[..length])?)
     }
 
     fn total_sectors(&self) -> u64 {
@@ -78,17 +111,61 @@ impl BlockDevice for Drive {
 
 #[derive(thiserror::Error, Debug)]
 pub enum IdeCreationError {
-    #[error("One or more channels were in pci native mode")]
-    PciNativeMode,
+    #[error("BAR `{0}` of the controller isn't a valid io port")]
+    InvalidBar(u8),
+    #[error("The controller is in pci native mode but its interrupt isn't routed, line `{0}`")]
+    NoInterruptLine(u8),
+}
+
+/// The io ports and interrupt line of a channel.
+struct ChannelPorts {
+    base: u16,
+    control: u16,
+    irq: u8,
 }
+
+impl ChannelPorts {
+    const PRIMARY_COMPATIBILITY: Self = Self {
+        base: 0x1F0,
+        control: 0x3F4,
+        irq: 14,
+    };
+
+    const SECONDARY_COMPATIBILITY: Self = Self {
+        base: 0x170,
+        control: 0x374,
+        irq: 15,
+    };
+}
+
+fn probe(
+    pci_device: PciDevice,
+    device_manager: &mut DeviceManager,
+    _mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), anyhow::Error> {
+    let controller = create_ide_controller(pci_device, device_manager, frame_allocator)?;
+    device_manager.register_device(Arc::new(Mutex::new(controller)));
+
+    Ok(())
+}
+
+/// Transfers use DMA if the controller is a bus master and the drive supports it, falling back to
+/// PIO otherwise.
+///
+/// Channels in pci native mode are switched to compatibility mode if they are programmable, so
+/// they don't share the pci interrupt line.
+///
 /// # Errors
-/// Will return `IdeCreationError::PciNativeMode` if the ide controller is in pci native mode
+/// Will return [`IdeCreationError`] if a channel is in pci native mode and its BARs or interrupt
+/// line are invalid
 pub fn create_ide_controller(
-    pci_device: DeviceInfo,
+    pci_device: PciDevice,
     device_manager: &mut DeviceManager,
+    frame_allocator: &mut BootInfoFrameAllocator,
 ) -> Result<IdeController, IdeCreationError> {
-    fn setup_drive(
-        drive: Drive,
+    fn register<D: BlockDevice + 'static>(
+        drive: D,
         device_manager: &mut DeviceManager,
     ) -> Arc<Mutex<dyn BlockDevice>> {
         let drive = Arc::new(Mutex::new(drive));
@@ -100,13 +177,62 @@ pub fn create_ide_controller(
         drive as Arc<Mutex<dyn BlockDevice>>
     }
 
-    // Pci native mode is unsupported
-    if pci_device.prog_if.pci_native_mode_1() || pci_device.prog_if.pci_native_mode_2() {
-        return Err(IdeCreationError::PciNativeMode);
+    fn setup_drive(
+        drive: IdeDrive,
+        device_manager: &mut DeviceManager,
+    ) -> Arc<Mutex<dyn BlockDevice>> {
+        match drive {
+            IdeDrive::Ata(drive) => register(drive, device_manager),
+            IdeDrive::Atapi(drive) => register(drive, device_manager),
+        }
+    }
+
+    let mut info = pci_device.info;
+    let prog_if = switch_to_compatibility_mode(pci_device.address, info.ide_prog_if());
+    info.prog_if = prog_if.into_bits();
+
+    let primary_ports = if prog_if.pci_native_mode_1() {
+        native_ports(&info, 0)?
+    } else {
+        ChannelPorts::PRIMARY_COMPATIBILITY
+    };
+
+    let sec_ports = if prog_if.pci_native_mode_2() {
+        native_ports(&info, 2)?
+    } else {
+        ChannelPorts::SECONDARY_COMPATIBILITY
+    };
+
+    for irq in [primary_ports.irq, sec_ports.irq] {
+        interrupts::unmask_irq(irq);
     }
 
-    let primary_channel = unsafe { Channel::new(0x1F0, 0x3F6) };
-    let sec_channel = unsafe { Channel::new(0x170, 0x376) };
+    let mut primary_channel = unsafe {
+        Channel::new(
+            primary_ports.base,
+            primary_ports.control,
+            primary_ports.irq,
+            &irq::PRIMARY_IRQ,
+        )
+    };
+    let mut sec_channel = unsafe {
+        Channel::new(
+            sec_ports.base,
+            sec_ports.control,
+            sec_ports.irq,
+            &irq::SECONDARY_IRQ,
+        )
+    };
+
+    if let Some(base_port) = bus_master_port(pci_device.address, &info) {
+        // the secondary channel's registers follow the primary's
+        attach_bus_master(&mut primary_channel, base_port, frame_allocator);
+        attach_bus_master(&mut sec_channel, base_port + 8, frame_allocator);
+
+        if primary_channel.bus_master.is_none() || sec_channel.bus_master.is_none() {
+            log::warn!("unable to allocate dma buffers below 4 GiB, using pio");
+        }
+    }
 
     let (drive_1, drive_2) = init_channel(primary_channel);
     let (drive_3, drive_4) = init_channel(sec_channel);
@@ -119,8 +245,104 @@ pub fn create_ide_controller(
     })
 }
 
-fn init_channel(mut channel: Channel) -> (Option<Drive>, Option<Drive>) {
-    channel.write_control(2);
+/// Switches the channels in pci native mode that are programmable to compatibility mode,
+/// returning the programming interface the controller reports afterwards.
+fn switch_to_compatibility_mode(address: PciAddress, prog_if: IdeProgIf) -> IdeProgIf {
+    let wanted = prog_if
+        .with_pci_native_mode_1(prog_if.pci_native_mode_1() && !prog_if.native_mode_w_1())
+        .with_pci_native_mode_2(prog_if.pci_native_mode_2() && !prog_if.native_mode_w_2());
+
+    if wanted.into_bits() == prog_if.into_bits() {
+        return prog_if;
+    }
+
+    // SAFETY: nothing uses the controller yet
+    unsafe { address.write_config_u8(PROG_IF_REGISTER, wanted.into_bits()) };
+
+    // read back since a controller can ignore the switch
+    let prog_if = IdeProgIf::from_bits(address.read_config_u8(PROG_IF_REGISTER));
+
+    log::info!("switched the ide controller to programming interface {prog_if:?}");
+
+    prog_if
+}
+
+/// The config space offset of the programming interface.
+const PROG_IF_REGISTER: u8 = 0x9;
+
+/// Reads the ports of a channel in pci native mode from the BARs starting at `bar`, the
+/// command block then the control block.
+fn native_ports(info: &DeviceInfo, bar: u8) -> Result<ChannelPorts, IdeCreationError> {
+    let irq = info.header.interrupt_line();
+
+    // the PIC only has 16 lines, 0xFF means the pin isn't routed
+    if irq >= 16 {
+        return Err(IdeCreationError::NoInterruptLine(irq));
+    }
+
+    Ok(ChannelPorts {
+        base: io_bar(info, bar)?,
+        control: io_bar(info, bar + 1)?,
+        irq,
+    })
+}
+
+fn io_bar(info: &DeviceInfo, index: u8) -> Result<u16, IdeCreationError> {
+    match info.header.get_bar(index) {
+        // 0 means the firmware didn't assign any ports
+        Some(Bar::IoSpace { addr }) if addr != 0 => {
+            u16::try_from(addr).map_err(|_| IdeCreationError::InvalidBar(index))
+        }
+        _ => Err(IdeCreationError::InvalidBar(index)),
+    }
+}
+
+/// Allocates the bus master of `channel`, its registers at `base_port`.
+fn attach_bus_master(
+    channel: &mut Channel,
+    base_port: u16,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) {
+    channel.bus_master = unsafe { BusMaster::new(base_port, frame_allocator) };
+
+    if channel.bus_master.is_some() {
+        // the handler checks it to tell if this channel interrupted
+        channel.irq.set_bus_master_status_port(base_port + 2);
+    }
+}
+
+/// Returns the base port of the bus master registers, from BAR4 of the controller, enabling bus
+/// mastering if the firmware left it off.
+fn bus_master_port(address: PciAddress, info: &DeviceInfo) -> Option<u16> {
+    if !info.ide_prog_if().master_and_dma() {
+        return None;
+    }
+
+    if !info.command.bus_master() {
+        address.enable_bus_master();
+
+        // read back since the bit can be hardwired to 0
+        if !address.command().bus_master() {
+            log::warn!("unable to enable bus mastering for the ide controller, using pio");
+            return None;
+        }
+    }
+
+    match info.header.get_bar(4) {
+        Some(Bar::IoSpace { addr }) => u16::try_from(addr).ok(),
+        Some(Bar::MemorySpace { .. }) | None => None,
+    }
+}
+
+/// A drive found on a channel.
+enum IdeDrive {
+    Ata(Drive),
+    Atapi(AtapiDrive),
+}
+
+fn init_channel(mut channel: Channel) -> (Option<IdeDrive>, Option<IdeDrive>) {
+    // enables interrupts, dma transfers wait for them
+    channel.write_control(0);
 
     let channel = Arc::new(Mutex::new(channel));
 
@@ -138,9 +360,13 @@ pub enum IdeError {
     DriveWriteFailed,
     #[error("Drive not ready for new data")]
     DriveNotReadyForNewData,
+    #[error("The bus master ran into an error during a dma transfer")]
+    DmaError,
+    #[error("The drive didn't respond in time")]
+    Timeout,
 }
 
-fn init_drive(channel_lock: &Arc<Mutex<Channel>>, drive_type: DriveType) -> Option<Drive> {
+fn init_drive(channel_lock: &Arc<Mutex<Channel>>, drive_type: DriveType) -> Option<IdeDrive> {
     channel_lock.with_mut_ref(|channel| {
         channel.write_hdd_sel(HddSelect::new().with_child(drive_type == DriveType::Child));
     });
@@ -151,25 +377,31 @@ fn init_drive(channel_lock: &Arc<Mutex<Channel>>, drive_type: DriveType) -> Opti
 
     let mut channel = channel_lock.acquire();
 
-    loop {
-        let status = channel.get_status_reg();
+    let status = channel.get_status_reg();
+
+    // no drive is attached
+    if Status::from_bits(0) == status {
+        return None;
+    }
 
-        if Status::from_bits(0) == status || status.error() {
+    // ATAPI drives abort IDENTIFY, a floating bus reads as all ones so it has no signature
+    if status.error() {
+        if channel.get_signature() != atapi::SIGNATURE {
             return None;
         }
 
-        if !status.busy() && status.data_request_ready() {
-            break;
-        }
-    }
+        drop(channel);
 
-    let mut buffer: [u16; 256] = [0; 256];
+        return atapi::init_drive(channel_lock, drive_type).map(IdeDrive::Atapi);
+    }
 
-    channel.read_ident_space(&mut buffer);
+    let status = wait_while_busy(&mut channel).ok()?;
 
-    let maybe = (&raw const buffer).cast::<IdentificationSpaceRaw>();
+    if status.error() || !status.data_request_ready() {
+        return None;
+    }
 
-    let mut ident_space = unsafe { maybe.read_unaligned() };
+    let mut ident_space = read_identification(&mut channel);
 
     // TODO: Turn this into a builder
     let mut drive = Drive {
@@ -179,14 +411,38 @@ fn init_drive(channel_lock: &Arc<Mutex<Channel>>, drive_type: DriveType) -> Opti
         caps: ident_space.capabilities,
         command_set: ident_space.command_sets_enabled,
         size: 0, // unknown
-        model: [Char::Null; 41],
+        lba48: false,
+        // word 49 bit 8
+        dma: ident_space.capabilities & (1 << 8) != 0 && channel.bus_master.is_some(),
+        model: parse_model(&mut ident_space),
     };
 
-    if drive.command_set & (1 << 26) != 0 {
-        drive.size = u64::from(ident_space.lba28_total_sectors);
+    // word 86 bit 10, set if the 48-bit address feature set is enabled
+    drive.lba48 = drive.command_set & (1 << 26) != 0;
+
+    drive.size = if drive.lba48 {
+        ident_space.lba48_total_sectors
     } else {
-        drive.size = ident_space.lba48_total_sectors;
-    }
+        u64::from(ident_space.lba28_total_sectors)
+    };
+
+    Some(IdeDrive::Ata(drive))
+}
+
+/// Reads the response to IDENTIFY or IDENTIFY PACKET.
+fn read_identification(channel: &mut Channel) -> IdentificationSpaceRaw {
+    let mut buffer: [u16; 256] = [0; 256];
+
+    channel.read_ident_space(&mut buffer);
+
+    let maybe = (&raw const buffer).cast::<IdentificationSpaceRaw>();
+
+    unsafe { maybe.read_unaligned() }
+}
+
+/// The model number is stored with the bytes of each word swapped.
+fn parse_model(ident_space: &mut IdentificationSpaceRaw) -> [Char; 41] {
+    let mut model = [Char::Null; 41];
 
     ident_space
         .model_number
@@ -197,83 +453,291 @@ fn init_drive(channel_lock: &Arc<Mutex<Channel>>, drive_type: DriveType) -> Opti
         })
         .map(|x| Char::from_u8(*x).unwrap_or(Char::QuotationMark))
         .enumerate()
-        .for_each(|(i, x)| drive.model[i] = x);
+        .for_each(|(i, x)| model[i] = x);
+
+    model
+}
 
-    drive.model[40] = Char::Null;
+/// The most sectors a single command can transfer, a sector count of 0 means 256 sectors for
+/// LBA28 and 65536 sectors for LBA48.
+const fn max_transfer(drive: &Drive) -> usize {
+    if drive.lba48 { u16::MAX as usize } else { 256 }
+}
 
-    Some(drive)
+/// Returns true if the EXT version of a command is needed to transfer `count` sectors at `lba`.
+const fn needs_lba48(lba: u64, count: u16) -> bool {
+    lba + count as u64 > LBA28_SECTORS || count > 256
 }
 
-fn ide_read_sectors(
+/// Selects the drive and writes the lba and sector count registers, using the LBA48 layout if
+/// `ext` is set.
+fn select_sectors(
+    channel: &mut Channel,
     drive: &Drive,
-    num_of_sectors: u8,
+    count: u16,
     lba: u64,
-    buffer: &mut [u8],
+    ext: bool,
 ) -> Result<(), IdeError> {
-    assert!((lba + u64::from(num_of_sectors)) <= drive.size);
+    let lba = lba.to_le_bytes();
+    let count = count.to_le_bytes();
+
+    wait_while_busy(channel)?;
+
+    if ext {
+        channel.write_hdd_sel(
+            HddSelect::new()
+                .with_child(drive.drive == DriveType::Child)
+                .with_lba_mode(true),
+        );
+
+        // the registers hold two bytes each, the high bytes are written first
+        channel.write_sec_count_0(count[1]);
+        channel.write_lba_0(lba[3]);
+        channel.write_lba_1(lba[4]);
+        channel.write_lba_2(lba[5]);
+    } else {
+        channel.write_hdd_sel(
+            HddSelect::new()
+                .with_child(drive.drive == DriveType::Child)
+                .with_lba_mode(true)
+                .with_head_or_lba_high(lba[3] & 0x0F),
+        );
+    }
+
+    // a count of 256 is written as 0
+    channel.write_sec_count_0(count[0]);
+    channel.write_lba_0(lba[0]);
+    channel.write_lba_1(lba[1]);
+    channel.write_lba_2(lba[2]);
+
+    Ok(())
+}
+
+/// Reads whole sectors starting at `lba` into `buffer`, split into as few commands as the drive
+/// allows.
+fn ide_read_sectors(drive: &Drive, lba: u64, buffer: &mut [u8]) -> Result<(), IdeError> {
+    if drive.dma {
+        return ide_dma_read_sectors(drive, lba, buffer);
+    }
+
+    let max_transfer = max_transfer(drive);
+
+    for (lba, sectors) in (lba..)
+        .step_by(max_transfer)
+        .zip(buffer.chunks_mut(SECTOR_SIZE * max_transfer))
+    {
+        let count = u16::try_from(sectors.len() / SECTOR_SIZE).expect("Should fit in a u16");
+        let ext = needs_lba48(lba, count);
 
-    let lba_0: u8 = (lba & 0xFF).try_into().expect("Should be only one byte"); // byte 1
+        let mut channel = drive.channel.acquire();
 
-    let lba_1: u8 = ((lba & 0xFF00) >> 8)
-        .try_into()
-        .expect("Should be only one byte"); // byte 2
+        select_sectors(&mut channel, drive, count, lba, ext)?;
 
-    let lba_2: u8 = ((lba & 0x00FF_0000) >> 16)
-        .try_into()
-        .expect("Should be only one byte"); // byte 3
+        channel.send_command(if ext {
+            Command::ReadPioExt
+        } else {
+            Command::ReadPio
+        });
 
-    let head: u8 = ((lba & 0x0F00_0000) >> 24)
-        .try_into()
-        .expect("Should be only one byte"); // head
+        // the drive interrupts when each sector is ready
+        for sector in sectors.chunks_exact_mut(SECTOR_SIZE) {
+            wait_for_data(&mut channel)?;
 
-    let mut channel = drive.channel.acquire();
+            for buffer_word in sector.chunks_exact_mut(2) {
+                let word = unsafe { channel.data_reg.read() };
+
+                buffer_word.copy_from_slice(&word.to_le_bytes());
+            }
+        }
+    }
+
+    Ok(())
+}
 
-    while channel.get_status_reg().busy() {} // wait until idlea
+/// Writes whole sectors from `buffer` starting at `lba` then flushes the drive's write cache.
+fn ide_write_sectors(drive: &Drive, lba: u64, buffer: &[u8]) -> Result<(), IdeError> {
+    if drive.dma {
+        return ide_dma_write_sectors(drive, lba, buffer);
+    }
 
-    channel.write_hdd_sel(
-        HddSelect::new()
-            .with_child(drive.drive == DriveType::Child)
-            .with_lba_mode(true)
-            .with_head_or_lba_high(head),
-    );
+    let max_transfer = max_transfer(drive);
 
-    channel.write_sec_count_0(num_of_sectors);
-    channel.write_lba_0(lba_0);
-    channel.write_lba_1(lba_1);
-    channel.write_lba_2(lba_2);
+    for (lba, sectors) in (lba..)
+        .step_by(max_transfer)
+        .zip(buffer.chunks(SECTOR_SIZE * max_transfer))
+    {
+        let count = u16::try_from(sectors.len() / SECTOR_SIZE).expect("Should fit in a u16");
+        let ext = needs_lba48(lba, count);
 
-    channel.send_command(Command::ReadPio);
+        let mut channel = drive.channel.acquire();
 
-    let mut words_read = 0;
+        select_sectors(&mut channel, drive, count, lba, ext)?;
 
-    for sector in buffer.chunks_mut(512).take(num_of_sectors.into()) {
-        // split the buffer into sectors
-        poll_ide(&mut channel)?;
+        channel.send_command(if ext {
+            Command::WritePioExt
+        } else {
+            Command::WritePio
+        });
 
-        for buffer_word in sector.chunks_exact_mut(2) {
-            let word = unsafe { channel.data_reg.read() };
+        // the drive interrupts after each sector is written, except before the first one
+        for (index, sector) in sectors.chunks_exact(SECTOR_SIZE).enumerate() {
+            if index == 0 {
+                poll_ide(&mut channel)?;
+            } else {
+                wait_for_data(&mut channel)?;
+            }
 
-            buffer_word[0] = (word & 0xff).try_into().expect("should be only one byte"); // byte 1
-            buffer_word[1] = (word >> 8).try_into().expect("should be only one byte"); // byte 1
+            for word in sector.chunks_exact(2) {
+                let word = u16::from_le_bytes([word[0], word[1]]);
 
-            words_read += 1;
+                unsafe { channel.data_reg.write(word) };
+            }
         }
+
+        wait_for_completion(&mut channel)?;
+
+        flush_cache(&mut channel, drive)?;
     }
 
-    for _ in (words_read % 256)..256 {
-        unsafe {
-            channel.data_reg.read();
-        }
+    Ok(())
+}
+
+fn ide_dma_read_sectors(drive: &Drive, lba: u64, buffer: &mut [u8]) -> Result<(), IdeError> {
+    for (lba, sectors) in (lba..)
+        .step_by(DMA_MAX_TRANSFER)
+        .zip(buffer.chunks_mut(dma::BUFFER_SIZE))
+    {
+        let mut channel = drive.channel.acquire();
+
+        dma_transfer(&mut channel, drive, lba, sectors.len(), true)?;
+
+        sectors.copy_from_slice(&channel.bus_master().buffer.as_slice()[..sectors.len()]);
+    }
+
+    Ok(())
+}
+
+fn ide_dma_write_sectors(drive: &Drive, lba: u64, buffer: &[u8]) -> Result<(), IdeError> {
+    for (lba, sectors) in (lba..)
+        .step_by(DMA_MAX_TRANSFER)
+        .zip(buffer.chunks(dma::BUFFER_SIZE))
+    {
+        let mut channel = drive.channel.acquire();
+
+        channel.bus_master().buffer.as_mut_slice()[..sectors.len()].copy_from_slice(sectors);
+
+        dma_transfer(&mut channel, drive, lba, sectors.len(), false)?;
+
+        flush_cache(&mut channel, drive)?;
+    }
+
+    Ok(())
+}
+
+/// Transfers `length` bytes at `lba` between the drive and the bus master's buffer, blocking
+/// until the drive interrupts.
+fn dma_transfer(
+    channel: &mut Channel,
+    drive: &Drive,
+    lba: u64,
+    length: usize,
+    read: bool,
+) -> Result<(), IdeError> {
+    let count = u16::try_from(length / SECTOR_SIZE).expect("Should fit in a u16");
+    let ext = needs_lba48(lba, count);
+
+    channel.bus_master().prepare(read);
+
+    select_sectors(channel, drive, count, lba, ext)?;
+
+    channel.send_command(match (read, ext) {
+        (true, false) => Command::ReadDma,
+        (true, true) => Command::ReadDmaExt,
+        (false, false) => Command::WrtieDma,
+        (false, true) => Command::WriteDmaExt,
+    });
+
+    channel.bus_master().start(read);
+
+    let status = channel.irq.wait(COMMAND_TIMEOUT);
+    // stopped even if the drive timed out so the controller doesn't keep writing to the buffer
+    let bus_master_status = channel.bus_master().stop();
+    let status = status?;
+
+    if bus_master_status.error() {
+        return Err(IdeError::DmaError);
+    }
+
+    check_status(channel, status)
+}
+
+/// Flushes the drive's write cache, the data isn't guaranteed to be on the disk until then.
+fn flush_cache(channel: &mut Channel, drive: &Drive) -> Result<(), IdeError> {
+    channel.send_command(if drive.lba48 {
+        Command::CacheFlushExt
+    } else {
+        Command::CacheFlush
+    });
+
+    wait_for_completion(channel)
+}
+
+/// Waits for the drive to interrupt at the end of a command.
+fn wait_for_completion(channel: &mut Channel) -> Result<(), IdeError> {
+    let status = channel.irq.wait(COMMAND_TIMEOUT)?;
+
+    check_status(channel, status)
+}
+
+/// Waits for the drive to interrupt when it's ready to transfer the next sector.
+fn wait_for_data(channel: &mut Channel) -> Result<(), IdeError> {
+    let status = channel.irq.wait(COMMAND_TIMEOUT)?;
+
+    check_status(channel, status)?;
+
+    if !status.data_request_ready() {
+        return Err(IdeError::DriveNotReadyForNewData);
     }
 
     Ok(())
 }
 
+/// Polls the drive until it's ready to transfer data, used where the drive doesn't interrupt.
 fn poll_ide(channel: &mut Channel) -> Result<(), IdeError> {
-    while channel.get_status_reg().busy() {}
+    // the status isn't valid until 400ns after a command is sent
+    channel.wait_400ns();
 
-    let status = channel.get_status_reg();
+    let status = wait_while_busy(channel)?;
+
+    check_status(channel, status)?;
+
+    if !status.data_request_ready() {
+        return Err(IdeError::DriveNotReadyForNewData);
+    }
+
+    Ok(())
+}
+
+/// Spins until the drive is no longer busy or [`BUSY_TIMEOUT`] passes.
+fn wait_while_busy(channel: &mut Channel) -> Result<Status, IdeError> {
+    let until = interrupts::now() + BUSY_TIMEOUT.get_nanoseconds();
+
+    loop {
+        let status = channel.get_status_reg();
+
+        if !status.busy() {
+            return Ok(status);
+        }
+
+        if interrupts::now() >= until {
+            return Err(IdeError::Timeout);
+        }
+
+        channel.wait_400ns();
+    }
+}
 
+fn check_status(channel: &mut Channel, status: Status) -> Result<(), IdeError> {
     if status.error() {
         return Err(IdeError::ControllerError(channel.get_err_reg()));
     }
@@ -282,9 +746,5 @@ fn poll_ide(channel: &mut Channel) -> Result<(), IdeError> {
         return Err(IdeError::DriveWriteFailed);
     }
 
-    if !status.data_request_ready() {
-        return Err(IdeError::DriveNotReadyForNewData);
-    }
-
     Ok(())
 }
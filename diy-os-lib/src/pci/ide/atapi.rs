@@ -0,0 +1,203 @@
+// reference docs at https://web.archive.org/web/20250302170528/https://wiki.osdev.org/ATAPI
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::ascii::Char;
+
+use super::structs::{Channel, Command, DriveType, HddSelect};
+use super::{
+    IdeError, parse_model, poll_ide, read_identification, wait_for_completion, wait_for_data,
+    wait_while_busy,
+};
+use crate::device_manager::{BlockDevice, BlockDeviceError, Device, check_access};
+use crate::multitasking::mutex::Mutex;
+
+/// The size of a sector on a CD.
+const SECTOR_SIZE: usize = 2048;
+
+/// The most bytes the drive sends each time it's ready, a whole number of sectors.
+const MAX_BYTE_COUNT: u16 = 0xF800;
+
+/// The signature an ATAPI drive leaves in the lba 1 and lba 2 registers after aborting
+/// IDENTIFY.
+pub(super) const SIGNATURE: (u8, u8) = (0x14, 0xEB);
+
+/// SCSI commands sent in a packet.
+#[repr(u8)]
+enum ScsiCommand {
+    ReadCapacity = 0x25,
+    Read = 0x28,
+}
+
+/// A CD drive, sectors are read with SCSI commands sent in packets.
+#[derive(Debug)]
+#[allow(unused)]
+pub(super) struct AtapiDrive {
+    channel: Arc<Mutex<Channel>>,
+    drive: DriveType,
+    model: [Char; 41],
+    /// 0 if there is no disc in the drive
+    sectors: u64,
+}
+
+/// Identifies the ATAPI drive on `channel_lock` and reads the size of the disc in it.
+pub(super) fn init_drive(
+    channel_lock: &Arc<Mutex<Channel>>,
+    drive_type: DriveType,
+) -> Option<AtapiDrive> {
+    let mut ident_space = {
+        let mut channel = channel_lock.acquire();
+
+        channel.send_command(Command::IdentifyPacket);
+        channel.wait_400ns();
+
+        let status = wait_while_busy(&mut channel).ok()?;
+
+        if status.error() || !status.data_request_ready() {
+            return None;
+        }
+
+        read_identification(&mut channel)
+    };
+
+    let mut drive = AtapiDrive {
+        channel: channel_lock.clone(),
+        drive: drive_type,
+        model: parse_model(&mut ident_space),
+        sectors: 0,
+    };
+
+    match drive.read_capacity() {
+        Ok(sectors) => drive.sectors = sectors,
+        Err(err) => log::info!("unable to read the capacity of the cd, assuming no disc, {err}"),
+    }
+
+    Some(drive)
+}
+
+impl AtapiDrive {
+    /// Returns the number of sectors on the disc.
+    fn read_capacity(&self) -> Result<u64, IdeError> {
+        let mut packet = [0u8; 12];
+        packet[0] = ScsiCommand::ReadCapacity as u8;
+
+        let mut response = [0u8; 8];
+        self.send_packet(packet, &mut response)?;
+
+        let last_lba = u32::from_be_bytes(response[..4].try_into().unwrap());
+        let block_size = u32::from_be_bytes(response[4..].try_into().unwrap());
+
+        if block_size as usize != SECTOR_SIZE {
+            log::warn!("cd reports sectors of {block_size} bytes, using {SECTOR_SIZE}");
+        }
+
+        Ok(u64::from(last_lba) + 1)
+    }
+
+    /// Sends a SCSI command in `packet`, reading the response into `buffer` which must be the
+    /// length of the response.
+    fn send_packet(&self, packet: [u8; 12], buffer: &mut [u8]) -> Result<(), IdeError> {
+        let mut channel = self.channel.acquire();
+
+        wait_while_busy(&mut channel)?;
+
+        channel.write_hdd_sel(HddSelect::new().with_child(self.drive == DriveType::Child));
+        channel.wait_400ns();
+
+        // pio, and the most bytes to send at once
+        let [byte_count_low, byte_count_high] = MAX_BYTE_COUNT.to_le_bytes();
+        channel.write_features(0);
+        channel.write_lba_1(byte_count_low);
+        channel.write_lba_2(byte_count_high);
+
+        channel.send_command(Command::Packet);
+
+        // the drive doesn't interrupt before taking the packet
+        poll_ide(&mut channel)?;
+
+        for word in packet.chunks_exact(2) {
+            let word = u16::from_le_bytes([word[0], word[1]]);
+
+            unsafe { channel.data_reg.write(word) };
+        }
+
+        let mut received = 0;
+
+        while received < buffer.len() {
+            wait_for_data(&mut channel)?;
+
+            let byte_count = usize::from(u16::from_le_bytes([
+                channel.read_lba_1(),
+                channel.read_lba_2(),
+            ]));
+
+            if byte_count == 0 {
+                return Err(IdeError::DriveNotReadyForNewData);
+            }
+
+            for index in (received..received + byte_count).step_by(2) {
+                let word = unsafe { channel.data_reg.read() }.to_le_bytes();
+
+                // anything past the end of the buffer is discarded
+                if let Some(bytes) = buffer.get_mut(index..index + 2) {
+                    bytes.copy_from_slice(&word);
+                }
+            }
+
+            received += byte_count;
+        }
+
+        wait_for_completion(&mut channel)
+    }
+}
+
+impl Device for AtapiDrive {
+    fn children(&self) -> Option<Box<dyn Iterator<Item = Arc<Mutex<dyn Device>>>>> {
+        None
+    }
+
+    fn as_block_device(&mut self) -> Option<&mut dyn BlockDevice> {
+        Some(self)
+    }
+}
+
+impl BlockDevice for AtapiDrive {
+    fn read_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let length = check_access(self.sectors, SECTOR_SIZE, lba, count, buffer.len())?;
+
+        if count == 0 {
+            return Ok(());
+        }
+
+        // the access is checked so the lba fits in 32 bits
+        let lba = u32::try_from(lba).expect("Should fit in a u32");
+
+        let mut packet = [0u8; 12];
+        packet[0] = ScsiCommand::Read as u8;
+        packet[2..6].copy_from_slice(&lba.to_be_bytes());
+        packet[7..9].copy_from_slice(&count.to_be_bytes());
+
+        Ok(self.send_packet(packet, &mut buffer[..length])?)
+    }
+
+    fn write_sectors(
+        &mut self,
+        _lba: u64,
+        _count: u16,
+        _buffer: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::ReadOnly)
+    }
+
+    fn total_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+}
@@ -0,0 +1,150 @@
+// reference docs at https://web.archive.org/web/20250121090104/https://wiki.osdev.org/ATA/ATAPI_using_DMA
+use bitfield_struct::bitfield;
+use x86_64::instructions::port::Port;
+use zerocopy::little_endian::{U16, U32};
+use zerocopy::{Immutable, IntoBytes, KnownLayout};
+
+use crate::memory::{BootInfoFrameAllocator, DmaBuffer};
+
+/// The size of the buffer transfers go through, a single region can cover up to 64 KiB.
+pub(super) const BUFFER_SIZE: usize = 0x1_0000;
+
+/// An entry of the physical region descriptor table, describes one region of memory the
+/// controller transfers to or from.
+#[derive(IntoBytes, KnownLayout, Immutable)]
+#[repr(C)]
+struct PhysicalRegionDescriptor {
+    address: U32,
+    /// 0 means 64 KiB
+    byte_count: U16,
+    flags: U16,
+}
+
+impl PhysicalRegionDescriptor {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 8);
+    };
+
+    /// Set on the last entry of the table
+    const END_OF_TABLE: u16 = 1 << 15;
+}
+
+#[bitfield(u8)]
+pub(super) struct BusMasterCommand {
+    #[bits(1)]
+    pub(super) start: bool,
+    #[bits(2)]
+    _reserved: (),
+    /// true if the controller writes to memory, a read from the drive
+    #[bits(1)]
+    pub(super) read: bool,
+    #[bits(4)]
+    _reserved2: (),
+}
+
+#[bitfield(u8)]
+pub(super) struct BusMasterStatus {
+    #[bits(1)]
+    pub(super) active: bool,
+    /// Cleared by writing 1
+    #[bits(1)]
+    pub(super) error: bool,
+    /// Cleared by writing 1
+    #[bits(1)]
+    pub(super) interrupt: bool,
+    #[bits(2)]
+    _reserved: (),
+    #[bits(1)]
+    pub(super) parent_dma_capable: bool,
+    #[bits(1)]
+    pub(super) child_dma_capable: bool,
+    #[bits(1)]
+    pub(super) simplex: bool,
+}
+
+/// The bus master registers of a channel and the memory its transfers go through.
+#[derive(Debug)]
+pub(super) struct BusMaster {
+    command: Port<u8>,
+    status: Port<u8>,
+    prdt_address: Port<u32>,
+    prdt: DmaBuffer,
+    pub(super) buffer: DmaBuffer,
+}
+
+impl BusMaster {
+    /// Allocates the descriptor table and buffer for the bus master registers at `base_port`,
+    /// returning [`None`] if there isn't enough memory below 4 GiB.
+    ///
+    /// # Safety
+    ///
+    /// `base_port` must be the bus master registers of a single channel, from BAR4 of the
+    /// controller.
+    pub(super) unsafe fn new(
+        base_port: u16,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Option<Self> {
+        let mut prdt = DmaBuffer::allocate(frame_allocator, 4096, 4096)?;
+        // aligned to its size so it never crosses a 64 KiB boundary
+        let buffer = DmaBuffer::allocate(frame_allocator, BUFFER_SIZE, BUFFER_SIZE as u64)?;
+
+        let descriptor = PhysicalRegionDescriptor {
+            address: U32::new(u32::try_from(buffer.phys_addr().as_u64()).ok()?),
+            byte_count: U16::new(0),
+            flags: U16::new(PhysicalRegionDescriptor::END_OF_TABLE),
+        };
+
+        descriptor.write_to_prefix(prdt.as_mut_slice()).ok()?;
+
+        Some(Self {
+            command: Port::new(base_port),
+            status: Port::new(base_port + 2),
+            prdt_address: Port::new(base_port + 4),
+            prdt,
+            buffer,
+        })
+    }
+
+    /// Points the controller at the descriptor table, sets the direction and clears the status
+    /// of the last transfer, the drive command is sent after this.
+    pub(super) fn prepare(&mut self, read: bool) {
+        let prdt = u32::try_from(self.prdt.phys_addr().as_u64()).expect("Should be below 4 GiB");
+
+        unsafe {
+            self.command.write(BusMasterCommand::new().into());
+            self.prdt_address.write(prdt);
+            self.command
+                .write(BusMasterCommand::new().with_read(read).into());
+        }
+
+        self.acknowledge();
+    }
+
+    pub(super) fn start(&mut self, read: bool) {
+        unsafe {
+            self.command.write(
+                BusMasterCommand::new()
+                    .with_read(read)
+                    .with_start(true)
+                    .into(),
+            );
+        };
+    }
+
+    /// Stops the transfer and returns the status, acknowledging the interrupt.
+    pub(super) fn stop(&mut self) -> BusMasterStatus {
+        unsafe { self.command.write(BusMasterCommand::new().into()) };
+
+        self.acknowledge()
+    }
+
+    /// Clears the error and interrupt bits, returning the status before they were cleared.
+    fn acknowledge(&mut self) -> BusMasterStatus {
+        let status = BusMasterStatus::from_bits(unsafe { self.status.read() });
+
+        // the other bits are kept, the drive capable bits are set by the firmware
+        unsafe { self.status.write(status.into()) };
+
+        status
+    }
+}
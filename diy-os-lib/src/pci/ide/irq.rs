@@ -0,0 +1,131 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU16, Ordering};
+use x86_64::instructions::port::{Port, PortReadOnly};
+
+use crate::interrupts::wait_for_interrupt;
+use crate::multitasking::SCHEDULER;
+use crate::pci::ide::IdeError;
+use crate::pci::ide::dma::BusMasterStatus;
+use crate::pci::ide::structs::Status;
+use crate::timer::Duration;
+
+/// The interrupt state of the primary channel.
+pub static PRIMARY_IRQ: ChannelIrq = ChannelIrq::new();
+/// The interrupt state of the secondary channel.
+pub static SECONDARY_IRQ: ChannelIrq = ChannelIrq::new();
+
+/// Handles an interrupt on `irq`, called from the interrupt handler.
+///
+/// In pci native mode both channels can share the line, so every channel on it is checked.
+pub fn handle(irq: u8) {
+    for channel in [&PRIMARY_IRQ, &SECONDARY_IRQ] {
+        if channel.irq() == Some(irq) {
+            channel.handle();
+        }
+    }
+}
+
+/// The interrupt state of a channel.
+///
+/// This lives outside of the channel's lock since the task waiting for the interrupt holds it,
+/// and a handler can't block on a [`Mutex`](crate::multitasking::mutex::Mutex). The handler only
+/// touches the status register to acknowledge the drive.
+#[derive(Debug)]
+pub struct ChannelIrq {
+    /// The line of the PIC, 14 or 15 in compatibility mode and the pci interrupt line in
+    /// native mode, `u8::MAX` until the channel is set up
+    irq: AtomicU8,
+    /// The io port of the status register, 0 until the channel is set up
+    status_port: AtomicU16,
+    /// The io port of the bus master status register, 0 if the controller isn't a bus master
+    bus_master_status_port: AtomicU16,
+    fired: AtomicBool,
+    /// The status register read by the handler
+    status: AtomicU8,
+}
+
+impl ChannelIrq {
+    const fn new() -> Self {
+        Self {
+            irq: AtomicU8::new(u8::MAX),
+            status_port: AtomicU16::new(0),
+            bus_master_status_port: AtomicU16::new(0),
+            fired: AtomicBool::new(false),
+            status: AtomicU8::new(0),
+        }
+    }
+
+    pub(super) fn set_ports(&self, irq: u8, status_port: u16) {
+        self.irq.store(irq, Ordering::Relaxed);
+        self.status_port.store(status_port, Ordering::Relaxed);
+    }
+
+    pub(super) fn set_bus_master_status_port(&self, port: u16) {
+        self.bus_master_status_port.store(port, Ordering::Relaxed);
+    }
+
+    fn irq(&self) -> Option<u8> {
+        let irq = self.irq.load(Ordering::Relaxed);
+
+        (irq != u8::MAX).then_some(irq)
+    }
+
+    /// Forgets interrupts from earlier commands, called before sending a command.
+    pub(super) fn clear(&self) {
+        self.fired.store(false, Ordering::Release);
+    }
+
+    /// Acknowledges the drive and wakes up the task waiting on this channel if it interrupted.
+    fn handle(&self) {
+        let port = self.status_port.load(Ordering::Relaxed);
+
+        let Some(irq) = self.irq().filter(|_| port != 0) else {
+            return;
+        };
+
+        let bus_master_port = self.bus_master_status_port.load(Ordering::Relaxed);
+
+        if bus_master_port != 0 {
+            let mut bus_master_port = Port::<u8>::new(bus_master_port);
+            let bus_master_status = BusMasterStatus::from_bits(unsafe { bus_master_port.read() });
+
+            if !bus_master_status.interrupt() {
+                return;
+            }
+
+            // the interrupt bit is cleared by writing 1, the error bit is left for the transfer
+            unsafe { bus_master_port.write(bus_master_status.with_error(false).into()) };
+        }
+
+        // reading the status register deasserts the interrupt
+        let status = Status::from_bits(unsafe { PortReadOnly::<u8>::new(port).read() });
+
+        // without a bus master a busy drive is the only sign the other channel on a shared line
+        // interrupted
+        if status.busy() {
+            return;
+        }
+
+        self.status.store(status.into_bits(), Ordering::Relaxed);
+        self.fired.store(true, Ordering::Release);
+
+        SCHEDULER.with_mut_ref(|scheduler| scheduler.wake_up_interrupt_waiters(irq));
+    }
+
+    /// Waits up to `timeout` for the channel to interrupt, returning the status the handler
+    /// read.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`IdeError::Timeout`] if the drive didn't interrupt in time.
+    pub(super) fn wait(&self, timeout: Duration) -> Result<Status, IdeError> {
+        let irq = self
+            .irq()
+            .expect("Should be set up before sending commands");
+
+        if wait_for_interrupt(irq, &self.fired, timeout) {
+            Ok(Status::from_bits(self.status.load(Ordering::Relaxed)))
+        } else {
+            Err(IdeError::Timeout)
+        }
+    }
+}
@@ -3,6 +3,8 @@ use bitfield_struct::bitfield;
 use core::ascii::Char;
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 
+use super::dma::BusMaster;
+use super::irq::ChannelIrq;
 use crate::multitasking::mutex::Mutex;
 
 //
@@ -244,6 +246,10 @@ pub(super) struct Drive {
     pub(super) caps: u16,
     pub(super) command_set: u32,
     pub(super) size: u64,
+    /// The drive supports 48-bit addressing and the EXT commands
+    pub(super) lba48: bool,
+    /// Transfers use the bus master of the channel instead of PIO
+    pub(super) dma: bool,
     pub(super) model: [Char; 41],
 }
 
@@ -281,12 +287,24 @@ pub(super) struct Channel {
     pub(super) alt_status_reg: PortReadOnly<u8>,
     pub(super) ctrl_reg: PortWriteOnly<u8>,
     // dev_addr unused
+    pub(super) irq: &'static ChannelIrq,
+    /// Set if the controller supports bus mastering, transfers use DMA through it
+    pub(super) bus_master: Option<BusMaster>,
 }
 
 #[allow(unused)]
 impl Channel {
-    /// Caller must make sure that this port is the base io port for the primary channel
-    pub(super) const unsafe fn new(base_port: u16, ctrl_port: u16) -> Self {
+    /// Caller must make sure that `base_port` and `ctrl_port` are the base of the command and
+    /// control blocks of a single channel, the control register is 2 past `ctrl_port` like in the
+    /// BARs, and that the channel interrupts on `irq_line`
+    pub(super) unsafe fn new(
+        base_port: u16,
+        ctrl_port: u16,
+        irq_line: u8,
+        irq: &'static ChannelIrq,
+    ) -> Self {
+        irq.set_ports(irq_line, base_port + 7);
+
         Self {
             data_reg: Port::new(base_port),
             err_reg: PortReadOnly::new(base_port + 1),
@@ -300,15 +318,33 @@ impl Channel {
             status_reg: PortReadOnly::new(base_port + 7),
             alt_status_reg: PortReadOnly::new(ctrl_port + 2),
             ctrl_reg: PortWriteOnly::new(ctrl_port + 2),
+            irq,
+            bus_master: None,
         }
     }
 
+    /// # Panics
+    ///
+    /// Panics if the controller doesn't support bus mastering.
+    pub(super) const fn bus_master(&mut self) -> &mut BusMaster {
+        self.bus_master
+            .as_mut()
+            .expect("Only drives on a channel with a bus master use dma")
+    }
+
     pub(super) fn get_err_reg(&mut self) -> ErrRaw {
         let bits = unsafe { self.err_reg.read() };
 
         ErrRaw::from_bits(bits)
     }
 
+    /// Each read of the alternate status register takes 100ns.
+    pub(super) fn wait_400ns(&mut self) {
+        for _ in 0..4 {
+            unsafe { self.alt_status_reg.read() };
+        }
+    }
+
     pub(super) fn get_status_reg(&mut self) -> Status {
         let bits = unsafe { self.status_reg.read() };
 
@@ -323,6 +359,24 @@ impl Channel {
         unsafe { self.hdd_select.write(value.into()) };
     }
 
+    pub(super) fn write_features(&mut self, value: u8) {
+        unsafe { self.feat_reg.write(value) };
+    }
+
+    pub(super) fn read_lba_1(&mut self) -> u8 {
+        unsafe { self.lba_1.read() }
+    }
+
+    pub(super) fn read_lba_2(&mut self) -> u8 {
+        unsafe { self.lba_2.read() }
+    }
+
+    /// The lba 1 and lba 2 registers after a reset or aborted IDENTIFY, identifies the type of
+    /// the drive.
+    pub(super) fn get_signature(&mut self) -> (u8, u8) {
+        (self.read_lba_1(), self.read_lba_2())
+    }
+
     pub(super) fn write_lba_0(&mut self, value: u8) {
         unsafe { self.lba_0.write(value) };
     }
@@ -339,7 +393,10 @@ impl Channel {
         unsafe { self.set_count_0.write(value) };
     }
 
+    /// Sends `cmd`, forgetting any interrupt from an earlier command.
     pub(super) fn send_command(&mut self, cmd: Command) {
+        self.irq.clear();
+
         unsafe { self.commmand_reg.write(cmd as u8) };
     }
 
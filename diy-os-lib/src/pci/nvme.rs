@@ -0,0 +1,683 @@
+// reference docs at https://wiki.osdev.org/NVMe
+// and the NVM Express base specification 1.4
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ascii::Char;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spinlock::Spinlock;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, OffsetPageTable, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use zerocopy::little_endian::{U16, U64};
+use zerocopy::{FromBytes, IntoBytes};
+
+use self::structs::{
+    AdminCommand, CompletionEntry, Configuration, Doorbell, IdentifyStructure, IoCommand, Register,
+    Registers, SubmissionEntry,
+};
+use crate::device_manager::driver::{PciDriver, PciMatch};
+use crate::device_manager::{BlockDevice, BlockDeviceError, Device, DeviceManager, check_access};
+use crate::interrupts::{self, wait_for_interrupt};
+use crate::memory::{self, BootInfoFrameAllocator, DmaBuffer};
+use crate::multitasking::SCHEDULER;
+use crate::multitasking::mutex::Mutex;
+use crate::pci::{MassStorageSubclass, PciDevice};
+use crate::timer::{Duration, Seconds, sleep};
+
+mod structs;
+
+/// Binds to nvme controllers.
+pub static DRIVER: PciDriver = PciDriver {
+    name: "nvme",
+    matches: &[PciMatch::mass_storage(
+        MassStorageSubclass::NonVolatileMemory,
+        None,
+    )],
+    probe,
+};
+
+/// The page size the controller is configured with, PRP entries point to pages this big.
+const PAGE_SIZE: usize = 4096;
+
+/// How long the controller has to complete a command before it's given up on.
+const COMMAND_TIMEOUT: Duration = Seconds(5).into();
+
+/// The number of entries in every queue, a queue of either kind fits in a page.
+const QUEUE_ENTRIES: u16 = 64;
+
+/// The size of the buffer transfers go through.
+const BUFFER_SIZE: usize = 0x2_0000;
+
+/// Every controller, for the interrupt handler.
+static CONTROLLERS: Spinlock<Vec<Arc<ControllerIrq>>> = Spinlock::new(Vec::new());
+
+#[derive(thiserror::Error, Debug)]
+pub enum NvmeCreationError {
+    #[error("BAR 0 of the controller isn't memory mapped")]
+    InvalidBar,
+    #[error("The controller's interrupt isn't routed, line `{0}`")]
+    NoInterruptLine(u8),
+    #[error("Failed to map the controller's registers, `{0:?}`")]
+    MapFailed(MapToError<Size4KiB>),
+    #[error("The controller doesn't support 4 KiB pages")]
+    UnsupportedPageSize,
+    #[error("Setting up the controller failed, `{0}`")]
+    ControllerError(#[from] NvmeError),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum NvmeError {
+    #[error("The controller didn't respond in time")]
+    Timeout,
+    #[error("The controller reported a fatal error")]
+    Fatal,
+    #[error("The command failed with status `{0:#x}`")]
+    CommandFailed(u16),
+    #[error("Unable to allocate dma memory below 4 GiB")]
+    OutOfMemory,
+}
+
+#[derive(Debug)]
+pub struct NvmeController {
+    namespaces: Vec<Arc<Mutex<dyn BlockDevice>>>,
+}
+
+impl Device for NvmeController {
+    fn children(&self) -> Option<Box<dyn Iterator<Item = Arc<Mutex<dyn Device>>>>> {
+        Some(Box::new(
+            self.namespaces
+                .clone()
+                .into_iter()
+                .map(|x| x as Arc<Mutex<dyn Device>>),
+        ))
+    }
+}
+
+/// The interrupt state of a controller.
+///
+/// This lives outside of the controller's lock since the task waiting for the interrupt holds it.
+#[derive(Debug)]
+struct ControllerIrq {
+    irq: u8,
+    registers: Registers,
+    fired: AtomicBool,
+}
+
+impl ControllerIrq {
+    /// Masks the interrupt and wakes up the task waiting on the controller, the interrupt stays
+    /// asserted until the completions are processed.
+    fn handle(&self) {
+        self.registers.write(Register::InterruptMaskSet, 1);
+        self.fired.store(true, Ordering::Release);
+
+        SCHEDULER.with_mut_ref(|scheduler| scheduler.wake_up_interrupt_waiters(self.irq));
+    }
+
+    fn unmask(&self) {
+        self.registers.write(Register::InterruptMaskClear, 1);
+    }
+}
+
+/// Handles an interrupt on `irq`, called from the interrupt handler.
+pub fn handle_irq(irq: u8) {
+    CONTROLLERS.with_ref(|controllers| {
+        controllers
+            .iter()
+            .filter(|controller| controller.irq == irq)
+            .for_each(|controller| controller.handle());
+    });
+}
+
+/// A submission queue and the completion queue it posts to.
+#[derive(Debug)]
+struct QueuePair {
+    submissions: DmaBuffer,
+    completions: DmaBuffer,
+    submission_doorbell: Doorbell,
+    completion_doorbell: Doorbell,
+    tail: u16,
+    head: u16,
+    /// The phase of completions that haven't been processed yet
+    phase: bool,
+}
+
+impl QueuePair {
+    /// Allocates the queues with the id `id`, `doorbells` is the start of the doorbell registers
+    /// and `stride` the distance between them.
+    fn new(
+        id: u16,
+        doorbells: VirtAddr,
+        stride: usize,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Result<Self, NvmeError> {
+        let submissions = DmaBuffer::allocate(frame_allocator, PAGE_SIZE, PAGE_SIZE as u64)
+            .ok_or(NvmeError::OutOfMemory)?;
+        let completions = DmaBuffer::allocate(frame_allocator, PAGE_SIZE, PAGE_SIZE as u64)
+            .ok_or(NvmeError::OutOfMemory)?;
+
+        let doorbell = |index: usize| unsafe { Doorbell::new(doorbells + (index * stride) as u64) };
+
+        Ok(Self {
+            submissions,
+            completions,
+            submission_doorbell: doorbell(usize::from(id) * 2),
+            completion_doorbell: doorbell(usize::from(id) * 2 + 1),
+            tail: 0,
+            head: 0,
+            phase: true,
+        })
+    }
+
+    fn submit(&mut self, entry: &SubmissionEntry) {
+        let offset = usize::from(self.tail) * size_of::<SubmissionEntry>();
+
+        entry
+            .write_to_prefix(&mut self.submissions.as_mut_slice()[offset..])
+            .expect("Should fit in the queue");
+
+        self.tail = (self.tail + 1) % QUEUE_ENTRIES;
+        self.submission_doorbell.ring(self.tail);
+    }
+
+    /// Returns the next completion if the controller posted one.
+    fn peek(&self) -> Option<CompletionEntry> {
+        let offset = usize::from(self.head) * size_of::<CompletionEntry>();
+
+        let (completion, _) =
+            CompletionEntry::read_from_prefix(&self.completions.as_slice()[offset..]).ok()?;
+
+        (completion.phase() == self.phase).then_some(completion)
+    }
+
+    /// Takes the next completion if the controller posted one, letting the controller reuse the
+    /// entry.
+    fn pop(&mut self) -> Option<CompletionEntry> {
+        let completion = self.peek()?;
+
+        self.head = (self.head + 1) % QUEUE_ENTRIES;
+
+        if self.head == 0 {
+            self.phase = !self.phase;
+        }
+
+        self.completion_doorbell.ring(self.head);
+
+        Some(completion)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Queue {
+    Admin,
+    Io,
+}
+
+/// The state shared by every namespace of a controller, commands go through a single io queue.
+#[derive(Debug)]
+struct Controller {
+    irq: Arc<ControllerIrq>,
+    admin: QueuePair,
+    io: QueuePair,
+    next_command_id: u16,
+    /// The buffer transfers go through
+    buffer: DmaBuffer,
+    /// The pages of the buffer after the first, for transfers over two pages
+    prp_list: DmaBuffer,
+    /// The most bytes a single command can transfer
+    max_transfer: usize,
+}
+
+impl Controller {
+    /// Submits `entry` and waits for the controller to complete it.
+    fn execute(
+        &mut self,
+        queue: Queue,
+        mut entry: SubmissionEntry,
+    ) -> Result<CompletionEntry, NvmeError> {
+        let command_id = self.next_command_id;
+        self.next_command_id = self.next_command_id.wrapping_add(1);
+
+        entry.command_id = U16::new(command_id);
+
+        let queue = match queue {
+            Queue::Admin => &mut self.admin,
+            Queue::Io => &mut self.io,
+        };
+
+        self.irq.fired.store(false, Ordering::Release);
+        queue.submit(&entry);
+
+        loop {
+            while let Some(completion) = queue.pop() {
+                // commands are run one at a time, anything else is left over from a timeout
+                if completion.command_id.get() != command_id {
+                    log::warn!(
+                        "discarding completion of nvme command {}",
+                        completion.command_id
+                    );
+                    continue;
+                }
+
+                self.irq.unmask();
+
+                return match completion.status_code() {
+                    0 => Ok(completion),
+                    status => Err(NvmeError::CommandFailed(status)),
+                };
+            }
+
+            // masked by the handler until the completions are processed
+            self.irq.unmask();
+
+            if !wait_for_interrupt(self.irq.irq, &self.irq.fired, COMMAND_TIMEOUT)
+                && queue.peek().is_none()
+            {
+                return Err(NvmeError::Timeout);
+            }
+        }
+    }
+
+    /// Points `entry` at the first `length` bytes of the buffer, listing the pages after the
+    /// second in the PRP list.
+    fn set_data(&mut self, mut entry: SubmissionEntry, length: usize) -> SubmissionEntry {
+        let buffer = self.buffer.phys_addr().as_u64();
+        let pages = length.div_ceil(PAGE_SIZE);
+
+        entry.prp1 = U64::new(buffer);
+        entry.prp2 = U64::new(match pages {
+            0 | 1 => 0,
+            2 => buffer + PAGE_SIZE as u64,
+            _ => {
+                let list = self.prp_list.as_mut_slice();
+
+                for (page, entry) in (1..pages).zip(list.chunks_exact_mut(size_of::<u64>())) {
+                    entry.copy_from_slice(&(buffer + (page * PAGE_SIZE) as u64).to_le_bytes());
+                }
+
+                self.prp_list.phys_addr().as_u64()
+            }
+        });
+
+        entry
+    }
+
+    /// Reads an identify structure into the buffer, returning the page it's in.
+    fn identify(
+        &mut self,
+        structure: IdentifyStructure,
+        namespace_id: u32,
+    ) -> Result<&[u8], NvmeError> {
+        let entry = SubmissionEntry::new(AdminCommand::Identify as u8, namespace_id)
+            .with_dword(10, structure as u32);
+        let entry = self.set_data(entry, PAGE_SIZE);
+
+        self.execute(Queue::Admin, entry)?;
+
+        Ok(&self.buffer.as_slice()[..PAGE_SIZE])
+    }
+
+    /// Creates the io queue pair on the controller, its completions interrupt on vector 0.
+    fn create_io_queues(&mut self) -> Result<(), NvmeError> {
+        // the queue id and the size minus one
+        let queue = u32::from(QUEUE_ENTRIES - 1) << 16 | 1;
+
+        let mut entry = SubmissionEntry::new(AdminCommand::CreateCompletionQueue as u8, 0)
+            .with_dword(10, queue)
+            // interrupts enabled and physically contiguous
+            .with_dword(11, 1 << 1 | 1);
+        entry.prp1 = U64::new(self.io.completions.phys_addr().as_u64());
+
+        self.execute(Queue::Admin, entry)?;
+
+        let mut entry = SubmissionEntry::new(AdminCommand::CreateSubmissionQueue as u8, 0)
+            .with_dword(10, queue)
+            // completes to queue 1 and physically contiguous
+            .with_dword(11, 1 << 16 | 1);
+        entry.prp1 = U64::new(self.io.submissions.phys_addr().as_u64());
+
+        self.execute(Queue::Admin, entry)?;
+
+        Ok(())
+    }
+
+    /// Identifies every active namespace, returning the id, number of sectors and sector size of
+    /// the usable ones.
+    fn active_namespaces(&mut self) -> Result<Vec<(u32, u64, usize)>, NvmeError> {
+        // a list of ids ending with 0
+        let namespace_ids: Vec<u32> = self
+            .identify(IdentifyStructure::ActiveNamespaces, 0)?
+            .chunks_exact(size_of::<u32>())
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+            .take_while(|&id| id != 0)
+            .collect();
+
+        let mut namespaces = Vec::new();
+
+        for id in namespace_ids {
+            let identification = self.identify(IdentifyStructure::Namespace, id)?;
+
+            // bytes 0-7
+            let sectors = u64::read_from_prefix(identification).unwrap().0;
+
+            // the low 4 bits of byte 26 select one of the formats from byte 128, the sector size is
+            // a power of 2 in byte 2 of the format
+            let format = 128 + usize::from(identification[26] & 0xF) * 4;
+            let lbads = identification[format + 2];
+
+            // 0 means the format isn't available, sectors are 512 bytes to 4 KiB
+            if !(9..=12).contains(&lbads) {
+                log::warn!("skipping nvme namespace {id} with a sector size shift of {lbads}");
+                continue;
+            }
+
+            let sector_size = 1usize << lbads;
+
+            if sector_size > self.max_transfer {
+                log::warn!("skipping nvme namespace {id} with {sector_size} byte sectors");
+                continue;
+            }
+
+            log::info!("nvme namespace {id} with {sectors} sectors of {sector_size} bytes");
+
+            namespaces.push((id, sectors, sector_size));
+        }
+
+        Ok(namespaces)
+    }
+
+    /// Reads or writes `count` sectors at `lba` through the first `length` bytes of the buffer.
+    fn transfer(
+        &mut self,
+        namespace_id: u32,
+        command: IoCommand,
+        lba: u64,
+        count: u16,
+        length: usize,
+    ) -> Result<(), NvmeError> {
+        let lba = lba.to_le_bytes();
+        let [lba_low, lba_high] = [&lba[..4], &lba[4..]]
+            .map(|half| u32::from_le_bytes(half.try_into().expect("Should be 4 bytes")));
+
+        let entry = SubmissionEntry::new(command as u8, namespace_id)
+            .with_dword(10, lba_low)
+            .with_dword(11, lba_high)
+            // the number of sectors minus one
+            .with_dword(12, u32::from(count - 1));
+        let entry = self.set_data(entry, length);
+
+        self.execute(Queue::Io, entry)?;
+
+        Ok(())
+    }
+}
+
+/// A namespace of an nvme controller, every namespace is its own disk.
+#[derive(Debug)]
+pub struct NvmeNamespace {
+    controller: Arc<Mutex<Controller>>,
+    id: u32,
+    sectors: u64,
+    sector_size: usize,
+}
+
+fn probe(
+    pci_device: PciDevice,
+    device_manager: &mut DeviceManager,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), anyhow::Error> {
+    let controller = create_nvme_controller(pci_device, device_manager, mapper, frame_allocator)?;
+    device_manager.register_device(Arc::new(Mutex::new(controller)));
+
+    Ok(())
+}
+
+/// Resets and enables the controller, creates the io queues and registers an [`NvmeNamespace`]
+/// for every active namespace.
+///
+/// # Errors
+/// Will return [`NvmeCreationError`] if BAR 0 or the interrupt line are invalid, mapping the
+/// registers fails, or the controller fails to set up
+pub fn create_nvme_controller(
+    pci_device: PciDevice,
+    device_manager: &mut DeviceManager,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<NvmeController, NvmeCreationError> {
+    let PciDevice { address, info } = pci_device;
+
+    let bar = info
+        .header
+        .memory_bar_address(0)
+        .ok_or(NvmeCreationError::InvalidBar)?;
+
+    let irq = info.header.interrupt_line();
+
+    // the PIC only has 16 lines, 0xFF means the pin isn't routed
+    if irq >= 16 {
+        return Err(NvmeCreationError::NoInterruptLine(irq));
+    }
+
+    // the controller reads the queues and transfers data itself
+    address.enable_bus_master();
+
+    let base =
+        unsafe { memory::map_mmio(mapper, frame_allocator, PhysAddr::new(bar), Registers::SIZE) }
+            .map_err(NvmeCreationError::MapFailed)?;
+
+    // SAFETY: just mapped
+    let registers = unsafe { Registers::new(base) };
+    let capabilities = registers.capabilities();
+
+    if capabilities.min_page_size() != 0 {
+        return Err(NvmeCreationError::UnsupportedPageSize);
+    }
+
+    // the doorbells of the admin and io queues
+    let stride = 4 << capabilities.doorbell_stride();
+    let doorbells = unsafe {
+        memory::map_mmio(
+            mapper,
+            frame_allocator,
+            PhysAddr::new(bar + Registers::SIZE as u64),
+            4 * stride,
+        )
+    }
+    .map_err(NvmeCreationError::MapFailed)?;
+
+    let ready_timeout = u64::from(capabilities.timeout().max(1)) * 500;
+
+    registers.write_configuration(registers.configuration().with_enable(false));
+    wait_ready(registers, false, ready_timeout)?;
+
+    let admin = QueuePair::new(0, doorbells, stride, frame_allocator)?;
+    let io = QueuePair::new(1, doorbells, stride, frame_allocator)?;
+
+    // the completion and submission queue sizes minus one
+    registers.write(
+        Register::AdminQueueAttributes,
+        u32::from(QUEUE_ENTRIES - 1) << 16 | u32::from(QUEUE_ENTRIES - 1),
+    );
+    registers.write_u64(
+        Register::AdminSubmissionQueue,
+        admin.submissions.phys_addr().as_u64(),
+    );
+    registers.write_u64(
+        Register::AdminCompletionQueue,
+        admin.completions.phys_addr().as_u64(),
+    );
+
+    registers.write_configuration(
+        Configuration::new()
+            .with_command_set(0)
+            .with_page_size(0)
+            .with_submission_entry_size(SubmissionEntry::SIZE_SHIFT)
+            .with_completion_entry_size(CompletionEntry::SIZE_SHIFT)
+            .with_enable(true),
+    );
+    wait_ready(registers, true, ready_timeout)?;
+
+    let controller_irq = Arc::new(ControllerIrq {
+        irq,
+        registers,
+        fired: AtomicBool::new(false),
+    });
+
+    CONTROLLERS.with_mut_ref(|controllers| controllers.push(controller_irq.clone()));
+    interrupts::unmask_irq(irq);
+
+    let mut controller = Controller {
+        irq: controller_irq,
+        admin,
+        io,
+        next_command_id: 0,
+        buffer: DmaBuffer::allocate(frame_allocator, BUFFER_SIZE, PAGE_SIZE as u64)
+            .ok_or(NvmeError::OutOfMemory)?,
+        prp_list: DmaBuffer::allocate(frame_allocator, PAGE_SIZE, PAGE_SIZE as u64)
+            .ok_or(NvmeError::OutOfMemory)?,
+        max_transfer: BUFFER_SIZE,
+    };
+
+    let identification = controller.identify(IdentifyStructure::Controller, 0)?;
+
+    // bytes 24-63, padded with spaces
+    let model: [Char; 40] = core::array::from_fn(|index| {
+        Char::from_u8(identification[24 + index]).unwrap_or(Char::QuotationMark)
+    });
+
+    // byte 77
+    controller.max_transfer = max_transfer(identification[77]);
+
+    log::info!("nvme controller {}", model.as_str().trim_end_matches(' '));
+
+    controller.create_io_queues()?;
+
+    let namespaces = controller.active_namespaces()?;
+
+    let controller = Arc::new(Mutex::new(controller));
+
+    let namespaces = namespaces
+        .into_iter()
+        .map(|(id, sectors, sector_size)| {
+            let namespace = Arc::new(Mutex::new(NvmeNamespace {
+                controller: controller.clone(),
+                id,
+                sectors,
+                sector_size,
+            }));
+
+            device_manager.register_device(namespace.clone() as Arc<Mutex<dyn Device>>);
+            device_manager.register_block_device(namespace.clone() as Arc<Mutex<dyn BlockDevice>>);
+
+            namespace as Arc<Mutex<dyn BlockDevice>>
+        })
+        .collect();
+
+    Ok(NvmeController { namespaces })
+}
+
+/// The largest transfer the buffer and the controller allow, `mdts` is the largest transfer in
+/// minimum pages as a power of 2, 0 is unlimited.
+fn max_transfer(mdts: u8) -> usize {
+    // a limit too big to compute is bigger then the buffer anyway
+    1usize
+        .checked_shl(u32::from(mdts))
+        .filter(|_| mdts != 0)
+        .and_then(|pages| PAGE_SIZE.checked_mul(pages))
+        .map_or(BUFFER_SIZE, |max_transfer| BUFFER_SIZE.min(max_transfer))
+}
+
+/// Polls the status until the controller is `ready` or `timeout_ms` passes.
+fn wait_ready(registers: Registers, ready: bool, timeout_ms: u64) -> Result<(), NvmeError> {
+    for _ in 0..=timeout_ms {
+        let status = registers.status();
+
+        if status.fatal() {
+            return Err(NvmeError::Fatal);
+        }
+
+        if status.ready() == ready {
+            return Ok(());
+        }
+
+        sleep(1);
+    }
+
+    Err(NvmeError::Timeout)
+}
+
+impl Device for NvmeNamespace {
+    fn children(&self) -> Option<Box<dyn Iterator<Item = Arc<Mutex<dyn Device>>>>> {
+        None
+    }
+
+    fn as_block_device(&mut self) -> Option<&mut dyn BlockDevice> {
+        Some(self)
+    }
+}
+
+impl BlockDevice for NvmeNamespace {
+    fn read_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let length = check_access(self.sectors, self.sector_size, lba, count, buffer.len())?;
+        let mut controller = self.controller.acquire();
+        let max_sectors = controller.max_transfer / self.sector_size;
+
+        for (lba, chunk) in (lba..)
+            .step_by(max_sectors)
+            .zip(buffer[..length].chunks_mut(max_sectors * self.sector_size))
+        {
+            let count = u16::try_from(chunk.len() / self.sector_size).expect("Should fit in a u16");
+
+            controller.transfer(self.id, IoCommand::Read, lba, count, chunk.len())?;
+
+            chunk.copy_from_slice(&controller.buffer.as_slice()[..chunk.len()]);
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        let length = check_access(self.sectors, self.sector_size, lba, count, buffer.len())?;
+        let mut controller = self.controller.acquire();
+        let max_sectors = controller.max_transfer / self.sector_size;
+
+        for (lba, chunk) in (lba..)
+            .step_by(max_sectors)
+            .zip(buffer[..length].chunks(max_sectors * self.sector_size))
+        {
+            let count = u16::try_from(chunk.len() / self.sector_size).expect("Should fit in a u16");
+
+            controller.buffer.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+
+            controller.transfer(self.id, IoCommand::Write, lba, count, chunk.len())?;
+        }
+
+        if length != 0 {
+            controller.execute(
+                Queue::Io,
+                SubmissionEntry::new(IoCommand::Flush as u8, self.id),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn total_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+}
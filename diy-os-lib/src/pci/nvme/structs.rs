@@ -0,0 +1,276 @@
+use bitfield_struct::bitfield;
+use x86_64::VirtAddr;
+use zerocopy::little_endian::{U16, U32, U64};
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
+
+/// The controller registers, at the start of BAR0.
+#[derive(Debug, Clone, Copy)]
+#[repr(usize)]
+pub(super) enum Register {
+    Capabilities = 0x00,
+    InterruptMaskSet = 0x0C,
+    InterruptMaskClear = 0x10,
+    Configuration = 0x14,
+    Status = 0x1C,
+    AdminQueueAttributes = 0x24,
+    AdminSubmissionQueue = 0x28,
+    AdminCompletionQueue = 0x30,
+}
+
+/// The memory mapped registers of a controller.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Registers {
+    base: VirtAddr,
+}
+
+impl Registers {
+    /// The number of bytes mapped, the doorbells start after this.
+    pub(super) const SIZE: usize = 0x1000;
+
+    /// # Safety
+    ///
+    /// `base` must be where BAR0 of a controller is mapped, uncached and [`Self::SIZE`] bytes
+    /// long.
+    pub(super) const unsafe fn new(base: VirtAddr) -> Self {
+        Self { base }
+    }
+
+    pub(super) fn read(self, register: Register) -> u32 {
+        unsafe {
+            (self.base + register as u64)
+                .as_ptr::<u32>()
+                .read_volatile()
+        }
+    }
+
+    pub(super) fn write(self, register: Register, value: u32) {
+        unsafe {
+            (self.base + register as u64)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value);
+        };
+    }
+
+    pub(super) fn read_u64(self, register: Register) -> u64 {
+        unsafe {
+            (self.base + register as u64)
+                .as_ptr::<u64>()
+                .read_volatile()
+        }
+    }
+
+    pub(super) fn write_u64(self, register: Register, value: u64) {
+        unsafe {
+            (self.base + register as u64)
+                .as_mut_ptr::<u64>()
+                .write_volatile(value);
+        };
+    }
+
+    pub(super) fn capabilities(self) -> Capabilities {
+        Capabilities::from_bits(self.read_u64(Register::Capabilities))
+    }
+
+    pub(super) fn configuration(self) -> Configuration {
+        Configuration::from_bits(self.read(Register::Configuration))
+    }
+
+    pub(super) fn write_configuration(self, configuration: Configuration) {
+        self.write(Register::Configuration, configuration.into_bits());
+    }
+
+    pub(super) fn status(self) -> ControllerStatus {
+        ControllerStatus::from_bits(self.read(Register::Status))
+    }
+}
+
+/// The doorbell of a queue, written to tell the controller about new submissions or processed
+/// completions.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Doorbell {
+    address: VirtAddr,
+}
+
+impl Doorbell {
+    /// # Safety
+    ///
+    /// `address` must be a mapped doorbell register.
+    pub(super) const unsafe fn new(address: VirtAddr) -> Self {
+        Self { address }
+    }
+
+    pub(super) fn ring(self, value: u16) {
+        unsafe {
+            self.address
+                .as_mut_ptr::<u32>()
+                .write_volatile(u32::from(value));
+        };
+    }
+}
+
+#[bitfield(u64)]
+pub(super) struct Capabilities {
+    /// The most entries a queue can have minus one
+    #[bits(16)]
+    pub(super) max_queue_entries: u16,
+    #[bits(1)]
+    pub(super) contiguous_queues_required: bool,
+    #[bits(2)]
+    _arbitration: u8,
+    #[bits(5)]
+    _reserved: (),
+    /// How long the controller can take to become ready, in 500 ms units
+    #[bits(8)]
+    pub(super) timeout: u8,
+    /// The stride between doorbells is `4 << doorbell_stride` bytes
+    #[bits(4)]
+    pub(super) doorbell_stride: u8,
+    #[bits(1)]
+    _subsystem_reset: bool,
+    #[bits(8)]
+    pub(super) command_sets: u8,
+    #[bits(3)]
+    _reserved2: (),
+    /// The smallest page size is `4096 << min_page_size` bytes
+    #[bits(4)]
+    pub(super) min_page_size: u8,
+    #[bits(4)]
+    _max_page_size: u8,
+    #[bits(8)]
+    _reserved3: (),
+}
+
+#[bitfield(u32)]
+pub(super) struct Configuration {
+    #[bits(1)]
+    pub(super) enable: bool,
+    #[bits(3)]
+    _reserved: (),
+    /// 0 for the NVM command set
+    #[bits(3)]
+    pub(super) command_set: u8,
+    /// The page size is `4096 << page_size` bytes
+    #[bits(4)]
+    pub(super) page_size: u8,
+    #[bits(3)]
+    _arbitration: u8,
+    #[bits(2)]
+    _shutdown: u8,
+    /// The size of a submission queue entry is `1 << submission_entry_size` bytes
+    #[bits(4)]
+    pub(super) submission_entry_size: u8,
+    /// The size of a completion queue entry is `1 << completion_entry_size` bytes
+    #[bits(4)]
+    pub(super) completion_entry_size: u8,
+    #[bits(8)]
+    _reserved2: (),
+}
+
+#[bitfield(u32)]
+pub(super) struct ControllerStatus {
+    #[bits(1)]
+    pub(super) ready: bool,
+    #[bits(1)]
+    pub(super) fatal: bool,
+    #[bits(30)]
+    _unused: (),
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub(super) enum AdminCommand {
+    CreateSubmissionQueue = 0x01,
+    CreateCompletionQueue = 0x05,
+    Identify = 0x06,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub(super) enum IoCommand {
+    Flush = 0x00,
+    Write = 0x01,
+    Read = 0x02,
+}
+
+/// What IDENTIFY returns, from the low byte of command dword 10.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy)]
+pub(super) enum IdentifyStructure {
+    Namespace = 0x00,
+    Controller = 0x01,
+    ActiveNamespaces = 0x02,
+}
+
+/// An entry of a submission queue.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, KnownLayout, Immutable)]
+#[repr(C)]
+pub(super) struct SubmissionEntry {
+    pub(super) opcode: u8,
+    pub(super) flags: u8,
+    pub(super) command_id: U16,
+    pub(super) namespace_id: U32,
+    _reserved: [U32; 2],
+    _metadata: U64,
+    /// The first page of the data
+    pub(super) prp1: U64,
+    /// The second page of the data, or a list of the rest of the pages
+    pub(super) prp2: U64,
+    pub(super) command_dwords: [U32; 6],
+}
+
+impl SubmissionEntry {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 1 << Self::SIZE_SHIFT);
+    };
+
+    /// For [`Configuration::submission_entry_size`].
+    pub(super) const SIZE_SHIFT: u8 = 6;
+
+    pub(super) fn new(opcode: u8, namespace_id: u32) -> Self {
+        let mut entry = Self::new_zeroed();
+
+        entry.opcode = opcode;
+        entry.namespace_id = U32::new(namespace_id);
+
+        entry
+    }
+
+    /// Sets command dword `index`, counting from 10 like the specification.
+    pub(super) const fn with_dword(mut self, index: usize, value: u32) -> Self {
+        self.command_dwords[index - 10] = U32::new(value);
+
+        self
+    }
+}
+
+/// An entry of a completion queue.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, KnownLayout, Immutable)]
+#[repr(C)]
+pub(super) struct CompletionEntry {
+    pub(super) result: U32,
+    _reserved: U32,
+    pub(super) submission_head: U16,
+    pub(super) submission_id: U16,
+    pub(super) command_id: U16,
+    /// Bit 0 is the phase, flipped by the controller every pass through the queue, the rest is
+    /// the status of the command
+    pub(super) status: U16,
+}
+
+impl CompletionEntry {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 1 << Self::SIZE_SHIFT);
+    };
+
+    /// For [`Configuration::completion_entry_size`].
+    pub(super) const SIZE_SHIFT: u8 = 4;
+
+    pub(super) const fn phase(&self) -> bool {
+        self.status.get() & 1 != 0
+    }
+
+    /// The status code and type, 0 on success.
+    pub(super) const fn status_code(&self) -> u16 {
+        self.status.get() >> 1
+    }
+}
@@ -0,0 +1,387 @@
+// reference docs at https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html
+// only the modern pci transport is supported, legacy only devices are rejected
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spinlock::Spinlock;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use self::queue::{UsedElement, VirtQueue};
+use self::structs::{CommonConfig, CommonRegister, DeviceStatus, IsrStatus};
+use crate::interrupts::{self, wait_for_interrupt};
+use crate::memory::{self, BootInfoFrameAllocator};
+use crate::multitasking::SCHEDULER;
+use crate::pci::PciDevice;
+use crate::pci::capability::CapabilityId;
+use crate::timer::{Duration, sleep};
+
+pub use self::structs::ConfigType;
+
+pub mod blk;
+pub mod queue;
+mod structs;
+
+/// The vendor id of every virtio device.
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+/// The device follows the virtio 1.0 specification rather than the legacy interface.
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// The most descriptors a queue is created with, the device might support more.
+const MAX_QUEUE_SIZE: u16 = 64;
+
+/// Every device, for the interrupt handler.
+static DEVICES: Spinlock<Vec<Arc<DeviceIrq>>> = Spinlock::new(Vec::new());
+
+#[derive(thiserror::Error, Debug)]
+pub enum VirtioCreationError {
+    #[error("The device doesn't have the `{0:?}` structure, it might only support legacy virtio")]
+    MissingStructure(ConfigType),
+    #[error("BAR `{0}` of the device isn't memory mapped")]
+    InvalidBar(u8),
+    #[error("The device's interrupt isn't routed, line `{0}`")]
+    NoInterruptLine(u8),
+    #[error("Failed to map the device's registers, `{0:?}`")]
+    MapFailed(MapToError<Size4KiB>),
+    #[error("The device is missing the features `{0:#x}`")]
+    MissingFeatures(u64),
+    #[error("The device didn't accept the features")]
+    FeaturesRejected,
+    #[error("The device didn't reset in time")]
+    ResetTimeout,
+    #[error("The device doesn't have queue `{0}`")]
+    MissingQueue(u16),
+    #[error("Unable to allocate dma memory below 4 GiB")]
+    OutOfMemory,
+}
+
+/// The interrupt state of a device.
+///
+/// This lives outside of the device's lock since the task waiting for the interrupt holds it.
+#[derive(Debug)]
+struct DeviceIrq {
+    irq: u8,
+    /// The interrupt status register
+    isr: VirtAddr,
+    fired: AtomicBool,
+}
+
+impl DeviceIrq {
+    /// Acknowledges the device and wakes up the task waiting on it if it interrupted.
+    fn handle(&self) {
+        // reading the status deasserts the interrupt
+        let status = IsrStatus::from_bits(unsafe { self.isr.as_ptr::<u8>().read_volatile() });
+
+        // another device on the same line interrupted
+        if !status.queue() && !status.config_changed() {
+            return;
+        }
+
+        self.fired.store(true, Ordering::Release);
+
+        SCHEDULER.with_mut_ref(|scheduler| scheduler.wake_up_interrupt_waiters(self.irq));
+    }
+}
+
+/// Handles an interrupt on `irq`, called from the interrupt handler.
+pub fn handle_irq(irq: u8) {
+    DEVICES.with_ref(|devices| {
+        devices
+            .iter()
+            .filter(|device| device.irq == irq)
+            .for_each(|device| device.handle());
+    });
+}
+
+/// The virtio pci transport of a device, the structures its capabilities describe.
+///
+/// Devices are set up by [`Transport::new`], [`Transport::negotiate`], creating the queues with
+/// [`Transport::create_queue`] and finally [`Transport::finish`].
+#[derive(Debug)]
+pub struct Transport {
+    common: CommonConfig,
+    /// The start of the doorbells
+    notify: VirtAddr,
+    /// The distance between doorbells
+    notify_multiplier: u32,
+    /// The device specific configuration
+    device_config: VirtAddr,
+    irq: Arc<DeviceIrq>,
+    features: u64,
+}
+
+/// Where a structure is, from a virtio capability.
+#[derive(Debug, Clone, Copy)]
+struct StructureLocation {
+    bar: u8,
+    offset: u32,
+    length: u32,
+    /// The config space offset of the capability
+    capability: u8,
+}
+
+impl Transport {
+    /// Maps the structures of the device and resets it.
+    ///
+    /// # Errors
+    /// Will return [`VirtioCreationError`] if a structure, its BAR or the interrupt line are
+    /// missing, mapping fails, or the device doesn't reset
+    pub fn new(
+        pci_device: &PciDevice,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Result<Self, VirtioCreationError> {
+        let address = pci_device.address;
+        let mut locations = [None; 4];
+
+        for capability in pci_device
+            .capabilities()
+            .filter(|capability| capability.kind() == CapabilityId::VendorSpecific)
+        {
+            let [_, _, _, config_type] = address.read_config(capability.offset).to_le_bytes();
+            let [bar, ..] = address.read_config(capability.offset + 4).to_le_bytes();
+
+            // the first capability of each type is the preferred one, BARs past 5 are reserved
+            // and the spec says to ignore capabilities using them
+            if let Some(config_type) = ConfigType::from_u8(config_type)
+                && bar <= 5
+                && locations[config_type as usize - 1].is_none()
+            {
+                locations[config_type as usize - 1] = Some(StructureLocation {
+                    bar,
+                    offset: address.read_config(capability.offset + 8),
+                    length: address.read_config(capability.offset + 12),
+                    capability: capability.offset,
+                });
+            }
+        }
+
+        let irq = pci_device.info.header.interrupt_line();
+
+        // the PIC only has 16 lines, 0xFF means the pin isn't routed
+        if irq >= 16 {
+            return Err(VirtioCreationError::NoInterruptLine(irq));
+        }
+
+        // the device reads the queues and transfers data itself
+        address.enable_bus_master();
+
+        let mut map = |config_type: ConfigType| {
+            let location = locations[config_type as usize - 1]
+                .ok_or(VirtioCreationError::MissingStructure(config_type))?;
+
+            let bar = pci_device
+                .info
+                .header
+                .memory_bar_address(location.bar)
+                .ok_or(VirtioCreationError::InvalidBar(location.bar))?;
+
+            let start = unsafe {
+                memory::map_mmio(
+                    mapper,
+                    frame_allocator,
+                    PhysAddr::new(bar + u64::from(location.offset)),
+                    usize::try_from(location.length.max(1)).unwrap(),
+                )
+            }
+            .map_err(VirtioCreationError::MapFailed)?;
+
+            Ok((start, location))
+        };
+
+        let (common, _) = map(ConfigType::Common)?;
+        let (notify, notify_location) = map(ConfigType::Notify)?;
+        let (isr, _) = map(ConfigType::Isr)?;
+        let (device_config, _) = map(ConfigType::Device)?;
+
+        // the notify capability has the multiplier after the common fields
+        let notify_multiplier = address.read_config(notify_location.capability + 16);
+
+        let transport = Self {
+            // SAFETY: just mapped
+            common: unsafe { CommonConfig::new(common) },
+            notify,
+            notify_multiplier,
+            device_config,
+            irq: Arc::new(DeviceIrq {
+                irq,
+                isr,
+                fired: AtomicBool::new(false),
+            }),
+            features: 0,
+        };
+
+        transport.reset()?;
+
+        transport
+            .common
+            .write_status(DeviceStatus::new().with_acknowledge(true).with_driver(true));
+
+        Ok(transport)
+    }
+
+    /// Resets the device, the reset is done once the status reads 0.
+    fn reset(&self) -> Result<(), VirtioCreationError> {
+        self.common.write_status(DeviceStatus::new());
+
+        for _ in 0..1000 {
+            if self.common.status().into_bits() == 0 {
+                return Ok(());
+            }
+
+            sleep(1);
+        }
+
+        Err(VirtioCreationError::ResetTimeout)
+    }
+
+    /// Accepts `required` and whichever of `optional` the device offers, returning the accepted
+    /// features. [`FEATURE_VERSION_1`] is always required.
+    ///
+    /// # Errors
+    /// Will return [`VirtioCreationError`] if the device lacks a required feature or rejects the
+    /// features
+    pub fn negotiate(&mut self, required: u64, optional: u64) -> Result<u64, VirtioCreationError> {
+        let required = required | FEATURE_VERSION_1;
+
+        // the features are read and written 32 bits at a time
+        let offered = (0..2).fold(0, |features, select| {
+            self.common
+                .write_u32(CommonRegister::DeviceFeatureSelect, select);
+
+            features
+                | u64::from(self.common.read_u32(CommonRegister::DeviceFeature)) << (32 * select)
+        });
+
+        if offered & required != required {
+            self.fail();
+            return Err(VirtioCreationError::MissingFeatures(required & !offered));
+        }
+
+        let features = offered & (required | optional);
+
+        for select in 0..2 {
+            self.common
+                .write_u32(CommonRegister::DriverFeatureSelect, select);
+            self.common.write_u32(
+                CommonRegister::DriverFeature,
+                u32::try_from(features >> (32 * select) & 0xFFFF_FFFF).unwrap(),
+            );
+        }
+
+        self.common
+            .write_status(self.common.status().with_features_ok(true));
+
+        // the device clears the bit if it can't work with the features
+        if !self.common.status().features_ok() {
+            self.fail();
+            return Err(VirtioCreationError::FeaturesRejected);
+        }
+
+        self.features = features;
+
+        Ok(features)
+    }
+
+    /// The features accepted by [`Self::negotiate`].
+    pub const fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Tells the device the driver gave up on it.
+    fn fail(&self) {
+        self.common
+            .write_status(self.common.status().with_failed(true));
+    }
+
+    /// Creates and enables queue `index` with at most 64 descriptors.
+    ///
+    /// # Errors
+    /// Will return [`VirtioCreationError`] if the device doesn't have the queue or the memory
+    /// can't be allocated
+    pub fn create_queue(
+        &self,
+        index: u16,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Result<VirtQueue, VirtioCreationError> {
+        self.common.write_u16(CommonRegister::QueueSelect, index);
+
+        let size = self.common.read_u16(CommonRegister::QueueSize);
+
+        if size == 0 {
+            return Err(VirtioCreationError::MissingQueue(index));
+        }
+
+        let size = size.min(MAX_QUEUE_SIZE);
+        let notify_offset = self.common.read_u16(CommonRegister::QueueNotifyOffset);
+        let notify = self.notify + u64::from(notify_offset) * u64::from(self.notify_multiplier);
+
+        let queue = VirtQueue::new(index, size, notify, frame_allocator)
+            .ok_or(VirtioCreationError::OutOfMemory)?;
+
+        self.common.write_u16(CommonRegister::QueueSize, size);
+        self.common.write_u64(
+            CommonRegister::QueueDescriptor,
+            queue.descriptor_address().as_u64(),
+        );
+        self.common
+            .write_u64(CommonRegister::QueueDriver, queue.driver_address().as_u64());
+        self.common
+            .write_u64(CommonRegister::QueueDevice, queue.device_address().as_u64());
+        self.common.write_u16(CommonRegister::QueueEnable, 1);
+
+        Ok(queue)
+    }
+
+    /// Starts the device once its queues are created.
+    pub fn finish(&self) {
+        DEVICES.with_mut_ref(|devices| devices.push(self.irq.clone()));
+        interrupts::unmask_irq(self.irq.irq);
+
+        self.common
+            .write_status(self.common.status().with_driver_ok(true));
+    }
+
+    /// Reads the 32-bit field of the device specific configuration at `offset`.
+    pub fn read_device_config(&self, offset: usize) -> u32 {
+        unsafe {
+            (self.device_config + offset as u64)
+                .as_ptr::<u32>()
+                .read_volatile()
+        }
+    }
+
+    /// Reads the 64-bit field of the device specific configuration at `offset`, retrying if the
+    /// device changes it between the two halves.
+    pub fn read_device_config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.common.read_u8(CommonRegister::ConfigGeneration);
+
+            let value = u64::from(self.read_device_config(offset))
+                | u64::from(self.read_device_config(offset + 4)) << 32;
+
+            if generation == self.common.read_u8(CommonRegister::ConfigGeneration) {
+                return value;
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for the device to be done with a chain of `queue`.
+    pub fn wait_used(&self, queue: &mut VirtQueue, timeout: Duration) -> Option<UsedElement> {
+        loop {
+            // the device updates the ring before interrupting, so forgetting interrupts before
+            // checking it can't miss one
+            self.irq.fired.store(false, Ordering::Release);
+
+            if let Some(used) = queue.pop_used() {
+                return Some(used);
+            }
+
+            if !wait_for_interrupt(self.irq.irq, &self.irq.fired, timeout) && !queue.has_used() {
+                return None;
+            }
+        }
+    }
+}
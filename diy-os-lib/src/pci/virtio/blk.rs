@@ -0,0 +1,286 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use x86_64::structures::paging::{Mapper, OffsetPageTable, Size4KiB};
+use zerocopy::little_endian::{U32, U64};
+use zerocopy::{Immutable, IntoBytes, KnownLayout};
+
+use super::queue::{Buffer, VirtQueue};
+use super::{Transport, VENDOR_ID, VirtioCreationError};
+use crate::device_manager::driver::{PciDriver, PciMatch};
+use crate::device_manager::{BlockDevice, BlockDeviceError, Device, DeviceManager, check_access};
+use crate::memory::{BootInfoFrameAllocator, DmaBuffer};
+use crate::multitasking::mutex::Mutex;
+use crate::pci::PciDevice;
+use crate::timer::{Duration, Seconds};
+
+/// The device ids of a block device, the transitional one and the virtio 1.0 one.
+pub const DEVICE_IDS: [u16; 2] = [0x1001, 0x1042];
+
+/// Binds to virtio block devices, transitional or not.
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &[
+        PciMatch::Id {
+            vendor: VENDOR_ID,
+            device: DEVICE_IDS[0],
+        },
+        PciMatch::Id {
+            vendor: VENDOR_ID,
+            device: DEVICE_IDS[1],
+        },
+    ],
+    probe,
+};
+
+/// The device has a limit on the size of a single buffer.
+const FEATURE_SIZE_MAX: u64 = 1 << 1;
+/// The device is read only.
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+/// The device has a write cache that has to be flushed.
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+/// The configuration fields, the capacity is always in 512 byte sectors.
+const CONFIG_CAPACITY: usize = 0x00;
+const CONFIG_SIZE_MAX: usize = 0x08;
+
+const SECTOR_SIZE: usize = 512;
+
+/// How long the device has to complete a request before it's given up on.
+const REQUEST_TIMEOUT: Duration = Seconds(5).into();
+
+/// The size of the buffer transfers go through.
+const BUFFER_SIZE: usize = 0x1_0000;
+
+#[derive(thiserror::Error, Debug)]
+pub enum VirtioBlkError {
+    #[error("The device didn't complete the request in time")]
+    Timeout,
+    #[error("The queue has no free descriptors left")]
+    QueueFull,
+    #[error("The device failed the request")]
+    IoError,
+    #[error("The device doesn't support the request")]
+    Unsupported,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy)]
+enum RequestType {
+    Read = 0,
+    Write = 1,
+    Flush = 4,
+}
+
+/// The header the device reads before the data of a request.
+#[derive(IntoBytes, KnownLayout, Immutable)]
+#[repr(C)]
+struct RequestHeader {
+    request_type: U32,
+    _reserved: U32,
+    sector: U64,
+}
+
+/// A virtio block device, requests go through its only queue one at a time.
+#[derive(Debug)]
+pub struct VirtioBlk {
+    transport: Transport,
+    queue: VirtQueue,
+    /// The header of the current request, followed by its status
+    request: DmaBuffer,
+    /// The buffer transfers go through
+    buffer: DmaBuffer,
+    sectors: u64,
+    /// The most bytes a single request can transfer
+    max_transfer: usize,
+}
+
+fn probe(
+    pci_device: PciDevice,
+    device_manager: &mut DeviceManager,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), anyhow::Error> {
+    Ok(create_virtio_blk(
+        pci_device,
+        device_manager,
+        mapper,
+        frame_allocator,
+    )?)
+}
+
+/// Sets up the block device and registers it.
+///
+/// # Errors
+/// Will return [`VirtioCreationError`] if the transport fails to set up
+pub fn create_virtio_blk(
+    pci_device: PciDevice,
+    device_manager: &mut DeviceManager,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), VirtioCreationError> {
+    let mut transport = Transport::new(&pci_device, mapper, frame_allocator)?;
+
+    let features = transport.negotiate(0, FEATURE_SIZE_MAX | FEATURE_READ_ONLY | FEATURE_FLUSH)?;
+    let queue = transport.create_queue(0, frame_allocator)?;
+
+    let sectors = transport.read_device_config_u64(CONFIG_CAPACITY);
+
+    let max_transfer = if features & FEATURE_SIZE_MAX == 0 {
+        BUFFER_SIZE
+    } else {
+        let size_max = usize::try_from(transport.read_device_config(CONFIG_SIZE_MAX)).unwrap();
+
+        BUFFER_SIZE
+            .min(size_max - size_max % SECTOR_SIZE)
+            .max(SECTOR_SIZE)
+    };
+
+    let device = VirtioBlk {
+        request: DmaBuffer::allocate(frame_allocator, size_of::<RequestHeader>() + 1, 16)
+            .ok_or(VirtioCreationError::OutOfMemory)?,
+        buffer: DmaBuffer::allocate(frame_allocator, BUFFER_SIZE, 4096)
+            .ok_or(VirtioCreationError::OutOfMemory)?,
+        transport,
+        queue,
+        sectors,
+        max_transfer,
+    };
+
+    device.transport.finish();
+
+    log::info!("virtio block device with {sectors} sectors");
+
+    let device = Arc::new(Mutex::new(device));
+
+    device_manager.register_device(device.clone() as Arc<Mutex<dyn Device>>);
+    device_manager.register_block_device(device as Arc<Mutex<dyn BlockDevice>>);
+
+    Ok(())
+}
+
+impl VirtioBlk {
+    /// Sends a request for `sector` with the first `length` bytes of the buffer as its data and
+    /// waits for the device to complete it.
+    fn request(
+        &mut self,
+        request_type: RequestType,
+        sector: u64,
+        length: usize,
+    ) -> Result<(), VirtioBlkError> {
+        let header = RequestHeader {
+            request_type: U32::new(request_type as u32),
+            _reserved: U32::new(0),
+            sector: U64::new(sector),
+        };
+
+        let request = self.request.as_mut_slice();
+        header
+            .write_to_prefix(request)
+            .expect("Should fit in the buffer");
+        // overwritten by the device once it's done
+        request[size_of::<RequestHeader>()] = u8::MAX;
+
+        let header = Buffer {
+            address: self.request.phys_addr(),
+            length: u32::try_from(size_of::<RequestHeader>()).unwrap(),
+            device_writable: false,
+        };
+        let data = Buffer {
+            address: self.buffer.phys_addr(),
+            length: u32::try_from(length).expect("Should be at most the buffer size"),
+            device_writable: matches!(request_type, RequestType::Read),
+        };
+        let status = Buffer {
+            address: self.request.phys_addr() + size_of::<RequestHeader>() as u64,
+            length: 1,
+            device_writable: true,
+        };
+
+        let added = if length == 0 {
+            self.queue.add(&[header, status])
+        } else {
+            self.queue.add(&[header, data, status])
+        };
+
+        added.ok_or(VirtioBlkError::QueueFull)?;
+        self.queue.notify();
+
+        // requests are sent one at a time, so the used chain is this one
+        self.transport
+            .wait_used(&mut self.queue, REQUEST_TIMEOUT)
+            .ok_or(VirtioBlkError::Timeout)?;
+
+        // the status the device wrote, 1 is an io error
+        match self.request.as_slice()[size_of::<RequestHeader>()] {
+            0 => Ok(()),
+            2 => Err(VirtioBlkError::Unsupported),
+            _ => Err(VirtioBlkError::IoError),
+        }
+    }
+}
+
+impl Device for VirtioBlk {
+    fn children(&self) -> Option<Box<dyn Iterator<Item = Arc<Mutex<dyn Device>>>>> {
+        None
+    }
+
+    fn as_block_device(&mut self) -> Option<&mut dyn BlockDevice> {
+        Some(self)
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn read_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let length = check_access(self.sectors, SECTOR_SIZE, lba, count, buffer.len())?;
+        let max_sectors = self.max_transfer / SECTOR_SIZE;
+
+        for (lba, chunk) in (lba..)
+            .step_by(max_sectors)
+            .zip(buffer[..length].chunks_mut(self.max_transfer))
+        {
+            self.request(RequestType::Read, lba, chunk.len())?;
+
+            chunk.copy_from_slice(&self.buffer.as_slice()[..chunk.len()]);
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        if self.transport.features() & FEATURE_READ_ONLY != 0 {
+            return Err(BlockDeviceError::ReadOnly);
+        }
+
+        let length = check_access(self.sectors, SECTOR_SIZE, lba, count, buffer.len())?;
+        let max_sectors = self.max_transfer / SECTOR_SIZE;
+
+        for (lba, chunk) in (lba..)
+            .step_by(max_sectors)
+            .zip(buffer[..length].chunks(self.max_transfer))
+        {
+            self.buffer.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+
+            self.request(RequestType::Write, lba, chunk.len())?;
+        }
+
+        if length != 0 && self.transport.features() & FEATURE_FLUSH != 0 {
+            self.request(RequestType::Flush, 0, 0)?;
+        }
+
+        Ok(())
+    }
+
+    fn total_sectors(&self) -> u64 {
+        self.sectors
+    }
+}
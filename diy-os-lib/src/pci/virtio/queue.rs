@@ -0,0 +1,262 @@
+use core::sync::atomic::{Ordering, fence};
+use x86_64::{PhysAddr, VirtAddr};
+use zerocopy::little_endian::{U16, U32, U64};
+use zerocopy::{FromBytes, IntoBytes};
+
+use super::structs::{Descriptor, DescriptorFlags, UsedRingEntry};
+use crate::memory::{BootInfoFrameAllocator, DmaBuffer};
+
+/// A buffer handed to the device as part of a chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    /// Set if the device writes to the buffer rather than reading it
+    pub device_writable: bool,
+}
+
+/// A chain the device is done with.
+#[derive(Debug, Clone, Copy)]
+pub struct UsedElement {
+    /// The first descriptor of the chain, as returned by [`VirtQueue::add`]
+    pub head: u16,
+    /// The number of bytes the device wrote
+    pub length: u32,
+}
+
+/// A split virtqueue, the descriptor table, the available ring and the used ring in one buffer.
+///
+/// Free descriptors are kept in a list linked through their `next` fields.
+#[derive(Debug)]
+pub struct VirtQueue {
+    memory: DmaBuffer,
+    index: u16,
+    size: u16,
+    /// The doorbell of the queue
+    notify: VirtAddr,
+    free_head: u16,
+    free_count: u16,
+    /// The index of the next entry of the available ring, wrapping
+    next_available: u16,
+    /// The index of the next entry of the used ring to process, wrapping
+    next_used: u16,
+}
+
+impl VirtQueue {
+    /// Allocates a queue with `size` descriptors, `notify` is the queue's doorbell.
+    ///
+    /// Returns [`None`] if the memory can't be allocated.
+    pub(super) fn new(
+        index: u16,
+        size: u16,
+        notify: VirtAddr,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Option<Self> {
+        let mut queue = Self {
+            memory: DmaBuffer::allocate(
+                frame_allocator,
+                Self::used_offset(size) + Self::used_size(size),
+                4096,
+            )?,
+            index,
+            size,
+            notify,
+            free_head: 0,
+            free_count: size,
+            next_available: 0,
+            next_used: 0,
+        };
+
+        for index in 0..size {
+            queue.write_descriptor(
+                index,
+                &Descriptor {
+                    address: U64::new(0),
+                    length: U32::new(0),
+                    flags: U16::new(0),
+                    next: U16::new((index + 1) % size),
+                },
+            );
+        }
+
+        Some(queue)
+    }
+
+    /// The descriptor table is 16 bytes per descriptor at the start.
+    const fn available_offset(size: u16) -> usize {
+        16 * size as usize
+    }
+
+    /// The flags, index and a ring entry per descriptor, followed by the used event.
+    const fn available_size(size: u16) -> usize {
+        2 * (3 + size as usize)
+    }
+
+    /// The used ring is 4 byte aligned after the available ring.
+    const fn used_offset(size: u16) -> usize {
+        (Self::available_offset(size) + Self::available_size(size)).next_multiple_of(4)
+    }
+
+    /// The flags, index and an 8 byte ring entry per descriptor, followed by the available event.
+    const fn used_size(size: u16) -> usize {
+        6 + 8 * size as usize
+    }
+
+    pub const fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub const fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub const fn descriptor_address(&self) -> PhysAddr {
+        self.memory.phys_addr()
+    }
+
+    pub fn driver_address(&self) -> PhysAddr {
+        self.memory.phys_addr() + Self::available_offset(self.size) as u64
+    }
+
+    pub fn device_address(&self) -> PhysAddr {
+        self.memory.phys_addr() + Self::used_offset(self.size) as u64
+    }
+
+    fn descriptor(&self, index: u16) -> Descriptor {
+        Descriptor::read_from_prefix(&self.memory.as_slice()[usize::from(index) * 16..])
+            .expect("Should be in the table")
+            .0
+    }
+
+    fn write_descriptor(&mut self, index: u16, descriptor: &Descriptor) {
+        descriptor
+            .write_to_prefix(&mut self.memory.as_mut_slice()[usize::from(index) * 16..])
+            .expect("Should be in the table");
+    }
+
+    /// Reads the 16-bit field at `offset`, volatile since the device writes the rings.
+    #[allow(clippy::cast_ptr_alignment)] // every field of the rings is aligned
+    fn read_u16(&self, offset: usize) -> u16 {
+        let field = self.memory.as_slice()[offset..offset + 2]
+            .as_ptr()
+            .cast::<u16>();
+
+        // SAFETY: in the buffer and aligned since every ring is
+        u16::from_le(unsafe { field.read_volatile() })
+    }
+
+    #[allow(clippy::cast_ptr_alignment)] // every field of the rings is aligned
+    fn write_u16(&mut self, offset: usize, value: u16) {
+        let field = self.memory.as_mut_slice()[offset..offset + 2]
+            .as_mut_ptr()
+            .cast::<u16>();
+
+        // SAFETY: in the buffer and aligned since every ring is
+        unsafe { field.write_volatile(value.to_le()) };
+    }
+
+    /// Chains `buffers` and makes them available to the device, returning the head of the chain.
+    ///
+    /// Returns [`None`] if there aren't enough free descriptors, the device has to be notified
+    /// with [`Self::notify`] afterwards.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+
+        for (position, buffer) in buffers.iter().enumerate() {
+            let next = self.descriptor(index).next.get();
+            let last = position == buffers.len() - 1;
+
+            self.write_descriptor(
+                index,
+                &Descriptor {
+                    address: U64::new(buffer.address.as_u64()),
+                    length: U32::new(buffer.length),
+                    flags: U16::new(
+                        DescriptorFlags::new()
+                            .with_next(!last)
+                            .with_write(buffer.device_writable)
+                            .into_bits(),
+                    ),
+                    next: U16::new(next),
+                },
+            );
+
+            if last {
+                self.free_head = next;
+            } else {
+                index = next;
+            }
+        }
+
+        self.free_count -= u16::try_from(buffers.len()).expect("Should fit in the queue");
+
+        // the ring entry after the flags and index
+        let available = Self::available_offset(self.size);
+        let entry = available + 4 + 2 * usize::from(self.next_available % self.size);
+
+        self.write_u16(entry, head);
+
+        // the chain has to be visible before the index that publishes it
+        fence(Ordering::Release);
+
+        self.next_available = self.next_available.wrapping_add(1);
+        self.write_u16(available + 2, self.next_available);
+
+        Some(head)
+    }
+
+    /// Tells the device the queue has new buffers.
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+
+        unsafe { self.notify.as_mut_ptr::<u16>().write_volatile(self.index) };
+    }
+
+    /// Returns whether the device is done with chains that haven't been popped.
+    pub fn has_used(&self) -> bool {
+        self.read_u16(Self::used_offset(self.size) + 2) != self.next_used
+    }
+
+    /// Takes the next chain the device is done with, freeing its descriptors.
+    pub fn pop_used(&mut self) -> Option<UsedElement> {
+        if !self.has_used() {
+            return None;
+        }
+
+        // the entry is only valid once the index is read
+        fence(Ordering::Acquire);
+
+        let entry = Self::used_offset(self.size) + 4 + 8 * usize::from(self.next_used % self.size);
+        let (used, _) = UsedRingEntry::read_from_prefix(&self.memory.as_slice()[entry..])
+            .expect("Should be in the ring");
+
+        self.next_used = self.next_used.wrapping_add(1);
+
+        let head = u16::try_from(used.id.get()).expect("Should be a descriptor index");
+        let mut tail = head;
+
+        self.free_count += 1;
+
+        while DescriptorFlags::from_bits(self.descriptor(tail).flags.get()).next() {
+            tail = self.descriptor(tail).next.get();
+            self.free_count += 1;
+        }
+
+        // put the chain in front of the free list
+        let mut descriptor = self.descriptor(tail);
+        descriptor.next = U16::new(self.free_head);
+        self.write_descriptor(tail, &descriptor);
+
+        self.free_head = head;
+
+        Some(UsedElement {
+            head,
+            length: used.length.get(),
+        })
+    }
+}
@@ -0,0 +1,199 @@
+use bitfield_struct::bitfield;
+use x86_64::VirtAddr;
+use zerocopy::little_endian::{U16, U32, U64};
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+/// Which structure a virtio capability describes, from byte 3 of the capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConfigType {
+    Common = 1,
+    Notify = 2,
+    Isr = 3,
+    Device = 4,
+}
+
+impl ConfigType {
+    pub(super) const fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Common),
+            2 => Some(Self::Notify),
+            3 => Some(Self::Isr),
+            4 => Some(Self::Device),
+            _ => None,
+        }
+    }
+}
+
+/// The registers of the common configuration structure.
+#[derive(Debug, Clone, Copy)]
+#[repr(usize)]
+pub(super) enum CommonRegister {
+    DeviceFeatureSelect = 0x00,
+    DeviceFeature = 0x04,
+    DriverFeatureSelect = 0x08,
+    DriverFeature = 0x0C,
+    DeviceStatus = 0x14,
+    ConfigGeneration = 0x15,
+    QueueSelect = 0x16,
+    QueueSize = 0x18,
+    QueueEnable = 0x1C,
+    QueueNotifyOffset = 0x1E,
+    QueueDescriptor = 0x20,
+    QueueDriver = 0x28,
+    QueueDevice = 0x30,
+}
+
+/// The memory mapped common configuration structure of a device.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct CommonConfig {
+    base: VirtAddr,
+}
+
+impl CommonConfig {
+    /// # Safety
+    ///
+    /// `base` must be where the common configuration structure of a device is mapped, uncached.
+    pub(super) const unsafe fn new(base: VirtAddr) -> Self {
+        Self { base }
+    }
+
+    pub(super) fn read_u8(self, register: CommonRegister) -> u8 {
+        unsafe { (self.base + register as u64).as_ptr::<u8>().read_volatile() }
+    }
+
+    pub(super) fn write_u8(self, register: CommonRegister, value: u8) {
+        unsafe {
+            (self.base + register as u64)
+                .as_mut_ptr::<u8>()
+                .write_volatile(value);
+        };
+    }
+
+    pub(super) fn read_u16(self, register: CommonRegister) -> u16 {
+        unsafe {
+            (self.base + register as u64)
+                .as_ptr::<u16>()
+                .read_volatile()
+        }
+    }
+
+    pub(super) fn write_u16(self, register: CommonRegister, value: u16) {
+        unsafe {
+            (self.base + register as u64)
+                .as_mut_ptr::<u16>()
+                .write_volatile(value);
+        };
+    }
+
+    pub(super) fn read_u32(self, register: CommonRegister) -> u32 {
+        unsafe {
+            (self.base + register as u64)
+                .as_ptr::<u32>()
+                .read_volatile()
+        }
+    }
+
+    pub(super) fn write_u32(self, register: CommonRegister, value: u32) {
+        unsafe {
+            (self.base + register as u64)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value);
+        };
+    }
+
+    /// Writes a 64-bit register as two halves, which every device has to accept.
+    pub(super) fn write_u64(self, register: CommonRegister, value: u64) {
+        let [low, high] = [value & 0xFFFF_FFFF, value >> 32]
+            .map(|half| u32::try_from(half).expect("Should be 32 bits"));
+
+        unsafe {
+            let address = (self.base + register as u64).as_mut_ptr::<u32>();
+
+            address.write_volatile(low);
+            address.add(1).write_volatile(high);
+        };
+    }
+
+    pub(super) fn status(self) -> DeviceStatus {
+        DeviceStatus::from_bits(self.read_u8(CommonRegister::DeviceStatus))
+    }
+
+    pub(super) fn write_status(self, status: DeviceStatus) {
+        self.write_u8(CommonRegister::DeviceStatus, status.into_bits());
+    }
+}
+
+/// How far the driver got setting up the device, writing 0 resets it.
+#[bitfield(u8)]
+pub(super) struct DeviceStatus {
+    /// The driver noticed the device
+    #[bits(1)]
+    pub(super) acknowledge: bool,
+    /// The driver knows how to drive the device
+    #[bits(1)]
+    pub(super) driver: bool,
+    /// The driver is set up and the device can be used
+    #[bits(1)]
+    pub(super) driver_ok: bool,
+    /// Feature negotiation is done, cleared by the device if it rejects the features
+    #[bits(1)]
+    pub(super) features_ok: bool,
+    #[bits(2)]
+    _reserved: (),
+    #[bits(1)]
+    pub(super) needs_reset: bool,
+    /// The driver gave up on the device
+    #[bits(1)]
+    pub(super) failed: bool,
+}
+
+/// The interrupt status, reading it acknowledges the interrupt.
+#[bitfield(u8)]
+pub(super) struct IsrStatus {
+    /// A queue has new used buffers
+    #[bits(1)]
+    pub(super) queue: bool,
+    #[bits(1)]
+    pub(super) config_changed: bool,
+    #[bits(6)]
+    _reserved: (),
+}
+
+#[bitfield(u16)]
+pub(super) struct DescriptorFlags {
+    /// The chain continues with the descriptor in `next`
+    #[bits(1)]
+    pub(super) next: bool,
+    /// The device writes to the buffer rather than reading it
+    #[bits(1)]
+    pub(super) write: bool,
+    #[bits(14)]
+    _unused: (),
+}
+
+/// An entry of the descriptor table, describes one buffer.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, KnownLayout, Immutable)]
+#[repr(C)]
+pub(super) struct Descriptor {
+    pub(super) address: U64,
+    pub(super) length: U32,
+    pub(super) flags: U16,
+    pub(super) next: U16,
+}
+
+impl Descriptor {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 16);
+    };
+}
+
+/// An entry of the used ring, a chain the device is done with.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, KnownLayout, Immutable)]
+#[repr(C)]
+pub(super) struct UsedRingEntry {
+    /// The first descriptor of the chain
+    pub(super) id: U32,
+    /// The number of bytes the device wrote
+    pub(super) length: U32,
+}
@@ -0,0 +1,44 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::random::RdRand;
+
+static FALLBACK_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// A xorshift generator for cpus without `RDRAND`.
+fn fallback_random() -> u64 {
+    let mut state = FALLBACK_STATE.load(Ordering::Relaxed);
+
+    if state == 0 {
+        // seeded from the timestamp counter, this is not cryptographically secure
+        // SAFETY: `RDTSC` is on every x86_64 cpu and only reads the timestamp counter
+        state = unsafe { core::arch::x86_64::_rdtsc() } | 1;
+    }
+
+    state ^= state << 13;
+    state ^= state >> 7;
+    state ^= state << 17;
+
+    FALLBACK_STATE.store(state, Ordering::Relaxed);
+
+    state
+}
+
+/// Returns a random number from `RDRAND`, or the fallback generator if it isn't supported.
+pub fn random_u64() -> u64 {
+    RdRand::new()
+        .and_then(RdRand::get_u64)
+        .unwrap_or_else(fallback_random)
+}
+
+/// Fills `buffer` with random bytes, from `RDRAND` or the fallback generator if it isn't
+/// supported.
+pub fn fill(buffer: &mut [u8]) {
+    let rdrand = RdRand::new();
+
+    for chunk in buffer.chunks_mut(size_of::<u64>()) {
+        let random = rdrand
+            .and_then(RdRand::get_u64)
+            .unwrap_or_else(fallback_random);
+
+        chunk.copy_from_slice(&random.to_ne_bytes()[..chunk.len()]);
+    }
+}
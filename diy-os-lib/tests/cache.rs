@@ -0,0 +1,58 @@
+//! Writes through a [`BlockCache`] in front of a [`RamDisk`].
+
+use std::sync::Arc;
+
+use diy_os::device_manager::BlockDevice;
+use diy_os::device_manager::cache::{self, BlockCache};
+use diy_os::device_manager::ramdisk::RamDisk;
+use diy_os::multitasking::mutex::Mutex;
+
+fn disk(sectors: usize) -> Arc<Mutex<RamDisk>> {
+    Arc::new(Mutex::new(RamDisk::new(sectors, 512)))
+}
+
+fn sector(disk: &Mutex<RamDisk>, lba: usize) -> Vec<u8> {
+    disk.with_ref(|disk| disk.as_bytes()[lba * 512..(lba + 1) * 512].to_vec())
+}
+
+#[test]
+fn writes_reach_the_disk_on_sync() {
+    let disk = disk(8);
+    let cache = BlockCache::new(disk.clone(), 8 * 512);
+
+    cache.acquire().write_sectors(2, 2, &[0xAB; 1024]).unwrap();
+
+    assert_eq!(sector(&disk, 2), [0; 512]);
+    assert_eq!(cache.acquire().usage(), (2, 2));
+
+    cache::sync(&cache).unwrap();
+
+    assert_eq!(sector(&disk, 2), [0xAB; 512]);
+    assert_eq!(sector(&disk, 3), [0xAB; 512]);
+    assert_eq!(cache.acquire().usage(), (2, 0));
+    assert_eq!(cache.acquire().stats().write_backs, 2);
+}
+
+#[test]
+fn evicted_sectors_are_written_back() {
+    let disk = disk(8);
+    let cache = BlockCache::new(disk.clone(), 2 * 512);
+
+    for lba in 0..3 {
+        cache
+            .acquire()
+            .write_sectors(lba, 1, &[u8::try_from(lba).unwrap() + 1; 512])
+            .unwrap();
+    }
+
+    // the first sector was the least recently used
+    assert_eq!(sector(&disk, 0), [1; 512]);
+    assert_eq!(sector(&disk, 1), [0; 512]);
+
+    let mut buffer = [0; 3 * 512];
+    cache.acquire().read_sectors(0, 3, &mut buffer).unwrap();
+
+    for (lba, data) in buffer.chunks_exact(512).enumerate() {
+        assert!(data.iter().all(|&byte| usize::from(byte) == lba + 1));
+    }
+}
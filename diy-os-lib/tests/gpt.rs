@@ -0,0 +1,128 @@
+//! Writes a GPT to a [`RamDisk`] and reads it back.
+
+use std::sync::Arc;
+
+use diy_os::device_manager::partition::read_partion_table;
+use diy_os::device_manager::ramdisk::RamDisk;
+use diy_os::device_manager::{BlockDevice, DeviceManager};
+use diy_os::filesystem::gpt::writer::PartionTable;
+use diy_os::filesystem::gpt::{FSGuid, HeaderCopy, PartionTableHeader, PartitionEntry};
+use diy_os::multitasking::mutex::Mutex;
+use zerocopy::IntoBytes;
+
+const SECTORS: usize = 16 * 2048;
+
+fn disk() -> Arc<Mutex<dyn BlockDevice>> {
+    Arc::new(Mutex::new(RamDisk::new(SECTORS, 512)))
+}
+
+fn name(entry: &PartitionEntry) -> String {
+    entry.name().unwrap().trim_end_matches('\0').to_owned()
+}
+
+/// Writes a table with a 1 MiB data partition followed by one filling the rest of the disk.
+fn write_table(disk: &Arc<Mutex<dyn BlockDevice>>) -> PartionTable {
+    let mut table = PartionTable::new(disk).unwrap();
+
+    assert_eq!(
+        table
+            .add_partion(FSGuid::MicrosoftData as u128, "data", Some(2048))
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        table
+            .add_partion(FSGuid::LinuxFilesystem as u128, "rest", None)
+            .unwrap(),
+        1
+    );
+
+    table.write(disk).unwrap();
+
+    table
+}
+
+#[test]
+fn round_trip() {
+    let disk = disk();
+    write_table(&disk);
+
+    let header = PartionTableHeader::from_device(&disk).unwrap();
+
+    assert_eq!(header.copy, HeaderCopy::Primary);
+    assert_eq!(header.lba_table_header, 1);
+    assert_eq!(header.lba_alt_table_header, SECTORS as u64 - 1);
+
+    let partions = read_partion_table(&disk).unwrap();
+
+    assert_eq!(partions.len(), 2);
+
+    let (number, data) = &partions[0];
+    assert_eq!(*number, 1);
+    assert_eq!(data.get_fs(), Ok(FSGuid::MicrosoftData));
+    assert_eq!(name(data), "data");
+    assert_eq!(data.starting_lba.get(), 2048);
+    assert_eq!(data.ending_lba.get(), 4095);
+
+    let (number, rest) = &partions[1];
+    assert_eq!(*number, 2);
+    assert_eq!(rest.get_fs(), Ok(FSGuid::LinuxFilesystem));
+    assert_eq!(name(rest), "rest");
+    assert_eq!(rest.starting_lba.get(), 4096);
+    assert_eq!(rest.ending_lba.get(), header.last_usable_logical_block);
+}
+
+#[test]
+fn from_device_loads_the_written_table() {
+    let disk = disk();
+    let written = write_table(&disk);
+
+    let read = PartionTable::from_device(&disk).unwrap();
+
+    assert_eq!(read.entries().as_bytes(), written.entries().as_bytes());
+}
+
+#[test]
+fn backup_header_is_used_when_the_primary_is_corrupt() {
+    let disk = disk();
+    let written = write_table(&disk);
+    let primary = PartionTableHeader::from_device(&disk).unwrap();
+
+    disk.acquire().write_sectors(1, 1, &[0; 512]).unwrap();
+
+    let backup = PartionTableHeader::from_device(&disk).unwrap();
+
+    assert_eq!(backup.copy, HeaderCopy::Backup);
+    assert_eq!(backup.lba_table_header, SECTORS as u64 - 1);
+    assert_eq!(backup.lba_alt_table_header, 1);
+    assert_eq!(backup.disk_guid, primary.disk_guid);
+    assert_eq!(
+        backup.read_entries(&disk).unwrap().as_bytes(),
+        written.entries().as_bytes()
+    );
+    assert_eq!(read_partion_table(&disk).unwrap().len(), 2);
+}
+
+#[test]
+fn scanned_partitions_are_children_of_the_disk() {
+    let disk = disk();
+    write_table(&disk);
+
+    let mut dm = DeviceManager {
+        devices: Vec::new(),
+        block_devices: vec![disk],
+        partitions: Vec::new(),
+        pci_devices: Vec::new(),
+    };
+
+    dm.cache_block_devices();
+    dm.scan_partitions();
+
+    let cached = dm.block_devices[0].clone();
+
+    assert_eq!(dm.partitions_of(&cached).count(), 2);
+
+    // the cached ramdisk then both partitions
+    let children = cached.acquire().children().unwrap().count();
+    assert_eq!(children, 3);
+}
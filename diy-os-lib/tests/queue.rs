@@ -0,0 +1,172 @@
+//! Services a [`RequestQueue`] in front of a [`RamDisk`] that records every transfer.
+
+use std::sync::Arc;
+
+use diy_os::device_manager::queue::{Operation, RequestQueue};
+use diy_os::device_manager::ramdisk::RamDisk;
+use diy_os::device_manager::{BlockDevice, BlockDeviceError, Device};
+use diy_os::multitasking::mutex::Mutex;
+
+/// A transfer that reached the disk.
+type Transfer = (Operation, u64, u16);
+
+#[derive(Debug)]
+struct Recorder {
+    disk: RamDisk,
+    transfers: Vec<Transfer>,
+}
+
+impl Device for Recorder {
+    fn children(&self) -> Option<Box<dyn Iterator<Item = Arc<Mutex<dyn Device>>>>> {
+        None
+    }
+}
+
+impl BlockDevice for Recorder {
+    fn read_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.transfers.push((Operation::Read, lba, count));
+        self.disk.read_sectors(lba, count, buffer)
+    }
+
+    fn write_sectors(
+        &mut self,
+        lba: u64,
+        count: u16,
+        buffer: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.transfers.push((Operation::Write, lba, count));
+        self.disk.write_sectors(lba, count, buffer)
+    }
+
+    fn total_sectors(&self) -> u64 {
+        self.disk.total_sectors()
+    }
+}
+
+fn setup() -> (Arc<Mutex<Recorder>>, Arc<RequestQueue>) {
+    let recorder = Arc::new(Mutex::new(Recorder {
+        disk: RamDisk::new(128, 512),
+        transfers: Vec::new(),
+    }));
+
+    let queue = RequestQueue::new(recorder.clone());
+
+    (recorder, queue)
+}
+
+fn service(queue: &RequestQueue) {
+    while queue.service() {}
+}
+
+fn transfers(recorder: &Mutex<Recorder>) -> Vec<Transfer> {
+    recorder.with_mut_ref(|recorder| core::mem::take(&mut recorder.transfers))
+}
+
+#[test]
+fn elevator_order() {
+    let (recorder, queue) = setup();
+
+    for lba in [50, 10, 70, 30] {
+        queue
+            .read_then(lba, 1, |result| drop(result.unwrap()))
+            .unwrap();
+    }
+
+    service(&queue);
+
+    assert_eq!(
+        transfers(&recorder),
+        [10, 30, 50, 70].map(|lba| (Operation::Read, lba, 1))
+    );
+
+    // it keeps going up from 71 before turning around
+    for lba in [60, 80] {
+        queue
+            .read_then(lba, 1, |result| drop(result.unwrap()))
+            .unwrap();
+    }
+
+    service(&queue);
+
+    assert_eq!(
+        transfers(&recorder),
+        [80, 60].map(|lba| (Operation::Read, lba, 1))
+    );
+}
+
+#[test]
+fn merges_adjacent_requests() {
+    let (recorder, queue) = setup();
+
+    let writes: Vec<_> = [6, 4, 5]
+        .into_iter()
+        .map(|lba| {
+            let data = vec![u8::try_from(lba).unwrap(); 512];
+
+            queue.write(lba, 1, data).unwrap()
+        })
+        .collect();
+    // a read isn't merged with writes
+    let read = queue.read(7, 1).unwrap();
+
+    service(&queue);
+
+    assert_eq!(
+        transfers(&recorder),
+        [(Operation::Write, 4, 3), (Operation::Read, 7, 1)]
+    );
+
+    let stats = queue.stats();
+    assert_eq!(stats.submitted, 4);
+    assert_eq!(stats.merged, 2);
+    assert_eq!(stats.transfers, 2);
+
+    // each request gets its own part of the transfer back
+    for (write, lba) in writes.into_iter().zip([6, 4, 5]) {
+        assert_eq!(write.wait().unwrap(), [lba; 512]);
+    }
+    assert_eq!(read.wait().unwrap(), [0; 512]);
+
+    let bytes = recorder.with_ref(|recorder| recorder.disk.as_bytes()[4 * 512..7 * 512].to_vec());
+    assert_eq!(bytes, [[4; 512], [5; 512], [6; 512]].concat());
+}
+
+#[test]
+fn read_before_overlapping_write() {
+    let (recorder, queue) = setup();
+
+    let read = queue.read(30, 1).unwrap();
+    // the elevator would get to 29 first, but the write overlaps the earlier read
+    let write = queue.write(29, 2, vec![0xAA; 1024]).unwrap();
+
+    service(&queue);
+
+    assert_eq!(
+        transfers(&recorder),
+        [(Operation::Read, 30, 1), (Operation::Write, 29, 2)]
+    );
+    assert_eq!(read.wait().unwrap(), [0; 512]);
+    write.wait().unwrap();
+}
+
+#[test]
+fn write_before_overlapping_read() {
+    let (recorder, queue) = setup();
+
+    let write = queue.write(40, 1, vec![0xBB; 512]).unwrap();
+    let read = queue.read(39, 2).unwrap();
+
+    service(&queue);
+
+    assert_eq!(
+        transfers(&recorder),
+        [(Operation::Write, 40, 1), (Operation::Read, 39, 2)]
+    );
+    write.wait().unwrap();
+    assert_eq!(read.wait().unwrap(), [[0; 512], [0xBB; 512]].concat());
+}
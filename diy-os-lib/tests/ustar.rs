@@ -0,0 +1,180 @@
+//! Opens files through [`Ustar`] in archives built with the `tar` crate.
+
+use diy_os::filesystem::ustar::Ustar;
+use diy_os::filesystem::{FileSystem, MountError};
+use tar::{Builder, EntryType, Header};
+
+/// Builds an archive in memory, leaked since [`Ustar`] borrows it for the life of the kernel.
+#[derive(Default)]
+struct Archive(Vec<(EntryType, String, Vec<u8>, Option<String>)>);
+
+impl Archive {
+    fn file(mut self, path: &str, data: &[u8]) -> Self {
+        self.0
+            .push((EntryType::Regular, path.to_owned(), data.to_vec(), None));
+        self
+    }
+
+    fn directory(mut self, path: &str) -> Self {
+        self.0
+            .push((EntryType::Directory, path.to_owned(), Vec::new(), None));
+        self
+    }
+
+    fn link(mut self, kind: EntryType, path: &str, target: &str) -> Self {
+        self.0
+            .push((kind, path.to_owned(), Vec::new(), Some(target.to_owned())));
+        self
+    }
+
+    fn build(self) -> &'static mut [u8] {
+        let mut builder = Builder::new(Vec::new());
+
+        for (kind, path, data, target) in self.0 {
+            let mut header = Header::new_ustar();
+            header.set_entry_type(kind);
+            header.set_path(&path).unwrap();
+            header.set_size(data.len() as u64);
+            header.set_mode(if kind.is_dir() { 0o755 } else { 0o644 });
+
+            if let Some(target) = target {
+                header.set_link_name(&target).unwrap();
+            }
+
+            header.set_cksum();
+            builder.append(&header, data.as_slice()).unwrap();
+        }
+
+        builder.into_inner().unwrap().leak()
+    }
+
+    fn mount(self) -> Ustar {
+        Ustar::new(self.build()).unwrap()
+    }
+}
+
+fn read(fs: &mut Ustar, path: &str) -> Option<Vec<u8>> {
+    let file = fs.open(path)?;
+
+    let mut buffer = vec![0; 4096];
+    let read = file.read(&mut buffer).unwrap();
+    buffer.truncate(read);
+
+    Some(buffer)
+}
+
+#[test]
+fn multi_block_files() {
+    let large: Vec<u8> = (0..1300u16).map(|i| i.to_le_bytes()[0]).collect();
+
+    let mut fs = Archive::default()
+        .file("large.bin", &large)
+        .file("after.txt", b"after the padding")
+        .mount();
+
+    assert_eq!(read(&mut fs, "large.bin").unwrap(), large);
+    assert_eq!(read(&mut fs, "after.txt").unwrap(), b"after the padding");
+    assert_eq!(fs.get_files().len(), 2);
+}
+
+#[test]
+fn directories() {
+    let mut fs = Archive::default()
+        .directory("docs/")
+        .file("docs/readme.txt", b"read me")
+        // no entry for `src`
+        .file("src/main.rs", b"fn main() {}")
+        .mount();
+
+    assert_eq!(read(&mut fs, "/docs/readme.txt").unwrap(), b"read me");
+    assert_eq!(read(&mut fs, "docs/./readme.txt").unwrap(), b"read me");
+    assert_eq!(
+        read(&mut fs, "src/../src/main.rs").unwrap(),
+        b"fn main() {}"
+    );
+
+    // directories have no data to read
+    assert!(read(&mut fs, "docs").is_none());
+    assert!(read(&mut fs, "docs/missing.txt").is_none());
+}
+
+#[test]
+fn long_paths_use_the_prefix() {
+    let path = format!("{}/file.txt", ["nested"; 20].join("/"));
+    assert!(path.len() > 100);
+
+    let mut fs = Archive::default().file(&path, b"deep").mount();
+
+    assert_eq!(read(&mut fs, &path).unwrap(), b"deep");
+}
+
+#[test]
+fn symlinks() {
+    let mut fs = Archive::default()
+        .file("docs/readme.txt", b"read me")
+        .link(EntryType::Symlink, "relative", "docs")
+        .link(EntryType::Symlink, "absolute", "/docs/readme.txt")
+        .link(EntryType::Symlink, "docs/up", "../docs")
+        .link(EntryType::Symlink, "chain", "relative")
+        .mount();
+
+    assert_eq!(read(&mut fs, "relative/readme.txt").unwrap(), b"read me");
+    assert_eq!(read(&mut fs, "absolute").unwrap(), b"read me");
+    assert_eq!(read(&mut fs, "docs/up/readme.txt").unwrap(), b"read me");
+    assert_eq!(read(&mut fs, "chain/readme.txt").unwrap(), b"read me");
+}
+
+#[test]
+fn symlink_loops_are_not_followed_forever() {
+    let mut fs = Archive::default()
+        .link(EntryType::Symlink, "a", "b")
+        .link(EntryType::Symlink, "b", "a")
+        .mount();
+
+    assert!(read(&mut fs, "a").is_none());
+}
+
+#[test]
+fn hard_links() {
+    let mut fs = Archive::default()
+        .file("original.txt", b"shared")
+        .link(EntryType::Link, "linked.txt", "./original.txt")
+        .mount();
+
+    assert_eq!(read(&mut fs, "linked.txt").unwrap(), b"shared");
+}
+
+#[test]
+fn later_entries_replace_earlier_ones() {
+    let mut fs = Archive::default()
+        .file("file.txt", b"old")
+        .file("file.txt", b"new")
+        .mount();
+
+    assert_eq!(read(&mut fs, "file.txt").unwrap(), b"new");
+}
+
+#[test]
+fn checksum_mismatch() {
+    let archive = Archive::default().file("file.txt", b"contents").build();
+
+    // a byte of the name
+    archive[0] ^= 0xFF;
+
+    assert!(matches!(
+        Ustar::new(archive),
+        Err(MountError::InvalidFileSystem("Header checksum mismatch"))
+    ));
+}
+
+#[test]
+fn truncated_archive() {
+    let archive: &'static [u8] = Archive::default().file("file.txt", &[1; 1024]).build();
+
+    assert!(matches!(
+        Ustar::new(&archive[..1024]),
+        Err(MountError::InvalidFileSystem(
+            "File runs past the end of the archive"
+        ))
+    ));
+}
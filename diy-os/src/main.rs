@@ -8,7 +8,6 @@
 #![test_runner(diy_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-use zerocopy::FromBytes;
 extern crate alloc;
 
 use alloc::{boxed::Box, string::String, sync::Arc};
@@ -19,10 +18,10 @@ use bootloader_api::{
 };
 use core::panic::PanicInfo;
 use diy_os::{
-    device_manager::{self, BlockDevice},
+    device_manager::{self, BlockDevice, DeviceManager, cache, queue},
     filesystem::{
-        FileSystem, FileSystemSetupError, VFS,
-        gpt::{self, PartionTableHeader, PartitionEntry},
+        FileSystem, FileSystemSetupError, VFS, devfs::DevFs, gpt, procfs::ProcFs, tmpfs::Tmpfs,
+        ustar::Ustar,
     },
     human_input_devices::{STDIN, process_keys},
     kernel_early,
@@ -32,7 +31,9 @@ use diy_os::{
     ps2::devices::ps2_device_1_task,
     timer::{Duration, Miliseconds, Seconds, TIME_KEEPER},
 };
+use ext2::ext2_setup;
 use fat16_read_only::fat_setup;
+use iso9660::iso9660_setup;
 use log::{Level, info, trace};
 use qemu_exit::QEMUExit;
 use refine::Refined;
@@ -65,7 +66,7 @@ extern "Rust" fn main_wrapper(boot_info: &'static mut BootInfo) -> ! {
 #[unsafe(no_mangle)]
 extern "Rust" fn main(boot_info: &'static mut BootInfo) -> anyhow::Result<!> {
     let frequency = refine_const!(1000u32, PitFrequency);
-    let (_boot_info, mut frame_allocator, mut mapper) = kernel_early(boot_info, frequency)?;
+    let (boot_info, mut frame_allocator, mut mapper) = kernel_early(boot_info, frequency)?;
 
     info!("start_address {:X}", 0x0000_0000_0804_aff8);
     info!("start_address {:X}", 0x0000_0000_0804_aff8 + 4000 * 3);
@@ -76,15 +77,49 @@ extern "Rust" fn main(boot_info: &'static mut BootInfo) -> anyhow::Result<!> {
 
     println!("Hello, world!");
 
-    let device_manager = device_manager::init_device_manager()?;
+    let device_manager = Arc::new(device_manager::init_device_manager(
+        &mut mapper,
+        &mut frame_allocator,
+    ));
 
     // hardcoded for now
     let device = device_manager.block_devices[1].clone();
 
-    let fs = setup_filesystem(&device)?;
+    let fs = setup_filesystem(&device_manager, &device)?;
 
     let mut vfs = VFS::new(fs);
 
+    if let Some(ramdisk) = ramdisk(boot_info) {
+        let initrd = Ustar::new(ramdisk)?;
+
+        info!(
+            "Mounting the ramdisk with {} entries at /initrd",
+            initrd.get_files().len()
+        );
+
+        vfs.mount("/initrd", Box::new(initrd));
+    }
+
+    // the first disc that mounts, cds have 2048 byte sectors
+    if let Some(cdrom) = device_manager
+        .block_devices
+        .iter()
+        .filter(|device| device.acquire().sector_size() == 2048)
+        .find_map(|device| {
+            iso9660_setup(device.clone())
+                .inspect_err(|err| log::warn!("failed to mount cd, {err}"))
+                .ok()
+        })
+    {
+        info!("Mounting the cd at /cdrom");
+
+        vfs.mount("/cdrom", cdrom);
+    }
+
+    vfs.mount("/tmp", Box::new(Tmpfs::new(Tmpfs::DEFAULT_CAPACITY)));
+    vfs.mount("/dev", Box::new(DevFs::new(&device_manager)));
+    vfs.mount("/proc", Box::new(ProcFs::new(device_manager.clone())));
+
     let file = vfs.open("/door/ads.txt").unwrap();
 
     let mut buf = [0u8; 100];
@@ -97,22 +132,6 @@ extern "Rust" fn main(boot_info: &'static mut BootInfo) -> anyhow::Result<!> {
     //
     // panic!("exit");
 
-    // for i in 0..=255 {
-    //     for j in 0..32 {
-    //         if let Some(dev_info) = diy_os::pci::get_info(i, j, 0) {
-    //             if dev_info.header_type.multi_func() {
-    //                 for k in 1..=7 {
-    //                     let device = diy_os::pci::get_info(i, j, k);
-    //                     println!("dev_info func {k}: {device:#X?}");
-    //                 }
-    //             }
-    //             println!("dev_info: {dev_info:#X?}");
-    //         }
-    //     }
-    // }
-
-    // let's just say we detected that bus 0 device 1 function 1 was the ide controller
-
     setup_tasks(&mut mapper, &mut frame_allocator)?;
 }
 
@@ -158,6 +177,22 @@ fn setup_tasks(
         frame_allocator,
     );
 
+    // # SAFETY: flusher_task sleeps once per loop
+    let flusher_task = Task::new(
+        String::from("Block cache flusher"),
+        cache::flusher_task,
+        mapper,
+        frame_allocator,
+    );
+
+    // # SAFETY: io_task blocks while there are no requests
+    let io_task = Task::new(
+        String::from("Block io"),
+        queue::io_task,
+        mapper,
+        frame_allocator,
+    );
+
     // let fat32_driver = Task::new(
     //     String::from("fat32 driver"),
     //     wrapper,
@@ -171,6 +206,8 @@ fn setup_tasks(
         let ps2_task = scheduler.spawn_task(ps2_task);
         let keys_task = scheduler.spawn_task(keys_task);
         let shell_task = scheduler.spawn_task(shell_task);
+        let _ = scheduler.spawn_task(flusher_task);
+        let _ = scheduler.spawn_task(io_task);
         // let _ = scheduler.spawn_task(fat32_driver);
         // let _ = scheduler.spawn_task(ide);
 
@@ -257,7 +294,15 @@ fn kernal_shell() -> ! {
                                 println!("time_since_boot: {}", time_keeper.time_since_boot.time);
                             });
                         }
+                        "SYNC" => match cache::sync_all() {
+                            Ok(()) => println!("synced the block caches"),
+                            Err(err) => println!("failed to sync the block caches, {err}"),
+                        },
                         "QUIT" | "EXIT" => {
+                            if let Err(err) = cache::sync_all() {
+                                println!("failed to sync the block caches, {err}");
+                            }
+
                             let exit_handle = qemu_exit::X86::new(0xf4, 3);
 
                             exit_handle.exit_success();
@@ -283,59 +328,60 @@ fn rsp() -> u64 {
 
     rsp
 }
-/// Reads the GPT from `device`, parses the partition table, and mounts the
-/// filesystem on the first partition.
+
+/// Returns the ramdisk loaded by the bootloader, if one was passed.
+fn ramdisk(boot_info: &'static BootInfo) -> Option<&'static [u8]> {
+    let addr = usize::try_from(boot_info.ramdisk_addr.into_option()?).unwrap();
+    let len = usize::try_from(boot_info.ramdisk_len).unwrap();
+
+    // SAFETY: the bootloader maps the ramdisk for the lifetime of the kernel and nothing else
+    // writes to it
+    Some(unsafe { core::slice::from_raw_parts(core::ptr::with_exposed_provenance(addr), len) })
+}
+
+/// Mounts the filesystem on the first partition of `disk`.
 ///
 /// Returns a boxed [`FileSystem`] for the mounted partition.
 ///
 /// # Errors
 ///
 /// Returns [`FileSystemSetupError`] if:
-/// - the GPT header is missing, corrupt, or fails CRC validation
+/// - the disk has no partitions, including when its partition table is invalid
 /// - the device read fails
+/// - there is no driver for the partition's type
 /// - the partition's filesystem driver fails to mount
 ///
 pub fn setup_filesystem(
-    device: &Arc<Mutex<dyn BlockDevice>>,
+    device_manager: &DeviceManager,
+    disk: &Arc<Mutex<dyn BlockDevice>>,
 ) -> Result<Box<dyn FileSystem>, FileSystemSetupError> {
-    let header = PartionTableHeader::from_device(device)?;
-
-    // Currently only 128 sized partition entries are implemented
-    assert_eq!(128, header.size_of_partion_entry);
-
-    let array_size = usize::try_from(header.size_of_partion_entry)
-        .unwrap()
-        .checked_mul(usize::try_from(header.num_of_partions).unwrap())
-        .unwrap();
-
-    let mut buffer = alloc::vec![0u8; array_size];
-
-    {
-        let mut drive = device.acquire();
-
-        let sector_size = drive.sector_size();
-
-        drive.read_sectors(
-            header.partion_entry_lba,
-            u8::try_from(array_size.div_ceil(sector_size)).unwrap(),
-            &mut buffer,
-        )?;
+    for partion in device_manager.partitions_of(disk) {
+        let partion = partion.acquire();
+        let entry = partion.entry();
+        let name = entry.name().unwrap();
+
+        log::debug!(
+            "partion {name} of type {}, attributes {:?}, partion {:?}",
+            entry.type_name(),
+            entry.attributes(),
+            entry
+        );
     }
 
-    let entries = <[PartitionEntry]>::ref_from_bytes(&buffer).unwrap();
-
-    for partion in entries {
-        if partion.partion_type_guid.get() != 0 {
-            let name = partion.name().unwrap();
+    let partion = device_manager
+        .partitions_of(disk)
+        .next()
+        .ok_or(FileSystemSetupError::NoPartitions)?;
 
-            log::debug!("partion {name}, partion {:?}", partion);
-        }
-    }
+    let entry = *partion.acquire().entry();
+    let partion = partion.clone() as Arc<Mutex<dyn BlockDevice>>;
 
-    match entries[0].get_fs().unwrap() {
-        gpt::FSGuid::SimpleFileSystem => todo!(),
-        gpt::FSGuid::MicrosoftData => Ok(fat_setup(device.clone(), &entries[0])?),
-        _ => todo!(),
+    match entry.get_fs() {
+        Ok(gpt::FSGuid::MicrosoftData | gpt::FSGuid::EfiSystem) => Ok(fat_setup(partion)?),
+        Ok(gpt::FSGuid::LinuxFilesystem | gpt::FSGuid::LinuxRootX86_64) => Ok(ext2_setup(partion)?),
+        _ => Err(FileSystemSetupError::UnsupportedPartionType(
+            entry.type_name(),
+        )),
     }
 }
 
@@ -0,0 +1,656 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use diy_os::device_manager::{BlockDevice, BlockDeviceError};
+use diy_os::filesystem::{FileSystem, FileTrait, INError, MountError, OUTError};
+use diy_os::multitasking::mutex::Mutex;
+use zerocopy::{FromBytes, FromZeros, IntoBytes, little_endian::U32};
+
+use crate::structs::{
+    BlockGroupDescriptor, DIRECT_BLOCKS, DOUBLY_INDIRECT_BLOCK, DirectoryEntryHeader,
+    IncompatFeatures, Inode, InodeType, ROOT_INODE, RoCompatFeatures, SINGLY_INDIRECT_BLOCK,
+    SUPERBLOCK_OFFSET, Superblock, TRIPLY_INDIRECT_BLOCK,
+};
+
+/// Symbolic links are followed at most this many times while resolving a path.
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+/// Symbolic links with a target shorter then this are stored in the inode's block pointers.
+const FAST_SYMLINK_MAX: u64 = 60;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Ext2Error {
+    #[error("The underlaying block device experienced an error")]
+    BlockDeviceError(#[from] BlockDeviceError),
+    #[error("The filesystem is corrupted, `{0}`")]
+    Corrupted(&'static str),
+    #[error("Too many levels of symbolic links")]
+    SymlinkLoop,
+    #[error("The filesystem is mounted read only")]
+    ReadOnly,
+    #[error("There are no free blocks left")]
+    NoSpace,
+    #[error("The file can't grow past the maximum file size")]
+    FileTooLarge,
+}
+
+impl From<Ext2Error> for MountError {
+    fn from(value: Ext2Error) -> Self {
+        match value {
+            Ext2Error::BlockDeviceError(err) => Self::BlockDeviceError(err),
+            Ext2Error::Corrupted(reason) => Self::InvalidFileSystem(reason),
+            _ => Self::InvalidFileSystem("Unexpected error while mounting"),
+        }
+    }
+}
+
+/// Where a block of a file is found, the slot in the inode and the index into each level of
+/// indirect blocks.
+#[derive(Debug, Clone, Copy)]
+struct BlockPath {
+    slot: usize,
+    indices: [u64; 3],
+    depth: usize,
+}
+
+pub struct Ext2FS {
+    drive: Arc<Mutex<dyn BlockDevice>>,
+    superblock: Superblock,
+    groups: Vec<BlockGroupDescriptor>,
+    read_only: bool,
+}
+
+impl Ext2FS {
+    /// Reads and validates the superblock and block group descriptor table.
+    ///
+    /// The filesystem is mounted read only if it was not cleanly unmounted or uses a read
+    /// only compatible feature that is not implemented.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MountError`] if a read fails, the superblock is invalid, or the filesystem uses
+    /// an unsupported incompatible feature.
+    pub fn new(drive: Arc<Mutex<dyn BlockDevice>>) -> Result<Self, MountError> {
+        let mut fs = Self {
+            drive,
+            superblock: Superblock::new_zeroed(),
+            groups: Vec::new(),
+            read_only: true,
+        };
+
+        let mut superblock = Superblock::new_zeroed();
+        fs.read_bytes(SUPERBLOCK_OFFSET, superblock.as_mut_bytes())?;
+
+        superblock
+            .validate()
+            .map_err(MountError::InvalidFileSystem)?;
+
+        if !IncompatFeatures::FileType.contains(superblock.incompat()) {
+            return Err(MountError::InvalidFileSystem(
+                "Uses unsupported incompatible features",
+            ));
+        }
+
+        let unsupported_ro_compat = !(RoCompatFeatures::SparseSuper | RoCompatFeatures::LargeFile)
+            .contains(superblock.ro_compat());
+        let unclean = superblock.state.get() & Superblock::STATE_VALID == 0;
+
+        if unsupported_ro_compat {
+            log::warn!("ext2 uses unsupported read only features, mounting read only");
+        }
+
+        if unclean {
+            log::warn!("ext2 was not cleanly unmounted, mounting read only");
+        }
+
+        log::debug!("ext2 superblock: {superblock:?}");
+
+        fs.superblock = superblock;
+        fs.read_only = unsupported_ro_compat || unclean;
+
+        let group_count = usize::try_from(superblock.group_count()).unwrap();
+        let mut groups = vec![BlockGroupDescriptor::new_zeroed(); group_count];
+        fs.read_bytes(fs.group_table_offset(), groups.as_mut_bytes())?;
+        fs.groups = groups;
+
+        let root = fs.read_inode(ROOT_INODE)?;
+        if root.file_type() != InodeType::Directory {
+            return Err(MountError::InvalidFileSystem(
+                "Root inode is not a directory",
+            ));
+        }
+
+        Ok(fs)
+    }
+
+    const fn block_size(&self) -> u64 {
+        self.superblock.block_size() as u64
+    }
+
+    /// The group descriptor table starts in the block after the superblock.
+    const fn group_table_offset(&self) -> u64 {
+        (self.superblock.first_data_block.get() as u64 + 1) * self.block_size()
+    }
+
+    /// Reads `buffer.len()` bytes starting `offset` bytes into the volume.
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let mut drive = self.drive.acquire();
+        let sector_size = drive.sector_size();
+        let sector_size_u64 = u64::try_from(sector_size).unwrap();
+
+        let first_sector = offset / sector_size_u64;
+        let skip = usize::try_from(offset % sector_size_u64).unwrap();
+        let sectors = (skip + buffer.len()).div_ceil(sector_size);
+
+        let mut sector_buffer = vec![0u8; sector_size * sectors];
+
+        for (lba, chunk) in (first_sector..)
+            .step_by(usize::from(u16::MAX))
+            .zip(sector_buffer.chunks_mut(sector_size * usize::from(u16::MAX)))
+        {
+            drive.read_sectors(
+                lba,
+                u16::try_from(chunk.len() / sector_size).unwrap(),
+                chunk,
+            )?;
+        }
+
+        buffer.copy_from_slice(&sector_buffer[skip..skip + buffer.len()]);
+
+        Ok(())
+    }
+
+    /// Writes `buffer` starting `offset` bytes into the volume, preserving the rest of any
+    /// partially written sector.
+    fn write_bytes(&self, offset: u64, buffer: &[u8]) -> Result<(), BlockDeviceError> {
+        let sector_size = self.drive.acquire().sector_size();
+        let sector_size_u64 = u64::try_from(sector_size).unwrap();
+
+        let first_sector = offset / sector_size_u64;
+        let skip = usize::try_from(offset % sector_size_u64).unwrap();
+        let sectors = (skip + buffer.len()).div_ceil(sector_size);
+
+        let mut sector_buffer = vec![0u8; sector_size * sectors];
+
+        if skip != 0 || !buffer.len().is_multiple_of(sector_size) {
+            self.read_bytes(first_sector * sector_size_u64, &mut sector_buffer)?;
+        }
+
+        sector_buffer[skip..skip + buffer.len()].copy_from_slice(buffer);
+
+        let mut drive = self.drive.acquire();
+
+        for (lba, chunk) in (first_sector..)
+            .step_by(usize::from(u16::MAX))
+            .zip(sector_buffer.chunks(sector_size * usize::from(u16::MAX)))
+        {
+            drive.write_sectors(
+                lba,
+                u16::try_from(chunk.len() / sector_size).unwrap(),
+                chunk,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn inode_offset(&self, inode: u32) -> Result<u64, Ext2Error> {
+        if inode == 0 || inode > self.superblock.inodes_count.get() {
+            return Err(Ext2Error::Corrupted("Inode number is out of range"));
+        }
+
+        let inodes_per_group = self.superblock.inodes_per_group.get();
+        let group = usize::try_from((inode - 1) / inodes_per_group).unwrap();
+        let index = u64::from((inode - 1) % inodes_per_group);
+
+        let table = self
+            .groups
+            .get(group)
+            .ok_or(Ext2Error::Corrupted("Inode is in a missing block group"))?
+            .inode_table
+            .get();
+
+        Ok(u64::from(table) * self.block_size() + index * u64::from(self.superblock.inode_size()))
+    }
+
+    fn read_inode(&self, inode: u32) -> Result<Inode, Ext2Error> {
+        let mut raw = Inode::new_zeroed();
+        self.read_bytes(self.inode_offset(inode)?, raw.as_mut_bytes())?;
+
+        Ok(raw)
+    }
+
+    fn write_inode(&self, inode: u32, raw: &Inode) -> Result<(), Ext2Error> {
+        self.write_bytes(self.inode_offset(inode)?, raw.as_bytes())?;
+
+        Ok(())
+    }
+
+    const fn pointers_per_block(&self) -> u64 {
+        self.block_size() / size_of::<u32>() as u64
+    }
+
+    /// Finds which inode slot and indirect block indices hold `file_block`.
+    ///
+    /// Returns None if the block is past what the triply indirect block can address.
+    fn block_path(&self, file_block: u64) -> Option<BlockPath> {
+        let per_block = self.pointers_per_block();
+        let direct = DIRECT_BLOCKS as u64;
+
+        if file_block < direct {
+            return Some(BlockPath {
+                slot: usize::try_from(file_block).unwrap(),
+                indices: [0; 3],
+                depth: 0,
+            });
+        }
+
+        let file_block = file_block - direct;
+        if file_block < per_block {
+            return Some(BlockPath {
+                slot: SINGLY_INDIRECT_BLOCK,
+                indices: [file_block, 0, 0],
+                depth: 1,
+            });
+        }
+
+        let file_block = file_block - per_block;
+        if file_block < per_block.pow(2) {
+            return Some(BlockPath {
+                slot: DOUBLY_INDIRECT_BLOCK,
+                indices: [file_block / per_block, file_block % per_block, 0],
+                depth: 2,
+            });
+        }
+
+        let file_block = file_block - per_block.pow(2);
+        if file_block < per_block.pow(3) {
+            return Some(BlockPath {
+                slot: TRIPLY_INDIRECT_BLOCK,
+                indices: [
+                    file_block / per_block.pow(2),
+                    (file_block / per_block) % per_block,
+                    file_block % per_block,
+                ],
+                depth: 3,
+            });
+        }
+
+        None
+    }
+
+    fn read_pointer(&self, block: u32, index: u64) -> Result<u32, Ext2Error> {
+        let mut pointer = U32::ZERO;
+        self.read_bytes(
+            u64::from(block) * self.block_size() + index * size_of::<u32>() as u64,
+            pointer.as_mut_bytes(),
+        )?;
+
+        Ok(pointer.get())
+    }
+
+    fn write_pointer(&self, block: u32, index: u64, pointer: u32) -> Result<(), Ext2Error> {
+        self.write_bytes(
+            u64::from(block) * self.block_size() + index * size_of::<u32>() as u64,
+            U32::new(pointer).as_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Maps a block of a file to a block of the volume, zero if the block is a hole.
+    fn map_block(&self, inode: &Inode, file_block: u64) -> Result<u32, Ext2Error> {
+        let path = self.block_path(file_block).ok_or(Ext2Error::Corrupted(
+            "File block is past the maximum file size",
+        ))?;
+
+        let mut block = inode.block[path.slot].get();
+
+        for index in &path.indices[..path.depth] {
+            if block == 0 {
+                break;
+            }
+
+            block = self.read_pointer(block, *index)?;
+        }
+
+        if block >= self.superblock.blocks_count.get() {
+            return Err(Ext2Error::Corrupted("Block pointer is out of range"));
+        }
+
+        Ok(block)
+    }
+
+    /// Reads the file into `buffer` starting at `offset`, holes are read as zeros.
+    ///
+    /// Returns the number of bytes read.
+    fn read_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, Ext2Error> {
+        let remaining = inode.size().saturating_sub(offset);
+        let length = usize::try_from(remaining)
+            .map_or(buffer.len(), |remaining| remaining.min(buffer.len()));
+
+        let block_size = self.block_size();
+        let mut read = 0;
+
+        while read < length {
+            let position = offset + u64::try_from(read).unwrap();
+            let in_block = position % block_size;
+            let chunk_length = usize::try_from(block_size - in_block)
+                .unwrap()
+                .min(length - read);
+            let chunk = &mut buffer[read..read + chunk_length];
+
+            match self.map_block(inode, position / block_size)? {
+                0 => chunk.fill(0),
+                block => self.read_bytes(u64::from(block) * block_size + in_block, chunk)?,
+            }
+
+            read += chunk_length;
+        }
+
+        Ok(read)
+    }
+
+    /// Searches a directory for `name`, returning the inode number of the entry.
+    fn find_entry(&self, directory: &Inode, name: &str) -> Result<Option<u32>, Ext2Error> {
+        let mut data = vec![0u8; usize::try_from(directory.size()).unwrap()];
+        let length = self.read_data(directory, 0, &mut data)?;
+        let mut offset = 0;
+
+        while offset < length {
+            let (header, rest) = DirectoryEntryHeader::ref_from_prefix(&data[offset..length])
+                .map_err(|_| Ext2Error::Corrupted("Truncated directory entry"))?;
+
+            let record_length = usize::from(header.record_length.get());
+            if record_length < size_of::<DirectoryEntryHeader>() || offset + record_length > length
+            {
+                return Err(Ext2Error::Corrupted("Invalid directory record length"));
+            }
+
+            let name_length = if self
+                .superblock
+                .incompat()
+                .contains(IncompatFeatures::FileType)
+            {
+                usize::from(header.name_length)
+            } else {
+                usize::from(u16::from_le_bytes([header.name_length, header.file_type]))
+            };
+
+            let entry_name = rest
+                .get(..name_length)
+                .ok_or(Ext2Error::Corrupted("Directory entry name is truncated"))?;
+
+            if header.inode.get() != 0 && entry_name == name.as_bytes() {
+                return Ok(Some(header.inode.get()));
+            }
+
+            offset += record_length;
+        }
+
+        Ok(None)
+    }
+
+    /// Reads the target of a symbolic link.
+    fn read_link(&self, inode: &Inode) -> Result<String, Ext2Error> {
+        let size = inode.size();
+
+        // Fast symlinks have no data blocks, unless the inode has an extended attribute block
+        let acl_blocks = if inode.file_acl.get() == 0 {
+            0
+        } else {
+            self.block_size() / 512
+        };
+        let fast = size < FAST_SYMLINK_MAX && u64::from(inode.blocks.get()) == acl_blocks;
+
+        let target = if fast {
+            inode.block.as_bytes()[..usize::try_from(size).unwrap()].to_vec()
+        } else {
+            let mut target = vec![0u8; usize::try_from(size).unwrap()];
+            self.read_data(inode, 0, &mut target)?;
+            target
+        };
+
+        String::from_utf8(target).map_err(|_| Ext2Error::Corrupted("Symlink target is not utf8"))
+    }
+
+    /// Walks `path` starting from the directory `start`, following symbolic links.
+    fn walk(
+        &self,
+        start: u32,
+        path: &str,
+        depth: usize,
+    ) -> Result<Option<(u32, Inode)>, Ext2Error> {
+        let mut current = (start, self.read_inode(start)?);
+
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            if current.1.file_type() != InodeType::Directory {
+                return Ok(None);
+            }
+
+            let directory = current.0;
+
+            let Some(child) = self.find_entry(&current.1, component)? else {
+                return Ok(None);
+            };
+
+            let inode = self.read_inode(child)?;
+
+            if inode.file_type() == InodeType::SymbolicLink {
+                if depth >= MAX_SYMLINK_DEPTH {
+                    return Err(Ext2Error::SymlinkLoop);
+                }
+
+                let target = self.read_link(&inode)?;
+                let base = if target.starts_with('/') {
+                    ROOT_INODE
+                } else {
+                    directory
+                };
+
+                match self.walk(base, &target, depth + 1)? {
+                    Some(resolved) => current = resolved,
+                    None => return Ok(None),
+                }
+            } else {
+                current = (child, inode);
+            }
+        }
+
+        Ok(Some(current))
+    }
+
+    fn lookup(&self, path: &str) -> Result<Option<(u32, Inode)>, Ext2Error> {
+        self.walk(ROOT_INODE, path, 0)
+    }
+
+    fn write_group(&self, group: usize) -> Result<(), Ext2Error> {
+        self.write_bytes(
+            self.group_table_offset()
+                + u64::try_from(group * size_of::<BlockGroupDescriptor>()).unwrap(),
+            self.groups[group].as_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    fn write_superblock(&self) -> Result<(), Ext2Error> {
+        self.write_bytes(SUPERBLOCK_OFFSET, self.superblock.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Allocates and zeroes a block, preferring `preferred_group`.
+    fn allocate_block(&mut self, preferred_group: usize) -> Result<u32, Ext2Error> {
+        let block_size = self.block_size();
+        let blocks_per_group = self.superblock.blocks_per_group.get();
+        let first_data_block = self.superblock.first_data_block.get();
+        let group_count = self.groups.len();
+
+        for group in (0..group_count).map(|offset| (preferred_group + offset) % group_count) {
+            if self.groups[group].free_blocks_count.get() == 0 {
+                continue;
+            }
+
+            let group_start = first_data_block + u32::try_from(group).unwrap() * blocks_per_group;
+            let blocks_in_group =
+                blocks_per_group.min(self.superblock.blocks_count.get() - group_start);
+
+            let bitmap_offset = u64::from(self.groups[group].block_bitmap.get()) * block_size;
+            let mut bitmap = vec![0u8; usize::try_from(block_size).unwrap()];
+            self.read_bytes(bitmap_offset, &mut bitmap)?;
+
+            let Some(bit) = (0..blocks_in_group)
+                .find(|bit| bitmap[usize::try_from(bit / 8).unwrap()] & (1 << (bit % 8)) == 0)
+            else {
+                continue;
+            };
+
+            let byte = usize::try_from(bit / 8).unwrap();
+            bitmap[byte] |= 1 << (bit % 8);
+            self.write_bytes(
+                bitmap_offset + u64::try_from(byte).unwrap(),
+                &bitmap[byte..=byte],
+            )?;
+
+            let descriptor = &mut self.groups[group];
+            descriptor.free_blocks_count = (descriptor.free_blocks_count.get() - 1).into();
+            self.write_group(group)?;
+
+            self.superblock.free_blocks_count =
+                (self.superblock.free_blocks_count.get().saturating_sub(1)).into();
+            self.write_superblock()?;
+
+            let block = group_start + bit;
+            self.write_bytes(
+                u64::from(block) * block_size,
+                &vec![0u8; usize::try_from(block_size).unwrap()],
+            )?;
+
+            return Ok(block);
+        }
+
+        Err(Ext2Error::NoSpace)
+    }
+
+    /// Maps a block of a file to a block of the volume, allocating it and any missing indirect
+    /// blocks.
+    fn map_block_for_write(
+        &mut self,
+        inode_number: u32,
+        inode: &mut Inode,
+        file_block: u64,
+    ) -> Result<u32, Ext2Error> {
+        let path = self.block_path(file_block).ok_or(Ext2Error::FileTooLarge)?;
+        let group =
+            usize::try_from((inode_number - 1) / self.superblock.inodes_per_group.get()).unwrap();
+        let sectors_per_block = u32::try_from(self.block_size() / 512).unwrap();
+
+        let mut block = inode.block[path.slot].get();
+        if block == 0 {
+            block = self.allocate_block(group)?;
+            inode.block[path.slot] = block.into();
+            inode.blocks = (inode.blocks.get() + sectors_per_block).into();
+        }
+
+        for index in &path.indices[..path.depth] {
+            let mut next = self.read_pointer(block, *index)?;
+
+            if next == 0 {
+                next = self.allocate_block(group)?;
+                self.write_pointer(block, *index, next)?;
+                inode.blocks = (inode.blocks.get() + sectors_per_block).into();
+            }
+
+            block = next;
+        }
+
+        Ok(block)
+    }
+
+    /// Writes `buffer` to the start of the file, growing it if needed.
+    fn write_data(
+        &mut self,
+        inode_number: u32,
+        inode: &mut Inode,
+        buffer: &[u8],
+    ) -> Result<usize, Ext2Error> {
+        if self.read_only {
+            return Err(Ext2Error::ReadOnly);
+        }
+
+        let new_size = u64::try_from(buffer.len()).unwrap();
+        if new_size > u64::from(u32::MAX)
+            && !self
+                .superblock
+                .ro_compat()
+                .contains(RoCompatFeatures::LargeFile)
+        {
+            return Err(Ext2Error::FileTooLarge);
+        }
+
+        let block_size = usize::try_from(self.block_size()).unwrap();
+
+        for (file_block, chunk) in (0u64..).zip(buffer.chunks(block_size)) {
+            let block = self.map_block_for_write(inode_number, inode, file_block)?;
+            self.write_bytes(u64::from(block) * self.block_size(), chunk)?;
+        }
+
+        if new_size > inode.size() {
+            inode.set_size(new_size);
+        }
+
+        self.write_inode(inode_number, inode)?;
+
+        Ok(buffer.len())
+    }
+}
+
+impl FileSystem for Ext2FS {
+    fn open(&mut self, path: &str) -> Option<Box<dyn FileTrait + '_>> {
+        let (inode_number, inode) = self
+            .lookup(path)
+            .inspect_err(|err| log::error!("failed to open {path}, {err}"))
+            .ok()??;
+
+        if inode.file_type() != InodeType::RegularFile {
+            return None;
+        }
+
+        Some(Box::new(Ext2File {
+            fs: self,
+            inode_number,
+            inode,
+        }))
+    }
+}
+
+struct Ext2File<'a> {
+    fs: &'a mut Ext2FS,
+    inode_number: u32,
+    inode: Inode,
+}
+
+impl FileTrait for Ext2File<'_> {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, INError> {
+        self.fs.read_data(&self.inode, 0, buf).map_err(|err| {
+            log::error!("failed to read inode {}, {err}", self.inode_number);
+            INError::NotReadable
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, OUTError> {
+        self.fs
+            .write_data(self.inode_number, &mut self.inode, buf)
+            .map_err(|err| {
+                log::error!("failed to write inode {}, {err}", self.inode_number);
+
+                match err {
+                    Ext2Error::FileTooLarge => OUTError::WriteLargerThenMaxFileSize,
+                    _ => OUTError::NotWritable,
+                }
+            })
+    }
+}
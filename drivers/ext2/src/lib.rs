@@ -0,0 +1,29 @@
+#![no_std]
+
+mod filesystem;
+mod structs;
+
+use alloc::{boxed::Box, sync::Arc};
+
+use diy_os::{
+    device_manager::BlockDevice,
+    filesystem::{FileSystem, MountError},
+    multitasking::mutex::Mutex,
+};
+
+use crate::filesystem::Ext2FS;
+
+extern crate alloc;
+
+/// Mounts the ext2 filesystem on `partion`.
+///
+/// `partion` should be a [`Partition`](diy_os::device_manager::partition::Partition) whose type
+/// is [`FSGuid::LinuxFilesystem`](diy_os::filesystem::gpt::FSGuid::LinuxFilesystem).
+///
+/// # Errors
+///
+/// Returns [`MountError`] if reading the superblock fails or the filesystem is invalid or
+/// unsupported.
+pub fn ext2_setup(partion: Arc<Mutex<dyn BlockDevice>>) -> Result<Box<dyn FileSystem>, MountError> {
+    Ok(Box::new(Ext2FS::new(partion)?))
+}
@@ -0,0 +1,276 @@
+// reference docs at https://web.archive.org/web/20250314212520/https://www.nongnu.org/ext2-doc/ext2.html
+// and https://web.archive.org/web/20250306110727/https://wiki.osdev.org/Ext2
+use bitflags::bitflags;
+use zerocopy::{
+    FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned,
+    little_endian::{U16, U32},
+};
+
+/// The superblock always starts 1024 bytes into the volume, regardless of the block size.
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+
+/// Inode number of the root directory.
+pub const ROOT_INODE: u32 = 2;
+
+/// Number of block pointers stored directly in the inode.
+pub const DIRECT_BLOCKS: usize = 12;
+pub const SINGLY_INDIRECT_BLOCK: usize = 12;
+pub const DOUBLY_INDIRECT_BLOCK: usize = 13;
+pub const TRIPLY_INDIRECT_BLOCK: usize = 14;
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct Superblock {
+    pub inodes_count: U32,
+    pub blocks_count: U32,
+    pub reserved_blocks_count: U32,
+    pub free_blocks_count: U32,
+    pub free_inodes_count: U32,
+    pub first_data_block: U32,
+    /// block size is `1024 << log_block_size`
+    pub log_block_size: U32,
+    pub log_frag_size: U32,
+    pub blocks_per_group: U32,
+    pub frags_per_group: U32,
+    pub inodes_per_group: U32,
+    pub mount_time: U32,
+    pub write_time: U32,
+    pub mount_count: U16,
+    pub max_mount_count: U16,
+    pub magic: U16,
+    pub state: U16,
+    pub errors: U16,
+    pub minor_rev_level: U16,
+    pub last_check: U32,
+    pub check_interval: U32,
+    pub creator_os: U32,
+    pub rev_level: U32,
+    pub default_reserved_uid: U16,
+    pub default_reserved_gid: U16,
+    // Fields past here are only valid if `rev_level` is dynamic
+    pub first_inode: U32,
+    pub inode_size: U16,
+    pub block_group_nr: U16,
+    pub feature_compat: U32,
+    pub feature_incompat: U32,
+    pub feature_ro_compat: U32,
+    pub uuid: [u8; 16],
+    pub volume_name: [u8; 16],
+    pub last_mounted: [u8; 64],
+    pub algorithm_usage_bitmap: U32,
+    pub prealloc_blocks: u8,
+    pub prealloc_dir_blocks: u8,
+    _alignment: U16,
+    pub journal_uuid: [u8; 16],
+    pub journal_inode: U32,
+    pub journal_device: U32,
+    pub last_orphan: U32,
+    pub hash_seed: [U32; 4],
+    pub default_hash_version: u8,
+    _padding: [u8; 3],
+    pub default_mount_options: U32,
+    pub first_meta_block_group: U32,
+    _reserved: [u8; 760],
+}
+
+impl Superblock {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 1024);
+    };
+
+    pub const MAGIC: u16 = 0xEF53;
+    /// Set when the filesystem was cleanly unmounted.
+    pub const STATE_VALID: u16 = 1;
+    pub const GOOD_OLD_REV: u32 = 0;
+    pub const GOOD_OLD_INODE_SIZE: u16 = 128;
+
+    /// Checks the fields needed to safely locate groups, inodes and blocks.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the first field that is invalid.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.magic.get() != Self::MAGIC {
+            return Err("Invalid superblock magic");
+        }
+
+        // Linux caps the block size at 64 KiB
+        if self.log_block_size.get() > 6 {
+            return Err("Block size is out of range");
+        }
+
+        if self.blocks_per_group.get() == 0 || self.inodes_per_group.get() == 0 {
+            return Err("Blocks or inodes per group is zero");
+        }
+
+        if self.first_data_block.get() >= self.blocks_count.get() {
+            return Err("First data block is past the end of the volume");
+        }
+
+        let inode_size = self.inode_size();
+        if inode_size < Self::GOOD_OLD_INODE_SIZE || !inode_size.is_power_of_two() {
+            return Err("Invalid inode size");
+        }
+
+        if usize::from(inode_size) > self.block_size() {
+            return Err("Inodes are larger then a block");
+        }
+
+        Ok(())
+    }
+
+    pub const fn block_size(&self) -> usize {
+        1024 << self.log_block_size.get()
+    }
+
+    pub const fn inode_size(&self) -> u16 {
+        if self.rev_level.get() == Self::GOOD_OLD_REV {
+            Self::GOOD_OLD_INODE_SIZE
+        } else {
+            self.inode_size.get()
+        }
+    }
+
+    pub const fn group_count(&self) -> u32 {
+        (self.blocks_count.get() - self.first_data_block.get())
+            .div_ceil(self.blocks_per_group.get())
+    }
+
+    pub const fn incompat(&self) -> IncompatFeatures {
+        IncompatFeatures::from_bits_retain(self.feature_incompat.get())
+    }
+
+    pub const fn ro_compat(&self) -> RoCompatFeatures {
+        RoCompatFeatures::from_bits_retain(self.feature_ro_compat.get())
+    }
+}
+
+bitflags! {
+    /// Features that must be understood to read the filesystem.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct IncompatFeatures: u32 {
+        const Compression = 0x0001;
+        const FileType = 0x0002;
+        const Recover = 0x0004;
+        const JournalDevice = 0x0008;
+        const MetaBlockGroups = 0x0010;
+    }
+}
+
+bitflags! {
+    /// Features that must be understood to write to the filesystem.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct RoCompatFeatures: u32 {
+        const SparseSuper = 0x0001;
+        const LargeFile = 0x0002;
+        const BtreeDirectory = 0x0004;
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct BlockGroupDescriptor {
+    pub block_bitmap: U32,
+    pub inode_bitmap: U32,
+    pub inode_table: U32,
+    pub free_blocks_count: U16,
+    pub free_inodes_count: U16,
+    pub used_dirs_count: U16,
+    _pad: U16,
+    _reserved: [u8; 12],
+}
+
+impl BlockGroupDescriptor {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 32);
+    };
+}
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct Inode {
+    pub mode: U16,
+    pub uid: U16,
+    pub size: U32,
+    pub access_time: U32,
+    pub creation_time: U32,
+    pub modification_time: U32,
+    pub deletion_time: U32,
+    pub gid: U16,
+    pub links_count: U16,
+    /// in 512 byte units, includes the indirect blocks
+    pub blocks: U32,
+    pub flags: U32,
+    pub osd1: U32,
+    pub block: [U32; 15],
+    pub generation: U32,
+    pub file_acl: U32,
+    /// the upper 32 bits of the size for regular files
+    pub dir_acl: U32,
+    pub fragment_address: U32,
+    pub osd2: [u8; 12],
+}
+
+impl Inode {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 128);
+    };
+
+    pub const fn file_type(&self) -> InodeType {
+        match self.mode.get() & 0xF000 {
+            0x1000 => InodeType::Fifo,
+            0x2000 => InodeType::CharacterDevice,
+            0x4000 => InodeType::Directory,
+            0x6000 => InodeType::BlockDevice,
+            0x8000 => InodeType::RegularFile,
+            0xA000 => InodeType::SymbolicLink,
+            0xC000 => InodeType::Socket,
+            _ => InodeType::Unknown,
+        }
+    }
+
+    pub const fn size(&self) -> u64 {
+        match self.file_type() {
+            InodeType::RegularFile => ((self.dir_acl.get() as u64) << 32) | self.size.get() as u64,
+            _ => self.size.get() as u64,
+        }
+    }
+
+    /// Splits `size` into the low and high 32 bits.
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn set_size(&mut self, size: u64) {
+        self.size = U32::new(size as u32);
+        self.dir_acl = U32::new((size >> 32) as u32);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeType {
+    Fifo,
+    CharacterDevice,
+    Directory,
+    BlockDevice,
+    RegularFile,
+    SymbolicLink,
+    Socket,
+    Unknown,
+}
+
+/// Fixed part of a directory entry, followed by `name_length` bytes of name.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct DirectoryEntryHeader {
+    /// zero if the entry is unused
+    pub inode: U32,
+    /// distance to the next entry
+    pub record_length: U16,
+    pub name_length: u8,
+    /// only valid with [`IncompatFeatures::FileType`], otherwise the high byte of the name length
+    pub file_type: u8,
+}
+
+impl DirectoryEntryHeader {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 8);
+    };
+}
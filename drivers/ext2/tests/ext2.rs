@@ -0,0 +1,245 @@
+//! Reads and writes images made with `mkfs.ext2`, checking what was written with `debugfs` and
+//! `e2fsck`.
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use diy_os::device_manager::BlockDevice;
+use diy_os::device_manager::ramdisk::RamDisk;
+use diy_os::filesystem::{FileSystem, MountError};
+use diy_os::multitasking::mutex::Mutex;
+
+const HELLO: &[u8] = b"hello from ext2\n";
+const NOTES: &[u8] = b"some notes\n";
+const DEEP: &[u8] = b"deep in the tree\n";
+
+/// Offsets of superblock fields from the start of the volume.
+const STATE: usize = 1024 + 58;
+const FEATURE_INCOMPAT: usize = 1024 + 96;
+
+/// Runs `program`, panicking if it's missing or fails, and returns its stdout.
+fn run(program: &str, args: &[&str]) -> Vec<u8> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .unwrap_or_else(|err| panic!("{program} isn't available, {err}"));
+
+    assert!(
+        output.status.success(),
+        "{program} failed, {}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    output.stdout
+}
+
+/// A directory for an image and the files put in it, removed once it's dropped even if the test
+/// failed.
+struct ImageDir(PathBuf);
+
+impl ImageDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("ext2-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        Self(dir)
+    }
+
+    fn image(&self) -> PathBuf {
+        self.0.join("disk.img")
+    }
+}
+
+impl Drop for ImageDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The contents of `large.bin`, big enough to need the doubly indirect block with 1 KiB blocks.
+fn large() -> Vec<u8> {
+    (0..300 * 1024u32).map(|i| (i % 251) as u8).collect()
+}
+
+/// A symlink target too long to be stored in the inode.
+const SLOW_TARGET: &str = "./docs/./nested/../nested/../nested/../nested/../nested/deep.txt";
+
+fn populate(root: &Path) {
+    fs::create_dir_all(root.join("docs/nested")).unwrap();
+    fs::write(root.join("hello.txt"), HELLO).unwrap();
+    fs::write(root.join("docs/notes.txt"), NOTES).unwrap();
+    fs::write(root.join("docs/nested/deep.txt"), DEEP).unwrap();
+    fs::write(root.join("large.bin"), large()).unwrap();
+
+    symlink("docs/notes.txt", root.join("fast-link")).unwrap();
+    symlink("/hello.txt", root.join("absolute-link")).unwrap();
+    symlink(SLOW_TARGET, root.join("slow-link")).unwrap();
+    symlink("..", root.join("docs/parent")).unwrap();
+    symlink("loop-b", root.join("loop-a")).unwrap();
+    symlink("loop-a", root.join("loop-b")).unwrap();
+}
+
+/// A 4 MiB image with 1 KiB blocks, returned as a [`RamDisk`] and the directory holding it.
+fn make_image(name: &str) -> (ImageDir, Arc<Mutex<RamDisk>>) {
+    let dir = ImageDir::new(name);
+
+    let root = dir.0.join("root");
+    populate(&root);
+
+    let image = dir.image();
+    run(
+        "mkfs.ext2",
+        &[
+            "-q",
+            "-F",
+            "-b",
+            "1024",
+            "-d",
+            root.to_str().unwrap(),
+            image.to_str().unwrap(),
+            "4M",
+        ],
+    );
+
+    let disk = RamDisk::from_bytes(fs::read(&image).unwrap(), 512);
+
+    (dir, Arc::new(Mutex::new(disk)))
+}
+
+fn mount(disk: &Arc<Mutex<RamDisk>>) -> Result<Box<dyn FileSystem>, MountError> {
+    ext2::ext2_setup(disk.clone() as Arc<Mutex<dyn BlockDevice>>)
+}
+
+fn read(fs: &mut dyn FileSystem, path: &str) -> Option<Vec<u8>> {
+    let file = fs.open(path)?;
+
+    let mut buffer = vec![0; 512 * 1024];
+    let read = file.read(&mut buffer).unwrap();
+    buffer.truncate(read);
+
+    Some(buffer)
+}
+
+/// Changes the byte `offset` bytes into the disk with `f`.
+fn patch(disk: &Arc<Mutex<RamDisk>>, offset: usize, f: impl FnOnce(&mut u8)) {
+    let mut disk = disk.acquire();
+    let lba = u64::try_from(offset / 512).unwrap();
+
+    let mut sector = [0; 512];
+    disk.read_sectors(lba, 1, &mut sector).unwrap();
+    f(&mut sector[offset % 512]);
+    disk.write_sectors(lba, 1, &sector).unwrap();
+}
+
+/// Writes the disk back to the image so the e2fsprogs tools can check it.
+fn save(dir: &ImageDir, disk: &Arc<Mutex<RamDisk>>) {
+    fs::write(dir.image(), disk.acquire().as_bytes()).unwrap();
+}
+
+#[test]
+fn reads_files() {
+    let (_dir, disk) = make_image("read");
+    let mut fs = mount(&disk).unwrap();
+
+    assert_eq!(read(&mut *fs, "/hello.txt").unwrap(), HELLO);
+    assert_eq!(read(&mut *fs, "large.bin").unwrap(), large());
+}
+
+#[test]
+fn reads_directories() {
+    let (_dir, disk) = make_image("directories");
+    let mut fs = mount(&disk).unwrap();
+
+    assert_eq!(read(&mut *fs, "/docs/notes.txt").unwrap(), NOTES);
+    assert_eq!(read(&mut *fs, "/docs/nested/deep.txt").unwrap(), DEEP);
+    assert_eq!(read(&mut *fs, "/docs/nested/../notes.txt").unwrap(), NOTES);
+
+    // directories can't be opened as files
+    assert!(read(&mut *fs, "/docs").is_none());
+    assert!(read(&mut *fs, "/docs/missing.txt").is_none());
+    assert!(read(&mut *fs, "/hello.txt/child").is_none());
+}
+
+#[test]
+fn follows_symlinks() {
+    assert!(SLOW_TARGET.len() >= 60);
+
+    let (_dir, disk) = make_image("symlinks");
+    let mut fs = mount(&disk).unwrap();
+
+    assert_eq!(read(&mut *fs, "/fast-link").unwrap(), NOTES);
+    assert_eq!(read(&mut *fs, "/absolute-link").unwrap(), HELLO);
+    assert_eq!(read(&mut *fs, "/slow-link").unwrap(), DEEP);
+    assert_eq!(read(&mut *fs, "/docs/parent/hello.txt").unwrap(), HELLO);
+
+    assert!(read(&mut *fs, "/loop-a").is_none());
+}
+
+#[test]
+fn writes_files() {
+    let (dir, disk) = make_image("write");
+
+    // grows past the direct blocks, allocating data and an indirect block
+    let notes: Vec<u8> = (0..20 * 1024u32).map(|i| (i % 239) as u8).collect();
+    let hello = b"HELLO FROM EXT2\n";
+
+    {
+        let mut fs = mount(&disk).unwrap();
+
+        let written = fs.open("/docs/notes.txt").unwrap().write(&notes).unwrap();
+        assert_eq!(written, notes.len());
+        assert_eq!(read(&mut *fs, "/docs/notes.txt").unwrap(), notes);
+
+        let written = fs.open("/hello.txt").unwrap().write(hello).unwrap();
+        assert_eq!(written, hello.len());
+    }
+
+    // a fresh mount only sees what reached the disk
+    let mut fs = mount(&disk).unwrap();
+    assert_eq!(read(&mut *fs, "/docs/notes.txt").unwrap(), notes);
+    assert_eq!(read(&mut *fs, "/hello.txt").unwrap(), hello);
+    assert_eq!(read(&mut *fs, "/large.bin").unwrap(), large());
+
+    save(&dir, &disk);
+
+    let image = dir.image();
+    let image = image.to_str().unwrap();
+
+    assert_eq!(run("debugfs", &["-R", "cat /docs/notes.txt", image]), notes);
+    assert_eq!(run("debugfs", &["-R", "cat /hello.txt", image]), hello);
+
+    // the bitmaps, free counts and block counts have to agree
+    run("e2fsck", &["-f", "-n", image]);
+}
+
+#[test]
+fn unclean_filesystems_are_read_only() {
+    let (_dir, disk) = make_image("unclean");
+
+    patch(&disk, STATE, |state| *state = 0);
+
+    let mut fs = mount(&disk).unwrap();
+
+    assert_eq!(read(&mut *fs, "/hello.txt").unwrap(), HELLO);
+    assert!(fs.open("/hello.txt").unwrap().write(b"changed").is_err());
+    assert_eq!(read(&mut *fs, "/hello.txt").unwrap(), HELLO);
+}
+
+#[test]
+fn unsupported_incompatible_features() {
+    let (_dir, disk) = make_image("incompat");
+
+    // the journal device feature
+    patch(&disk, FEATURE_INCOMPAT, |features| *features |= 0x08);
+
+    assert!(matches!(
+        mount(&disk).err(),
+        Some(MountError::InvalidFileSystem(
+            "Uses unsupported incompatible features"
+        ))
+    ));
+}
@@ -14,8 +14,9 @@ use crate::fat::fat16::ExtenedBootRecord as Fat16EBR;
 
 extern crate alloc;
 
+pub mod exfat;
+
 struct Fat16FS {
-    partion_lba: u64,
     ebr: Fat16EBR,
     drive: Arc<Mutex<dyn BlockDevice>>,
 }
@@ -66,7 +67,7 @@ impl Fat16FS {
 
         self.drive
             .acquire()
-            .read_sectors(sector + self.partion_lba, 1, &mut entry)
+            .read_sectors(sector, 1, &mut entry)
             .unwrap();
 
         let entry = unsafe { core::mem::transmute::<[u8; 512], Sector>(entry) };
@@ -154,7 +155,6 @@ impl FileSystem for Fat16FS {
             drive: self.drive.clone(),
             metadata: file_entry?.metadata,
             ebr: &self.ebr,
-            partion_lba: self.partion_lba,
         }))
     }
 }
@@ -169,7 +169,6 @@ struct Fat16File<'a> {
     drive: Arc<Mutex<dyn BlockDevice>>,
     metadata: Directory,
     ebr: &'a Fat16EBR,
-    partion_lba: u64,
 }
 
 impl FileTrait for Fat16File<'_> {
@@ -182,7 +181,7 @@ impl FileTrait for Fat16File<'_> {
         let () = self
             .drive
             .acquire()
-            .read_sectors(u64::from(sector) + self.partion_lba, 1, buf)
+            .read_sectors(u64::from(sector), 1, buf)
             .unwrap();
 
         Ok(512)
@@ -194,15 +193,8 @@ impl FileTrait for Fat16File<'_> {
 }
 
 //TODO remove function and inline
-pub fn fat16_read_only(
-    partion_lba: u64,
-    device: Arc<Mutex<dyn BlockDevice>>,
-) -> Box<dyn FileSystem> {
-    let ebr = Fat16EBR::new(&device, partion_lba);
-
-    Box::new(Fat16FS {
-        partion_lba,
-        ebr,
-        drive: device,
-    })
+pub fn fat16_read_only(device: Arc<Mutex<dyn BlockDevice>>) -> Box<dyn FileSystem> {
+    let ebr = Fat16EBR::new(&device);
+
+    Box::new(Fat16FS { ebr, drive: device })
 }
@@ -0,0 +1,537 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use diy_os::device_manager::{BlockDevice, BlockDeviceError};
+use diy_os::filesystem::{FileSystem, FileTrait, INError, MountError, OUTError};
+use diy_os::multitasking::mutex::Mutex;
+use zerocopy::{FromBytes, IntoBytes, little_endian::U16};
+
+use crate::fat::exfat::{
+    BAD_CLUSTER, BOOT_REGION_SECTORS, BootSector, END_OF_CHAIN, EXTENDED_BOOT_SIGNATURE, EntryType,
+    FileAttributes, FileEntry, FileNameEntry, RawDirectoryEntry, StreamExtensionEntry,
+    UpCaseTableEntry, boot_checksum, decompress_up_case_table, entry_set_checksum, name_hash,
+    table_checksum,
+};
+
+/// Reads `buffer.len()` bytes starting at `lba`, splitting the read into as many requests as
+/// needed.
+fn read_device(
+    drive: &Arc<Mutex<dyn BlockDevice>>,
+    lba: u64,
+    buffer: &mut [u8],
+) -> Result<(), BlockDeviceError> {
+    let mut drive = drive.acquire();
+    let sector_size = drive.sector_size();
+
+    for (lba, chunk) in (lba..)
+        .step_by(usize::from(u16::MAX))
+        .zip(buffer.chunks_mut(sector_size * usize::from(u16::MAX)))
+    {
+        drive.read_sectors(
+            lba,
+            u16::try_from(chunk.len().div_ceil(sector_size)).unwrap(),
+            chunk,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// A contiguous or fat chained run of clusters.
+#[derive(Debug, Clone, Copy)]
+struct Stream {
+    first_cluster: u32,
+    valid_data_length: u64,
+    /// None if the length is only known by following the fat chain, only the case for the
+    /// root directory
+    data_length: Option<u64>,
+    no_fat_chain: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    name_hash: u16,
+    attributes: FileAttributes,
+    stream: Stream,
+}
+
+pub struct ExFatFS {
+    boot: BootSector,
+    drive: Arc<Mutex<dyn BlockDevice>>,
+    allocation_bitmap: Vec<u8>,
+    up_case_table: Vec<u16>,
+}
+
+impl ExFatFS {
+    /// Validates the boot region and loads the allocation bitmap and up case table.
+    ///
+    /// Falls back to the backup boot region if the main boot region is invalid.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MountError`] if both boot regions are invalid, a read fails, or the root
+    /// directory is missing the allocation bitmap or up case table.
+    pub fn new(drive: Arc<Mutex<dyn BlockDevice>>) -> Result<Self, MountError> {
+        let boot = match Self::read_boot_region(&drive, 0) {
+            Ok(boot) => boot,
+            Err(MountError::InvalidFileSystem(reason)) => {
+                log::warn!("main exfat boot region is invalid, `{reason}` trying backup");
+
+                (9..=12)
+                    .find_map(|shift| {
+                        Self::read_boot_region(&drive, BOOT_REGION_SECTORS << shift)
+                            .ok()
+                            .filter(|boot| boot.bytes_per_sector_shift == shift)
+                    })
+                    .ok_or(MountError::InvalidFileSystem(
+                        "Both exfat boot regions are invalid",
+                    ))?
+            }
+            Err(err) => return Err(err),
+        };
+
+        log::debug!("exfat boot sector: {boot:?}");
+
+        let mut fs = Self {
+            boot,
+            drive,
+            allocation_bitmap: Vec::new(),
+            up_case_table: Vec::new(),
+        };
+
+        let root = fs.read_directory(&fs.root_stream())?;
+
+        let bitmap = root
+            .iter()
+            .find(|entry| entry.is(EntryType::AllocationBitmap))
+            .ok_or(MountError::InvalidFileSystem(
+                "Missing allocation bitmap entry",
+            ))?;
+
+        let mut allocation_bitmap = vec![0u8; usize::try_from(bitmap.data_length.get()).unwrap()];
+        fs.read_stream(
+            &Stream {
+                first_cluster: bitmap.first_cluster.get(),
+                valid_data_length: bitmap.data_length.get(),
+                data_length: Some(bitmap.data_length.get()),
+                no_fat_chain: false,
+            },
+            &mut allocation_bitmap,
+        )?;
+
+        if allocation_bitmap.len() * 8 < usize::try_from(fs.boot.cluster_count.get()).unwrap() {
+            return Err(MountError::InvalidFileSystem(
+                "Allocation bitmap is smaller then the cluster heap",
+            ));
+        }
+
+        fs.allocation_bitmap = allocation_bitmap;
+
+        let up_case = root
+            .iter()
+            .find(|entry| entry.is(EntryType::UpCaseTable))
+            .ok_or(MountError::InvalidFileSystem("Missing up case table entry"))?;
+        let up_case = UpCaseTableEntry::ref_from_bytes(up_case.as_bytes()).unwrap();
+
+        let mut raw_table = vec![0u8; usize::try_from(up_case.data_length.get()).unwrap()];
+        fs.read_stream(
+            &Stream {
+                first_cluster: up_case.first_cluster.get(),
+                valid_data_length: up_case.data_length.get(),
+                data_length: Some(up_case.data_length.get()),
+                no_fat_chain: false,
+            },
+            &mut raw_table,
+        )?;
+
+        let checksum = table_checksum(&raw_table);
+        if checksum != up_case.table_checksum.get() {
+            return Err(MountError::InvalidFileSystem(
+                "Up case table checksum does not match",
+            ));
+        }
+
+        let raw_table = <[U16]>::ref_from_prefix_with_elems(&raw_table, raw_table.len() / 2)
+            .unwrap()
+            .0;
+
+        fs.up_case_table = decompress_up_case_table(raw_table);
+
+        Ok(fs)
+    }
+
+    /// Reads and validates a boot region starting `byte_offset` bytes into the partition.
+    fn read_boot_region(
+        drive: &Arc<Mutex<dyn BlockDevice>>,
+        byte_offset: u64,
+    ) -> Result<BootSector, MountError> {
+        let device_sector_size = u64::try_from(drive.acquire().sector_size()).unwrap();
+        let lba = byte_offset / device_sector_size;
+
+        // the device can only read whole sectors, which can be bigger then the boot sector
+        let mut first_sector = vec![
+            0u8;
+            usize::try_from(device_sector_size)
+                .unwrap()
+                .max(size_of::<BootSector>())
+        ];
+        read_device(drive, lba, &mut first_sector)?;
+
+        let boot = BootSector::read_from_prefix(&first_sector).unwrap().0;
+
+        boot.validate().map_err(MountError::InvalidFileSystem)?;
+
+        if boot.bytes_per_sector() < usize::try_from(device_sector_size).unwrap() {
+            return Err(MountError::InvalidFileSystem(
+                "Sectors are smaller then the device's sectors",
+            ));
+        }
+
+        let bytes_per_sector = boot.bytes_per_sector();
+        let mut region =
+            vec![0u8; bytes_per_sector * usize::try_from(BOOT_REGION_SECTORS).unwrap()];
+        read_device(drive, lba, &mut region)?;
+
+        let mut sectors = region.chunks_exact(bytes_per_sector);
+
+        // Sector 0 is the boot sector which was validated above
+        let _ = sectors.next();
+
+        // Sectors 1 to 8 are the extended boot sectors
+        for sector in sectors.by_ref().take(8) {
+            let signature = u32::from_le_bytes(sector[bytes_per_sector - 4..].try_into().unwrap());
+
+            if signature != EXTENDED_BOOT_SIGNATURE {
+                return Err(MountError::InvalidFileSystem(
+                    "Invalid extended boot signature",
+                ));
+            }
+        }
+
+        let checksum = boot_checksum(&region[..bytes_per_sector * 11]);
+
+        // Sector 11 is the checksum repeated for the entire sector
+        let all_match = region[bytes_per_sector * 11..]
+            .chunks_exact(4)
+            .all(|stored| u32::from_le_bytes(stored.try_into().unwrap()) == checksum);
+
+        if !all_match {
+            return Err(MountError::InvalidFileSystem(
+                "Boot region checksum mismatch",
+            ));
+        }
+
+        Ok(boot)
+    }
+
+    const fn root_stream(&self) -> Stream {
+        Stream {
+            first_cluster: self.boot.first_cluster_of_root_directory.get(),
+            valid_data_length: u64::MAX,
+            data_length: None,
+            no_fat_chain: false,
+        }
+    }
+
+    /// Reads sectors relative to the start of the volume.
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let device_sector_size = self.drive.acquire().sector_size();
+        let device_sectors_per_sector =
+            u64::try_from(self.boot.bytes_per_sector() / device_sector_size).unwrap();
+
+        read_device(&self.drive, sector * device_sectors_per_sector, buffer)
+    }
+
+    fn is_cluster_allocated(&self, cluster: u32) -> bool {
+        let index = usize::try_from(cluster - 2).unwrap();
+
+        self.allocation_bitmap
+            .get(index / 8)
+            .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+    }
+
+    /// Returns the next cluster in the chain or None at the end of the chain.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, MountError> {
+        let bytes_per_sector = self.boot.bytes_per_sector();
+        let active_fat =
+            u32::from(self.boot.number_of_fats == 2 && self.boot.volume_flags.get() & 1 != 0);
+        let fat_start = self.boot.fat_offset.get() + active_fat * self.boot.fat_length.get();
+
+        let offset = usize::try_from(cluster).unwrap() * size_of::<u32>();
+        let sector = u64::from(fat_start) + u64::try_from(offset / bytes_per_sector).unwrap();
+
+        let mut buffer = vec![0u8; bytes_per_sector];
+        self.read_sectors(sector, &mut buffer)?;
+
+        let entry_offset = offset % bytes_per_sector;
+        let entry = u32::from_le_bytes(
+            buffer[entry_offset..entry_offset + size_of::<u32>()]
+                .try_into()
+                .unwrap(),
+        );
+
+        match entry {
+            END_OF_CHAIN => Ok(None),
+            BAD_CLUSTER => Err(MountError::InvalidFileSystem(
+                "Cluster chain has a bad cluster",
+            )),
+            next if self.boot.valid_cluster(next) => Ok(Some(next)),
+            _ => Err(MountError::InvalidFileSystem("Invalid fat entry")),
+        }
+    }
+
+    /// Reads a stream into `buffer` until either the buffer is full or the stream ends.
+    ///
+    /// Returns the number of bytes read, bytes past the valid data length are zeroed.
+    fn read_stream(&self, stream: &Stream, buffer: &mut [u8]) -> Result<usize, MountError> {
+        let length = stream.data_length.map_or(buffer.len(), |data_length| {
+            buffer
+                .len()
+                .min(usize::try_from(data_length).unwrap_or(usize::MAX))
+        });
+
+        let bytes_per_cluster = self.boot.bytes_per_cluster();
+        let mut cluster_buffer = vec![0u8; bytes_per_cluster];
+        let mut cluster = Some(stream.first_cluster);
+        let mut read = 0;
+
+        for chunk in buffer[..length].chunks_mut(bytes_per_cluster) {
+            let Some(current) = cluster else {
+                break;
+            };
+
+            if !self.boot.valid_cluster(current) {
+                return Err(MountError::InvalidFileSystem("Cluster is out of range"));
+            }
+
+            if !self.allocation_bitmap.is_empty() && !self.is_cluster_allocated(current) {
+                return Err(MountError::InvalidFileSystem(
+                    "Cluster is not marked as allocated",
+                ));
+            }
+
+            self.read_sectors(
+                self.boot.first_sector_of_cluster(current),
+                &mut cluster_buffer,
+            )?;
+
+            chunk.copy_from_slice(&cluster_buffer[..chunk.len()]);
+            read += chunk.len();
+
+            cluster = if stream.no_fat_chain {
+                Some(current + 1)
+            } else {
+                self.next_cluster(current)?
+            };
+        }
+
+        if let Ok(valid) = usize::try_from(stream.valid_data_length)
+            && valid < read
+        {
+            buffer[valid..read].fill(0);
+        }
+
+        Ok(read)
+    }
+
+    /// Reads all entries of a directory up to the end of directory marker.
+    fn read_directory(&self, stream: &Stream) -> Result<Vec<RawDirectoryEntry>, MountError> {
+        let bytes_per_cluster = self.boot.bytes_per_cluster();
+        let mut entries = Vec::new();
+        let mut cluster = Some(stream.first_cluster);
+        let mut remaining = stream.data_length.unwrap_or(u64::MAX);
+        let mut buffer = vec![0u8; bytes_per_cluster];
+
+        while let Some(current) = cluster
+            && remaining > 0
+        {
+            if !self.boot.valid_cluster(current) {
+                return Err(MountError::InvalidFileSystem("Cluster is out of range"));
+            }
+
+            self.read_sectors(self.boot.first_sector_of_cluster(current), &mut buffer)?;
+
+            let length = usize::try_from(remaining).map_or(bytes_per_cluster, |remaining| {
+                remaining.min(bytes_per_cluster)
+            });
+
+            for entry in <[RawDirectoryEntry]>::ref_from_bytes(&buffer[..length])
+                .map_err(|_| MountError::InvalidFileSystem("Directory length is misaligned"))?
+            {
+                if entry.end_of_directory() {
+                    return Ok(entries);
+                }
+
+                entries.push(*entry);
+            }
+
+            remaining = remaining.saturating_sub(u64::try_from(bytes_per_cluster).unwrap());
+
+            cluster = if stream.no_fat_chain {
+                Some(current + 1)
+            } else {
+                self.next_cluster(current)?
+            };
+        }
+
+        Ok(entries)
+    }
+
+    /// Groups the raw entries of a directory into file entry sets.
+    fn parse_entry_sets(entries: &[RawDirectoryEntry]) -> Result<Vec<Entry>, MountError> {
+        let mut files = Vec::new();
+        let mut index = 0;
+
+        while let Some(primary) = entries.get(index) {
+            if !primary.in_use() || !primary.is(EntryType::File) {
+                index += 1;
+                continue;
+            }
+
+            let file = FileEntry::ref_from_bytes(primary.as_bytes()).unwrap();
+            let set_length = usize::from(file.secondary_count) + 1;
+
+            let set =
+                entries
+                    .get(index..index + set_length)
+                    .ok_or(MountError::InvalidFileSystem(
+                        "Truncated directory entry set",
+                    ))?;
+
+            if entry_set_checksum(set) != file.set_checksum.get() {
+                return Err(MountError::InvalidFileSystem(
+                    "Directory entry set checksum mismatch",
+                ));
+            }
+
+            let stream = set
+                .get(1)
+                .filter(|entry| entry.is(EntryType::StreamExtension))
+                .ok_or(MountError::InvalidFileSystem(
+                    "File entry is missing a stream extension",
+                ))?;
+            let stream = StreamExtensionEntry::ref_from_bytes(stream.as_bytes()).unwrap();
+
+            let name_length = usize::from(stream.name_length);
+            let name = char::decode_utf16(
+                set[2..]
+                    .iter()
+                    .filter(|entry| entry.is(EntryType::FileName))
+                    .flat_map(|entry| {
+                        FileNameEntry::ref_from_bytes(entry.as_bytes())
+                            .unwrap()
+                            .file_name
+                            .map(U16::get)
+                    })
+                    .take(name_length),
+            )
+            .map(|char| char.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+
+            files.push(Entry {
+                name,
+                name_hash: stream.name_hash.get(),
+                attributes: file.attributes(),
+                stream: Stream {
+                    first_cluster: stream.first_cluster.get(),
+                    valid_data_length: stream.valid_data_length.get(),
+                    data_length: Some(stream.data_length.get()),
+                    no_fat_chain: stream.no_fat_chain(),
+                },
+            });
+
+            index += set_length;
+        }
+
+        Ok(files)
+    }
+
+    fn up_case(&self, name: &str) -> Vec<u16> {
+        name.encode_utf16()
+            .map(|char| {
+                self.up_case_table
+                    .get(usize::from(char))
+                    .copied()
+                    .unwrap_or(char)
+            })
+            .collect()
+    }
+
+    /// Walks `path` from the root directory, comparing names case insensitively.
+    fn lookup(&self, path: &str) -> Result<Option<Entry>, MountError> {
+        let mut current = Entry {
+            name: String::from("/"),
+            name_hash: 0,
+            attributes: FileAttributes::Directory,
+            stream: self.root_stream(),
+        };
+
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            if !current.attributes.contains(FileAttributes::Directory) {
+                return Ok(None);
+            }
+
+            let up_cased = self.up_case(component);
+            let hash = name_hash(&up_cased);
+
+            let entries = self.read_directory(&current.stream)?;
+            let found = Self::parse_entry_sets(&entries)?
+                .into_iter()
+                .find(|entry| entry.name_hash == hash && self.up_case(&entry.name) == up_cased);
+
+            match found {
+                Some(entry) => current = entry,
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(current))
+    }
+}
+
+impl FileSystem for ExFatFS {
+    fn open(&mut self, path: &str) -> Option<Box<dyn FileTrait + '_>> {
+        let entry = self
+            .lookup(path)
+            .inspect_err(|err| log::error!("failed to open {path}, {err}"))
+            .ok()??;
+
+        if entry.attributes.contains(FileAttributes::Directory) {
+            return None;
+        }
+
+        Some(Box::new(ExFatFile { fs: self, entry }))
+    }
+}
+
+struct ExFatFile<'a> {
+    fs: &'a ExFatFS,
+    entry: Entry,
+}
+
+impl FileTrait for ExFatFile<'_> {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, INError> {
+        self.fs.read_stream(&self.entry.stream, buf).map_err(|err| {
+            log::error!("failed to read {}, {err}", self.entry.name);
+            INError::NotReadable
+        })
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, OUTError> {
+        Err(OUTError::NotWritable)
+    }
+}
+
+/// Mounts the exFAT volume on `device`.
+///
+/// # Errors
+///
+/// Returns [`MountError`] if the volume is invalid, see [`ExFatFS::new`].
+pub fn exfat_read_only(
+    device: Arc<Mutex<dyn BlockDevice>>,
+) -> Result<Box<dyn FileSystem>, MountError> {
+    Ok(Box::new(ExFatFS::new(device)?))
+}
@@ -13,6 +13,7 @@ use either::Either::{self, Left, Right};
 
 use crate::fat::fat32::ExtenedBootRecord;
 
+pub mod exfat;
 pub mod fat16;
 pub mod fat32;
 
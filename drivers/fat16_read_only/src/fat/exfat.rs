@@ -0,0 +1,312 @@
+// reference docs at https://web.archive.org/web/20250318005738/https://learn.microsoft.com/en-us/windows/win32/fileio/exfat-specification
+use bitflags::bitflags;
+use zerocopy::{
+    FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned,
+    little_endian::{U16, U32, U64},
+};
+
+/// The main and backup boot regions are each 12 sectors long.
+pub const BOOT_REGION_SECTORS: u64 = 12;
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct BootSector {
+    pub jump_boot: [u8; 3],
+    pub file_system_name: [u8; 8],
+    must_be_zero: [u8; 53],
+    pub partition_offset: U64,
+    pub volume_length: U64,
+    /// in sectors from the start of the volume
+    pub fat_offset: U32,
+    /// in sectors
+    pub fat_length: U32,
+    /// in sectors from the start of the volume
+    pub cluster_heap_offset: U32,
+    pub cluster_count: U32,
+    pub first_cluster_of_root_directory: U32,
+    pub volume_serial_number: U32,
+    pub file_system_revision: U16,
+    pub volume_flags: U16,
+    pub bytes_per_sector_shift: u8,
+    pub sectors_per_cluster_shift: u8,
+    pub number_of_fats: u8,
+    pub drive_select: u8,
+    pub percent_in_use: u8,
+    _reserved: [u8; 7],
+    _boot_code: [u8; 390],
+    pub boot_signature: U16,
+}
+
+impl BootSector {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 512);
+    };
+
+    pub const JUMP_BOOT: [u8; 3] = [0xEB, 0x76, 0x90];
+    pub const FILE_SYSTEM_NAME: [u8; 8] = *b"EXFAT   ";
+    pub const BOOT_SIGNATURE: u16 = 0xAA55;
+
+    /// Checks every field the spec requires to hold for a valid exFAT boot sector.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the first field that is invalid.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.jump_boot != Self::JUMP_BOOT {
+            return Err("Invalid jump boot instruction");
+        }
+
+        if self.file_system_name != Self::FILE_SYSTEM_NAME {
+            return Err("Invalid filesystem name");
+        }
+
+        if self.must_be_zero.iter().any(|byte| *byte != 0) {
+            return Err("The legacy BPB region is not zeroed");
+        }
+
+        if self.boot_signature.get() != Self::BOOT_SIGNATURE {
+            return Err("Invalid boot signature");
+        }
+
+        if !(9..=12).contains(&self.bytes_per_sector_shift) {
+            return Err("Bytes per sector is out of range");
+        }
+
+        // A cluster can at most be 32 MiB
+        if self.sectors_per_cluster_shift > 25 - self.bytes_per_sector_shift {
+            return Err("Sectors per cluster is out of range");
+        }
+
+        if !(1..=2).contains(&self.number_of_fats) {
+            return Err("Number of fats must be 1 or 2");
+        }
+
+        if !self.valid_cluster(self.first_cluster_of_root_directory.get()) {
+            return Err("Root directory cluster is out of range");
+        }
+
+        Ok(())
+    }
+
+    pub const fn bytes_per_sector(&self) -> usize {
+        1 << self.bytes_per_sector_shift
+    }
+
+    pub const fn bytes_per_cluster(&self) -> usize {
+        1 << (self.bytes_per_sector_shift + self.sectors_per_cluster_shift)
+    }
+
+    /// Returns the first sector of `cluster` relative to the start of the volume.
+    pub fn first_sector_of_cluster(&self, cluster: u32) -> u64 {
+        u64::from(self.cluster_heap_offset.get())
+            + (u64::from(cluster - 2) << self.sectors_per_cluster_shift)
+    }
+
+    /// Returns true if `cluster` is within the cluster heap.
+    pub const fn valid_cluster(&self, cluster: u32) -> bool {
+        // the heap starts at cluster 2, comparing the index can't overflow
+        cluster >= 2 && cluster - 2 < self.cluster_count.get()
+    }
+}
+
+/// Calculates the checksum stored in the last sector of a boot region.
+///
+/// `region` must be the first 11 sectors of the region, the `volume_flags` and
+/// `percent_in_use` fields are skipped as they can change without updating the checksum.
+pub fn boot_checksum(region: &[u8]) -> u32 {
+    region
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !matches!(i, 106 | 107 | 112))
+        .fold(0u32, |checksum, (_, byte)| {
+            checksum.rotate_right(1).wrapping_add(u32::from(*byte))
+        })
+}
+
+/// Extended boot sectors end with this signature.
+pub const EXTENDED_BOOT_SIGNATURE: u32 = 0xAA55_0000;
+
+/// Fat entry marking the end of a cluster chain.
+pub const END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+/// Fat entry marking a bad cluster.
+pub const BAD_CLUSTER: u32 = 0xFFFF_FFF7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[allow(dead_code)]
+pub enum EntryType {
+    EndOfDirectory = 0x00,
+    AllocationBitmap = 0x81,
+    UpCaseTable = 0x82,
+    VolumeLabel = 0x83,
+    File = 0x85,
+    VolumeGuid = 0xA0,
+    StreamExtension = 0xC0,
+    FileName = 0xC1,
+}
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct RawDirectoryEntry {
+    pub entry_type: u8,
+    pub custom: [u8; 19],
+    pub first_cluster: U32,
+    pub data_length: U64,
+}
+
+impl RawDirectoryEntry {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 32);
+    };
+
+    pub const fn end_of_directory(&self) -> bool {
+        self.entry_type == EntryType::EndOfDirectory as u8
+    }
+
+    /// Entries with the in use bit cleared have been deleted.
+    pub const fn in_use(&self) -> bool {
+        self.entry_type & 0x80 != 0
+    }
+
+    pub const fn is(&self, entry_type: EntryType) -> bool {
+        self.entry_type == entry_type as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct UpCaseTableEntry {
+    pub entry_type: u8,
+    _reserved_1: [u8; 3],
+    pub table_checksum: U32,
+    _reserved_2: [u8; 12],
+    pub first_cluster: U32,
+    pub data_length: U64,
+}
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct FileEntry {
+    pub entry_type: u8,
+    pub secondary_count: u8,
+    pub set_checksum: U16,
+    pub file_attributes: U16,
+    _reserved_1: U16,
+    pub create_timestamp: U32,
+    pub last_modified_timestamp: U32,
+    pub last_accessed_timestamp: U32,
+    pub create_10ms_increment: u8,
+    pub last_modified_10ms_increment: u8,
+    pub create_utc_offset: u8,
+    pub last_modified_utc_offset: u8,
+    pub last_accessed_utc_offset: u8,
+    _reserved_2: [u8; 7],
+}
+
+impl FileEntry {
+    pub const fn attributes(&self) -> FileAttributes {
+        FileAttributes::from_bits_retain(self.file_attributes.get())
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FileAttributes: u16 {
+        const ReadOnly = 0x01;
+        const Hidden = 0x02;
+        const System = 0x04;
+        const Directory = 0x10;
+        const Archive = 0x20;
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct StreamExtensionEntry {
+    pub entry_type: u8,
+    pub general_secondary_flags: u8,
+    _reserved_1: u8,
+    pub name_length: u8,
+    pub name_hash: U16,
+    _reserved_2: U16,
+    pub valid_data_length: U64,
+    _reserved_3: U32,
+    pub first_cluster: U32,
+    pub data_length: U64,
+}
+
+impl StreamExtensionEntry {
+    /// If set the clusters of the stream are contiguous and the fat must not be read.
+    pub const fn no_fat_chain(&self) -> bool {
+        self.general_secondary_flags & 0b10 != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct FileNameEntry {
+    pub entry_type: u8,
+    pub general_secondary_flags: u8,
+    pub file_name: [U16; 15],
+}
+
+/// Calculates the checksum of a directory entry set.
+///
+/// The `set_checksum` field of the primary entry is skipped.
+pub fn entry_set_checksum(entries: &[RawDirectoryEntry]) -> u16 {
+    entries
+        .as_bytes()
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !matches!(i, 2 | 3))
+        .fold(0u16, |checksum, (_, byte)| {
+            checksum.rotate_right(1).wrapping_add(u16::from(*byte))
+        })
+}
+
+/// Calculates the hash stored in the stream extension of a up cased file name.
+pub fn name_hash(up_cased_name: &[u16]) -> u16 {
+    up_cased_name
+        .iter()
+        .flat_map(|char| char.to_le_bytes())
+        .fold(0u16, |hash, byte| {
+            hash.rotate_right(1).wrapping_add(u16::from(byte))
+        })
+}
+
+/// Calculates the checksum of the raw (compressed) up case table.
+pub fn table_checksum(table: &[u8]) -> u32 {
+    table.iter().fold(0u32, |checksum, byte| {
+        checksum.rotate_right(1).wrapping_add(u32::from(*byte))
+    })
+}
+
+/// Expands the compressed up case table.
+///
+/// A `0xFFFF` entry is followed by the number of characters that map to themselves.
+pub fn decompress_up_case_table(raw: &[U16]) -> alloc::vec::Vec<u16> {
+    let mut table = alloc::vec::Vec::with_capacity(usize::from(u16::MAX) + 1);
+    let mut raw = raw.iter().map(|char| char.get());
+
+    while let Some(char) = raw.next() {
+        if char == 0xFFFF {
+            let Some(identity_count) = raw.next() else {
+                // a trailing 0xFFFF maps to itself
+                table.push(char);
+                break;
+            };
+
+            for _ in 0..identity_count {
+                // the table can't be longer then the amount of u16 characters
+                let Ok(next) = u16::try_from(table.len()) else {
+                    break;
+                };
+                table.push(next);
+            }
+        } else {
+            table.push(char);
+        }
+    }
+
+    table
+}
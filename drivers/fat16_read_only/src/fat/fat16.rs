@@ -23,13 +23,10 @@ impl ExtenedBootRecord {
         self.signature == 0x28 || self.signature == 0x29
     }
 
-    pub fn new(device: &Arc<Mutex<dyn BlockDevice>>, partion_lba: u64) -> Self {
+    pub fn new(device: &Arc<Mutex<dyn BlockDevice>>) -> Self {
         let mut ebr = [0u8; 512];
 
-        device
-            .acquire()
-            .read_sectors(partion_lba, 1, &mut ebr)
-            .unwrap();
+        device.acquire().read_sectors(0, 1, &mut ebr).unwrap();
 
         let ebr = unsafe { core::mem::transmute::<[u8; 512], Self>(ebr) };
 
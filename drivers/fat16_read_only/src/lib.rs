@@ -9,15 +9,15 @@
 mod drivers;
 mod fat;
 
-use alloc::{boxed::Box, sync::Arc};
+use alloc::{boxed::Box, sync::Arc, vec};
 
 use diy_os::{
-    device_manager::{BlockDevice, BlockDeviceError},
-    filesystem::{FileSystem, gpt::PartitionEntry},
+    device_manager::BlockDevice,
+    filesystem::{FileSystem, MountError},
     multitasking::mutex::Mutex,
     println,
 };
-use zerocopy::{FromZeros, IntoBytes};
+use zerocopy::FromBytes;
 
 use crate::fat::{BIOSParameterBlock, Cluster, FATType};
 
@@ -42,21 +42,25 @@ extern crate alloc;
 /// Reads the BIOS Parameter Block from a FAT partition, detects the FAT
 /// variant, and instantiates the appropriate filesystem driver.
 ///
-/// `partion` must reference a partition whose filesystem GUID is
-/// [`FSGuid::MicrosoftData`]. The BPB is read from the partition's starting LBA.
+/// `partion` should be a [`Partition`](diy_os::device_manager::partition::Partition) whose type
+/// is [`FSGuid::MicrosoftData`](diy_os::filesystem::gpt::FSGuid::MicrosoftData). The BPB is read
+/// from the first sector of the partition.
 ///
 /// # Errors
 ///
-/// Returns [`BlockDeviceError`] if reading the BPB sector fails.
-pub fn fat_setup(
-    device: Arc<Mutex<dyn BlockDevice>>,
-    partion: &PartitionEntry,
-) -> Result<Box<dyn FileSystem>, BlockDeviceError> {
-    let mut drive = device.acquire();
+/// Returns [`MountError`] if reading the BPB sector fails, the filesystem is invalid or it's
+/// FAT12 or FAT32, which aren't supported yet.
+pub fn fat_setup(partion: Arc<Mutex<dyn BlockDevice>>) -> Result<Box<dyn FileSystem>, MountError> {
+    let mut drive = partion.acquire();
 
-    let mut bios = BIOSParameterBlock::new_zeroed();
+    // the device can only read whole sectors, which are bigger then the BPB
+    let mut first_sector = vec![0u8; drive.sector_size().max(size_of::<BIOSParameterBlock>())];
 
-    drive.read_sectors(partion.starting_lba.get(), 1, bios.as_mut_bytes())?;
+    drive.read_sectors(0, 1, &mut first_sector)?;
+
+    let bios = BIOSParameterBlock::read_from_prefix(&first_sector)
+        .unwrap()
+        .0;
 
     log::debug!("bpb : {bios:?}");
 
@@ -67,13 +71,11 @@ pub fn fat_setup(
     drop(drive);
 
     match fat_type {
-        FATType::ExFAT => todo!(),
-        FATType::FAT12 => todo!(),
-        FATType::FAT16 => Ok(drivers::fat16_read_only(partion.starting_lba.get(), device)),
-        FATType::FAT32 => {
-            todo!()
-            // drivers::primitive_memmapped_fat32_read_only_driver(partion_addr, boot_recorded_addr)
-        }
+        FATType::ExFAT => drivers::exfat::exfat_read_only(partion),
+        FATType::FAT12 => Err(MountError::InvalidFileSystem("FAT12 is not supported")),
+        FATType::FAT16 => Ok(drivers::fat16_read_only(partion)),
+        // drivers::primitive_memmapped_fat32_read_only_driver(partion_addr, boot_recorded_addr)
+        FATType::FAT32 => Err(MountError::InvalidFileSystem("FAT32 is not supported")),
     }
 }
 
@@ -0,0 +1,283 @@
+//! Mounts small exFAT volumes built by hand, checking the boot region checksum and up case table
+//! are validated.
+
+use std::sync::Arc;
+
+use diy_os::device_manager::BlockDevice;
+use diy_os::device_manager::ramdisk::RamDisk;
+use diy_os::filesystem::MountError;
+use diy_os::multitasking::mutex::Mutex;
+
+const CONTENTS: &[u8] = b"hello from an exfat volume\n";
+const NAME: &str = "hello.txt";
+
+const FAT_OFFSET: u32 = 24;
+const CLUSTER_HEAP_OFFSET: u32 = 32;
+const CLUSTER_COUNT: u32 = 16;
+const VOLUME_SECTORS: u32 = CLUSTER_HEAP_OFFSET + CLUSTER_COUNT;
+
+const BITMAP_CLUSTER: u32 = 2;
+const UP_CASE_CLUSTER: u32 = 3;
+const ROOT_CLUSTER: u32 = 4;
+const FILE_CLUSTER: u32 = 5;
+
+/// Offset of the checksum in the up case table entry.
+const UP_CASE_CHECKSUM: usize = 4;
+
+/// The same checksum the boot region, entry sets and up case table use, of a different width.
+fn checksum_u32<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u32 {
+    bytes.into_iter().fold(0u32, |checksum, byte| {
+        checksum.rotate_right(1).wrapping_add(u32::from(*byte))
+    })
+}
+
+fn checksum_u16<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u16 {
+    bytes.into_iter().fold(0u16, |checksum, byte| {
+        checksum.rotate_right(1).wrapping_add(u16::from(*byte))
+    })
+}
+
+fn put(buffer: &mut [u8], offset: usize, bytes: &[u8]) {
+    buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// A volume of one sector per cluster holding [`NAME`] in the root directory, both boot regions
+/// are valid.
+struct Volume {
+    bytes: Vec<u8>,
+    sector_size: usize,
+}
+
+impl Volume {
+    fn new(sector_size: usize) -> Self {
+        let mut volume = Self {
+            bytes: vec![0; VOLUME_SECTORS as usize * sector_size],
+            sector_size,
+        };
+
+        let region = volume.boot_region();
+        put(&mut volume.bytes, 0, &region);
+        put(&mut volume.bytes, 12 * sector_size, &region);
+
+        // media type and end of chain for the reserved entries, then a cluster for each
+        let fat = volume.sector_mut(FAT_OFFSET);
+        put(fat, 0, &0xFFFF_FFF8u32.to_le_bytes());
+        put(fat, 4, &u32::MAX.to_le_bytes());
+        for cluster in BITMAP_CLUSTER..=FILE_CLUSTER {
+            put(fat, cluster as usize * 4, &u32::MAX.to_le_bytes());
+        }
+
+        volume.cluster_mut(BITMAP_CLUSTER)[0] = 0b1111;
+
+        // a-z map to A-Z, everything else to itself
+        let up_case: Vec<u8> = [0xFFFF, u16::from(b'a')]
+            .into_iter()
+            .chain((b'A'..=b'Z').map(u16::from))
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        put(volume.cluster_mut(UP_CASE_CLUSTER), 0, &up_case);
+
+        let mut bitmap_entry = [0; 32];
+        bitmap_entry[0] = 0x81;
+        put(&mut bitmap_entry, 20, &BITMAP_CLUSTER.to_le_bytes());
+        put(&mut bitmap_entry, 24, &2u64.to_le_bytes());
+
+        let mut up_case_entry = [0; 32];
+        up_case_entry[0] = 0x82;
+        put(
+            &mut up_case_entry,
+            UP_CASE_CHECKSUM,
+            &checksum_u32(&up_case).to_le_bytes(),
+        );
+        put(&mut up_case_entry, 20, &UP_CASE_CLUSTER.to_le_bytes());
+        put(
+            &mut up_case_entry,
+            24,
+            &(up_case.len() as u64).to_le_bytes(),
+        );
+
+        let root = volume.cluster_mut(ROOT_CLUSTER);
+        put(root, 0, &bitmap_entry);
+        put(root, 32, &up_case_entry);
+        put(root, 64, &file_entry_set());
+
+        put(volume.cluster_mut(FILE_CLUSTER), 0, CONTENTS);
+
+        volume
+    }
+
+    /// The boot sector, extended boot sectors, two zeroed sectors and the checksum sector.
+    fn boot_region(&self) -> Vec<u8> {
+        let sector_size = self.sector_size;
+        let mut region = vec![0; 12 * sector_size];
+
+        put(&mut region, 0, &[0xEB, 0x76, 0x90]);
+        put(&mut region, 3, b"EXFAT   ");
+        put(&mut region, 72, &u64::from(VOLUME_SECTORS).to_le_bytes());
+        put(&mut region, 80, &FAT_OFFSET.to_le_bytes());
+        put(&mut region, 84, &1u32.to_le_bytes());
+        put(&mut region, 88, &CLUSTER_HEAP_OFFSET.to_le_bytes());
+        put(&mut region, 92, &CLUSTER_COUNT.to_le_bytes());
+        put(&mut region, 96, &ROOT_CLUSTER.to_le_bytes());
+        put(&mut region, 104, &0x0100u16.to_le_bytes());
+        region[108] = u8::try_from(sector_size.trailing_zeros()).unwrap();
+        region[110] = 1;
+        region[111] = 0x80;
+        put(&mut region, 510, &0xAA55u16.to_le_bytes());
+
+        for sector in 1..=8 {
+            put(
+                &mut region,
+                (sector + 1) * sector_size - 4,
+                &0xAA55_0000u32.to_le_bytes(),
+            );
+        }
+
+        let checksum = checksum_u32(
+            region[..11 * sector_size]
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !matches!(i, 106 | 107 | 112))
+                .map(|(_, byte)| byte),
+        );
+
+        for stored in region[11 * sector_size..].chunks_exact_mut(4) {
+            stored.copy_from_slice(&checksum.to_le_bytes());
+        }
+
+        region
+    }
+
+    fn sector_mut(&mut self, sector: u32) -> &mut [u8] {
+        let sector = sector as usize;
+
+        &mut self.bytes[sector * self.sector_size..(sector + 1) * self.sector_size]
+    }
+
+    fn cluster_mut(&mut self, cluster: u32) -> &mut [u8] {
+        self.sector_mut(CLUSTER_HEAP_OFFSET + cluster - 2)
+    }
+
+    fn mount(self) -> Result<Box<dyn diy_os::filesystem::FileSystem>, MountError> {
+        let disk: Arc<Mutex<dyn BlockDevice>> = Arc::new(Mutex::new(RamDisk::from_bytes(
+            self.bytes,
+            self.sector_size,
+        )));
+
+        fat16_read_only::fat_setup(disk)
+    }
+}
+
+/// The file, stream extension and file name entries of [`NAME`].
+fn file_entry_set() -> [u8; 96] {
+    let mut set = [0; 96];
+
+    set[0] = 0x85;
+    set[1] = 2;
+    // archive
+    put(&mut set, 4, &0x20u16.to_le_bytes());
+
+    let up_cased: Vec<u8> = NAME
+        .to_uppercase()
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+
+    let stream = &mut set[32..64];
+    stream[0] = 0xC0;
+    // allocation possible and no fat chain
+    stream[1] = 0b11;
+    stream[3] = u8::try_from(NAME.len()).unwrap();
+    put(stream, 4, &checksum_u16(&up_cased).to_le_bytes());
+    put(stream, 8, &(CONTENTS.len() as u64).to_le_bytes());
+    put(stream, 20, &FILE_CLUSTER.to_le_bytes());
+    put(stream, 24, &(CONTENTS.len() as u64).to_le_bytes());
+
+    let name: Vec<u8> = NAME.encode_utf16().flat_map(u16::to_le_bytes).collect();
+
+    let file_name = &mut set[64..];
+    file_name[0] = 0xC1;
+    put(file_name, 2, &name);
+
+    let checksum = checksum_u16(
+        set.iter()
+            .enumerate()
+            .filter(|(i, _)| !matches!(i, 2 | 3))
+            .map(|(_, byte)| byte),
+    );
+    put(&mut set, 2, &checksum.to_le_bytes());
+
+    set
+}
+
+fn read_file(volume: Volume, path: &str) -> Vec<u8> {
+    let mut filesystem = volume.mount().unwrap();
+    let file = filesystem.open(path).expect("Should be in the volume");
+
+    let mut buffer = [0; 512];
+    let read = file.read(&mut buffer).unwrap();
+
+    buffer[..read].to_vec()
+}
+
+fn mount_error(volume: Volume) -> &'static str {
+    match volume.mount().err() {
+        Some(MountError::InvalidFileSystem(reason)) => reason,
+        err => panic!("expected the volume to be invalid, got {err:?}"),
+    }
+}
+
+#[test]
+fn reads_a_file() {
+    assert_eq!(read_file(Volume::new(512), "/hello.txt"), CONTENTS);
+}
+
+#[test]
+fn names_are_up_cased_with_the_table() {
+    assert_eq!(read_file(Volume::new(512), "/HELLO.TXT"), CONTENTS);
+}
+
+#[test]
+fn reads_a_volume_with_4096_byte_sectors() {
+    assert_eq!(read_file(Volume::new(4096), "/hello.txt"), CONTENTS);
+}
+
+#[test]
+fn falls_back_to_the_backup_boot_region() {
+    let mut volume = Volume::new(512);
+
+    // the boot code isn't validated by itself, only by the checksum
+    volume.bytes[200] ^= 0xFF;
+
+    assert_eq!(read_file(volume, "/hello.txt"), CONTENTS);
+}
+
+#[test]
+fn both_boot_region_checksums_mismatch() {
+    let mut volume = Volume::new(512);
+
+    volume.bytes[200] ^= 0xFF;
+    volume.bytes[12 * 512 + 200] ^= 0xFF;
+
+    assert_eq!(mount_error(volume), "Both exfat boot regions are invalid");
+}
+
+#[test]
+fn up_case_table_checksum_mismatch() {
+    let mut volume = Volume::new(512);
+
+    volume.cluster_mut(UP_CASE_CLUSTER)[0] ^= 0xFF;
+
+    assert_eq!(mount_error(volume), "Up case table checksum does not match");
+}
+
+#[test]
+fn cluster_count_of_u32_max() {
+    let mut volume = Volume::new(512);
+
+    put(&mut volume.bytes, 92, &u32::MAX.to_le_bytes());
+    put(&mut volume.bytes, 12 * 512 + 92, &u32::MAX.to_le_bytes());
+
+    // the checksums no longer match, the cluster count must not overflow before that's found
+    assert_eq!(mount_error(volume), "Both exfat boot regions are invalid");
+}
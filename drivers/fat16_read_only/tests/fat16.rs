@@ -1,43 +1,168 @@
-// #![feature(vec_into_raw_parts)]
-//
-// use std::fs;
-//
-// use diy_os::{RAMDISK_INFO, RamdiskInfo};
-// use primitive_memmapped_fat32_read_only_driver::wrapper;
-//
-// fn setup_ramdisk() -> (*mut u8, usize, usize) {
-//     let ramdisk_path = "/home/cole/Documents/Projects/diy-os/drivers/primitive_memmapped_fat32_read_only_driver/tests/ramdisk.img";
-//     // let ramdisk_path = "/home/cole/Documents/Projects/diy-os/sfs.img";
-//
-//     let ramdisk = fs::read(ramdisk_path).unwrap();
-//
-//     let raw_parts = ramdisk.into_raw_parts();
-//
-//     let addr = raw_parts.0.expose_provenance();
-//
-//     println!("addr: {addr}");
-//
-//     let info = RamdiskInfo {
-//         addr: addr.try_into().unwrap(),
-//         len: raw_parts.1.try_into().unwrap(),
-//     };
-//
-//     RAMDISK_INFO.with_mut_ref(|ramdisk_info| {
-//         *ramdisk_info = Some(info);
-//     });
-//
-//     raw_parts
-// }
-//
-// fn cleanup_ramdisk(raw_parts: (*mut u8, usize, usize)) {
-//     unsafe { Vec::from_raw_parts(raw_parts.0, raw_parts.1, raw_parts.2) };
-// }
-//
-// #[test]
-// fn main() {
-//     let raw_parts = setup_ramdisk();
-//
-//     wrapper();
-//
-//     cleanup_ramdisk(raw_parts);
-// }
+//! Reads images made with `sgdisk`, `mkfs.fat` and `mtools`, the tests that need them are
+//! ignored, run them with `cargo test -- --ignored`.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+
+use diy_os::device_manager::file::FileBlockDevice;
+use diy_os::device_manager::partition::{Partition, read_partion_table};
+use diy_os::device_manager::ramdisk::RamDisk;
+use diy_os::device_manager::{BlockDevice, BlockDeviceError};
+use diy_os::filesystem::gpt::FSGuid;
+use diy_os::multitasking::mutex::Mutex;
+
+const CONTENTS: &[u8] = b"hello from a fat16 image\n";
+
+/// The file is in a directory with a long name since the driver only names files by their long
+/// file name entries.
+const PATH: &str = "/testfiles/greeting.text";
+
+/// Runs `program`, panicking if it's missing or fails.
+fn run(program: &str, args: &[&str]) {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .unwrap_or_else(|err| panic!("{program} isn't available, {err}"));
+
+    assert!(
+        output.status.success(),
+        "{program} failed, {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// A directory for an image, removed once it's dropped even if the test failed.
+struct ImageDir(PathBuf);
+
+impl ImageDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("fat16-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        Self(dir)
+    }
+
+    fn image(&self) -> PathBuf {
+        self.0.join("disk.img")
+    }
+}
+
+impl Drop for ImageDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Makes an 8 MiB image with a gpt and a fat16 partition from 1 MiB holding [`PATH`].
+fn make_image(name: &str) -> ImageDir {
+    let dir = ImageDir::new(name);
+
+    let image = dir.image();
+    let file = dir.0.join("greeting.text");
+    fs::write(&file, CONTENTS).unwrap();
+
+    let image_str = image.to_str().unwrap();
+    // mtools reaches the partition through the offset after the `@@`
+    let partition = format!("{image_str}@@1M");
+
+    run("truncate", &["-s", "8M", image_str]);
+    run(
+        "sgdisk",
+        &["--new=1:2048:0", "--typecode=1:0700", image_str],
+    );
+    // the backup gpt takes the last 33 sectors, the size is in KiB
+    run(
+        "mkfs.fat",
+        &["-F", "16", "--offset", "2048", image_str, "7150"],
+    );
+    run("mmd", &["-i", &partition, "::testfiles"]);
+    run(
+        "mcopy",
+        &[
+            "-i",
+            &partition,
+            file.to_str().unwrap(),
+            "::testfiles/greeting.text",
+        ],
+    );
+
+    dir
+}
+
+/// Mounts the first partition of `disk` and reads [`PATH`].
+fn read_greeting(disk: Arc<Mutex<dyn BlockDevice>>) -> Vec<u8> {
+    let partitions = read_partion_table(&disk).unwrap();
+    let (number, entry) = partitions[0];
+
+    let partition = Arc::new(Mutex::new(Partition::new(disk, entry, number)));
+    let mut filesystem = fat16_read_only::fat_setup(partition).unwrap();
+
+    let file = filesystem.open(PATH).expect("Should be in the image");
+
+    let mut buffer = [0; 512];
+    let read = file.read(&mut buffer).unwrap();
+
+    buffer[..read.min(CONTENTS.len())].to_vec()
+}
+
+#[test]
+#[ignore = "needs truncate, sgdisk, mkfs.fat and mtools"]
+fn gpt() {
+    let dir = make_image("gpt");
+
+    let disk: Arc<Mutex<dyn BlockDevice>> = Arc::new(Mutex::new(RamDisk::from_bytes(
+        fs::read(dir.image()).unwrap(),
+        512,
+    )));
+
+    let partitions = read_partion_table(&disk).unwrap();
+
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0].0, 1);
+    assert_eq!(partitions[0].1.get_fs(), Ok(FSGuid::MicrosoftData));
+    assert_eq!(partitions[0].1.starting_lba.get(), 2048);
+}
+
+#[test]
+#[ignore = "needs truncate, sgdisk, mkfs.fat and mtools"]
+fn read_from_ramdisk() {
+    let dir = make_image("ramdisk");
+
+    let disk = RamDisk::from_bytes(fs::read(dir.image()).unwrap(), 512);
+
+    assert_eq!(read_greeting(Arc::new(Mutex::new(disk))), CONTENTS);
+}
+
+#[test]
+#[ignore = "needs truncate, sgdisk, mkfs.fat and mtools"]
+fn read_from_file() {
+    let dir = make_image("file");
+
+    let disk = FileBlockDevice::open(dir.image(), 512, true).unwrap();
+
+    assert_eq!(read_greeting(Arc::new(Mutex::new(disk))), CONTENTS);
+}
+
+#[test]
+fn ramdisk_bounds() {
+    let mut disk = RamDisk::new(4, 512);
+
+    disk.write_sectors(3, 1, &[0xAA; 512]).unwrap();
+
+    let mut buffer = [0; 1024];
+    disk.read_sectors(2, 2, &mut buffer).unwrap();
+
+    assert!(buffer[..512].iter().all(|&byte| byte == 0));
+    assert!(buffer[512..].iter().all(|&byte| byte == 0xAA));
+
+    assert!(matches!(
+        disk.read_sectors(3, 2, &mut buffer),
+        Err(BlockDeviceError::OutOfBounds { .. })
+    ));
+    assert!(matches!(
+        disk.write_sectors(0, 2, &[0; 512]),
+        Err(BlockDeviceError::BufferTooSmall { .. })
+    ));
+}
@@ -0,0 +1,407 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use diy_os::device_manager::{BlockDevice, BlockDeviceError};
+use diy_os::filesystem::{FileSystem, FileSystemError, FileTrait, INError, MountError, OUTError};
+use diy_os::multitasking::mutex::Mutex;
+use zerocopy::{FromBytes, FromZeros, IntoBytes};
+
+use crate::structs::{
+    DirectoryRecord, FIRST_VOLUME_DESCRIPTOR, MAX_VOLUME_DESCRIPTORS, SECTOR_SIZE, SystemUseEntry,
+    VolumeDescriptor, VolumeDescriptorType,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum IsoError {
+    #[error("The underlaying block device experienced an error")]
+    BlockDeviceError(#[from] BlockDeviceError),
+    #[error("The filesystem is corrupted, `{0}`")]
+    Corrupted(&'static str),
+}
+
+impl From<IsoError> for MountError {
+    fn from(value: IsoError) -> Self {
+        match value {
+            IsoError::BlockDeviceError(err) => Self::BlockDeviceError(err),
+            IsoError::Corrupted(reason) => Self::InvalidFileSystem(reason),
+        }
+    }
+}
+
+/// How the names of directory records are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NameFormat {
+    /// Upper case d-characters with a version suffix
+    Iso,
+    /// UCS-2 names from the Joliet supplementary volume descriptor
+    Joliet,
+    /// Alternate names in the system use area
+    RockRidge,
+}
+
+/// A directory record with its decoded name.
+struct Entry {
+    name: String,
+    record: DirectoryRecord,
+}
+
+pub struct IsoFS {
+    drive: Arc<Mutex<dyn BlockDevice>>,
+    root: DirectoryRecord,
+    names: NameFormat,
+    /// The size of the volume in sectors, from the primary volume descriptor
+    volume_sectors: u64,
+}
+
+impl IsoFS {
+    /// Reads the volume descriptors, picking the best names the disc has.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MountError`] if a read fails, there is no primary volume descriptor, or the
+    /// logical block size isn't 2048 bytes.
+    pub fn new(drive: Arc<Mutex<dyn BlockDevice>>) -> Result<Self, MountError> {
+        if drive.acquire().sector_size() != SECTOR_SIZE {
+            return Err(MountError::InvalidFileSystem(
+                "Only devices with 2048 byte sectors are supported",
+            ));
+        }
+
+        let mut fs = Self {
+            drive,
+            root: DirectoryRecord::new_zeroed(),
+            names: NameFormat::Iso,
+            volume_sectors: 0,
+        };
+
+        let mut primary = None;
+        let mut joliet = None;
+
+        for sector in FIRST_VOLUME_DESCRIPTOR..FIRST_VOLUME_DESCRIPTOR + MAX_VOLUME_DESCRIPTORS {
+            let mut descriptor = VolumeDescriptor::new_zeroed();
+            fs.read_bytes(sector * SECTOR_SIZE as u64, descriptor.as_mut_bytes())?;
+
+            if descriptor.identifier != VolumeDescriptor::IDENTIFIER {
+                return Err(MountError::InvalidFileSystem(
+                    "Invalid volume descriptor identifier",
+                ));
+            }
+
+            match descriptor.descriptor_type() {
+                Some(VolumeDescriptorType::Primary) if primary.is_none() => {
+                    primary = Some(descriptor);
+                }
+                Some(VolumeDescriptorType::Supplementary) if descriptor.is_joliet() => {
+                    joliet = Some(descriptor);
+                }
+                Some(VolumeDescriptorType::Terminator) => break,
+                _ => {}
+            }
+        }
+
+        let primary = primary.ok_or(MountError::InvalidFileSystem(
+            "Missing the primary volume descriptor",
+        ))?;
+
+        if usize::from(primary.logical_block_size.get()) != SECTOR_SIZE {
+            return Err(MountError::InvalidFileSystem(
+                "Only a logical block size of 2048 bytes is supported",
+            ));
+        }
+
+        fs.root = primary.root_directory();
+        fs.volume_sectors = u64::from(primary.volume_space_size.get());
+
+        if fs.uses_rock_ridge()? {
+            fs.names = NameFormat::RockRidge;
+        } else if let Some(joliet) = joliet {
+            fs.root = joliet.root_directory();
+            fs.names = NameFormat::Joliet;
+        }
+
+        log::debug!("mounted iso9660 using {:?} names", fs.names);
+
+        Ok(fs)
+    }
+
+    /// Reads `buffer.len()` bytes starting `offset` bytes into the volume.
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let mut drive = self.drive.acquire();
+        let sector_size_u64 = SECTOR_SIZE as u64;
+
+        let first_sector = offset / sector_size_u64;
+        let skip = usize::try_from(offset % sector_size_u64).unwrap();
+        let sectors = (skip + buffer.len()).div_ceil(SECTOR_SIZE);
+
+        let mut sector_buffer = vec![0u8; SECTOR_SIZE * sectors];
+
+        for (lba, chunk) in (first_sector..)
+            .step_by(usize::from(u16::MAX))
+            .zip(sector_buffer.chunks_mut(SECTOR_SIZE * usize::from(u16::MAX)))
+        {
+            drive.read_sectors(
+                lba,
+                u16::try_from(chunk.len() / SECTOR_SIZE).unwrap(),
+                chunk,
+            )?;
+        }
+
+        buffer.copy_from_slice(&sector_buffer[skip..skip + buffer.len()]);
+
+        Ok(())
+    }
+
+    /// Reads the whole extent described by `record`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`IsoError::Corrupted`] if the extent goes past the end of the volume, before
+    /// allocating for it.
+    fn read_extent(&self, record: &DirectoryRecord) -> Result<Vec<u8>, IsoError> {
+        let end = record
+            .data_length()
+            .div_ceil(SECTOR_SIZE as u64)
+            .checked_add(record.extent());
+
+        if end.is_none_or(|end| end > self.volume_sectors) {
+            return Err(IsoError::Corrupted("Extent is past the end of the volume"));
+        }
+
+        let length = usize::try_from(record.data_length())
+            .map_err(|_| IsoError::Corrupted("Extent is too large"))?;
+
+        let mut data = vec![0u8; length];
+        self.read_bytes(record.extent() * SECTOR_SIZE as u64, &mut data)?;
+
+        Ok(data)
+    }
+
+    /// Rock Ridge is in use if the `.` record of the root directory starts its system use area
+    /// with a SUSP indicator.
+    fn uses_rock_ridge(&self) -> Result<bool, IsoError> {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        self.read_bytes(self.root.extent() * SECTOR_SIZE as u64, &mut sector)?;
+
+        let Some((record, system_use)) = split_record(&sector) else {
+            return Err(IsoError::Corrupted("Invalid root directory"));
+        };
+
+        let system_use = system_use.get(system_use_offset(&record)..).unwrap_or(&[]);
+
+        Ok(system_use.starts_with(&SystemUseEntry::SHARING_PROTOCOL)
+            && system_use.get(4..6) == Some(&[0xBE, 0xEF]))
+    }
+
+    /// Reads the entries of the directory described by `record`, skipping `.` and `..`.
+    fn read_directory(&self, record: &DirectoryRecord) -> Result<Vec<Entry>, IsoError> {
+        let data = self.read_extent(record)?;
+        let mut entries = Vec::new();
+
+        // records never cross a sector boundary, the rest of the sector is zeroed
+        for sector in data.chunks(SECTOR_SIZE) {
+            let mut offset = 0;
+
+            while let Some(&length) = sector.get(offset)
+                && length != 0
+            {
+                let length = usize::from(length);
+
+                let Some((record, rest)) =
+                    sector.get(offset..offset + length).and_then(split_record)
+                else {
+                    return Err(IsoError::Corrupted("Invalid directory record"));
+                };
+
+                let identifier = rest
+                    .get(..usize::from(record.name_length))
+                    .ok_or(IsoError::Corrupted("Directory record name is too long"))?;
+
+                // `.` and `..` are stored as a single 0 or 1 byte
+                if !matches!(identifier, [0 | 1]) {
+                    let system_use = rest.get(system_use_offset(&record)..).unwrap_or(&[]);
+
+                    entries.push(Entry {
+                        name: self.decode_name(identifier, system_use),
+                        record,
+                    });
+                }
+
+                offset += length;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn decode_name(&self, identifier: &[u8], system_use: &[u8]) -> String {
+        match self.names {
+            NameFormat::RockRidge => {
+                rock_ridge_name(system_use).unwrap_or_else(|| iso_name(identifier))
+            }
+            NameFormat::Joliet => {
+                let name: String = char::decode_utf16(
+                    identifier
+                        .chunks_exact(2)
+                        .map(|unit| u16::from_be_bytes([unit[0], unit[1]])),
+                )
+                .map(|char| char.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect();
+
+                strip_version(&name)
+            }
+            NameFormat::Iso => iso_name(identifier),
+        }
+    }
+
+    /// Finds the record at `path`, names without Rock Ridge or Joliet are matched ignoring
+    /// case.
+    fn lookup(&self, path: &str) -> Result<Option<DirectoryRecord>, IsoError> {
+        let mut current = self.root;
+
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            if !current.is_directory() {
+                return Ok(None);
+            }
+
+            let found = self.read_directory(&current)?.into_iter().find(|entry| {
+                if self.names == NameFormat::Iso {
+                    entry.name.eq_ignore_ascii_case(component)
+                } else {
+                    entry.name == component
+                }
+            });
+
+            let Some(entry) = found else {
+                return Ok(None);
+            };
+
+            current = entry.record;
+        }
+
+        Ok(Some(current))
+    }
+}
+
+/// Splits a directory record from the identifier and system use area that follow it.
+fn split_record(bytes: &[u8]) -> Option<(DirectoryRecord, &[u8])> {
+    let (record, rest) = DirectoryRecord::read_from_prefix(bytes).ok()?;
+
+    let length = usize::from(record.length).checked_sub(size_of::<DirectoryRecord>())?;
+
+    Some((record, rest.get(..length)?))
+}
+
+/// The system use area starts after the identifier, padded to an even offset.
+const fn system_use_offset(record: &DirectoryRecord) -> usize {
+    let name_length = record.name_length as usize;
+
+    name_length + (name_length + 1) % 2
+}
+
+/// Collects the Rock Ridge alternate name from the `NM` entries of a system use area.
+fn rock_ridge_name(system_use: &[u8]) -> Option<String> {
+    let mut name = String::new();
+    let mut offset = 0;
+
+    while let Some((entry, _)) = system_use
+        .get(offset..)
+        .and_then(|rest| SystemUseEntry::read_from_prefix(rest).ok())
+    {
+        let length = usize::from(entry.length);
+
+        // a length too small to hold the header is padding
+        if length < size_of::<SystemUseEntry>() {
+            break;
+        }
+
+        let data = system_use.get(offset + size_of::<SystemUseEntry>()..offset + length)?;
+
+        if entry.signature == SystemUseEntry::ALTERNATE_NAME
+            && let [flags, component @ ..] = data
+        {
+            name.push_str(&String::from_utf8_lossy(component));
+
+            if flags & SystemUseEntry::NAME_CONTINUES == 0 {
+                return Some(name);
+            }
+        }
+
+        offset += length;
+    }
+
+    (!name.is_empty()).then_some(name)
+}
+
+/// Decodes a plain ISO 9660 name, dropping the version and the `.` of names without an
+/// extension.
+fn iso_name(identifier: &[u8]) -> String {
+    let name = strip_version(&String::from_utf8_lossy(identifier));
+
+    String::from(name.strip_suffix('.').unwrap_or(&name))
+}
+
+fn strip_version(name: &str) -> String {
+    String::from(name.split_once(';').map_or(name, |(name, _)| name))
+}
+
+impl FileSystem for IsoFS {
+    fn open(&mut self, path: &str) -> Option<Box<dyn FileTrait + '_>> {
+        let record = self
+            .lookup(path)
+            .inspect_err(|err| log::error!("failed to open {path}, {err}"))
+            .ok()??;
+
+        if record.is_directory() {
+            return None;
+        }
+
+        Some(Box::new(IsoFile { fs: self, record }))
+    }
+
+    fn create(&mut self, _path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+
+    fn mkdir(&mut self, _path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+
+    fn unlink(&mut self, _path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+}
+
+struct IsoFile<'a> {
+    fs: &'a IsoFS,
+    record: DirectoryRecord,
+}
+
+impl FileTrait for IsoFile<'_> {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, INError> {
+        let length = usize::try_from(self.record.data_length())
+            .unwrap_or(usize::MAX)
+            .min(buf.len());
+
+        self.fs
+            .read_bytes(
+                self.record.extent() * SECTOR_SIZE as u64,
+                &mut buf[..length],
+            )
+            .map_err(|err| {
+                log::error!("failed to read extent {}, {err}", self.record.extent());
+                INError::NotReadable
+            })?;
+
+        Ok(length)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, OUTError> {
+        Err(OUTError::NotWritable)
+    }
+}
@@ -0,0 +1,32 @@
+#![no_std]
+
+mod filesystem;
+mod structs;
+
+use alloc::{boxed::Box, sync::Arc};
+
+use diy_os::{
+    device_manager::BlockDevice,
+    filesystem::{FileSystem, MountError},
+    multitasking::mutex::Mutex,
+};
+
+use crate::filesystem::IsoFS;
+
+extern crate alloc;
+
+/// Mounts the read only ISO 9660 filesystem on `device`, a CD or an image of one with 2048 byte
+/// sectors.
+///
+/// Rock Ridge names are used if the disc has them, then Joliet names, falling back to the
+/// plain ISO 9660 names.
+///
+/// # Errors
+///
+/// Returns [`MountError`] if reading the volume descriptors fails or the filesystem is invalid
+/// or unsupported.
+pub fn iso9660_setup(
+    device: Arc<Mutex<dyn BlockDevice>>,
+) -> Result<Box<dyn FileSystem>, MountError> {
+    Ok(Box::new(IsoFS::new(device)?))
+}
@@ -0,0 +1,173 @@
+// reference docs at https://web.archive.org/web/20250308140931/https://wiki.osdev.org/ISO_9660
+// and https://web.archive.org/web/20250227175011/https://en.wikipedia.org/wiki/ISO_9660
+use zerocopy::{
+    FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned, big_endian,
+    little_endian::{U16, U32},
+};
+
+/// The size of a sector, and the logical block size of every disc this driver supports.
+pub const SECTOR_SIZE: usize = 2048;
+
+/// The volume descriptors start after the system area.
+pub const FIRST_VOLUME_DESCRIPTOR: u64 = 16;
+
+/// Volume descriptors are read at most this many times, in case the set isn't terminated.
+pub const MAX_VOLUME_DESCRIPTORS: u64 = 64;
+
+/// A little endian value followed by the same value in big endian, only the little endian half
+/// is read.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct BothEndianU16 {
+    little: U16,
+    big: big_endian::U16,
+}
+
+impl BothEndianU16 {
+    pub const fn get(self) -> u16 {
+        self.little.get()
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct BothEndianU32 {
+    little: U32,
+    big: big_endian::U32,
+}
+
+impl BothEndianU32 {
+    pub const fn get(self) -> u32 {
+        self.little.get()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum VolumeDescriptorType {
+    BootRecord = 0,
+    Primary = 1,
+    Supplementary = 2,
+    Partition = 3,
+    Terminator = 255,
+}
+
+/// The primary and supplementary volume descriptors share a layout, a supplementary descriptor
+/// with a Joliet escape sequence stores its names in UCS-2.
+#[derive(Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct VolumeDescriptor {
+    pub descriptor_type: u8,
+    /// Always `CD001`
+    pub identifier: [u8; 5],
+    pub version: u8,
+    pub flags: u8,
+    pub system_identifier: [u8; 32],
+    pub volume_identifier: [u8; 32],
+    _unused: [u8; 8],
+    pub volume_space_size: BothEndianU32,
+    pub escape_sequences: [u8; 32],
+    pub volume_set_size: BothEndianU16,
+    pub volume_sequence_number: BothEndianU16,
+    pub logical_block_size: BothEndianU16,
+    pub path_table_size: BothEndianU32,
+    pub l_path_table: U32,
+    pub optional_l_path_table: U32,
+    pub m_path_table: big_endian::U32,
+    pub optional_m_path_table: big_endian::U32,
+    pub root_directory: [u8; 34],
+    _rest: [u8; 1858],
+}
+
+impl VolumeDescriptor {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == SECTOR_SIZE);
+    };
+
+    pub const IDENTIFIER: [u8; 5] = *b"CD001";
+
+    /// The escape sequences for UCS-2 levels 1, 2 and 3.
+    const JOLIET_ESCAPE_SEQUENCES: [[u8; 3]; 3] = [*b"%/@", *b"%/C", *b"%/E"];
+
+    pub const fn descriptor_type(&self) -> Option<VolumeDescriptorType> {
+        match self.descriptor_type {
+            0 => Some(VolumeDescriptorType::BootRecord),
+            1 => Some(VolumeDescriptorType::Primary),
+            2 => Some(VolumeDescriptorType::Supplementary),
+            3 => Some(VolumeDescriptorType::Partition),
+            255 => Some(VolumeDescriptorType::Terminator),
+            _ => None,
+        }
+    }
+
+    pub fn is_joliet(&self) -> bool {
+        self.descriptor_type() == Some(VolumeDescriptorType::Supplementary)
+            && Self::JOLIET_ESCAPE_SEQUENCES
+                .iter()
+                .any(|sequence| self.escape_sequences.starts_with(sequence))
+    }
+
+    pub fn root_directory(&self) -> DirectoryRecord {
+        DirectoryRecord::read_from_prefix(&self.root_directory)
+            .unwrap()
+            .0
+    }
+}
+
+/// The fixed part of a directory record, followed by the name and the system use area.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct DirectoryRecord {
+    /// The length of the whole record, 0 means the rest of the sector is padding
+    pub length: u8,
+    pub extended_attribute_length: u8,
+    pub extent: BothEndianU32,
+    pub data_length: BothEndianU32,
+    pub recording_date: [u8; 7],
+    pub flags: u8,
+    pub file_unit_size: u8,
+    pub interleave_gap_size: u8,
+    pub volume_sequence_number: BothEndianU16,
+    pub name_length: u8,
+}
+
+impl DirectoryRecord {
+    const _SIZE_CHECK: () = const {
+        assert!(size_of::<Self>() == 33);
+    };
+
+    const FLAG_DIRECTORY: u8 = 1 << 1;
+
+    pub const fn is_directory(&self) -> bool {
+        self.flags & Self::FLAG_DIRECTORY != 0
+    }
+
+    /// The first logical block of the data, sectors for every supported disc.
+    pub const fn extent(&self) -> u64 {
+        self.extent.get() as u64 + self.extended_attribute_length as u64
+    }
+
+    pub const fn data_length(&self) -> u64 {
+        self.data_length.get() as u64
+    }
+}
+
+/// The header of a System Use Sharing Protocol entry, Rock Ridge stores its extensions as
+/// these in the system use area of a directory record.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+#[repr(C)]
+pub struct SystemUseEntry {
+    pub signature: [u8; 2],
+    pub length: u8,
+    pub version: u8,
+}
+
+impl SystemUseEntry {
+    /// Marks the use of SUSP, found in the `.` record of the root directory.
+    pub const SHARING_PROTOCOL: [u8; 2] = *b"SP";
+    /// A Rock Ridge alternate name.
+    pub const ALTERNATE_NAME: [u8; 2] = *b"NM";
+
+    /// Set in the flags of an alternate name that continues in the next `NM` entry.
+    pub const NAME_CONTINUES: u8 = 1 << 0;
+}
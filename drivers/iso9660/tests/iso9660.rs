@@ -0,0 +1,399 @@
+//! Mounts small ISO 9660 images built by hand, with plain, Joliet and Rock Ridge names.
+
+use std::sync::Arc;
+
+use diy_os::device_manager::BlockDevice;
+use diy_os::device_manager::ramdisk::RamDisk;
+use diy_os::filesystem::{FileSystem, MountError};
+use diy_os::multitasking::mutex::Mutex;
+
+const SECTOR_SIZE: usize = 2048;
+
+const HELLO: &[u8] = b"hello from iso9660\n";
+const README: &[u8] = b"read me\n";
+const NOTES: &[u8] = b"some notes\n";
+
+const ROOT: u32 = 20;
+const JOLIET_ROOT: u32 = 21;
+const HELLO_EXTENT: u32 = 22;
+const README_EXTENT: u32 = 23;
+const NOTES_EXTENT: u32 = 24;
+const DOCS: u32 = 25;
+const JOLIET_DOCS: u32 = 26;
+const VOLUME_SECTORS: u32 = 27;
+
+fn put(buffer: &mut [u8], offset: usize, bytes: &[u8]) {
+    buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+fn both_endian_u16(value: u16) -> [u8; 4] {
+    let mut bytes = [0; 4];
+    put(&mut bytes, 0, &value.to_le_bytes());
+    put(&mut bytes, 2, &value.to_be_bytes());
+    bytes
+}
+
+fn both_endian_u32(value: u32) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    put(&mut bytes, 0, &value.to_le_bytes());
+    put(&mut bytes, 4, &value.to_be_bytes());
+    bytes
+}
+
+fn length(data: &[u8]) -> u32 {
+    u32::try_from(data.len()).unwrap()
+}
+
+fn ucs2(name: &str) -> Vec<u8> {
+    name.encode_utf16().flat_map(u16::to_be_bytes).collect()
+}
+
+/// A directory record, the identifier is padded to an even length before the system use area.
+fn record(
+    extent: u32,
+    length: u32,
+    directory: bool,
+    identifier: &[u8],
+    system_use: &[u8],
+) -> Vec<u8> {
+    let mut record = vec![0; 33];
+
+    put(&mut record, 2, &both_endian_u32(extent));
+    put(&mut record, 10, &both_endian_u32(length));
+    record[25] = if directory { 1 << 1 } else { 0 };
+    put(&mut record, 28, &both_endian_u16(1));
+    record[32] = u8::try_from(identifier.len()).unwrap();
+
+    record.extend_from_slice(identifier);
+    if identifier.len().is_multiple_of(2) {
+        record.push(0);
+    }
+
+    record.extend_from_slice(system_use);
+    if !record.len().is_multiple_of(2) {
+        record.push(0);
+    }
+
+    record[0] = u8::try_from(record.len()).unwrap();
+    record
+}
+
+/// Rock Ridge `NM` entries holding `parts`, every one but the last continues the name.
+fn alternate_name(parts: &[&str]) -> Vec<u8> {
+    parts
+        .iter()
+        .enumerate()
+        .flat_map(|(i, part)| {
+            let flags = u8::from(i + 1 < parts.len());
+            let length = u8::try_from(5 + part.len()).unwrap();
+
+            [b'N', b'M', length, 1, flags]
+                .into_iter()
+                .chain(part.bytes())
+        })
+        .collect()
+}
+
+/// A file or directory under one of the directories of the image.
+struct Node {
+    iso: &'static str,
+    joliet: &'static str,
+    rock_ridge: &'static [&'static str],
+    extent: u32,
+    length: u32,
+    directory: bool,
+}
+
+/// An image holding `HELLO.TXT`, `README.` and `DOCS/NOTES.TXT` under different names for each
+/// name format.
+struct Image {
+    joliet: bool,
+    rock_ridge: bool,
+    /// The volume size stored in the primary volume descriptor
+    volume_sectors: u32,
+    /// The data length of both `DOCS` records
+    docs_length: u32,
+}
+
+impl Default for Image {
+    fn default() -> Self {
+        Self {
+            joliet: false,
+            rock_ridge: false,
+            volume_sectors: VOLUME_SECTORS,
+            docs_length: 2048,
+        }
+    }
+}
+
+impl Image {
+    fn root(&self, docs: u32) -> [Node; 3] {
+        [
+            Node {
+                iso: "HELLO.TXT;1",
+                joliet: "Hello World.txt;1",
+                rock_ridge: &["hello.txt"],
+                extent: HELLO_EXTENT,
+                length: length(HELLO),
+                directory: false,
+            },
+            Node {
+                iso: "README.;1",
+                joliet: "ReadMe;1",
+                rock_ridge: &["a name split over ", "two entries"],
+                extent: README_EXTENT,
+                length: length(README),
+                directory: false,
+            },
+            Node {
+                iso: "DOCS",
+                joliet: "Docs",
+                rock_ridge: &["docs"],
+                extent: docs,
+                length: self.docs_length,
+                directory: true,
+            },
+        ]
+    }
+
+    fn docs() -> [Node; 1] {
+        [Node {
+            iso: "NOTES.TXT;1",
+            joliet: "Notes.txt;1",
+            rock_ridge: &["notes.txt"],
+            extent: NOTES_EXTENT,
+            length: length(NOTES),
+            directory: false,
+        }]
+    }
+
+    /// The extent of a directory, starting with `.` and `..`.
+    fn directory(&self, nodes: &[Node], joliet: bool, root: bool) -> Vec<u8> {
+        let rock_ridge = self.rock_ridge && !joliet;
+
+        // the SUSP indicator, only read from the `.` record of the root
+        let sharing_protocol: &[u8] = if rock_ridge && root {
+            &[b'S', b'P', 7, 1, 0xBE, 0xEF, 0]
+        } else {
+            &[]
+        };
+
+        let mut directory = record(0, 2048, true, &[0], sharing_protocol);
+        directory.extend(record(0, 2048, true, &[1], &[]));
+
+        for node in nodes {
+            let (identifier, system_use) = if joliet {
+                (ucs2(node.joliet), Vec::new())
+            } else if rock_ridge {
+                (
+                    node.iso.as_bytes().to_vec(),
+                    alternate_name(node.rock_ridge),
+                )
+            } else {
+                (node.iso.as_bytes().to_vec(), Vec::new())
+            };
+
+            directory.extend(record(
+                node.extent,
+                node.length,
+                node.directory,
+                &identifier,
+                &system_use,
+            ));
+        }
+
+        directory
+    }
+
+    fn volume_descriptor(&self, descriptor_type: u8, root: u32, escape: &[u8]) -> Vec<u8> {
+        let mut descriptor = vec![0; SECTOR_SIZE];
+
+        descriptor[0] = descriptor_type;
+        put(&mut descriptor, 1, b"CD001");
+        descriptor[6] = 1;
+        put(&mut descriptor, 80, &both_endian_u32(self.volume_sectors));
+        put(&mut descriptor, 88, escape);
+        put(&mut descriptor, 120, &both_endian_u16(1));
+        put(&mut descriptor, 124, &both_endian_u16(1));
+        put(&mut descriptor, 128, &both_endian_u16(2048));
+        put(&mut descriptor, 156, &record(root, 2048, true, &[0], &[]));
+
+        descriptor
+    }
+
+    fn build(&self) -> Vec<u8> {
+        let mut bytes = vec![0; VOLUME_SECTORS as usize * SECTOR_SIZE];
+        let sector = |sector: u32| sector as usize * SECTOR_SIZE;
+
+        put(
+            &mut bytes,
+            sector(16),
+            &self.volume_descriptor(1, ROOT, &[]),
+        );
+
+        let mut terminator = 17;
+        if self.joliet {
+            put(
+                &mut bytes,
+                sector(17),
+                &self.volume_descriptor(2, JOLIET_ROOT, b"%/E"),
+            );
+            terminator += 1;
+        }
+
+        put(
+            &mut bytes,
+            sector(terminator),
+            &[255, b'C', b'D', b'0', b'0', b'1', 1],
+        );
+
+        put(
+            &mut bytes,
+            sector(ROOT),
+            &self.directory(&self.root(DOCS), false, true),
+        );
+        put(
+            &mut bytes,
+            sector(DOCS),
+            &self.directory(&Self::docs(), false, false),
+        );
+        put(
+            &mut bytes,
+            sector(JOLIET_ROOT),
+            &self.directory(&self.root(JOLIET_DOCS), true, true),
+        );
+        put(
+            &mut bytes,
+            sector(JOLIET_DOCS),
+            &self.directory(&Self::docs(), true, false),
+        );
+
+        put(&mut bytes, sector(HELLO_EXTENT), HELLO);
+        put(&mut bytes, sector(README_EXTENT), README);
+        put(&mut bytes, sector(NOTES_EXTENT), NOTES);
+
+        bytes
+    }
+
+    fn mount(&self) -> Result<Box<dyn FileSystem>, MountError> {
+        mount(RamDisk::from_bytes(self.build(), SECTOR_SIZE))
+    }
+}
+
+fn mount(disk: RamDisk) -> Result<Box<dyn FileSystem>, MountError> {
+    iso9660::iso9660_setup(Arc::new(Mutex::new(disk)) as Arc<Mutex<dyn BlockDevice>>)
+}
+
+fn read(fs: &mut dyn FileSystem, path: &str) -> Option<Vec<u8>> {
+    let file = fs.open(path)?;
+
+    let mut buffer = vec![0; SECTOR_SIZE];
+    let read = file.read(&mut buffer).unwrap();
+    buffer.truncate(read);
+
+    Some(buffer)
+}
+
+#[test]
+fn plain_names() {
+    let mut fs = Image::default().mount().unwrap();
+
+    assert_eq!(read(&mut *fs, "/HELLO.TXT").unwrap(), HELLO);
+    assert_eq!(read(&mut *fs, "/hello.txt").unwrap(), HELLO);
+    assert_eq!(read(&mut *fs, "/README").unwrap(), README);
+    assert_eq!(read(&mut *fs, "/docs/notes.txt").unwrap(), NOTES);
+
+    // directories can't be opened as files
+    assert!(read(&mut *fs, "/DOCS").is_none());
+    assert!(read(&mut *fs, "/DOCS/MISSING.TXT").is_none());
+    assert!(read(&mut *fs, "/HELLO.TXT/CHILD").is_none());
+}
+
+#[test]
+fn joliet_names() {
+    let mut fs = Image {
+        joliet: true,
+        ..Image::default()
+    }
+    .mount()
+    .unwrap();
+
+    assert_eq!(read(&mut *fs, "/Hello World.txt").unwrap(), HELLO);
+    assert_eq!(read(&mut *fs, "/ReadMe").unwrap(), README);
+    assert_eq!(read(&mut *fs, "/Docs/Notes.txt").unwrap(), NOTES);
+
+    // Joliet names are matched exactly, the plain names aren't used
+    assert!(read(&mut *fs, "/hello world.txt").is_none());
+    assert!(read(&mut *fs, "/HELLO.TXT").is_none());
+}
+
+#[test]
+fn rock_ridge_names() {
+    // Rock Ridge is preferred over Joliet
+    let mut fs = Image {
+        joliet: true,
+        rock_ridge: true,
+        ..Image::default()
+    }
+    .mount()
+    .unwrap();
+
+    assert_eq!(read(&mut *fs, "/hello.txt").unwrap(), HELLO);
+    assert_eq!(
+        read(&mut *fs, "/a name split over two entries").unwrap(),
+        README
+    );
+    assert_eq!(read(&mut *fs, "/docs/notes.txt").unwrap(), NOTES);
+
+    assert!(read(&mut *fs, "/HELLO.TXT").is_none());
+    assert!(read(&mut *fs, "/Hello World.txt").is_none());
+}
+
+#[test]
+fn extents_past_the_end_of_the_volume() {
+    // `DOCS` is still on the disk, only past the size in the primary volume descriptor
+    let mut fs = Image {
+        volume_sectors: DOCS,
+        ..Image::default()
+    }
+    .mount()
+    .unwrap();
+
+    assert_eq!(read(&mut *fs, "/HELLO.TXT").unwrap(), HELLO);
+    assert!(read(&mut *fs, "/DOCS/NOTES.TXT").is_none());
+
+    // rejected before allocating for it
+    let mut fs = Image {
+        docs_length: u32::MAX,
+        ..Image::default()
+    }
+    .mount()
+    .unwrap();
+
+    assert!(read(&mut *fs, "/DOCS/NOTES.TXT").is_none());
+}
+
+#[test]
+fn only_2048_byte_sectors() {
+    let disk = RamDisk::from_bytes(Image::default().build(), 512);
+
+    assert!(matches!(
+        mount(disk).err(),
+        Some(MountError::InvalidFileSystem(
+            "Only devices with 2048 byte sectors are supported"
+        ))
+    ));
+}
+
+#[test]
+fn invalid_volume_descriptor_identifier() {
+    let mut bytes = Image::default().build();
+    bytes[16 * SECTOR_SIZE + 1] = b'X';
+
+    assert!(matches!(
+        mount(RamDisk::from_bytes(bytes, SECTOR_SIZE)).err(),
+        Some(MountError::InvalidFileSystem(
+            "Invalid volume descriptor identifier"
+        ))
+    ));
+}
@@ -6,13 +6,18 @@ fn main() {
 
     // set by cargo for the kernel artifact dependency
     let kernel_path = env::var("CARGO_BIN_FILE_KERNEL").unwrap();
-    let disk_builder = DiskImageBuilder::new(PathBuf::from(kernel_path));
+    let mut disk_builder = DiskImageBuilder::new(PathBuf::from(kernel_path));
 
     // specify output paths
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     let uefi_path = out_dir.join("os-uefi.img");
 
-    // disk_builder.set_ramdisk("bin/hello_world.tar".into());
+    // the ramdisk is mounted at /initrd, build it with make_tar.sh
+    let ramdisk_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../bin/hello_world.tar");
+    println!("cargo:rerun-if-changed={}", ramdisk_path.display());
+    if ramdisk_path.exists() {
+        disk_builder.set_ramdisk(ramdisk_path);
+    }
 
     // create the disk images
     disk_builder.create_uefi_image(&uefi_path).unwrap();
@@ -12,7 +12,7 @@ use core::{
 #[derive(Debug)]
 pub struct Spinlock<T: ?Sized> {
     locked: AtomicBool,
-    #[cfg(not(test))]
+    #[cfg(not(any(test, feature = "host")))]
     interrupts_enabled: UnsafeCell<Option<bool>>,
     data: UnsafeCell<T>,
 }
@@ -21,7 +21,7 @@ impl<T> Spinlock<T> {
     pub const fn new(data: T) -> Self {
         Self {
             locked: AtomicBool::new(false),
-            #[cfg(not(test))]
+            #[cfg(not(any(test, feature = "host")))]
             interrupts_enabled: UnsafeCell::new(None),
             data: UnsafeCell::new(data),
         }
@@ -61,8 +61,9 @@ impl<T: ?Sized> Spinlock<T> {
         }
     }
 
+    #[cfg_attr(any(test, feature = "host"), allow(clippy::unused_self))]
     fn enable_interrupts(&self) {
-        #[cfg(not(test))]
+        #[cfg(not(any(test, feature = "host")))]
         unsafe {
             if (*self.interrupts_enabled.get()) == Some(true) {
                 x86_64::instructions::interrupts::enable();
@@ -70,13 +71,14 @@ impl<T: ?Sized> Spinlock<T> {
         }
     }
 
+    #[cfg_attr(any(test, feature = "host"), allow(clippy::unused_self))]
     fn disable_interrupts(&self) {
-        #[cfg(not(test))]
+        #[cfg(not(any(test, feature = "host")))]
         unsafe {
             *self.interrupts_enabled.get() = Some(x86_64::instructions::interrupts::are_enabled());
         }
 
-        #[cfg(not(test))]
+        #[cfg(not(any(test, feature = "host")))]
         x86_64::instructions::interrupts::disable();
     }
 
@@ -178,7 +180,7 @@ impl<T: fmt::Display + ?Sized> fmt::Display for SpinlockGuard<'_, T> {
 #[derive(Debug)]
 pub struct SpinlockWithCallback<T: ?Sized> {
     locked: AtomicBool,
-    #[cfg(not(test))]
+    #[cfg(not(any(test, feature = "host")))]
     interrupts_enabled: UnsafeCell<Option<bool>>,
     callback: fn(&'static str) -> (),
     data: UnsafeCell<T>,
@@ -188,7 +190,7 @@ impl<T> SpinlockWithCallback<T> {
     pub const fn new(data: T, callback: fn(&'static str) -> ()) -> Self {
         Self {
             locked: AtomicBool::new(false),
-            #[cfg(not(test))]
+            #[cfg(not(any(test, feature = "host")))]
             interrupts_enabled: UnsafeCell::new(None),
             callback,
             data: UnsafeCell::new(data),
@@ -236,8 +238,9 @@ impl<T: ?Sized> SpinlockWithCallback<T> {
         }
     }
 
+    #[cfg_attr(any(test, feature = "host"), allow(clippy::unused_self))]
     fn enable_interrupts(&self) {
-        #[cfg(not(test))]
+        #[cfg(not(any(test, feature = "host")))]
         unsafe {
             if (*self.interrupts_enabled.get()) == Some(true) {
                 x86_64::instructions::interrupts::enable();
@@ -245,13 +248,14 @@ impl<T: ?Sized> SpinlockWithCallback<T> {
         }
     }
 
+    #[cfg_attr(any(test, feature = "host"), allow(clippy::unused_self))]
     fn disable_interrupts(&self) {
-        #[cfg(not(test))]
+        #[cfg(not(any(test, feature = "host")))]
         unsafe {
             *self.interrupts_enabled.get() = Some(x86_64::instructions::interrupts::are_enabled());
         }
 
-        #[cfg(not(test))]
+        #[cfg(not(any(test, feature = "host")))]
         x86_64::instructions::interrupts::disable();
     }
 